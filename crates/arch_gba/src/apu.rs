@@ -0,0 +1,31 @@
+/*
+ * Sound. Sound generation is not emulated yet, only the power state that stop mode controls.
+ */
+#[derive(Debug)]
+pub struct Apu {
+    powered: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu { powered: true }
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    pub fn power_down(&mut self) {
+        self.powered = false;
+    }
+
+    pub fn power_up(&mut self) {
+        self.powered = true;
+    }
+}
@@ -0,0 +1,336 @@
+use super::eeprom::{Eeprom, EepromSize};
+use super::flash::{Flash, FlashChip};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use util::save::{self, DetectionSource, SaveType};
+
+/*
+ * Cartridge backup memory, the battery backed chip games save to. See GBATEK "GBA Cart Backup
+ * IDs" and "GBA Cart Backup SRAM/FRAM".
+ *
+ *   SRAM  32KB at 0x0E000000, mirrored, 8 bit bus
+ *   Flash 64KB or 128KB in two banks at 0x0E000000, mirrored, 8 bit bus, see flash.rs
+ *   EEPROM 512 bytes or 8KB, bit serial in the upper ROM mirror at 0x0D000000, see eeprom.rs
+ *
+ * The contents can be kept in a save file. Writes mark it dirty and the bus schedules a
+ * SaveFlush event; the file is written once no write happened for FLUSH_DELAY cycles, so a game
+ * saving byte by byte writes the file once. The file is replaced by writing a temporary file
+ * next to it and renaming it over, a crash leaves either the old or the new save, never a mix.
+ */
+pub const SRAM_SIZE: usize = 32 * 1024;
+
+// About a second.
+pub const FLUSH_DELAY: u64 = 1 << 24;
+
+// The save file of a ROM, "game.gba" saves to "game.sav".
+pub fn save_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("sav")
+}
+
+#[derive(Debug)]
+pub struct Sram {
+    data: Vec<u8>,
+}
+
+impl Sram {
+    pub fn new() -> Self {
+        Sram {
+            data: vec![0xFF; SRAM_SIZE],
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        self.data[addr as usize % SRAM_SIZE]
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8) {
+        self.data[addr as usize % SRAM_SIZE] = value;
+    }
+}
+
+impl Default for Sram {
+    fn default() -> Self {
+        Sram::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum BackupChip {
+    None,
+    Sram(Sram),
+    Flash(Flash),
+    Eeprom(Eeprom),
+}
+
+#[derive(Debug)]
+struct SaveFile {
+    path: PathBuf,
+    dirty: bool,
+    last_write: u64,
+    // A SaveFlush event is scheduled.
+    flush_pending: bool,
+    // The last failed write from a SaveFlush event, see take_error.
+    last_error: Option<io::Error>,
+}
+
+#[derive(Debug)]
+pub struct Backup {
+    pub chip: BackupChip,
+    file: Option<SaveFile>,
+}
+
+impl Backup {
+    pub fn new(chip: BackupChip) -> Self {
+        Backup { chip, file: None }
+    }
+
+    /*
+     * The chip the backup library linked into the ROM talks to, see util::save. ROMs without an ID
+     * string, like most homebrew, get SRAM, which needs no protocol.
+     */
+    pub fn for_game(rom: &[u8]) -> Self {
+        let detection = save::detect_image(rom);
+        let chip = match detection.save_type {
+            SaveType::None if detection.source == DetectionSource::Override => BackupChip::None,
+            SaveType::None | SaveType::Sram => BackupChip::Sram(Sram::new()),
+            SaveType::Flash64K => BackupChip::Flash(Flash::new(FlashChip::Panasonic)),
+            SaveType::Flash128K => BackupChip::Flash(Flash::new(FlashChip::Sanyo)),
+            SaveType::Eeprom => BackupChip::Eeprom(Eeprom::new(None)),
+        };
+        Backup::new(chip)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match &self.chip {
+            BackupChip::None => &[],
+            BackupChip::Sram(sram) => &sram.data,
+            BackupChip::Flash(flash) => &flash.data,
+            BackupChip::Eeprom(eeprom) => eeprom.contents(),
+        }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        match &mut self.chip {
+            BackupChip::None => &mut [],
+            BackupChip::Sram(sram) => &mut sram.data,
+            BackupChip::Flash(flash) => &mut flash.data,
+            BackupChip::Eeprom(eeprom) => &mut eeprom.data,
+        }
+    }
+
+    /*
+     * Keeps the contents in the file at path, loading them from it if it exists. A file of a
+     * different size is loaded as far as it fits, the rest keeps the erased value.
+     */
+    pub fn attach_file(&mut self, path: &Path) -> io::Result<()> {
+        match fs::read(path) {
+            Ok(contents) => {
+                if let BackupChip::Eeprom(eeprom) = &mut self.chip {
+                    eeprom.infer_size(EepromSize::from_save_len(contents.len()));
+                }
+                let data = self.data_mut();
+                let len = contents.len().min(data.len());
+                data[..len].copy_from_slice(&contents[..len]);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.file = Some(SaveFile {
+            path: path.to_path_buf(),
+            dirty: false,
+            last_write: 0,
+            flush_pending: false,
+            last_error: None,
+        });
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|f| f.path.as_path())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.file.as_ref().is_some_and(|f| f.dirty)
+    }
+
+    // Reads from 0x0E000000-0x0FFFFFFF. Without a chip the data bus floats high.
+    pub fn read_8(&self, addr: u32, now: u64) -> u8 {
+        match &self.chip {
+            BackupChip::None | BackupChip::Eeprom(_) => 0xFF,
+            BackupChip::Sram(sram) => sram.read(addr),
+            BackupChip::Flash(flash) => flash.read(addr, now),
+        }
+    }
+
+    // Returns true if a SaveFlush event has to be scheduled.
+    pub fn write_8(&mut self, addr: u32, value: u8, now: u64) -> bool {
+        let modified = match &mut self.chip {
+            BackupChip::None | BackupChip::Eeprom(_) => false,
+            BackupChip::Sram(sram) => {
+                sram.write(addr, value);
+                true
+            }
+            BackupChip::Flash(flash) => flash.write(addr, value, now),
+        };
+        modified && self.touch(now)
+    }
+
+    pub fn eeprom(&self) -> Option<&Eeprom> {
+        match &self.chip {
+            BackupChip::Eeprom(eeprom) => Some(eeprom),
+            _ => None,
+        }
+    }
+
+    pub fn eeprom_mut(&mut self) -> Option<&mut Eeprom> {
+        match &mut self.chip {
+            BackupChip::Eeprom(eeprom) => Some(eeprom),
+            _ => None,
+        }
+    }
+
+    // Sends a bit to the EEPROM. Returns true if a SaveFlush event has to be scheduled.
+    pub fn write_eeprom(&mut self, value: u16, now: u64) -> bool {
+        let modified = match self.eeprom_mut() {
+            Some(eeprom) => eeprom.write(value, now),
+            None => false,
+        };
+        modified && self.touch(now)
+    }
+
+    fn touch(&mut self, now: u64) -> bool {
+        match &mut self.file {
+            Some(file) => {
+                file.dirty = true;
+                file.last_write = now;
+                let schedule = !file.flush_pending;
+                file.flush_pending = true;
+                schedule
+            }
+            None => false,
+        }
+    }
+
+    /*
+     * Handles the SaveFlush event. Writes the file if the game stopped writing, otherwise
+     * returns the time to check again. A failed write is kept for take_error and retried after
+     * the next write to the chip.
+     */
+    pub fn handle_flush(&mut self, now: u64) -> Option<u64> {
+        let file = self.file.as_mut()?;
+        let due = file.last_write + FLUSH_DELAY;
+        if now < due {
+            return Some(due);
+        }
+        file.flush_pending = false;
+        if let Err(e) = self.flush() {
+            if let Some(file) = &mut self.file {
+                file.last_error = Some(e);
+            }
+        }
+        None
+    }
+
+    // The error of the last failed write from a SaveFlush event, once.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.file.as_mut()?.last_error.take()
+    }
+
+    // Writes the save file now if it has unsaved changes, e.g. on shutdown.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let (path, dirty) = match &self.file {
+            Some(file) => (file.path.clone(), file.dirty),
+            None => return Ok(()),
+        };
+        if dirty {
+            write_atomic(&path, self.data())?;
+            if let Some(file) = &mut self.file {
+                file.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Backup {
+    fn default() -> Self {
+        Backup::new(BackupChip::Sram(Sram::new()))
+    }
+}
+
+// Last chance for unsaved changes, errors can only be ignored here.
+impl Drop for Backup {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arch_gba-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("game.sav")
+    }
+
+    #[test]
+    fn test_save_file() {
+        let path = temp_path("save_file");
+        let _ = fs::remove_file(&path);
+        let mut backup = Backup::default();
+        backup.attach_file(&path).unwrap();
+        assert_eq!(backup.read_8(0x0E00_0000, 0), 0xFF);
+
+        // Writes are debounced, the flush waits for FLUSH_DELAY without writes
+        assert!(backup.write_8(0x0E00_0000, 0x12, 100));
+        assert!(!backup.write_8(0x0E00_8001, 0x34, 200));
+        assert_eq!(backup.read_8(0x0E00_0001, 0), 0x34);
+        assert_eq!(
+            backup.handle_flush(100 + FLUSH_DELAY),
+            Some(200 + FLUSH_DELAY)
+        );
+        assert!(!path.exists());
+        assert_eq!(backup.handle_flush(200 + FLUSH_DELAY), None);
+        assert!(!backup.is_dirty());
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(saved.len(), SRAM_SIZE);
+        assert_eq!(&saved[..3], &[0x12, 0x34, 0xFF]);
+        assert!(!path.with_extension("sav.tmp").exists());
+
+        // The next write schedules a new flush, dropping the device flushes as well
+        assert!(backup.write_8(0x0E00_0002, 0x56, 300));
+        drop(backup);
+        let mut backup = Backup::default();
+        backup.attach_file(&path).unwrap();
+        assert_eq!(backup.read_8(0x0E00_0002, 0), 0x56);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_flush_error() {
+        let path = temp_path("flush_error");
+        let mut backup = Backup::default();
+        backup.attach_file(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert!(backup.write_8(0x0E00_0000, 0x12, 0));
+        assert_eq!(backup.handle_flush(FLUSH_DELAY), None);
+        assert!(backup.is_dirty());
+        assert!(backup.take_error().is_some());
+        assert!(backup.take_error().is_none());
+    }
+}
@@ -0,0 +1,1124 @@
+use super::apu::Apu;
+use super::backup::{Backup, FLUSH_DELAY};
+use super::dma::{DmaChannel, DmaTiming};
+use super::eeprom::EepromSize;
+use super::gpio::{self, Gpio};
+use super::interrupt::{Interrupt, InterruptController};
+use super::io::{self, Hook, IoRegister, IO_SIZE};
+use super::observer::{Access, AccessKind, Observers};
+use super::ppu::Ppu;
+use super::scheduler::{EventKind, Scheduler};
+use super::system::PowerState;
+use super::timer::{Timer, TIMER_COUNT_UP};
+use super::timing::MemoryTiming;
+use arm7tdmi::bus::{Bus, MemoryAccess, MemoryWidth};
+
+/*
+ * The GBA system bus. See GBATEK "GBA Memory Map".
+ *
+ * Owns the memory and every device the CPU reaches through memory mapped I/O. The devices share
+ * the scheduler and the interrupt controller, both live here so bus accesses can reach them.
+ *
+ *   0x00000000 BIOS        16KB
+ *   0x02000000 EWRAM      256KB, mirrored
+ *   0x03000000 IWRAM       32KB, mirrored
+ *   0x04000000 I/O
+ *   0x05000000 Palette      1KB, mirrored
+ *   0x06000000 VRAM        96KB, mirrored every 128KB, the last 32KB mirror the OBJ tiles
+ *   0x07000000 OAM          1KB, mirrored
+ *   0x08000000 ROM         32MB, mirrored at 0x0A000000 and 0x0C000000 (wait states 0, 1, 2)
+ *   0x080000C4 GPIO        on carts with a clock or sensors, see gpio.rs
+ *   0x0D000000 EEPROM      if present, the last 256 bytes only for ROMs over 16MB
+ *   0x0E000000 Backup      SRAM 32KB or Flash 64/128KB, mirrored, 8 bit bus, see backup.rs
+ */
+pub const BIOS_SIZE: usize = 16 * 1024;
+pub const EWRAM_SIZE: usize = 256 * 1024;
+pub const IWRAM_SIZE: usize = 32 * 1024;
+pub const PALETTE_SIZE: usize = 1024;
+pub const VRAM_SIZE: usize = 96 * 1024;
+pub const OAM_SIZE: usize = 1024;
+pub const ROM_MAX_SIZE: usize = 32 * 1024 * 1024;
+
+pub struct GbaBus {
+    pub bios: Vec<u8>,
+    pub ewram: Vec<u8>,
+    pub iwram: Vec<u8>,
+    pub palette: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub oam: Vec<u8>,
+    pub rom: Vec<u8>,
+    pub backup: Backup,
+    pub gpio: Gpio,
+    pub scheduler: Scheduler,
+    pub interrupts: InterruptController,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub timing: MemoryTiming,
+    pub dma: [DmaChannel; 4],
+    pub timers: [Timer; 4],
+    pub observers: Observers,
+    pub power: PowerState,
+    // Work RAM ranges written by DMA, the system drops the code cached from them.
+    pub(crate) dma_writes: Vec<(u32, u32)>,
+    postflg: u8,
+    // Registers without a hook
+    io: Vec<u8>,
+    open_bus: OpenBus,
+}
+
+/*
+ * Reads from unmapped memory return what is left on the data bus, which is the opcode the CPU
+ * fetched last, i.e. the second stage of its pipeline (ARMCpu::pipeline()[1]) while the
+ * instruction doing the read executes. The bus keeps track of it as the opcodes are fetched. See
+ * GBATEK "Unpredictable Things".
+ *
+ * The BIOS can only be read while the CPU executes from it. Reads from outside return the last
+ * opcode fetched from the BIOS.
+ */
+#[derive(Debug, Default)]
+struct OpenBus {
+    // Address and value of the last opcode fetch.
+    addr: u32,
+    opcode: u32,
+    // The THUMB opcode fetched before it, the first pipeline stage.
+    previous: u32,
+    thumb: bool,
+    bios: u32,
+}
+
+/*
+ * Multiboot images are copied to EWRAM and entered at 0x020000C0. The BIOS fills in the transfer
+ * mode at 0xC4 (1 joybus, 2 normal, 3 multiplay) and the slave ID at 0xC5 (1-3, always 1 in
+ * normal mode).
+ */
+pub const MULTIBOOT_BASE: u32 = 0x0200_0000;
+pub const MULTIBOOT_ENTRY: u32 = 0x0200_00C0;
+pub const MULTIBOOT_NORMAL_MODE: u8 = 2;
+
+// The last opcode fetched by the BIOS before it jumps to the cartridge.
+pub const BIOS_LATCH_AFTER_BOOT: u32 = 0xE129_F000;
+
+fn read_u16(mem: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([mem[offset], mem[offset + 1]])
+}
+
+fn read_u32(mem: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        mem[offset],
+        mem[offset + 1],
+        mem[offset + 2],
+        mem[offset + 3],
+    ])
+}
+
+fn write_u16(mem: &mut [u8], offset: usize, value: u16) {
+    mem[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(mem: &mut [u8], offset: usize, value: u32) {
+    mem[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// 0x06018000-0x0601FFFF mirrors 0x06010000-0x06017FFF.
+fn vram_offset(addr: u32) -> usize {
+    let offset = addr as usize & 0x1_FFFF;
+    if offset >= VRAM_SIZE {
+        offset - 0x8000
+    } else {
+        offset
+    }
+}
+
+impl GbaBus {
+    pub fn new(bios: Vec<u8>, rom: Vec<u8>) -> Self {
+        let mut bios = bios;
+        bios.resize(BIOS_SIZE, 0);
+        let gpio = Gpio::for_game(rom.get(0xAC..0xB0).unwrap_or(&[]));
+        let mut bus = GbaBus {
+            bios,
+            ewram: vec![0; EWRAM_SIZE],
+            iwram: vec![0; IWRAM_SIZE],
+            palette: vec![0; PALETTE_SIZE],
+            vram: vec![0; VRAM_SIZE],
+            oam: vec![0; OAM_SIZE],
+            backup: Backup::for_game(&rom),
+            rom,
+            gpio,
+            scheduler: Scheduler::new(),
+            interrupts: InterruptController::new(),
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            timing: MemoryTiming::new(),
+            dma: [DmaChannel::default(); 4],
+            timers: [Timer::default(); 4],
+            observers: Observers::new(),
+            power: PowerState::Running,
+            dma_writes: vec![],
+            postflg: 0,
+            io: vec![0; IO_SIZE as usize],
+            open_bus: OpenBus::default(),
+        };
+        bus.ppu.start(&mut bus.scheduler);
+        // No keys pressed, sound bias at the middle
+        bus.io[0x130] = 0xFF;
+        bus.io[0x131] = 0x03;
+        bus.io[0x089] = 0x02;
+        bus
+    }
+
+    // Moves time forward and runs every event that became due.
+    pub fn advance(&mut self, cycles: u64) {
+        self.scheduler.advance(cycles);
+        while let Some((time, kind)) = self.scheduler.pop_due() {
+            match kind {
+                EventKind::HBlankStart | EventKind::LineEnd => {
+                    self.ppu
+                        .handle_event(kind, time, &mut self.scheduler, &mut self.interrupts)
+                }
+                EventKind::SaveFlush => {
+                    if let Some(time) = self.backup.handle_flush(time) {
+                        self.scheduler.schedule_at(EventKind::SaveFlush, time);
+                    }
+                }
+                EventKind::TimerOverflow(n) => self.timer_overflow(n, time),
+            }
+        }
+    }
+
+    // Timer n overflowed at the given time, count-up timers after it count the overflow.
+    fn timer_overflow(&mut self, n: usize, time: u64) {
+        let irq = [
+            Interrupt::Timer0,
+            Interrupt::Timer1,
+            Interrupt::Timer2,
+            Interrupt::Timer3,
+        ];
+        self.timers[n].overflow(time);
+        if let Some(cycles) = self.timers[n].cycles_to_overflow(time) {
+            self.scheduler
+                .schedule_at(EventKind::TimerOverflow(n), time + cycles);
+        }
+        let mut n = n;
+        loop {
+            if self.timers[n].irq_enabled() {
+                self.interrupts.request(irq[n]);
+            }
+            n += 1;
+            if n == 4 || !self.timers[n].count_up(time) {
+                break;
+            }
+        }
+    }
+
+    fn reschedule_timer(&mut self, n: usize) {
+        let now = self.scheduler.now();
+        self.scheduler.cancel(EventKind::TimerOverflow(n));
+        if let Some(cycles) = self.timers[n].cycles_to_overflow(now) {
+            self.scheduler.schedule(EventKind::TimerOverflow(n), cycles);
+        }
+    }
+
+    /*
+     * HALTCNT (0x4000301). Bit 7 clear halts the CPU until an enabled interrupt is requested,
+     * bit 7 set stops the CPU and powers down the display and sound as well.
+     */
+    fn write_haltcnt(&mut self, value: u8) {
+        if value & 0x80 == 0 {
+            self.power = PowerState::Halted;
+        } else {
+            self.power = PowerState::Stopped;
+            self.ppu.power_down(&mut self.scheduler);
+            self.apu.power_down();
+        }
+    }
+
+    // Leaves halt or stop mode.
+    pub fn wake(&mut self) {
+        if self.power == PowerState::Stopped {
+            self.ppu.power_up(&mut self.scheduler);
+            self.apu.power_up();
+        }
+        self.power = PowerState::Running;
+    }
+
+    fn read_io_8(&self, addr: u32) -> u8 {
+        let offset = addr & 0x00FF_FFFF;
+        match io::register(offset) {
+            Some(r) if r.read != 0 => (self.read_register(r) >> ((offset - r.offset) * 8)) as u8,
+            _ => (self.open_bus(addr & !3) >> ((addr & 3) * 8)) as u8,
+        }
+    }
+
+    fn read_io_16(&self, addr: u32) -> u16 {
+        self.read_io_8(addr) as u16 | (self.read_io_8(addr + 1) as u16) << 8
+    }
+
+    // Writes the bytes of a halfword selected by lanes, each register once.
+    fn write_io(&mut self, addr: u32, value: u16, lanes: u16) {
+        let offset = addr & 0x00FF_FFFE;
+        let mut byte = 0;
+        while byte < 2 {
+            let r = match io::register(offset + byte) {
+                Some(r) => r,
+                None => {
+                    byte += 1;
+                    continue;
+                }
+            };
+            let (mut reg_value, mut reg_mask) = (0, 0);
+            while byte < 2 && offset + byte < r.offset + r.size {
+                let lane = 0xFF << (byte * 8);
+                if lanes & lane != 0 {
+                    let shift = (offset + byte - r.offset) * 8;
+                    reg_value |= ((value & lane) as u32 >> (byte * 8)) << shift;
+                    reg_mask |= 0xFF << shift;
+                }
+                byte += 1;
+            }
+            self.write_register(r, reg_value, reg_mask);
+        }
+    }
+
+    // The value of an I/O register as the CPU reads it, also for debug views.
+    pub fn read_register(&self, r: &IoRegister) -> u32 {
+        let value = match r.hook {
+            Hook::None => self.stored(r),
+            Hook::Dispstat => self.ppu.dispstat as u32,
+            Hook::Vcount => self.ppu.vcount as u32,
+            Hook::InterruptEnable => self.interrupts.ie as u32,
+            Hook::InterruptAcknowledge => self.interrupts.if_ as u32,
+            Hook::Waitcnt => self.timing.waitcnt() as u32,
+            Hook::InterruptMaster => self.interrupts.ime as u32,
+            Hook::DmaControl(n) => self.dma[n].control as u32,
+            Hook::TimerReload(n) => self.timers[n].counter_at(self.scheduler.now()) as u32,
+            Hook::TimerControl(n) => self.timers[n].control as u32,
+            Hook::Postflg => self.postflg as u32,
+            Hook::Haltcnt => 0,
+        };
+        value & r.read
+    }
+
+    // Writes the bits of value selected by mask, limited to the writable ones.
+    fn write_register(&mut self, r: &IoRegister, value: u32, mask: u32) {
+        let mask = mask & r.write;
+        if mask == 0 {
+            return;
+        }
+        let merge = |old: u32| (old & !mask) | (value & mask);
+        match r.hook {
+            Hook::None => {
+                let stored = merge(self.stored(r));
+                self.store(r, stored);
+            }
+            Hook::Dispstat => self
+                .ppu
+                .write_dispstat(merge(self.ppu.dispstat as u32) as u16),
+            Hook::Vcount => {}
+            Hook::InterruptEnable => self
+                .interrupts
+                .write_ie(merge(self.interrupts.ie as u32) as u16),
+            Hook::InterruptAcknowledge => self.interrupts.acknowledge((value & mask) as u16),
+            Hook::Waitcnt => self
+                .timing
+                .write_waitcnt(merge(self.timing.waitcnt() as u32) as u16),
+            Hook::InterruptMaster => self
+                .interrupts
+                .write_ime(merge(self.interrupts.ime as u32) as u16),
+            Hook::DmaControl(n) => {
+                let base = 0x0B0 + n as u32 * 12;
+                let sad = self.stored_at(base, 4);
+                let dad = self.stored_at(base + 4, 4);
+                let cnt_l = self.stored_at(base + 8, 2) as u16;
+                let control = merge(self.dma[n].control as u32) as u16;
+                self.dma[n].write_control(n, control, sad, dad, cnt_l);
+                if self.dma[n].is_enabled() && self.dma[n].timing() == DmaTiming::Immediate {
+                    self.run_dma(n);
+                }
+            }
+            Hook::TimerReload(n) => {
+                self.timers[n].reload = merge(self.timers[n].reload as u32) as u16
+            }
+            Hook::TimerControl(n) => {
+                let control = merge(self.timers[n].control as u32) as u16;
+                // Timer 0 has no previous timer to count
+                let control = if n == 0 {
+                    control & !TIMER_COUNT_UP
+                } else {
+                    control
+                };
+                self.timers[n].write_control(control, self.scheduler.now());
+                self.reschedule_timer(n);
+            }
+            Hook::Postflg => self.postflg = merge(self.postflg as u32) as u8,
+            Hook::Haltcnt => self.write_haltcnt(value as u8),
+        }
+    }
+
+    fn stored_at(&self, offset: u32, size: u32) -> u32 {
+        let mut bytes = [0; 4];
+        let offset = offset as usize;
+        bytes[..size as usize].copy_from_slice(&self.io[offset..offset + size as usize]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn stored(&self, r: &IoRegister) -> u32 {
+        self.stored_at(r.offset, r.size)
+    }
+
+    fn store(&mut self, r: &IoRegister, value: u32) {
+        let offset = r.offset as usize;
+        let size = r.size as usize;
+        self.io[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    // The regions that are plain byte addressable memory, with the offset after mirroring.
+    fn memory(&self, addr: u32) -> Option<(&[u8], usize)> {
+        let a = addr as usize;
+        match addr >> 24 {
+            0x00 if a < BIOS_SIZE && self.bios_readable() => Some((&self.bios, a)),
+            0x02 => Some((&self.ewram, a % EWRAM_SIZE)),
+            0x03 => Some((&self.iwram, a % IWRAM_SIZE)),
+            0x05 => Some((&self.palette, a % PALETTE_SIZE)),
+            0x06 => Some((&self.vram, vram_offset(addr))),
+            0x07 => Some((&self.oam, a % OAM_SIZE)),
+            _ => None,
+        }
+    }
+
+    fn bios_readable(&self) -> bool {
+        (self.open_bus.addr as usize) < BIOS_SIZE
+    }
+
+    // Copies a multiboot image to EWRAM as received in normal mode, the rest is cut off.
+    pub fn load_multiboot(&mut self, image: &[u8]) {
+        let len = image.len().min(EWRAM_SIZE);
+        self.ewram[..len].copy_from_slice(&image[..len]);
+        self.ewram[0xC4] = MULTIBOOT_NORMAL_MODE;
+        self.ewram[0xC5] = 1;
+    }
+
+    pub fn set_bios_latch(&mut self, value: u32) {
+        self.open_bus.bios = value;
+    }
+
+    // Value of a read from unmapped memory, for a word aligned address.
+    fn open_bus(&self, addr: u32) -> u32 {
+        if addr >> 24 == 0 && (addr as usize) < BIOS_SIZE {
+            return self.open_bus.bios;
+        }
+        let ob = &self.open_bus;
+        if !ob.thumb {
+            return ob.opcode;
+        }
+        // The opcode at $+4, where $ is the executing instruction, combined with the ones at $+2
+        // and $+6 depending on the region it is executed from.
+        let current = ob.opcode & 0xFFFF;
+        let previous = ob.previous & 0xFFFF;
+        let aligned = ob.addr & 2 == 0;
+        match ob.addr >> 24 {
+            0x00 | 0x07 if aligned => {
+                let next = self
+                    .memory(ob.addr + 2)
+                    .map_or(0, |(mem, offset)| read_u16(mem, offset));
+                current | (next as u32) << 16
+            }
+            0x00 | 0x03 | 0x07 if !aligned => previous | current << 16,
+            0x03 => current | previous << 16,
+            _ => current * 0x0001_0001,
+        }
+    }
+
+    // Opcode fetches see the BIOS, the fetch address decides if it is readable.
+    fn fetched(&mut self, addr: u32, value: u32, thumb: bool) {
+        if (addr as usize) < BIOS_SIZE {
+            self.open_bus.bios = read_u32(&self.bios, addr as usize & !3);
+        }
+        let ob = &mut self.open_bus;
+        ob.previous = ob.opcode;
+        ob.opcode = value;
+        ob.thumb = thumb;
+    }
+
+    // Like memory, without the read only BIOS.
+    fn memory_mut(&mut self, addr: u32) -> Option<(&mut [u8], usize)> {
+        let a = addr as usize;
+        match addr >> 24 {
+            0x02 => Some((&mut self.ewram, a % EWRAM_SIZE)),
+            0x03 => Some((&mut self.iwram, a % IWRAM_SIZE)),
+            0x05 => Some((&mut self.palette, a % PALETTE_SIZE)),
+            0x06 => Some((&mut self.vram, vram_offset(addr))),
+            0x07 => Some((&mut self.oam, a % OAM_SIZE)),
+            _ => None,
+        }
+    }
+
+    /*
+     * ROM reads past the end of the image return the address bus, which the cartridge leaves
+     * holding the low 16 bits of the halfword address.
+     */
+    fn read_rom_16(&self, addr: u32) -> u16 {
+        if self.gpio.is_readable() && gpio::is_gpio(addr) {
+            return self.gpio.read(addr);
+        }
+        let offset = addr as usize % ROM_MAX_SIZE;
+        if offset + 1 < self.rom.len() {
+            read_u16(&self.rom, offset)
+        } else {
+            (offset >> 1) as u16
+        }
+    }
+
+    fn is_eeprom(&self, addr: u32) -> bool {
+        addr >> 24 == 0x0D
+            && self.backup.eeprom().is_some()
+            && (self.rom.len() <= 16 * 1024 * 1024 || addr & 0x00FF_FF00 == 0x00FF_FF00)
+    }
+
+    fn read_eeprom(&mut self) -> u16 {
+        let now = self.scheduler.now();
+        self.backup.eeprom_mut().map_or(0, |e| e.read(now))
+    }
+
+    fn write_eeprom(&mut self, value: u16) {
+        if self.backup.write_eeprom(value, self.scheduler.now()) {
+            self.scheduler.schedule(EventKind::SaveFlush, FLUSH_DELAY);
+        }
+    }
+
+    /*
+     * Runs a transfer of channel n to the end. The CPU is stopped meanwhile, the cycles the
+     * transfer takes are not counted yet. A DMA3 transfer to the EEPROM sends a request, its
+     * length tells the EEPROM size if it is not known yet.
+     */
+    fn run_dma(&mut self, n: usize) {
+        let channel = self.dma[n];
+        if n == 3 && self.is_eeprom(channel.dest) {
+            let size = EepromSize::from_request_len(channel.count);
+            if let Some(eeprom) = self.backup.eeprom_mut() {
+                eeprom.infer_size(size);
+            }
+        }
+        let (unit, source_step, dest_step) =
+            (channel.unit(), channel.source_step(), channel.dest_step());
+        let (mut source, mut dest) = (channel.source, channel.dest);
+        for _ in 0..channel.count {
+            if unit == 4 {
+                let value = self.peek_32(source);
+                self.poke_32(dest, value);
+            } else {
+                let value = if self.is_eeprom(source) {
+                    self.read_eeprom()
+                } else {
+                    self.peek_16(source)
+                };
+                self.poke_16(dest, value);
+            }
+            source = source.wrapping_add(source_step);
+            dest = dest.wrapping_add(dest_step);
+        }
+        if channel.count > 0 && matches!(channel.dest >> 24, 0x02 | 0x03) {
+            let last = dest.wrapping_sub(dest_step);
+            let start = channel.dest.min(last);
+            self.dma_writes
+                .push((start, channel.dest.max(last) - start + unit));
+        }
+        if self.dma[n].finish(source, dest) {
+            let irq = [
+                Interrupt::Dma0,
+                Interrupt::Dma1,
+                Interrupt::Dma2,
+                Interrupt::Dma3,
+            ];
+            self.interrupts.request(irq[n]);
+        }
+    }
+
+    /*
+     * Video memory has a 16 bit data bus. A byte write to palette RAM or BG VRAM writes the byte
+     * to both halves of the halfword, one to OBJ VRAM or OAM is ignored. OBJ VRAM starts at
+     * 0x06010000 in the tile modes 0-2 and at 0x06014000 in the bitmap modes 3-5.
+     */
+    fn write_video_8(&mut self, addr: u32, value: u8) {
+        let obj_vram = if self.stored_at(0x000, 2) & 7 >= 3 {
+            0x1_4000
+        } else {
+            0x1_0000
+        };
+        let ignored = match addr >> 24 {
+            0x06 => vram_offset(addr) >= obj_vram,
+            0x07 => true,
+            _ => false,
+        };
+        if !ignored {
+            if let Some((mem, offset)) = self.memory_mut(addr & !1) {
+                write_u16(mem, offset, value as u16 * 0x0101);
+            }
+        }
+    }
+
+    // The SRAM data bus is 8 bits wide, wider reads repeat the byte.
+    fn read_sram(&self, addr: u32) -> u8 {
+        if self.gpio.is_tilt(addr) {
+            return self.gpio.read_tilt(addr);
+        }
+        self.backup.read_8(addr, self.scheduler.now())
+    }
+
+    // Wider writes store the byte of the value selected by the low address bits.
+    fn write_sram(&mut self, addr: u32, value: u32) {
+        let byte = (value >> ((addr & 3) * 8)) as u8;
+        if self.gpio.is_tilt(addr) {
+            return self.gpio.write_tilt(addr, byte);
+        }
+        if self.backup.write_8(addr, byte, self.scheduler.now()) {
+            self.scheduler.schedule(EventKind::SaveFlush, FLUSH_DELAY);
+        }
+    }
+
+    /*
+     * Accesses without wait states or observers, for the CPU through the Bus trait and for
+     * debuggers. Reads have no side effects.
+     */
+    pub fn peek_8(&self, addr: u32) -> u8 {
+        if let Some((mem, offset)) = self.memory(addr) {
+            return mem[offset];
+        }
+        match addr >> 24 {
+            0x04 => self.read_io_8(addr),
+            0x08..=0x0D => (self.read_rom_16(addr & !1) >> ((addr & 1) * 8)) as u8,
+            0x0E | 0x0F => self.read_sram(addr),
+            _ => (self.open_bus(addr & !3) >> ((addr & 3) * 8)) as u8,
+        }
+    }
+
+    pub fn peek_16(&self, addr: u32) -> u16 {
+        if let 0x0E | 0x0F = addr >> 24 {
+            return self.read_sram(addr) as u16 * 0x0101;
+        }
+        let addr = addr & !1;
+        if let Some((mem, offset)) = self.memory(addr) {
+            return read_u16(mem, offset);
+        }
+        if self.is_eeprom(addr) {
+            let now = self.scheduler.now();
+            return self.backup.eeprom().map_or(0, |e| e.peek(now));
+        }
+        match addr >> 24 {
+            0x04 => self.read_io_16(addr),
+            0x08..=0x0D => self.read_rom_16(addr),
+            _ => (self.open_bus(addr & !3) >> ((addr & 2) * 8)) as u16,
+        }
+    }
+
+    pub fn peek_32(&self, addr: u32) -> u32 {
+        if let 0x0E | 0x0F = addr >> 24 {
+            return self.read_sram(addr) as u32 * 0x0101_0101;
+        }
+        let addr = addr & !3;
+        if let Some((mem, offset)) = self.memory(addr) {
+            return read_u32(mem, offset);
+        }
+        self.peek_16(addr) as u32 | (self.peek_16(addr + 2) as u32) << 16
+    }
+
+    pub fn poke_8(&mut self, addr: u32, value: u8) {
+        if let 0x05..=0x07 = addr >> 24 {
+            return self.write_video_8(addr, value);
+        }
+        if let Some((mem, offset)) = self.memory_mut(addr) {
+            mem[offset] = value;
+            return;
+        }
+        match addr >> 24 {
+            0x04 => self.write_io(
+                addr,
+                (value as u16) << ((addr & 1) * 8),
+                0xFF << ((addr & 1) * 8),
+            ),
+            0x0E | 0x0F => self.write_sram(addr, value as u32 * 0x0101_0101),
+            _ => {}
+        }
+    }
+
+    pub fn poke_16(&mut self, addr: u32, value: u16) {
+        if let 0x0E | 0x0F = addr >> 24 {
+            return self.write_sram(addr, value as u32 * 0x0001_0001);
+        }
+        let addr = addr & !1;
+        if let Some((mem, offset)) = self.memory_mut(addr) {
+            return write_u16(mem, offset, value);
+        }
+        if addr >> 24 == 0x04 {
+            self.write_io(addr, value, 0xFFFF);
+        } else if self.is_eeprom(addr) {
+            self.write_eeprom(value);
+        } else if gpio::is_gpio(addr) {
+            self.gpio.write(addr, value);
+        }
+    }
+
+    pub fn poke_32(&mut self, addr: u32, value: u32) {
+        if let 0x0E | 0x0F = addr >> 24 {
+            return self.write_sram(addr, value);
+        }
+        let addr = addr & !3;
+        if let Some((mem, offset)) = self.memory_mut(addr) {
+            return write_u32(mem, offset, value);
+        }
+        self.poke_16(addr, value as u16);
+        self.poke_16(addr + 2, (value >> 16) as u16);
+    }
+
+    // Address of the executing instruction, the opcode fetched last is two instructions ahead.
+    fn executing_pc(&self) -> u32 {
+        let size = if self.open_bus.thumb { 2 } else { 4 };
+        self.open_bus.addr.wrapping_sub(2 * size)
+    }
+
+    // Called before the instruction at pc executes.
+    #[cold]
+    pub(crate) fn notify_execute(&mut self, pc: u32, thumb: bool) {
+        let (width, value) = if thumb {
+            (MemoryWidth::Halfword, self.peek_16(pc) as u32)
+        } else {
+            (MemoryWidth::Word, self.peek_32(pc))
+        };
+        self.observers.notify(&Access {
+            kind: AccessKind::Execute,
+            addr: pc,
+            width,
+            value,
+            pc,
+        });
+    }
+
+    #[cold]
+    fn notify(&mut self, kind: AccessKind, addr: u32, width: MemoryWidth, value: u32) {
+        let access = Access {
+            kind,
+            addr,
+            width,
+            value,
+            pc: self.executing_pc(),
+        };
+        self.observers.notify(&access);
+    }
+}
+
+impl Bus for GbaBus {
+    fn read_8(&mut self, addr: u32, _access: MemoryAccess) -> u8 {
+        let value = self.peek_8(addr);
+        if self.observers.watches(AccessKind::Read) {
+            self.notify(AccessKind::Read, addr, MemoryWidth::Byte, value as u32);
+        }
+        value
+    }
+
+    fn read_16(&mut self, addr: u32, _access: MemoryAccess) -> u16 {
+        let value = if self.is_eeprom(addr) {
+            self.read_eeprom()
+        } else {
+            self.peek_16(addr)
+        };
+        if self.observers.watches(AccessKind::Read) {
+            self.notify(AccessKind::Read, addr, MemoryWidth::Halfword, value as u32);
+        }
+        value
+    }
+
+    fn read_32(&mut self, addr: u32, _access: MemoryAccess) -> u32 {
+        let value = self.peek_32(addr);
+        if self.observers.watches(AccessKind::Read) {
+            self.notify(AccessKind::Read, addr, MemoryWidth::Word, value);
+        }
+        value
+    }
+
+    fn write_8(&mut self, addr: u32, value: u8, _access: MemoryAccess) {
+        self.poke_8(addr, value);
+        if self.observers.watches(AccessKind::Write) {
+            self.notify(AccessKind::Write, addr, MemoryWidth::Byte, value as u32);
+        }
+    }
+
+    fn write_16(&mut self, addr: u32, value: u16, _access: MemoryAccess) {
+        self.poke_16(addr, value);
+        if self.observers.watches(AccessKind::Write) {
+            self.notify(AccessKind::Write, addr, MemoryWidth::Halfword, value as u32);
+        }
+    }
+
+    fn write_32(&mut self, addr: u32, value: u32, _access: MemoryAccess) {
+        self.poke_32(addr, value);
+        if self.observers.watches(AccessKind::Write) {
+            self.notify(AccessKind::Write, addr, MemoryWidth::Word, value);
+        }
+    }
+
+    fn fetch_16(&mut self, addr: u32, _access: MemoryAccess) -> u16 {
+        self.open_bus.addr = addr;
+        let value = self.peek_16(addr);
+        self.fetched(addr, value as u32, true);
+        value
+    }
+
+    fn fetch_32(&mut self, addr: u32, _access: MemoryAccess) -> u32 {
+        self.open_bus.addr = addr;
+        let value = self.peek_32(addr);
+        self.fetched(addr, value, false);
+        value
+    }
+
+    fn wait_states(&mut self, addr: u32, width: MemoryWidth, access: MemoryAccess) -> u32 {
+        self.timing.wait_states(addr, width, access)
+    }
+
+    fn fetch_wait_states(&mut self, addr: u32, width: MemoryWidth, access: MemoryAccess) -> u32 {
+        self.timing.fetch_wait_states(addr, width, access)
+    }
+
+    fn idle(&mut self, cycles: u32) {
+        self.timing.idle(cycles);
+    }
+
+    fn cycles_to_next_event(&self) -> u32 {
+        match self.scheduler.next_event() {
+            Some(time) => time
+                .saturating_sub(self.scheduler.now())
+                .min(u32::MAX as u64) as u32,
+            None => u32::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupChip;
+    use crate::eeprom::Eeprom;
+
+    #[test]
+    fn test_io_registers() {
+        let mut bus = GbaBus::new(vec![], vec![]);
+        bus.write_16(0x0400_0200, 0xFFFF, MemoryAccess::NonSequential);
+        assert_eq!(bus.interrupts.ie, 0x3FFF);
+        bus.interrupts.request(Interrupt::VBlank);
+        bus.interrupts.request(Interrupt::Keypad);
+        // A byte write acknowledges only its own byte
+        bus.write_8(0x0400_0202, 0xFF, MemoryAccess::NonSequential);
+        assert_eq!(
+            bus.read_16(0x0400_0202, MemoryAccess::NonSequential),
+            Interrupt::Keypad.mask()
+        );
+        bus.write_32(0x0400_0208, 1, MemoryAccess::NonSequential);
+        assert!(bus.interrupts.irq_line());
+
+        bus.write_8(0x0400_0301, 0, MemoryAccess::NonSequential);
+        assert_eq!(bus.power, PowerState::Halted);
+        bus.wake();
+        bus.write_16(0x0400_0300, 0x8001, MemoryAccess::NonSequential);
+        assert_eq!(bus.power, PowerState::Stopped);
+        assert_eq!(bus.read_8(0x0400_0300, MemoryAccess::NonSequential), 1);
+        assert!(!bus.ppu.is_powered() && !bus.apu.is_powered());
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut rom = vec![0; 0x100];
+        rom[0x10..0x14].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        let mut bus = GbaBus::new(vec![0xAA; 16], rom);
+        let n = MemoryAccess::NonSequential;
+
+        // BIOS is read only
+        bus.write_32(0, 0, n);
+        assert_eq!(bus.read_32(0, n), 0xAAAA_AAAA);
+
+        // RAM mirrors
+        bus.write_32(0x0200_0000, 0x1122_3344, n);
+        assert_eq!(bus.read_32(0x0204_0000, n), 0x1122_3344);
+        assert_eq!(bus.read_16(0x0200_0002, n), 0x1122);
+        bus.write_8(0x0300_7FFF, 0x5A, n);
+        assert_eq!(bus.read_8(0x03FF_FFFF, n), 0x5A);
+        bus.write_16(0x0500_0402, 0x7FFF, n);
+        assert_eq!(bus.read_16(0x0500_0002, n), 0x7FFF);
+        bus.write_16(0x0700_0000, 0x1234, n);
+        assert_eq!(bus.read_16(0x0700_0400, n), 0x1234);
+
+        // VRAM: 128KB mirrors, the upper 32KB mirror 0x06010000
+        bus.write_16(0x0601_8000, 0xBEEF, n);
+        assert_eq!(bus.read_16(0x0601_0000, n), 0xBEEF);
+        assert_eq!(bus.read_16(0x0603_0000, n), 0xBEEF);
+
+        // Misaligned accesses are aligned down
+        assert_eq!(bus.read_32(0x0200_0002, n), 0x1122_3344);
+
+        // ROM in all three wait state regions, open bus past the end, writes ignored
+        for &base in [0x0800_0000, 0x0A00_0000, 0x0C00_0000].iter() {
+            assert_eq!(bus.read_32(base + 0x10, n), 0x1234_5678);
+            assert_eq!(bus.read_8(base + 0x13, n), 0x12);
+        }
+        bus.write_32(0x0800_0010, 0, n);
+        assert_eq!(bus.read_32(0x0800_0010, n), 0x1234_5678);
+        assert_eq!(bus.read_16(0x0800_1000, n), 0x0800);
+        assert_eq!(bus.read_32(0x0900_1000, n), 0x0801_0800);
+
+        // SRAM has an 8 bit bus
+        bus.write_32(0x0E00_0001, 0xAABB_CCDD, n);
+        assert_eq!(bus.read_8(0x0E00_0001, n), 0xCC);
+        assert_eq!(bus.read_16(0x0E00_0001, n), 0xCCCC);
+        assert_eq!(bus.read_32(0x0E00_8001, n), 0xCCCC_CCCC);
+        bus.write_16(0x0F00_0002, 0x1234, n);
+        assert_eq!(bus.read_8(0x0E00_0002, n), 0x34);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let bios = (0..16u32).flat_map(|i| (0xE000_0000 | i).to_le_bytes().to_vec());
+        let mut bus = GbaBus::new(bios.collect(), vec![0x34, 0x12, 0x78, 0x56]);
+        let n = MemoryAccess::NonSequential;
+        let s = MemoryAccess::Sequential;
+        for (i, &v) in [0x1111, 0x2222, 0x3333, 0x4444].iter().enumerate() {
+            bus.write_16(0x0300_0000 + i as u32 * 2, v, n);
+            bus.write_16(0x0700_0000 + i as u32 * 2, v, n);
+        }
+
+        // ARM: the last fetched opcode
+        bus.fetch_32(0x0000_0008, n);
+        assert_eq!(bus.read_32(0x0000_0000, n), 0xE000_0000, "BIOS is readable");
+        bus.fetch_32(0x0800_0000, n);
+        assert_eq!(bus.read_32(0x1000_0000, n), 0x5678_1234);
+        assert_eq!(bus.read_8(0x0100_0001, n), 0x12);
+        assert_eq!(bus.read_16(0x0000_4002, n), 0x5678);
+        // The BIOS returns the last opcode fetched from it
+        assert_eq!(bus.read_32(0x0000_0000, n), 0xE000_0002);
+        assert_eq!(bus.read_16(0x0000_0012, n), 0xE000);
+
+        // THUMB: depends on the region and the alignment of $+4
+        bus.fetch_16(0x0800_0002, n);
+        assert_eq!(bus.read_32(0x1000_0000, n), 0x5678_5678);
+        bus.fetch_16(0x0300_0002, n);
+        bus.fetch_16(0x0300_0004, s);
+        assert_eq!(bus.read_32(0x1000_0000, n), 0x2222_3333);
+        bus.fetch_16(0x0300_0006, s);
+        assert_eq!(bus.read_32(0x1000_0000, n), 0x4444_3333);
+        bus.fetch_16(0x0700_0002, n);
+        bus.fetch_16(0x0700_0004, s);
+        assert_eq!(bus.read_32(0x1000_0000, n), 0x4444_3333);
+        bus.fetch_16(0x0700_0006, s);
+        assert_eq!(bus.read_32(0x1000_0000, n), 0x4444_3333);
+    }
+
+    #[test]
+    fn test_io_register_table() {
+        let mut bus = GbaBus::new(vec![], vec![]);
+        let n = MemoryAccess::NonSequential;
+
+        // Unused bits read as 0, read only bits keep their value
+        bus.write_16(0x0400_0008, 0xFFFF, n);
+        assert_eq!(bus.read_16(0x0400_0008, n), 0xDFFF);
+        assert_eq!(bus.read_16(0x0400_0130, n), 0x03FF);
+        bus.write_16(0x0400_0130, 0, n);
+        assert_eq!(bus.read_16(0x0400_0130, n), 0x03FF);
+        bus.write_16(0x0400_0084, 0xFFFF, n);
+        assert_eq!(bus.read_16(0x0400_0084, n), 0x0080);
+
+        // Write only registers and unused addresses read as open bus
+        bus.fetch_32(0x0300_0000, n);
+        bus.write_16(0x0400_0010, 0x1FF, n);
+        assert_eq!(bus.read_16(0x0400_0010, n), 0);
+        bus.iwram[..4].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        bus.fetch_32(0x0300_0000, n);
+        assert_eq!(bus.read_16(0x0400_0010, n), 0x5678);
+        assert_eq!(bus.read_32(0x0400_00E0, n), 0x1234_5678);
+        assert_eq!(bus.read_8(0x0400_0301, n), 0x56);
+
+        // Byte writes merge with the stored value, including write only bits
+        bus.write_32(0x0400_0028, 0xFFFF_FFFF, n);
+        bus.write_8(0x0400_002B, 0, n);
+        assert_eq!(bus.read_register(io::register(0x028).unwrap()), 0);
+        assert_eq!(bus.stored_at(0x028, 4), 0x00FF_FFFF);
+
+        // DMA registers are latched when the channel is enabled
+        bus.write_32(0x0400_00D4, 0x0800_0000, n);
+        bus.write_32(0x0400_00D8, 0x0200_0000, n);
+        bus.write_32(0x0400_00DC, 0x9000_0000, n);
+        assert!(bus.dma[3].is_enabled());
+        assert_eq!(bus.dma[3].source, 0x0800_0000);
+        assert_eq!(bus.dma[3].count, 0x1_0000);
+        bus.write_32(0x0400_00D4, 0x0300_0000, n);
+        bus.write_16(0x0400_00DE, 0x9000, n);
+        assert_eq!(bus.dma[3].source, 0x0800_0000);
+        assert_eq!(bus.read_16(0x0400_00DE, n), 0x9000);
+
+        // TMxCNT_L writes the reload value and reads the counter
+        bus.write_16(0x0400_0100, 0xFF00, n);
+        assert_eq!(bus.read_16(0x0400_0100, n), 0);
+        bus.write_8(0x0400_0102, 0x80, n);
+        assert!(bus.timers[0].is_running());
+        assert_eq!(bus.read_16(0x0400_0100, n), 0xFF00);
+    }
+
+    #[test]
+    fn test_timers() {
+        let n = MemoryAccess::NonSequential;
+        let mut bus = GbaBus::new(vec![], vec![]);
+        bus.write_16(0x0400_0200, 0x3FFF, n);
+
+        // Timer 0 overflows every 0x10 ticks of 64 cycles, timer 1 counts the overflows
+        bus.write_16(0x0400_0100, 0xFFF0, n);
+        bus.write_16(0x0400_0104, 0xFFFE, n);
+        bus.write_16(0x0400_0106, 0x00C4, n);
+        bus.write_16(0x0400_0102, 0x0081, n);
+        bus.advance(64 * 0x10 - 1);
+        assert_eq!(bus.read_16(0x0400_0100, n), 0xFFFF);
+        assert_eq!(bus.interrupts.if_, 0);
+        bus.advance(1);
+        assert_eq!(bus.read_16(0x0400_0100, n), 0xFFF0);
+        assert_eq!(bus.read_16(0x0400_0104, n), 0xFFFF);
+        assert_eq!(bus.interrupts.if_, 0);
+
+        // The second overflow of timer 0 overflows timer 1, only that one requests an IRQ
+        bus.advance(64 * 0x10 + 64 * 3);
+        assert_eq!(bus.read_16(0x0400_0100, n), 0xFFF3);
+        assert_eq!(bus.read_16(0x0400_0104, n), 0xFFFE);
+        assert_eq!(bus.interrupts.if_, Interrupt::Timer1.mask());
+
+        // Stopped, neither counts
+        bus.write_16(0x0400_0102, 0x0001, n);
+        bus.advance(64 * 0x100);
+        assert_eq!(bus.read_16(0x0400_0100, n), 0xFFF3);
+        assert_eq!(bus.read_16(0x0400_0104, n), 0xFFFE);
+    }
+
+    #[test]
+    fn test_backup_chip() {
+        let n = MemoryAccess::NonSequential;
+        let mut rom = vec![0; 0x400];
+        rom[0x200..0x20C].copy_from_slice(b"FLASH1M_V103");
+        let mut bus = GbaBus::new(vec![], rom);
+        bus.write_8(0x0E00_5555, 0xAA, n);
+        bus.write_8(0x0E00_2AAA, 0x55, n);
+        bus.write_8(0x0E00_5555, 0x90, n);
+        assert_eq!(bus.read_8(0x0E00_0000, n), 0x62);
+        assert_eq!(bus.read_8(0x0E00_0001, n), 0x13);
+
+        let mut rom = vec![0; 0x400];
+        rom[0x200..0x20B].copy_from_slice(b"EEPROM_V124");
+        assert!(GbaBus::new(vec![], rom).backup.eeprom().is_some());
+        assert!(GbaBus::new(vec![], vec![]).backup.eeprom().is_none());
+    }
+
+    #[test]
+    fn test_dma_eeprom() {
+        let mut bus = GbaBus::new(vec![], vec![0; 0x100]);
+        bus.backup = Backup::new(BackupChip::Eeprom(Eeprom::new(None)));
+        let n = MemoryAccess::NonSequential;
+        let dma3 = |bus: &mut GbaBus, source: u32, dest: u32, count: u16, control: u16| {
+            bus.write_32(0x0400_00D4, source, n);
+            bus.write_32(0x0400_00D8, dest, n);
+            bus.write_32(0x0400_00DC, count as u32 | (control as u32) << 16, n);
+        };
+        let send = |bus: &mut GbaBus, bits: &[u8]| {
+            for (i, &b) in bits.iter().enumerate() {
+                bus.write_16(0x0200_0000 + i as u32 * 2, b as u16, n);
+            }
+            dma3(bus, 0x0200_0000, 0x0D00_0000, bits.len() as u16, 0x8000);
+        };
+
+        // A 73 bit write request, 6 bit address: the 512 byte chip
+        let mut request = vec![1, 0, 0, 0, 0, 0, 1, 0];
+        request.extend((0..64).map(|i| (0xA5u64 >> (63 - i) & 1) as u8));
+        request.push(0);
+        send(&mut bus, &request);
+        let eeprom = bus.backup.eeprom().unwrap();
+        assert_eq!(eeprom.size(), Some(EepromSize::Small));
+        assert_eq!(&eeprom.data[16..24], &0xA5u64.to_be_bytes());
+        assert!(!bus.dma[3].is_enabled());
+        assert_eq!(bus.read_16(0x0D00_0000, n), 0);
+        bus.advance(crate::eeprom::WRITE_CYCLES);
+        assert_eq!(bus.read_16(0x0DFF_FF00, n), 1);
+
+        // Read it back, with the DMA3 interrupt at the end
+        send(&mut bus, &[1, 1, 0, 0, 0, 0, 1, 0, 0]);
+        dma3(&mut bus, 0x0D00_0000, 0x0200_0100, 68, 0xC000);
+        let bits: Vec<u16> = (0..68).map(|i| bus.peek_16(0x0200_0100 + i * 2)).collect();
+        assert_eq!(bits[..4], [0, 0, 0, 0]);
+        assert_eq!(bits[4..].iter().fold(0, |v, &b| v << 1 | b as u64), 0xA5);
+        assert_eq!(bus.interrupts.if_, Interrupt::Dma3.mask());
+        assert_eq!(bus.dma[3].source, 0x0D00_0000 + 68 * 2);
+    }
+
+    #[test]
+    fn test_dma_words() {
+        let mut bus = GbaBus::new(vec![], vec![]);
+        let n = MemoryAccess::NonSequential;
+        for i in 0..4 {
+            bus.write_32(0x0300_0000 + i * 4, i + 1, n);
+        }
+        // Word transfer, source decrementing, destination fixed
+        bus.write_32(0x0400_00B0, 0x0300_000C, n);
+        bus.write_32(0x0400_00B4, 0x0300_0100, n);
+        bus.write_32(0x0400_00B8, 0x84C0_0003, n);
+        assert_eq!(bus.peek_32(0x0300_0100), 2);
+        assert_eq!(bus.dma[0].source, 0x0300_0000);
+        assert_eq!(bus.interrupts.if_, 0);
+    }
+
+    #[test]
+    fn test_gpio_rtc() {
+        use crate::rtc::{DateTime, FixedClock, Rtc, CS, SCK, SIO};
+
+        let mut rom = vec![0; 0x100];
+        rom[0xC4] = 0x42;
+        let mut bus = GbaBus::new(vec![], rom);
+        let n = MemoryAccess::NonSequential;
+        assert_eq!(bus.read_16(gpio::GPIO_DATA, n), 0x42);
+        bus.gpio.rtc = Some(Rtc::new(Box::new(FixedClock::new(DateTime::from_unix(0)))));
+        bus.write_16(gpio::GPIO_CONTROL, 1, n);
+        bus.write_16(gpio::GPIO_DIRECTION, 0x7, n);
+
+        // Read the status register, 24 hour mode
+        bus.write_16(gpio::GPIO_DATA, SCK as u16, n);
+        bus.write_16(gpio::GPIO_DATA, (CS | SCK) as u16, n);
+        for i in 0..8 {
+            let sio = (0xC6 >> i & 1) << 1;
+            bus.write_16(gpio::GPIO_DATA, (CS | sio) as u16, n);
+            bus.write_16(gpio::GPIO_DATA, (CS | SCK | sio) as u16, n);
+        }
+        bus.write_16(gpio::GPIO_DIRECTION, 0x5, n);
+        let mut status = 0;
+        for i in 0..8 {
+            bus.write_16(gpio::GPIO_DATA, CS as u16, n);
+            bus.write_16(gpio::GPIO_DATA, (CS | SCK) as u16, n);
+            status |= (bus.read_16(gpio::GPIO_DATA, n) as u8 & SIO) >> 1 << i;
+        }
+        assert_eq!(status, crate::rtc::STATUS_24H);
+    }
+
+    #[test]
+    fn test_video_byte_writes() {
+        let mut bus = GbaBus::new(vec![], vec![]);
+        let n = MemoryAccess::NonSequential;
+
+        bus.write_8(0x0500_0003, 0x1F, n);
+        assert_eq!(bus.read_16(0x0500_0002, n), 0x1F1F);
+        bus.write_8(0x0600_FFFE, 0x42, n);
+        assert_eq!(bus.read_16(0x0600_FFFE, n), 0x4242);
+
+        // OBJ VRAM and OAM ignore byte writes
+        bus.write_16(0x0601_0000, 0x1234, n);
+        bus.write_8(0x0601_0000, 0xFF, n);
+        bus.write_8(0x0601_8001, 0xFF, n);
+        assert_eq!(bus.read_16(0x0601_0000, n), 0x1234);
+        bus.write_16(0x0700_0000, 0x1234, n);
+        bus.write_8(0x0700_0001, 0xFF, n);
+        assert_eq!(bus.read_16(0x0700_0000, n), 0x1234);
+
+        // In the bitmap modes BG VRAM extends to 0x06013FFF
+        bus.write_16(0x0400_0000, 3, n);
+        bus.write_8(0x0601_3FFF, 0x77, n);
+        assert_eq!(bus.read_16(0x0601_3FFE, n), 0x7777);
+        bus.write_8(0x0601_4000, 0x77, n);
+        assert_eq!(bus.read_16(0x0601_4000, n), 0);
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ * DMA channels. See GBATEK "GBA DMA Transfers".
+ *
+ * Enabling a channel latches the source, destination and word count into the internal registers
+ * the transfer runs from, later writes to the I/O registers do not change a running transfer.
+ * Only immediate transfers run yet, the bus runs them to the end when the channel is enabled.
+ */
+pub const DMA_ENABLE: u16 = 1 << 15;
+const DMA_IRQ: u16 = 1 << 14;
+const DMA_WORD: u16 = 1 << 10;
+const DMA_REPEAT: u16 = 1 << 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmaTiming {
+    Immediate,
+    VBlank,
+    HBlank,
+    // Sound FIFO for DMA1 and 2, video capture for DMA3
+    Special,
+}
+
+// Internal address masks, DMA0 can only reach internal memory, only DMA3 can write the cartridge.
+const SOURCE_MASK: [u32; 4] = [0x07FF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF, 0x0FFF_FFFF];
+const DEST_MASK: [u32; 4] = [0x07FF_FFFF, 0x07FF_FFFF, 0x07FF_FFFF, 0x0FFF_FFFF];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DmaChannel {
+    pub control: u16,
+    pub source: u32,
+    pub dest: u32,
+    // Units left to transfer, a count of 0 means the maximum.
+    pub count: u32,
+}
+
+impl DmaChannel {
+    pub fn is_enabled(&self) -> bool {
+        self.control & DMA_ENABLE != 0
+    }
+
+    /*
+     * Writes DMAxCNT_H. The registers are latched on the rising edge of the enable bit, sad, dad
+     * and cnt_l are the current values of DMAxSAD, DMAxDAD and DMAxCNT_L.
+     */
+    pub fn write_control(&mut self, n: usize, value: u16, sad: u32, dad: u32, cnt_l: u16) {
+        if !self.is_enabled() && value & DMA_ENABLE != 0 {
+            self.source = sad & SOURCE_MASK[n];
+            self.dest = dad & DEST_MASK[n];
+            self.count = match (cnt_l, n) {
+                (0, 3) => 0x1_0000,
+                (0, _) => 0x4000,
+                (c, 3) => c as u32,
+                (c, _) => c as u32 & 0x3FFF,
+            };
+        }
+        self.control = value;
+    }
+
+    pub fn timing(&self) -> DmaTiming {
+        match self.control >> 12 & 3 {
+            0 => DmaTiming::Immediate,
+            1 => DmaTiming::VBlank,
+            2 => DmaTiming::HBlank,
+            _ => DmaTiming::Special,
+        }
+    }
+
+    // Bytes per unit.
+    pub fn unit(&self) -> u32 {
+        if self.control & DMA_WORD != 0 {
+            4
+        } else {
+            2
+        }
+    }
+
+    // Address steps after each unit: increment, decrement, fixed, increment (and reload).
+    fn step(&self, adjust: u16) -> u32 {
+        match adjust {
+            1 => self.unit().wrapping_neg(),
+            2 => 0,
+            _ => self.unit(),
+        }
+    }
+
+    pub fn source_step(&self) -> u32 {
+        self.step(self.control >> 7 & 3)
+    }
+
+    pub fn dest_step(&self) -> u32 {
+        self.step(self.control >> 5 & 3)
+    }
+
+    /*
+     * Ends a transfer with the addresses it got to. The destination is reloaded for the next
+     * repeat if set up so, the channel stays enabled if it repeats. Returns true if it requests
+     * an interrupt.
+     */
+    pub fn finish(&mut self, source: u32, dest: u32) -> bool {
+        self.source = source;
+        if self.control >> 5 & 3 != 3 {
+            self.dest = dest;
+        }
+        if self.control & DMA_REPEAT == 0 || self.timing() == DmaTiming::Immediate {
+            self.control &= !DMA_ENABLE;
+        }
+        self.control & DMA_IRQ != 0
+    }
+}
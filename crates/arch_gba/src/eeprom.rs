@@ -0,0 +1,229 @@
+/*
+ * EEPROM backup chips. See GBATEK "GBA Cart Backup EEPROM".
+ *
+ * The chip is accessed a bit at a time through bit 0 of halfwords in the upper ROM mirror, with
+ * DMA3 transfers as the games do it. Requests are sent most significant bit first:
+ *
+ *   read   "11", address, "0"                    then 68 bits are read, 4 junk bits and 64 data
+ *   write  "10", address, 64 data bits, "0"      then reads return 0 until the write is done
+ *
+ * The address counts 8 byte blocks and is 6 bits wide for the 512 byte chips, 14 bits for the
+ * 8KB ones (of which the low 10 are used). Nothing in the ROM tells them apart, so the width is
+ * inferred from the length of the first DMA transfer that sends a request, or from the size of
+ * an existing save file.
+ */
+pub const EEPROM_SMALL_SIZE: usize = 512;
+pub const EEPROM_LARGE_SIZE: usize = 8 * 1024;
+
+// Time in cycles a write takes, about 6.5ms.
+pub const WRITE_CYCLES: u64 = 108_368;
+
+const READ_BITS: u32 = 68;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EepromSize {
+    // 512 bytes, 6 bit addresses
+    Small,
+    // 8KB, 14 bit addresses
+    Large,
+}
+
+impl EepromSize {
+    pub fn address_bits(self) -> u32 {
+        match self {
+            EepromSize::Small => 6,
+            EepromSize::Large => 14,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            EepromSize::Small => EEPROM_SMALL_SIZE,
+            EepromSize::Large => EEPROM_LARGE_SIZE,
+        }
+    }
+
+    // The size a DMA transfer of count halfwords sending a read or write request is for.
+    pub fn from_request_len(count: u32) -> Option<Self> {
+        match count {
+            9 | 73 => Some(EepromSize::Small),
+            17 | 81 => Some(EepromSize::Large),
+            _ => None,
+        }
+    }
+
+    pub fn from_save_len(len: usize) -> Option<Self> {
+        match len {
+            EEPROM_SMALL_SIZE => Some(EepromSize::Small),
+            EEPROM_LARGE_SIZE => Some(EepromSize::Large),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    // Bits of a request received so far.
+    Request { bits: u128, len: u32 },
+    Reading { data: u64, pos: u32 },
+}
+
+#[derive(Debug)]
+pub struct Eeprom {
+    // Unknown until inferred, requests are decoded as for the 8KB chips until then.
+    size: Option<EepromSize>,
+    pub(crate) data: Vec<u8>,
+    state: State,
+    busy_until: u64,
+}
+
+impl Eeprom {
+    pub fn new(size: Option<EepromSize>) -> Self {
+        Eeprom {
+            size,
+            data: vec![0xFF; EEPROM_LARGE_SIZE],
+            state: State::Idle,
+            busy_until: 0,
+        }
+    }
+
+    pub fn size(&self) -> Option<EepromSize> {
+        self.size
+    }
+
+    // Sets the size if it is not known yet.
+    pub fn infer_size(&mut self, size: Option<EepromSize>) {
+        if self.size.is_none() {
+            self.size = size;
+        }
+    }
+
+    pub(crate) fn contents(&self) -> &[u8] {
+        let len = self.size.map_or(EEPROM_LARGE_SIZE, |s| s.bytes());
+        &self.data[..len]
+    }
+
+    fn block_offset(&self, addr: u128) -> usize {
+        let size = self.size.unwrap_or(EepromSize::Large);
+        let blocks = size.bytes() / 8;
+        (addr as usize & (blocks - 1)) * 8
+    }
+
+    // The next bit read returns, without moving on.
+    pub fn peek(&self, now: u64) -> u16 {
+        match self.state {
+            State::Reading { pos, .. } if pos < 4 => 0,
+            State::Reading { data, pos } => (data >> (63 - (pos - 4))) as u16 & 1,
+            _ => (now >= self.busy_until) as u16,
+        }
+    }
+
+    pub fn read(&mut self, now: u64) -> u16 {
+        let bit = self.peek(now);
+        if let State::Reading { data, pos } = self.state {
+            self.state = if pos + 1 == READ_BITS {
+                State::Idle
+            } else {
+                State::Reading { data, pos: pos + 1 }
+            };
+        }
+        bit
+    }
+
+    // Receives bit 0 of value. Returns true if the contents changed.
+    pub fn write(&mut self, value: u16, now: u64) -> bool {
+        let bit = (value & 1) as u128;
+        let (bits, len) = match self.state {
+            State::Request { bits, len } => (bits << 1 | bit, len + 1),
+            // A new request, the first bit is always set
+            _ if bit == 0 => {
+                self.state = State::Idle;
+                return false;
+            }
+            _ => (bit, 1),
+        };
+        self.state = State::Request { bits, len };
+
+        let address_bits = self.size.unwrap_or(EepromSize::Large).address_bits();
+        let read = len >= 2 && bits >> (len - 2) & 1 == 1;
+        if read && len == 2 + address_bits + 1 {
+            let offset = self.block_offset(bits >> 1);
+            let mut block = [0; 8];
+            block.copy_from_slice(&self.data[offset..offset + 8]);
+            self.state = State::Reading {
+                data: u64::from_be_bytes(block),
+                pos: 0,
+            };
+        } else if !read && len == 2 + address_bits + 64 + 1 {
+            let offset = self.block_offset(bits >> 65);
+            let data = (bits >> 1) as u64;
+            self.data[offset..offset + 8].copy_from_slice(&data.to_be_bytes());
+            self.busy_until = now + WRITE_CYCLES;
+            self.state = State::Idle;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(eeprom: &mut Eeprom, value: u128, len: u32) -> bool {
+        let mut modified = false;
+        for i in (0..len).rev() {
+            modified |= eeprom.write((value >> i) as u16 & 1, 0);
+        }
+        modified
+    }
+
+    fn read_block(eeprom: &mut Eeprom, addr: u128, now: u64) -> u64 {
+        let bits = eeprom.size().unwrap().address_bits();
+        send(eeprom, 0b11 << (bits + 1) | addr << 1, bits + 3);
+        let stream: Vec<u16> = (0..READ_BITS).map(|_| eeprom.read(now)).collect();
+        assert_eq!(&stream[..4], &[0, 0, 0, 0]);
+        stream[4..].iter().fold(0, |v, &b| v << 1 | b as u64)
+    }
+
+    #[test]
+    fn test_read_write() {
+        for &size in [EepromSize::Small, EepromSize::Large].iter() {
+            let mut eeprom = Eeprom::new(Some(size));
+            let bits = size.address_bits();
+            let data = 0x0123_4567_89AB_CDEFu128;
+            let request = 0b10 << (bits + 65) | 3 << 65 | data << 1;
+            assert!(send(&mut eeprom, request, bits + 67));
+            assert_eq!(
+                &eeprom.data[24..32],
+                &0x0123_4567_89AB_CDEFu64.to_be_bytes()
+            );
+
+            // Busy until the write completes
+            assert_eq!(eeprom.read(WRITE_CYCLES - 1), 0);
+            assert_eq!(eeprom.read(WRITE_CYCLES), 1);
+
+            assert_eq!(
+                read_block(&mut eeprom, 3, WRITE_CYCLES),
+                0x0123_4567_89AB_CDEF
+            );
+            assert_eq!(read_block(&mut eeprom, 4, WRITE_CYCLES), u64::MAX);
+            assert_eq!(eeprom.read(WRITE_CYCLES), 1);
+            assert_eq!(eeprom.contents().len(), size.bytes());
+        }
+    }
+
+    #[test]
+    fn test_infer_size() {
+        assert_eq!(EepromSize::from_request_len(9), Some(EepromSize::Small));
+        assert_eq!(EepromSize::from_request_len(81), Some(EepromSize::Large));
+        assert_eq!(EepromSize::from_request_len(68), None);
+        assert_eq!(EepromSize::from_save_len(512), Some(EepromSize::Small));
+
+        let mut eeprom = Eeprom::new(None);
+        eeprom.infer_size(EepromSize::from_request_len(73));
+        eeprom.infer_size(EepromSize::from_request_len(81));
+        assert_eq!(eeprom.size(), Some(EepromSize::Small));
+    }
+}
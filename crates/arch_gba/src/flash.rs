@@ -0,0 +1,329 @@
+/*
+ * Flash backup chips. See GBATEK "GBA Cart Backup Flash ROM".
+ *
+ * The chip is mapped at 0x0E000000 in place of SRAM, 64KB at a time and mirrored. Commands are
+ * written with the unlock sequence 0xAA to 0x5555, 0x55 to 0x2AAA, then the command to 0x5555:
+ *
+ *   0x90 enter ID mode, reads from 0x0000 and 0x0001 return the manufacturer and device ID
+ *   0xF0 leave ID mode
+ *   0x80 erase, followed by another unlock sequence and 0x10 to 0x5555 to erase the chip or
+ *        0x30 to the address of a 4KB sector to erase the sector
+ *   0xA0 program, the next write stores a byte. Atmel chips program a 128 byte page instead,
+ *        the page is erased and the next 128 writes to it store the bytes
+ *   0xB0 bank switch, the next write to 0x0000 selects the 64KB bank (128KB chips only)
+ *
+ * Program and erase take a while, until they complete reads return the inverted bit 7 of the
+ * expected value (0xFF for erases), which is what games poll for.
+ */
+pub const FLASH_BANK_SIZE: usize = 64 * 1024;
+pub const SECTOR_SIZE: usize = 4 * 1024;
+pub const ATMEL_PAGE_SIZE: usize = 128;
+
+const COMMAND_1: u32 = 0x5555;
+const COMMAND_2: u32 = 0x2AAA;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashChip {
+    // 64KB
+    Panasonic,
+    Atmel,
+    Macronix64K,
+    // 128KB
+    Sanyo,
+    Macronix128K,
+}
+
+impl FlashChip {
+    // Manufacturer and device ID.
+    pub fn id(self) -> (u8, u8) {
+        match self {
+            FlashChip::Panasonic => (0x32, 0x1B),
+            FlashChip::Atmel => (0x1F, 0x3D),
+            FlashChip::Macronix64K => (0xC2, 0x1C),
+            FlashChip::Sanyo => (0x62, 0x13),
+            FlashChip::Macronix128K => (0xC2, 0x09),
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            FlashChip::Sanyo | FlashChip::Macronix128K => 2 * FLASH_BANK_SIZE,
+            _ => FLASH_BANK_SIZE,
+        }
+    }
+}
+
+// Time in cycles until an operation completes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashTiming {
+    pub program: u64,
+    pub sector_erase: u64,
+    pub chip_erase: u64,
+}
+
+impl Default for FlashTiming {
+    fn default() -> Self {
+        FlashTiming {
+            program: 650,
+            sector_erase: 30_000,
+            chip_erase: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ready,
+    // Got 0xAA, then 0x55 of the unlock sequence.
+    Unlock1,
+    Unlock2,
+    // After 0x80, the same for the erase command.
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+    Program,
+    // Atmel: bytes left in the page being programmed.
+    ProgramPage(usize),
+    BankSwitch,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Busy {
+    until: u64,
+    // Offset in the chip and the value it reads as once done.
+    offset: usize,
+    value: u8,
+}
+
+#[derive(Debug)]
+pub struct Flash {
+    pub chip: FlashChip,
+    pub timing: FlashTiming,
+    pub(crate) data: Vec<u8>,
+    state: State,
+    id_mode: bool,
+    bank: usize,
+    busy: Option<Busy>,
+}
+
+impl Flash {
+    pub fn new(chip: FlashChip) -> Self {
+        Flash {
+            chip,
+            timing: FlashTiming::default(),
+            data: vec![0xFF; chip.size()],
+            state: State::Ready,
+            id_mode: false,
+            bank: 0,
+            busy: None,
+        }
+    }
+
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
+    pub fn is_busy(&self, now: u64) -> bool {
+        self.busy.is_some_and(|b| now < b.until)
+    }
+
+    fn offset(&self, addr: u32) -> usize {
+        self.bank * FLASH_BANK_SIZE + (addr as usize & 0xFFFF)
+    }
+
+    pub fn read(&self, addr: u32, now: u64) -> u8 {
+        let offset = self.offset(addr);
+        if let Some(busy) = self.busy {
+            if now < busy.until && busy.offset == offset {
+                return !busy.value & 0x80;
+            }
+        }
+        if self.id_mode && addr & 0xFFFF < 2 {
+            let (manufacturer, device) = self.chip.id();
+            return if addr & 1 == 0 { manufacturer } else { device };
+        }
+        self.data[offset]
+    }
+
+    // Returns true if the contents changed.
+    pub fn write(&mut self, addr: u32, value: u8, now: u64) -> bool {
+        let addr = addr & 0xFFFF;
+        let (state, modified) = match (self.state, addr, value) {
+            (State::Program, _, _) => {
+                let offset = self.offset(addr);
+                self.data[offset] = value;
+                self.set_busy(offset, value, now + self.timing.program);
+                (State::Ready, true)
+            }
+            (State::ProgramPage(left), _, _) => {
+                let offset = self.offset(addr);
+                if left == ATMEL_PAGE_SIZE {
+                    let page = offset & !(ATMEL_PAGE_SIZE - 1);
+                    self.fill(page, ATMEL_PAGE_SIZE);
+                }
+                self.data[offset] = value;
+                self.set_busy(offset, value, now + self.timing.program);
+                match left - 1 {
+                    0 => (State::Ready, true),
+                    left => (State::ProgramPage(left), true),
+                }
+            }
+            (State::BankSwitch, 0x0000, _) => {
+                self.bank = value as usize & 1;
+                (State::Ready, false)
+            }
+            (State::Ready, COMMAND_1, 0xAA) => (State::Unlock1, false),
+            (State::Unlock1, COMMAND_2, 0x55) => (State::Unlock2, false),
+            (State::Unlock2, COMMAND_1, cmd) => self.command(cmd),
+            (State::Erase, COMMAND_1, 0xAA) => (State::EraseUnlock1, false),
+            (State::EraseUnlock1, COMMAND_2, 0x55) => (State::EraseUnlock2, false),
+            (State::EraseUnlock2, COMMAND_1, 0x10) => {
+                let size = self.data.len();
+                self.fill(0, size);
+                self.set_busy(self.offset(addr), 0xFF, now + self.timing.chip_erase);
+                (State::Ready, true)
+            }
+            (State::EraseUnlock2, _, 0x30) => {
+                let offset = self.offset(addr);
+                self.fill(offset & !(SECTOR_SIZE - 1), SECTOR_SIZE);
+                self.set_busy(offset, 0xFF, now + self.timing.sector_erase);
+                (State::Ready, true)
+            }
+            // Anything else aborts the sequence
+            _ => (State::Ready, false),
+        };
+        self.state = state;
+        modified
+    }
+
+    fn command(&mut self, cmd: u8) -> (State, bool) {
+        let state = match cmd {
+            0x90 => {
+                self.id_mode = true;
+                State::Ready
+            }
+            0xF0 => {
+                self.id_mode = false;
+                State::Ready
+            }
+            0x80 => State::Erase,
+            0xA0 if self.chip == FlashChip::Atmel => State::ProgramPage(ATMEL_PAGE_SIZE),
+            0xA0 => State::Program,
+            0xB0 if self.chip.size() > FLASH_BANK_SIZE => State::BankSwitch,
+            _ => State::Ready,
+        };
+        (state, false)
+    }
+
+    fn fill(&mut self, offset: usize, len: usize) {
+        for b in self.data[offset..offset + len].iter_mut() {
+            *b = 0xFF;
+        }
+    }
+
+    fn set_busy(&mut self, offset: usize, value: u8, until: u64) {
+        self.busy = Some(Busy {
+            until,
+            offset,
+            value,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(flash: &mut Flash, cmd: u8) {
+        flash.write(0x0E00_5555, 0xAA, 0);
+        flash.write(0x0E00_2AAA, 0x55, 0);
+        flash.write(0x0E00_5555, cmd, 0);
+    }
+
+    #[test]
+    fn test_chip_id() {
+        for &(chip, id) in [
+            (FlashChip::Panasonic, [0x32, 0x1B]),
+            (FlashChip::Atmel, [0x1F, 0x3D]),
+            (FlashChip::Sanyo, [0x62, 0x13]),
+            (FlashChip::Macronix128K, [0xC2, 0x09]),
+        ]
+        .iter()
+        {
+            let mut flash = Flash::new(chip);
+            command(&mut flash, 0x90);
+            assert_eq!([flash.read(0x0E00_0000, 0), flash.read(0x0E00_0001, 0)], id);
+            command(&mut flash, 0xF0);
+            assert_eq!(flash.read(0x0E00_0000, 0), 0xFF);
+        }
+    }
+
+    #[test]
+    fn test_program_erase() {
+        let mut flash = Flash::new(FlashChip::Panasonic);
+        // A write without a command does nothing
+        assert!(!flash.write(0x0E00_0010, 0x12, 0));
+        assert_eq!(flash.read(0x0E00_0010, 0), 0xFF);
+
+        // Programming is busy for a while, mirrored every 64KB
+        command(&mut flash, 0xA0);
+        assert!(flash.write(0x0E00_1010, 0x12, 100));
+        assert_eq!(flash.read(0x0E00_1010, 100), 0x80);
+        assert_eq!(flash.read(0x0E00_1010, 100 + 649), 0x80);
+        assert_eq!(flash.read(0x0E00_1010, 100 + 650), 0x12);
+        assert_eq!(flash.read(0x0F01_1010, 1000), 0x12);
+
+        // Sector erase clears the 4KB sector only
+        command(&mut flash, 0xA0);
+        flash.write(0x0E00_2000, 0x34, 0);
+        command(&mut flash, 0x80);
+        flash.write(0x0E00_5555, 0xAA, 1000);
+        flash.write(0x0E00_2AAA, 0x55, 1000);
+        assert!(flash.write(0x0E00_1FFF, 0x30, 1000));
+        assert!(flash.is_busy(1000));
+        assert_eq!(flash.read(0x0E00_1FFF, 1000), 0x00);
+        assert!(!flash.is_busy(31_000));
+        assert_eq!(flash.read(0x0E00_1010, 31_000), 0xFF);
+        assert_eq!(flash.read(0x0E00_2000, 31_000), 0x34);
+
+        // Chip erase
+        command(&mut flash, 0x80);
+        command(&mut flash, 0x10);
+        assert!(flash.data.iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_bank_switch() {
+        let mut flash = Flash::new(FlashChip::Sanyo);
+        command(&mut flash, 0xB0);
+        flash.write(0x0E00_0000, 1, 0);
+        assert_eq!(flash.bank(), 1);
+        command(&mut flash, 0xA0);
+        flash.write(0x0E00_0042, 0x56, 0);
+        assert_eq!(flash.data[FLASH_BANK_SIZE + 0x42], 0x56);
+        command(&mut flash, 0xB0);
+        flash.write(0x0E00_0000, 0, 0);
+        assert_eq!(flash.read(0x0E00_0042, 1000), 0xFF);
+
+        // 64KB chips have no banks
+        let mut flash = Flash::new(FlashChip::Panasonic);
+        command(&mut flash, 0xB0);
+        flash.write(0x0E00_0000, 1, 0);
+        assert_eq!(flash.bank(), 0);
+    }
+
+    #[test]
+    fn test_atmel_page() {
+        let mut flash = Flash::new(FlashChip::Atmel);
+        for &page in [0x0E00_0100, 0x0E00_0180].iter() {
+            command(&mut flash, 0xA0);
+            for i in 0..ATMEL_PAGE_SIZE as u32 {
+                flash.write(page + i, (page + i) as u8 & 0x7F, 0);
+            }
+        }
+        // Each page takes 128 writes, after them writes are ignored again
+        assert_eq!(flash.read(0x0E00_0100, 1000), 0x00);
+        assert_eq!(flash.read(0x0E00_01FF, 1000), 0x7F);
+        assert!(!flash.write(0x0E00_0200, 0x22, 1000));
+    }
+}
@@ -0,0 +1,211 @@
+use super::rtc::{HostClock, Rtc};
+use super::sensors::{GyroSensor, NoSensors, SensorHost, SolarSensor, TiltSensor};
+
+/*
+ * Cartridge GPIO port, in the ROM area of carts with a clock or sensors. See GBATEK "GBA Cart
+ * I/O Port (GPIO)".
+ *
+ *   0x080000C4 data       4 bits, the pins
+ *   0x080000C6 direction  bit set: the pin is an output of the GBA
+ *   0x080000C8 control    bit 0 set: the registers can be read, else reads return the ROM
+ *
+ * Pins the GBA drives read back what was written, the others what the devices drive. The tilt
+ * sensor is mapped in the SRAM area instead but is kept here with the other cartridge add-ons,
+ * see sensors.rs.
+ */
+pub const GPIO_DATA: u32 = 0x0800_00C4;
+pub const GPIO_DIRECTION: u32 = 0x0800_00C6;
+pub const GPIO_CONTROL: u32 = 0x0800_00C8;
+
+const RTC: u8 = 1 << 0;
+const SOLAR: u8 = 1 << 1;
+const GYRO: u8 = 1 << 2;
+const RUMBLE: u8 = 1 << 3;
+const TILT: u8 = 1 << 4;
+
+// Game codes without the region letter and the devices on the cartridge.
+const GAMES: [(&[u8; 3], u8); 10] = [
+    // Pokemon Ruby, Sapphire, Emerald
+    (b"AXV", RTC),
+    (b"AXP", RTC),
+    (b"BPE", RTC),
+    // Boktai 1-3
+    (b"U3I", RTC | SOLAR),
+    (b"U32", RTC | SOLAR),
+    (b"U33", RTC | SOLAR),
+    // WarioWare Twisted
+    (b"RZW", GYRO | RUMBLE),
+    // Drill Dozer
+    (b"V49", RUMBLE),
+    // Yoshi Topsy-Turvy, Koro Koro Puzzle
+    (b"KYG", TILT),
+    (b"KHP", TILT),
+];
+
+pub fn is_gpio(addr: u32) -> bool {
+    (GPIO_DATA..=GPIO_CONTROL + 1).contains(&addr)
+}
+
+pub struct Gpio {
+    data: u8,
+    direction: u8,
+    readable: bool,
+    pub rtc: Option<Rtc>,
+    pub solar: Option<SolarSensor>,
+    pub gyro: Option<GyroSensor>,
+    pub tilt: Option<TiltSensor>,
+    pub has_rumble: bool,
+    rumble: bool,
+    host: Box<dyn SensorHost>,
+}
+
+impl Default for Gpio {
+    fn default() -> Self {
+        Gpio {
+            data: 0,
+            direction: 0,
+            readable: false,
+            rtc: None,
+            solar: None,
+            gyro: None,
+            tilt: None,
+            has_rumble: false,
+            rumble: false,
+            host: Box::new(NoSensors),
+        }
+    }
+}
+
+impl Gpio {
+    pub fn new() -> Self {
+        Gpio::default()
+    }
+
+    // The devices of the cartridge with the game code at 0xAC in the ROM header.
+    pub fn for_game(code: &[u8]) -> Self {
+        let mut gpio = Gpio::new();
+        let devices = GAMES
+            .iter()
+            .find(|(g, _)| code.starts_with(&g[..]))
+            .map_or(0, |&(_, d)| d);
+        if devices & RTC != 0 {
+            gpio.rtc = Some(Rtc::new(Box::new(HostClock)));
+        }
+        if devices & SOLAR != 0 {
+            gpio.solar = Some(SolarSensor::default());
+        }
+        if devices & GYRO != 0 {
+            gpio.gyro = Some(GyroSensor::default());
+        }
+        if devices & TILT != 0 {
+            gpio.tilt = Some(TiltSensor::default());
+        }
+        gpio.has_rumble = devices & RUMBLE != 0;
+        gpio
+    }
+
+    // Where sensor values come from and rumble goes to.
+    pub fn set_sensor_host(&mut self, host: Box<dyn SensorHost>) {
+        self.host = host;
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    fn inputs(&self) -> u8 {
+        self.rtc.as_ref().map_or(0, |rtc| rtc.read_pins())
+            | self.solar.as_ref().map_or(0, |s| s.read_pins())
+            | self.gyro.as_ref().map_or(0, |g| g.read_pins())
+    }
+
+    pub fn read(&self, addr: u32) -> u16 {
+        let value = match addr & !1 {
+            GPIO_DATA => self.data & self.direction | self.inputs() & !self.direction,
+            GPIO_DIRECTION => self.direction,
+            GPIO_CONTROL => self.readable as u8,
+            _ => 0,
+        };
+        (value & 0xF) as u16
+    }
+
+    pub fn write(&mut self, addr: u32, value: u16) {
+        let value = value as u8 & 0xF;
+        match addr & !1 {
+            GPIO_DATA => self.data = value,
+            GPIO_DIRECTION => self.direction = value,
+            GPIO_CONTROL => self.readable = value & 1 != 0,
+            _ => {}
+        }
+        let pins = self.data & self.direction;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.write_pins(pins);
+        }
+        if let Some(solar) = self.solar.as_mut() {
+            solar.write_pins(pins, &mut *self.host);
+        }
+        if let Some(gyro) = self.gyro.as_mut() {
+            gyro.write_pins(pins, &mut *self.host);
+        }
+        let rumble = pins & 8 != 0;
+        if self.has_rumble && rumble != self.rumble {
+            self.rumble = rumble;
+            self.host.rumble(rumble);
+        }
+    }
+
+    pub fn is_tilt(&self, addr: u32) -> bool {
+        self.tilt.is_some() && TiltSensor::is_tilt(addr)
+    }
+
+    pub fn read_tilt(&self, addr: u32) -> u8 {
+        self.tilt.as_ref().map_or(0, |t| t.read(addr))
+    }
+
+    pub fn write_tilt(&mut self, addr: u32, value: u8) {
+        if let Some(tilt) = self.tilt.as_mut() {
+            tilt.write(addr, value, &mut *self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut gpio = Gpio::for_game(b"BPEE");
+        assert!(gpio.rtc.is_some());
+        assert!(Gpio::for_game(b"AGBE").rtc.is_none());
+
+        gpio.write(GPIO_CONTROL, 1);
+        gpio.write(GPIO_DIRECTION, 0x5);
+        gpio.write(GPIO_DATA, 0xF);
+        assert!(gpio.is_readable());
+        assert_eq!(gpio.read(GPIO_DIRECTION), 0x5);
+        // SIO is an input, the RTC is not sending
+        assert_eq!(gpio.read(GPIO_DATA), 0x5);
+        assert_eq!(gpio.read(GPIO_CONTROL), 1);
+    }
+
+    #[test]
+    fn test_sensors() {
+        use crate::sensors::ScriptedSensors;
+
+        let script = ScriptedSensors::new();
+        let mut gpio = Gpio::for_game(b"RZWE");
+        assert!(gpio.gyro.is_some() && gpio.has_rumble && gpio.rtc.is_none());
+        gpio.set_sensor_host(Box::new(script.clone()));
+        gpio.write(GPIO_DIRECTION, 0xB);
+        gpio.write(GPIO_DATA, 0x8);
+        gpio.write(GPIO_DATA, 0x8);
+        gpio.write(GPIO_DATA, 0x0);
+        assert_eq!(script.rumble_events(), vec![true, false]);
+
+        // Boktai has both the clock and the solar sensor
+        let gpio = Gpio::for_game(b"U3IE");
+        assert!(gpio.rtc.is_some() && gpio.solar.is_some());
+        assert!(Gpio::for_game(b"KYGE").is_tilt(0x0E00_8200));
+    }
+}
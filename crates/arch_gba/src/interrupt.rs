@@ -0,0 +1,102 @@
+/*
+ * Interrupt controller. See GBATEK "GBA Interrupt Control".
+ *
+ * IE (0x4000200) enables sources, IF (0x4000202) holds requested interrupts and is acknowledged
+ * by writing 1 bits, IME (0x4000208) bit 0 is the master enable. The CPU IRQ line is high while
+ * IME is set and an enabled interrupt is requested. Halt ends on an enabled request even with
+ * IME clear.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    HBlank = 1,
+    VCount = 2,
+    Timer0 = 3,
+    Timer1 = 4,
+    Timer2 = 5,
+    Timer3 = 6,
+    Serial = 7,
+    Dma0 = 8,
+    Dma1 = 9,
+    Dma2 = 10,
+    Dma3 = 11,
+    Keypad = 12,
+    GamePak = 13,
+}
+
+impl Interrupt {
+    pub fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InterruptController {
+    pub ie: u16,
+    pub if_: u16,
+    pub ime: bool,
+}
+
+impl InterruptController {
+    // Bits 14-15 of IE and IF do not exist.
+    const MASK: u16 = 0x3FFF;
+
+    pub fn new() -> Self {
+        InterruptController::default()
+    }
+
+    pub fn request(&mut self, irq: Interrupt) {
+        self.if_ |= irq.mask();
+    }
+
+    pub fn write_ie(&mut self, value: u16) {
+        self.ie = value & InterruptController::MASK;
+    }
+
+    // Writing 1 to a bit of IF acknowledges that interrupt.
+    pub fn acknowledge(&mut self, value: u16) {
+        self.if_ &= !value;
+    }
+
+    pub fn write_ime(&mut self, value: u16) {
+        self.ime = value & 1 == 1;
+    }
+
+    // An enabled interrupt is requested, this wakes the CPU from halt.
+    pub fn pending(&self) -> bool {
+        self.ie & self.if_ != 0
+    }
+
+    // Only the keypad, serial and cartridge interrupts end stop mode.
+    pub fn wakes_from_stop(&self) -> bool {
+        let sources =
+            Interrupt::Keypad.mask() | Interrupt::Serial.mask() | Interrupt::GamePak.mask();
+        self.ie & self.if_ & sources != 0
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.ime && self.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_line() {
+        let mut ic = InterruptController::new();
+        ic.request(Interrupt::VBlank);
+        assert!(!ic.pending());
+        ic.write_ie(Interrupt::VBlank.mask() | 0xC000);
+        assert_eq!(ic.ie, 1);
+        assert!(ic.pending());
+        assert!(!ic.irq_line());
+        ic.write_ime(1);
+        assert!(ic.irq_line());
+        ic.acknowledge(Interrupt::HBlank.mask());
+        assert!(ic.irq_line());
+        ic.acknowledge(Interrupt::VBlank.mask());
+        assert!(!ic.irq_line());
+    }
+}
@@ -0,0 +1,240 @@
+/*
+ * The I/O registers at 0x04000000-0x040003FF. See GBATEK "GBA I/O Map".
+ *
+ * Every register is described by an entry in REGISTERS: its offset, name, size in bytes, the bits
+ * that can be read and written, and the hook that runs on access. Registers without a hook are
+ * kept in plain storage by the bus. Bits outside the read mask read as 0, registers that are
+ * write only (read mask 0) and unused addresses read as open bus.
+ */
+pub const IO_BASE: u32 = 0x0400_0000;
+pub const IO_SIZE: u32 = 0x400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+    // Plain storage
+    None,
+    Dispstat,
+    Vcount,
+    InterruptEnable,
+    // Writing 1 to a bit of IF acknowledges the interrupt
+    InterruptAcknowledge,
+    Waitcnt,
+    InterruptMaster,
+    // Latches the channel registers when the enable bit is set
+    DmaControl(usize),
+    // Writes the reload value, reads the counter
+    TimerReload(usize),
+    // Loads the counter when the timer is started
+    TimerControl(usize),
+    Postflg,
+    Haltcnt,
+}
+
+#[derive(Debug)]
+pub struct IoRegister {
+    pub offset: u32,
+    pub name: &'static str,
+    pub size: u32,
+    pub read: u32,
+    pub write: u32,
+    pub hook: Hook,
+}
+
+const fn reg(offset: u32, name: &'static str, size: u32, read: u32, write: u32) -> IoRegister {
+    IoRegister {
+        offset,
+        name,
+        size,
+        read,
+        write,
+        hook: Hook::None,
+    }
+}
+
+const fn hooked(
+    offset: u32,
+    name: &'static str,
+    size: u32,
+    read: u32,
+    write: u32,
+    hook: Hook,
+) -> IoRegister {
+    IoRegister {
+        offset,
+        name,
+        size,
+        read,
+        write,
+        hook,
+    }
+}
+
+// Sorted by offset.
+pub static REGISTERS: &[IoRegister] = &[
+    // LCD
+    reg(0x000, "DISPCNT", 2, 0xFFFF, 0xFFF7),
+    reg(0x002, "GREENSWAP", 2, 0x0001, 0x0001),
+    hooked(0x004, "DISPSTAT", 2, 0xFF3F, 0xFF38, Hook::Dispstat),
+    hooked(0x006, "VCOUNT", 2, 0x00FF, 0, Hook::Vcount),
+    reg(0x008, "BG0CNT", 2, 0xDFFF, 0xDFFF),
+    reg(0x00A, "BG1CNT", 2, 0xDFFF, 0xDFFF),
+    reg(0x00C, "BG2CNT", 2, 0xFFFF, 0xFFFF),
+    reg(0x00E, "BG3CNT", 2, 0xFFFF, 0xFFFF),
+    reg(0x010, "BG0HOFS", 2, 0, 0x01FF),
+    reg(0x012, "BG0VOFS", 2, 0, 0x01FF),
+    reg(0x014, "BG1HOFS", 2, 0, 0x01FF),
+    reg(0x016, "BG1VOFS", 2, 0, 0x01FF),
+    reg(0x018, "BG2HOFS", 2, 0, 0x01FF),
+    reg(0x01A, "BG2VOFS", 2, 0, 0x01FF),
+    reg(0x01C, "BG3HOFS", 2, 0, 0x01FF),
+    reg(0x01E, "BG3VOFS", 2, 0, 0x01FF),
+    reg(0x020, "BG2PA", 2, 0, 0xFFFF),
+    reg(0x022, "BG2PB", 2, 0, 0xFFFF),
+    reg(0x024, "BG2PC", 2, 0, 0xFFFF),
+    reg(0x026, "BG2PD", 2, 0, 0xFFFF),
+    reg(0x028, "BG2X", 4, 0, 0x0FFF_FFFF),
+    reg(0x02C, "BG2Y", 4, 0, 0x0FFF_FFFF),
+    reg(0x030, "BG3PA", 2, 0, 0xFFFF),
+    reg(0x032, "BG3PB", 2, 0, 0xFFFF),
+    reg(0x034, "BG3PC", 2, 0, 0xFFFF),
+    reg(0x036, "BG3PD", 2, 0, 0xFFFF),
+    reg(0x038, "BG3X", 4, 0, 0x0FFF_FFFF),
+    reg(0x03C, "BG3Y", 4, 0, 0x0FFF_FFFF),
+    reg(0x040, "WIN0H", 2, 0, 0xFFFF),
+    reg(0x042, "WIN1H", 2, 0, 0xFFFF),
+    reg(0x044, "WIN0V", 2, 0, 0xFFFF),
+    reg(0x046, "WIN1V", 2, 0, 0xFFFF),
+    reg(0x048, "WININ", 2, 0x3F3F, 0x3F3F),
+    reg(0x04A, "WINOUT", 2, 0x3F3F, 0x3F3F),
+    reg(0x04C, "MOSAIC", 2, 0, 0xFFFF),
+    reg(0x050, "BLDCNT", 2, 0x3FFF, 0x3FFF),
+    reg(0x052, "BLDALPHA", 2, 0x1F1F, 0x1F1F),
+    reg(0x054, "BLDY", 2, 0, 0x001F),
+    // Sound
+    reg(0x060, "SOUND1CNT_L", 2, 0x007F, 0x007F),
+    reg(0x062, "SOUND1CNT_H", 2, 0xFFC0, 0xFFFF),
+    reg(0x064, "SOUND1CNT_X", 2, 0x4000, 0xC7FF),
+    reg(0x068, "SOUND2CNT_L", 2, 0xFFC0, 0xFFFF),
+    reg(0x06C, "SOUND2CNT_H", 2, 0x4000, 0xC7FF),
+    reg(0x070, "SOUND3CNT_L", 2, 0x00E0, 0x00E0),
+    reg(0x072, "SOUND3CNT_H", 2, 0xE000, 0xE0FF),
+    reg(0x074, "SOUND3CNT_X", 2, 0x4000, 0xC7FF),
+    reg(0x078, "SOUND4CNT_L", 2, 0xFF00, 0xFF3F),
+    reg(0x07C, "SOUND4CNT_H", 2, 0x40FF, 0xC0FF),
+    reg(0x080, "SOUNDCNT_L", 2, 0xFF77, 0xFF77),
+    reg(0x082, "SOUNDCNT_H", 2, 0x770F, 0xFF0F),
+    reg(0x084, "SOUNDCNT_X", 2, 0x008F, 0x0080),
+    reg(0x088, "SOUNDBIAS", 2, 0xC3FE, 0xC3FE),
+    reg(0x090, "WAVE_RAM0", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x094, "WAVE_RAM1", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x098, "WAVE_RAM2", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x09C, "WAVE_RAM3", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x0A0, "FIFO_A", 4, 0, 0xFFFF_FFFF),
+    reg(0x0A4, "FIFO_B", 4, 0, 0xFFFF_FFFF),
+    // DMA
+    reg(0x0B0, "DMA0SAD", 4, 0, 0x07FF_FFFF),
+    reg(0x0B4, "DMA0DAD", 4, 0, 0x07FF_FFFF),
+    reg(0x0B8, "DMA0CNT_L", 2, 0, 0x3FFF),
+    hooked(0x0BA, "DMA0CNT_H", 2, 0xF7E0, 0xF7E0, Hook::DmaControl(0)),
+    reg(0x0BC, "DMA1SAD", 4, 0, 0x0FFF_FFFF),
+    reg(0x0C0, "DMA1DAD", 4, 0, 0x07FF_FFFF),
+    reg(0x0C4, "DMA1CNT_L", 2, 0, 0x3FFF),
+    hooked(0x0C6, "DMA1CNT_H", 2, 0xF7E0, 0xF7E0, Hook::DmaControl(1)),
+    reg(0x0C8, "DMA2SAD", 4, 0, 0x0FFF_FFFF),
+    reg(0x0CC, "DMA2DAD", 4, 0, 0x07FF_FFFF),
+    reg(0x0D0, "DMA2CNT_L", 2, 0, 0x3FFF),
+    hooked(0x0D2, "DMA2CNT_H", 2, 0xF7E0, 0xF7E0, Hook::DmaControl(2)),
+    reg(0x0D4, "DMA3SAD", 4, 0, 0x0FFF_FFFF),
+    reg(0x0D8, "DMA3DAD", 4, 0, 0x0FFF_FFFF),
+    reg(0x0DC, "DMA3CNT_L", 2, 0, 0xFFFF),
+    hooked(0x0DE, "DMA3CNT_H", 2, 0xFFE0, 0xFFE0, Hook::DmaControl(3)),
+    // Timers
+    hooked(0x100, "TM0CNT_L", 2, 0xFFFF, 0xFFFF, Hook::TimerReload(0)),
+    hooked(0x102, "TM0CNT_H", 2, 0x00C7, 0x00C7, Hook::TimerControl(0)),
+    hooked(0x104, "TM1CNT_L", 2, 0xFFFF, 0xFFFF, Hook::TimerReload(1)),
+    hooked(0x106, "TM1CNT_H", 2, 0x00C7, 0x00C7, Hook::TimerControl(1)),
+    hooked(0x108, "TM2CNT_L", 2, 0xFFFF, 0xFFFF, Hook::TimerReload(2)),
+    hooked(0x10A, "TM2CNT_H", 2, 0x00C7, 0x00C7, Hook::TimerControl(2)),
+    hooked(0x10C, "TM3CNT_L", 2, 0xFFFF, 0xFFFF, Hook::TimerReload(3)),
+    hooked(0x10E, "TM3CNT_H", 2, 0x00C7, 0x00C7, Hook::TimerControl(3)),
+    // Serial communication
+    reg(0x120, "SIODATA32", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x124, "SIOMULTI2", 2, 0xFFFF, 0xFFFF),
+    reg(0x126, "SIOMULTI3", 2, 0xFFFF, 0xFFFF),
+    reg(0x128, "SIOCNT", 2, 0x7FFF, 0x7FFF),
+    reg(0x12A, "SIODATA8", 2, 0xFFFF, 0xFFFF),
+    // Keypad
+    reg(0x130, "KEYINPUT", 2, 0x03FF, 0),
+    reg(0x132, "KEYCNT", 2, 0xC3FF, 0xC3FF),
+    reg(0x134, "RCNT", 2, 0xC1FF, 0xC1FF),
+    reg(0x136, "IR", 2, 0xFFFF, 0xFFFF),
+    reg(0x140, "JOYCNT", 2, 0x0047, 0x0047),
+    reg(0x150, "JOY_RECV", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x154, "JOY_TRANS", 4, 0xFFFF_FFFF, 0xFFFF_FFFF),
+    reg(0x158, "JOYSTAT", 2, 0x003A, 0x0030),
+    // Interrupt, wait state and power control
+    hooked(0x200, "IE", 2, 0x3FFF, 0x3FFF, Hook::InterruptEnable),
+    hooked(0x202, "IF", 2, 0x3FFF, 0x3FFF, Hook::InterruptAcknowledge),
+    hooked(0x204, "WAITCNT", 2, 0x5FFF, 0x5FFF, Hook::Waitcnt),
+    hooked(0x208, "IME", 2, 0x0001, 0x0001, Hook::InterruptMaster),
+    hooked(0x300, "POSTFLG", 1, 0x01, 0x01, Hook::Postflg),
+    hooked(0x301, "HALTCNT", 1, 0, 0xFF, Hook::Haltcnt),
+];
+
+// The register containing the byte at the given I/O offset.
+pub fn register(offset: u32) -> Option<&'static IoRegister> {
+    let i = match REGISTERS.binary_search_by_key(&offset, |r| r.offset) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let r = &REGISTERS[i];
+    if offset < r.offset + r.size {
+        Some(r)
+    } else {
+        None
+    }
+}
+
+/*
+ * Name of the register at an address, for disassembly annotations and debug views. Addresses
+ * inside a register get the byte offset appended, e.g. BG2X+2.
+ */
+pub fn name(addr: u32) -> Option<String> {
+    if !(IO_BASE..IO_BASE + IO_SIZE).contains(&addr) {
+        return None;
+    }
+    let offset = addr - IO_BASE;
+    register(offset).map(|r| match offset - r.offset {
+        0 => r.name.to_string(),
+        n => format!("{}+{}", r.name, n),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        for pair in REGISTERS.windows(2) {
+            assert!(
+                pair[0].offset + pair[0].size <= pair[1].offset,
+                "{} overlaps {}",
+                pair[0].name,
+                pair[1].name
+            );
+        }
+        for r in REGISTERS.iter() {
+            let mask = u32::MAX >> (32 - r.size * 8);
+            assert_eq!(r.read & !mask, 0, "{}", r.name);
+            assert_eq!(r.write & !mask, 0, "{}", r.name);
+        }
+        assert_eq!(register(0x02E).unwrap().name, "BG2Y");
+        assert!(register(0x056).is_none());
+        assert_eq!(name(0x0400_0000).unwrap(), "DISPCNT");
+        assert_eq!(name(0x0400_002A).unwrap(), "BG2X+2");
+        assert_eq!(name(0x0400_0301).unwrap(), "HALTCNT");
+        assert_eq!(name(0x0400_0400), None);
+    }
+}
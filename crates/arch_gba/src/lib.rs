@@ -1,5 +1,22 @@
 extern crate arm7tdmi;
 use crate::arm7tdmi::arm::ARMCpu;
+pub mod apu;
+pub mod backup;
+pub mod bus;
+pub mod dma;
+pub mod eeprom;
+pub mod flash;
+pub mod gpio;
+pub mod interrupt;
+pub mod io;
+pub mod observer;
+pub mod ppu;
+pub mod rtc;
+pub mod scheduler;
+pub mod sensors;
+pub mod system;
+pub mod timer;
+pub mod timing;
 
 
 pub fn dump_cpu(_a: &ARMCpu) {
@@ -13,6 +30,6 @@ mod tests {
     use crate::arm7tdmi::arm::ARMCpu;
     #[test]
     fn it_works() {
-        dump_cpu(&ARMCpu{});
+        dump_cpu(&ARMCpu::new());
     }
 }
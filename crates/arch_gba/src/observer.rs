@@ -0,0 +1,96 @@
+use arm7tdmi::bus::MemoryWidth;
+use std::ops::RangeInclusive;
+
+/*
+ * Memory access observers, for watchpoints, cheat search, coverage and the like.
+ *
+ * A callback is registered for one kind of access over an address range and is called after each
+ * matching access with the access details. Reads and writes are those of the CPU, executes are
+ * reported before the instruction at the address executes. With no observer of a kind registered
+ * an access only costs a bit test.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read = 0,
+    Write = 1,
+    Execute = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: u32,
+    pub width: MemoryWidth,
+    pub value: u32,
+    // Address of the instruction doing the access.
+    pub pc: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObserverId(usize);
+
+struct Observer {
+    id: ObserverId,
+    kind: AccessKind,
+    range: RangeInclusive<u32>,
+    callback: Box<dyn FnMut(&Access)>,
+}
+
+#[derive(Default)]
+pub struct Observers {
+    observers: Vec<Observer>,
+    // Bit per AccessKind with at least one observer.
+    kinds: u8,
+    next_id: usize,
+}
+
+impl Observers {
+    pub fn new() -> Self {
+        Observers::default()
+    }
+
+    pub fn add<F>(
+        &mut self,
+        kind: AccessKind,
+        range: RangeInclusive<u32>,
+        callback: F,
+    ) -> ObserverId
+    where
+        F: FnMut(&Access) + 'static,
+    {
+        let id = ObserverId(self.next_id);
+        self.next_id += 1;
+        self.observers.push(Observer {
+            id,
+            kind,
+            range,
+            callback: Box::new(callback),
+        });
+        self.kinds |= 1 << kind as u8;
+        id
+    }
+
+    // Returns false if there is no such observer.
+    pub fn remove(&mut self, id: ObserverId) -> bool {
+        let len = self.observers.len();
+        self.observers.retain(|o| o.id != id);
+        self.kinds = self
+            .observers
+            .iter()
+            .fold(0, |kinds, o| kinds | 1 << o.kind as u8);
+        self.observers.len() != len
+    }
+
+    #[inline]
+    pub fn watches(&self, kind: AccessKind) -> bool {
+        self.kinds & (1 << kind as u8) != 0
+    }
+
+    pub fn notify(&mut self, access: &Access) {
+        for o in self.observers.iter_mut() {
+            if o.kind == access.kind && o.range.contains(&access.addr) {
+                (o.callback)(access);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use super::interrupt::{Interrupt, InterruptController};
+use super::scheduler::{EventKind, Scheduler};
+
+/*
+ * Display timing. See GBATEK "LCD Dimensions and Timings".
+ *
+ * A scanline is 1232 cycles, 1008 of drawing and 224 of HBlank. A frame is 228 lines, 160 drawn
+ * lines followed by 68 lines of VBlank. Only the timing, the DISPSTAT flags and the interrupts are
+ * emulated here.
+ */
+pub const HDRAW_CYCLES: u64 = 1008;
+pub const HBLANK_CYCLES: u64 = 224;
+pub const LINE_CYCLES: u64 = HDRAW_CYCLES + HBLANK_CYCLES;
+pub const VDRAW_LINES: u16 = 160;
+pub const TOTAL_LINES: u16 = 228;
+pub const FRAME_CYCLES: u64 = LINE_CYCLES * TOTAL_LINES as u64;
+
+// DISPSTAT bits
+const VBLANK_FLAG: u16 = 1 << 0;
+const HBLANK_FLAG: u16 = 1 << 1;
+const VCOUNT_FLAG: u16 = 1 << 2;
+const VBLANK_IRQ: u16 = 1 << 3;
+const HBLANK_IRQ: u16 = 1 << 4;
+const VCOUNT_IRQ: u16 = 1 << 5;
+const DISPSTAT_WRITABLE: u16 = 0xFF38;
+
+#[derive(Debug)]
+pub struct Ppu {
+    pub dispstat: u16,
+    pub vcount: u16,
+    // Cleared in stop mode, the display timing does not run then.
+    powered: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            dispstat: 0,
+            vcount: 0,
+            powered: true,
+        }
+    }
+
+    // Starts the display timing at the beginning of the current line.
+    pub fn start(&mut self, scheduler: &mut Scheduler) {
+        scheduler.schedule(EventKind::HBlankStart, HDRAW_CYCLES);
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    pub fn power_down(&mut self, scheduler: &mut Scheduler) {
+        self.powered = false;
+        scheduler.cancel(EventKind::HBlankStart);
+        scheduler.cancel(EventKind::LineEnd);
+    }
+
+    // Restarts the display timing at the start of the current line.
+    pub fn power_up(&mut self, scheduler: &mut Scheduler) {
+        if !self.powered {
+            self.powered = true;
+            self.dispstat &= !HBLANK_FLAG;
+            self.start(scheduler);
+        }
+    }
+
+    pub fn write_dispstat(&mut self, value: u16) {
+        self.dispstat = (self.dispstat & !DISPSTAT_WRITABLE) | (value & DISPSTAT_WRITABLE);
+    }
+
+    // time is the time the event was scheduled for, the next event is scheduled relative to it.
+    pub fn handle_event(
+        &mut self,
+        kind: EventKind,
+        time: u64,
+        scheduler: &mut Scheduler,
+        interrupts: &mut InterruptController,
+    ) {
+        match kind {
+            EventKind::HBlankStart => {
+                self.dispstat |= HBLANK_FLAG;
+                if self.dispstat & HBLANK_IRQ != 0 {
+                    interrupts.request(Interrupt::HBlank);
+                }
+                scheduler.schedule_at(EventKind::LineEnd, time + HBLANK_CYCLES);
+            }
+            EventKind::LineEnd => {
+                self.dispstat &= !HBLANK_FLAG;
+                self.vcount = (self.vcount + 1) % TOTAL_LINES;
+                if self.vcount == VDRAW_LINES {
+                    self.dispstat |= VBLANK_FLAG;
+                    if self.dispstat & VBLANK_IRQ != 0 {
+                        interrupts.request(Interrupt::VBlank);
+                    }
+                } else if self.vcount == TOTAL_LINES - 1 {
+                    // The flag is already clear on the last line of VBlank.
+                    self.dispstat &= !VBLANK_FLAG;
+                }
+                if self.vcount == self.dispstat >> 8 {
+                    self.dispstat |= VCOUNT_FLAG;
+                    if self.dispstat & VCOUNT_IRQ != 0 {
+                        interrupts.request(Interrupt::VCount);
+                    }
+                } else {
+                    self.dispstat &= !VCOUNT_FLAG;
+                }
+                scheduler.schedule_at(EventKind::HBlankStart, time + HDRAW_CYCLES);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ppu: &mut Ppu, s: &mut Scheduler, ic: &mut InterruptController, cycles: u64) {
+        s.advance(cycles);
+        while let Some((time, kind)) = s.pop_due() {
+            ppu.handle_event(kind, time, s, ic);
+        }
+    }
+
+    #[test]
+    fn test_timing() {
+        let mut ppu = Ppu::new();
+        let mut s = Scheduler::new();
+        let mut ic = InterruptController::new();
+        ppu.start(&mut s);
+        ppu.write_dispstat(VBLANK_IRQ | HBLANK_IRQ | VCOUNT_IRQ | 2 << 8 | VBLANK_FLAG);
+        assert_eq!(ppu.dispstat & VBLANK_FLAG, 0);
+
+        run(&mut ppu, &mut s, &mut ic, HDRAW_CYCLES);
+        assert_eq!(ppu.dispstat & HBLANK_FLAG, HBLANK_FLAG);
+        assert_eq!(ic.if_, Interrupt::HBlank.mask());
+
+        run(&mut ppu, &mut s, &mut ic, HBLANK_CYCLES + LINE_CYCLES);
+        assert_eq!(ppu.vcount, 2);
+        assert_eq!(ppu.dispstat & (HBLANK_FLAG | VCOUNT_FLAG), VCOUNT_FLAG);
+        assert_eq!(ic.if_ & Interrupt::VCount.mask(), Interrupt::VCount.mask());
+
+        run(&mut ppu, &mut s, &mut ic, LINE_CYCLES * 158);
+        assert_eq!(ppu.vcount, VDRAW_LINES);
+        assert_eq!(ppu.dispstat & VBLANK_FLAG, VBLANK_FLAG);
+        assert_eq!(ic.if_ & Interrupt::VBlank.mask(), Interrupt::VBlank.mask());
+
+        run(&mut ppu, &mut s, &mut ic, LINE_CYCLES * 68);
+        assert_eq!(ppu.vcount, 0);
+        assert_eq!(s.now(), FRAME_CYCLES);
+        assert_eq!(ppu.dispstat & VBLANK_FLAG, 0);
+    }
+}
@@ -0,0 +1,470 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Seiko S-3511 real time clock, on the cartridge GPIO port. See GBATEK "GBA Cart Real-Time Clock
+ * (RTC)".
+ *
+ *   GPIO bit 0 SCK, bit 1 SIO, bit 2 CS
+ *
+ * A transfer starts when CS goes high. Bits are latched on the rising edge of SCK, least
+ * significant bit first: a command byte, then the register bytes from or to the chip. The
+ * command byte is
+ *
+ *   bits 0-3 fixed 0110, bits 4-6 register, bit 7 read
+ *
+ * Games usually build it the other way around and send it most significant bit first, e.g.
+ * 0x65 for reading the date and time, which is the same bits. The registers are in BCD:
+ *
+ *   0 reset         (no data)  status cleared, date and time set to 2000-01-01 00:00:00
+ *   2 date and time 7 bytes    year, month, day, day of week, hour, minute, second
+ *   3 force IRQ     (no data)
+ *   4 status        1 byte     bit 1, 3, 5 IRQ setup, bit 6 24 hour mode, bit 7 power lost
+ *   6 time          3 bytes    hour, minute, second
+ *
+ * Bit 7 of the hour is set from 12:00 on, in both modes. Times are taken from a Clock, writes
+ * keep the difference to it.
+ */
+pub const SCK: u8 = 1 << 0;
+pub const SIO: u8 = 1 << 1;
+pub const CS: u8 = 1 << 2;
+
+pub const STATUS_24H: u8 = 1 << 6;
+const STATUS_WRITABLE: u8 = 0x6A;
+
+const REGISTER_LEN: [usize; 8] = [0, 0, 7, 0, 1, 0, 3, 0];
+
+// 2000-01-01 00:00:00
+const RESET_TIME: i64 = 946_684_800;
+
+// Seconds since 1970-01-01 00:00:00.
+pub trait Clock {
+    fn now(&mut self) -> i64;
+}
+
+// The time of the host, in UTC.
+#[derive(Debug, Default)]
+pub struct HostClock;
+
+impl Clock for HostClock {
+    fn now(&mut self) -> i64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        }
+    }
+}
+
+// Always the same time, for tests and replays.
+#[derive(Debug)]
+pub struct FixedClock(pub i64);
+
+impl FixedClock {
+    pub fn new(time: DateTime) -> Self {
+        FixedClock(time.to_unix())
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&mut self) -> i64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    // Proleptic Gregorian calendar, see http://howardhinnant.github.io/date_algorithms.html
+    fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = if y >= 0 { y } else { y - 399 } / 400;
+        let yoe = y - era * 400;
+        let m = month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86_400);
+        let rem = secs.rem_euclid(86_400) as u32;
+        let z = days + 719_468;
+        let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+        }
+    }
+
+    pub fn to_unix(&self) -> i64 {
+        let days = DateTime::days_from_civil(self.year, self.month, self.day);
+        days * 86_400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64
+    }
+
+    // 0 is Sunday.
+    pub fn weekday(&self) -> u32 {
+        let days = DateTime::days_from_civil(self.year, self.month, self.day);
+        (days + 4).rem_euclid(7) as u32
+    }
+}
+
+fn bcd(value: u32) -> u8 {
+    (value / 10 * 16 + value % 10) as u8
+}
+
+fn from_bcd(value: u8) -> Option<u32> {
+    let (hi, lo) = (value >> 4, value & 0xF);
+    if hi > 9 || lo > 9 {
+        return None;
+    }
+    Some(hi as u32 * 10 + lo as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // CS low
+    Idle,
+    Command {
+        bits: u8,
+        pos: usize,
+    },
+    Reading {
+        data: [u8; 7],
+        len: usize,
+        pos: usize,
+    },
+    Writing {
+        reg: u8,
+        data: [u8; 7],
+        len: usize,
+        pos: usize,
+    },
+    // Transfer over or invalid, until CS goes low
+    Done,
+}
+
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    // Seconds added to the clock, set by writing the time.
+    offset: i64,
+    pub status: u8,
+    state: State,
+    pins: u8,
+    // Bit the chip drives on SIO while it is read.
+    output: u8,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Rtc {
+            clock,
+            offset: 0,
+            status: STATUS_24H,
+            state: State::Idle,
+            pins: 0,
+            output: 0,
+        }
+    }
+
+    pub fn date_time(&mut self) -> DateTime {
+        DateTime::from_unix(self.clock.now() + self.offset)
+    }
+
+    fn set_date_time(&mut self, time: DateTime) {
+        self.offset = time.to_unix() - self.clock.now();
+    }
+
+    fn hour(&self, hour: u32) -> u8 {
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        if self.status & STATUS_24H != 0 {
+            bcd(hour) | pm
+        } else {
+            bcd(hour % 12) | pm
+        }
+    }
+
+    fn read_register(&mut self, reg: u8) -> [u8; 7] {
+        let t = self.date_time();
+        let time = [self.hour(t.hour), bcd(t.minute), bcd(t.second)];
+        let mut data = [0; 7];
+        match reg {
+            2 => {
+                let date = [
+                    bcd(t.year.rem_euclid(100) as u32),
+                    bcd(t.month),
+                    bcd(t.day),
+                    bcd(t.weekday()),
+                ];
+                data[..4].copy_from_slice(&date);
+                data[4..].copy_from_slice(&time);
+            }
+            4 => data[0] = self.status,
+            6 => data[..3].copy_from_slice(&time),
+            _ => {}
+        }
+        data
+    }
+
+    // In 12 hour mode bit 7 tells PM, in 24 hour mode it is ignored.
+    fn parse_hour(&self, value: u8) -> Option<u32> {
+        let hour = from_bcd(value & 0x3F)?;
+        if self.status & STATUS_24H == 0 && value & 0x80 != 0 {
+            Some(hour % 12 + 12)
+        } else {
+            Some(hour)
+        }
+    }
+
+    fn parse_time(&self, mut t: DateTime, data: &[u8]) -> Option<DateTime> {
+        t.hour = self.parse_hour(data[0])?;
+        t.minute = from_bcd(data[1])?;
+        t.second = from_bcd(data[2])?;
+        if t.hour > 23 || t.minute > 59 || t.second > 59 {
+            return None;
+        }
+        Some(t)
+    }
+
+    fn parse_date_time(&self, now: DateTime, data: &[u8; 7]) -> Option<DateTime> {
+        let t = DateTime {
+            year: 2000 + from_bcd(data[0])? as i64,
+            month: from_bcd(data[1])?,
+            day: from_bcd(data[2])?,
+            ..now
+        };
+        if t.month < 1 || t.month > 12 || t.day < 1 || t.day > 31 {
+            return None;
+        }
+        self.parse_time(t, &data[4..])
+    }
+
+    // The day of week is computed from the date, writing it has no effect. Invalid values are
+    // ignored.
+    fn write_register(&mut self, reg: u8, data: &[u8; 7]) {
+        let now = self.date_time();
+        let time = match reg {
+            2 => self.parse_date_time(now, data),
+            4 => {
+                self.status = data[0] & STATUS_WRITABLE;
+                None
+            }
+            6 => self.parse_time(now, &data[..3]),
+            _ => None,
+        };
+        if let Some(time) = time {
+            self.set_date_time(time);
+        }
+    }
+
+    fn execute(&mut self, reg: u8) {
+        if reg == 0 {
+            self.status = 0;
+            self.offset = RESET_TIME - self.clock.now();
+        }
+    }
+
+    // The pins the chip drives.
+    pub fn read_pins(&self) -> u8 {
+        match self.state {
+            State::Reading { .. } | State::Done => self.output << 1,
+            _ => 0,
+        }
+    }
+
+    // Pins driven by the GBA, SIO only counts while it is an output.
+    pub fn write_pins(&mut self, pins: u8) {
+        let old = self.pins;
+        self.pins = pins;
+        if pins & CS == 0 {
+            self.state = State::Idle;
+            self.output = 0;
+            return;
+        }
+        if old & CS == 0 {
+            self.state = State::Command { bits: 0, pos: 0 };
+            return;
+        }
+        if old & SCK != 0 || pins & SCK == 0 {
+            return;
+        }
+        let sio = (pins & SIO) >> 1;
+        self.state = match self.state {
+            State::Command { bits, pos } => {
+                let bits = bits | sio << pos;
+                if pos < 7 {
+                    State::Command { bits, pos: pos + 1 }
+                } else {
+                    self.command(bits)
+                }
+            }
+            State::Reading { data, len, pos } => {
+                self.output = data[pos / 8] >> (pos % 8) & 1;
+                if pos + 1 < len * 8 {
+                    State::Reading {
+                        data,
+                        len,
+                        pos: pos + 1,
+                    }
+                } else {
+                    State::Done
+                }
+            }
+            State::Writing {
+                reg,
+                mut data,
+                len,
+                pos,
+            } => {
+                data[pos / 8] |= sio << (pos % 8);
+                if pos + 1 < len * 8 {
+                    State::Writing {
+                        reg,
+                        data,
+                        len,
+                        pos: pos + 1,
+                    }
+                } else {
+                    self.write_register(reg, &data);
+                    State::Done
+                }
+            }
+            state => state,
+        };
+    }
+
+    fn command(&mut self, bits: u8) -> State {
+        if bits & 0xF != 0x6 {
+            return State::Done;
+        }
+        let reg = bits >> 4 & 7;
+        let len = REGISTER_LEN[reg as usize];
+        if bits & 0x80 != 0 {
+            State::Reading {
+                data: self.read_register(reg),
+                len,
+                pos: 0,
+            }
+        } else if len == 0 {
+            self.execute(reg);
+            State::Done
+        } else {
+            State::Writing {
+                reg,
+                data: [0; 7],
+                len,
+                pos: 0,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends the bits of value like a game, SIO as an output.
+    fn send(rtc: &mut Rtc, value: u8) {
+        for i in 0..8 {
+            let sio = (value >> i & 1) << 1;
+            rtc.write_pins(CS | sio);
+            rtc.write_pins(CS | SCK | sio);
+        }
+    }
+
+    fn receive(rtc: &mut Rtc, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        for i in 0..len * 8 {
+            rtc.write_pins(CS);
+            rtc.write_pins(CS | SCK);
+            data[i / 8] |= (rtc.read_pins() & SIO) >> 1 << (i % 8);
+        }
+        data
+    }
+
+    fn transfer(rtc: &mut Rtc, command: u8, write: &[u8], read: usize) -> Vec<u8> {
+        rtc.write_pins(SCK);
+        rtc.write_pins(CS | SCK);
+        send(rtc, command);
+        for &b in write {
+            send(rtc, b);
+        }
+        let data = receive(rtc, read);
+        rtc.write_pins(SCK);
+        data
+    }
+
+    #[test]
+    fn test_date_time() {
+        let t = DateTime::from_unix(0);
+        assert_eq!((t.year, t.month, t.day, t.weekday()), (1970, 1, 1, 4));
+        let t = DateTime {
+            year: 2004,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 58,
+        };
+        assert_eq!(DateTime::from_unix(t.to_unix()), t);
+        assert_eq!(t.weekday(), 0);
+        assert_eq!(DateTime::from_unix(RESET_TIME).year, 2000);
+    }
+
+    #[test]
+    fn test_registers() {
+        let clock = FixedClock::new(DateTime {
+            year: 2004,
+            month: 12,
+            day: 31,
+            hour: 13,
+            minute: 5,
+            second: 9,
+        });
+        let mut rtc = Rtc::new(Box::new(clock));
+
+        // Date and time, read with the command sent most significant bit first by games
+        let read_date_time = 0x65u8.reverse_bits();
+        let date = transfer(&mut rtc, read_date_time, &[], 7);
+        assert_eq!(date, [0x04, 0x12, 0x31, 0x05, 0x93, 0x05, 0x09]);
+
+        // 12 hour mode
+        transfer(&mut rtc, 0x46, &[0x00], 0);
+        assert_eq!(transfer(&mut rtc, 0xC6, &[], 1), [0x00]);
+        assert_eq!(transfer(&mut rtc, 0xE6, &[], 3), [0x81, 0x05, 0x09]);
+
+        // Setting the time keeps the date, the clock runs on from it
+        transfer(&mut rtc, 0x46, &[0xFF], 0);
+        assert_eq!(rtc.status, STATUS_WRITABLE);
+        transfer(&mut rtc, 0x66, &[0x08, 0x30, 0x00], 0);
+        let t = rtc.date_time();
+        assert_eq!((t.day, t.hour, t.minute, t.second), (31, 8, 30, 0));
+
+        // Invalid values are ignored, reset goes back to 2000
+        transfer(&mut rtc, 0x26, &[0x99, 0x13, 0x01, 0, 0, 0, 0], 0);
+        assert_eq!(rtc.date_time().month, 12);
+        transfer(&mut rtc, 0x06, &[], 0);
+        assert_eq!(rtc.status, 0);
+        assert_eq!(rtc.date_time().to_unix(), RESET_TIME);
+
+        // A command without the fixed code is ignored
+        assert_eq!(transfer(&mut rtc, 0xC5, &[], 1), [0]);
+    }
+}
@@ -0,0 +1,95 @@
+/*
+ * Event scheduler.
+ *
+ * Time is counted in CPU cycles (16.78 MHz) since power on. Hardware that does something at a
+ * known point in time (the end of a scanline, a timer overflow, ...) schedules an event instead
+ * of being ticked every cycle, and the system runs the CPU up to the next event.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    // PPU: end of the visible part of a scanline
+    HBlankStart,
+    // PPU: end of a scanline, VCOUNT advances
+    LineEnd,
+    // Backup: check whether the save file can be written
+    SaveFlush,
+    // Timer n: the counter overflows
+    TimerOverflow(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    now: u64,
+    // Kept sorted by time, the next event last. Events at the same time run in the order they
+    // were scheduled.
+    events: Vec<(u64, EventKind)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler::default()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn schedule(&mut self, kind: EventKind, cycles_from_now: u64) {
+        self.schedule_at(kind, self.now + cycles_from_now);
+    }
+
+    // Schedules at an absolute time, e.g. relative to the time of the event being handled.
+    pub fn schedule_at(&mut self, kind: EventKind, time: u64) {
+        let pos = self
+            .events
+            .iter()
+            .position(|&(t, _)| t <= time)
+            .unwrap_or(self.events.len());
+        self.events.insert(pos, (time, kind));
+    }
+
+    pub fn cancel(&mut self, kind: EventKind) {
+        self.events.retain(|&(_, k)| k != kind);
+    }
+
+    // Time of the next event, if any.
+    pub fn next_event(&self) -> Option<u64> {
+        self.events.last().map(|&(t, _)| t)
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    // Removes and returns the next event that is due. Returns the time it was scheduled for,
+    // which may be earlier than now if the CPU overshot it.
+    pub fn pop_due(&mut self) -> Option<(u64, EventKind)> {
+        match self.events.last() {
+            Some(&(t, _)) if t <= self.now => self.events.pop(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_order() {
+        let mut s = Scheduler::new();
+        s.schedule(EventKind::LineEnd, 100);
+        s.schedule(EventKind::HBlankStart, 50);
+        assert_eq!(s.next_event(), Some(50));
+        assert_eq!(s.pop_due(), None);
+
+        s.advance(120);
+        assert_eq!(s.pop_due(), Some((50, EventKind::HBlankStart)));
+        assert_eq!(s.pop_due(), Some((100, EventKind::LineEnd)));
+        assert_eq!(s.pop_due(), None);
+
+        s.schedule(EventKind::LineEnd, 10);
+        s.cancel(EventKind::LineEnd);
+        assert_eq!(s.next_event(), None);
+    }
+}
@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/*
+ * Cartridge sensors and rumble. See GBATEK "GBA Cart Solar Sensor", "GBA Cart Gyro Sensor",
+ * "GBA Cart Tilt Sensor" and "GBA Cart Rumble".
+ *
+ *   Solar  GPIO bit 0 clock, bit 1 reset, bit 3 flag: after a reset the flag goes high once the
+ *          clock pulses counted reach the light threshold, fewer pulses mean more light
+ *   Gyro   GPIO bit 0 start, bit 1 clock, bit 2 data: start samples the rotation speed, each
+ *          falling clock edge sends a bit of it, most significant first
+ *   Rumble GPIO bit 3, the motor runs while it is high
+ *   Tilt   not on the GPIO port but in the SRAM area: writing 0x55 to 0x0E008000 and 0xAA to
+ *          0x0E008100 samples, 0x0E008200-0x0E008500 read X and Y low and high bytes, bit 7 of
+ *          the X high byte is set when done
+ *
+ * Values come from and rumble goes to a SensorHost, the frontend.
+ */
+pub const TILT_LEVEL: u16 = 0x3A0;
+pub const GYRO_REST: u16 = 0x6C0;
+
+pub trait SensorHost {
+    // Light falling on the solar sensor, 0 (dark) to 255.
+    fn luminance(&mut self) -> u8;
+    // Tilt X and Y, 12 bits, TILT_LEVEL when held level.
+    fn tilt(&mut self) -> (u16, u16);
+    // Rotation speed around the Z axis, 12 bits, GYRO_REST when still.
+    fn gyro(&mut self) -> u16;
+    // Called when the rumble motor starts or stops.
+    fn rumble(&mut self, on: bool);
+}
+
+// Dark, level and still.
+#[derive(Debug, Default)]
+pub struct NoSensors;
+
+impl SensorHost for NoSensors {
+    fn luminance(&mut self) -> u8 {
+        0
+    }
+
+    fn tilt(&mut self) -> (u16, u16) {
+        (TILT_LEVEL, TILT_LEVEL)
+    }
+
+    fn gyro(&mut self) -> u16 {
+        GYRO_REST
+    }
+
+    fn rumble(&mut self, _on: bool) {}
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    luminance: VecDeque<u8>,
+    tilt: VecDeque<(u16, u16)>,
+    gyro: VecDeque<u16>,
+    rumble: Vec<bool>,
+}
+
+// Takes the next queued value, the last one stays once the queue runs out.
+fn next<T: Copy>(queue: &mut VecDeque<T>, default: T) -> T {
+    if queue.len() > 1 {
+        queue.pop_front().unwrap()
+    } else {
+        queue.front().copied().unwrap_or(default)
+    }
+}
+
+/*
+ * Sensor values queued up front and rumble events recorded, for headless runs and tests.
+ * Clones share the script, keep one to queue values and check the events while the system owns
+ * another.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct ScriptedSensors(Rc<RefCell<Script>>);
+
+impl ScriptedSensors {
+    pub fn new() -> Self {
+        ScriptedSensors::default()
+    }
+
+    pub fn push_luminance(&self, value: u8) {
+        self.0.borrow_mut().luminance.push_back(value);
+    }
+
+    pub fn push_tilt(&self, x: u16, y: u16) {
+        self.0.borrow_mut().tilt.push_back((x, y));
+    }
+
+    pub fn push_gyro(&self, value: u16) {
+        self.0.borrow_mut().gyro.push_back(value);
+    }
+
+    pub fn rumble_events(&self) -> Vec<bool> {
+        self.0.borrow().rumble.clone()
+    }
+}
+
+impl SensorHost for ScriptedSensors {
+    fn luminance(&mut self) -> u8 {
+        next(&mut self.0.borrow_mut().luminance, 0)
+    }
+
+    fn tilt(&mut self) -> (u16, u16) {
+        next(&mut self.0.borrow_mut().tilt, (TILT_LEVEL, TILT_LEVEL))
+    }
+
+    fn gyro(&mut self) -> u16 {
+        next(&mut self.0.borrow_mut().gyro, GYRO_REST)
+    }
+
+    fn rumble(&mut self, on: bool) {
+        self.0.borrow_mut().rumble.push(on);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SolarSensor {
+    counter: u8,
+    threshold: u8,
+    pins: u8,
+}
+
+impl SolarSensor {
+    pub fn write_pins(&mut self, pins: u8, host: &mut dyn SensorHost) {
+        if pins & 2 != 0 {
+            self.counter = 0;
+            self.threshold = 0xFF - host.luminance();
+        } else if self.pins & 1 == 0 && pins & 1 != 0 {
+            self.counter = self.counter.saturating_add(1);
+        }
+        self.pins = pins;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        ((self.counter >= self.threshold) as u8) << 3
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GyroSensor {
+    sample: u16,
+    output: u8,
+    pins: u8,
+}
+
+impl GyroSensor {
+    pub fn write_pins(&mut self, pins: u8, host: &mut dyn SensorHost) {
+        if pins & 1 != 0 {
+            self.sample = host.gyro() & 0xFFF;
+        }
+        if self.pins & 2 != 0 && pins & 2 == 0 {
+            self.output = (self.sample >> 15) as u8;
+            self.sample <<= 1;
+        }
+        self.pins = pins;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        self.output << 2
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TiltSensor {
+    unlocked: bool,
+    x: u16,
+    y: u16,
+    ready: bool,
+}
+
+impl TiltSensor {
+    pub fn is_tilt(addr: u32) -> bool {
+        addr >> 24 == 0x0E && (0x8000..0x8600).contains(&(addr & 0xFFFF))
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        match addr & 0xFF00 {
+            0x8200 => self.x as u8,
+            0x8300 => (self.x >> 8) as u8 & 0xF | (self.ready as u8) << 7,
+            0x8400 => self.y as u8,
+            0x8500 => (self.y >> 8) as u8 & 0xF,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8, host: &mut dyn SensorHost) {
+        match (addr & 0xFF00, value) {
+            (0x8000, 0x55) => self.unlocked = true,
+            (0x8100, 0xAA) if self.unlocked => {
+                let (x, y) = host.tilt();
+                self.x = x & 0xFFF;
+                self.y = y & 0xFFF;
+                self.ready = true;
+                self.unlocked = false;
+            }
+            _ => self.unlocked = false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted() {
+        let mut script = ScriptedSensors::new();
+        script.push_gyro(0x700);
+        script.push_gyro(0x600);
+        assert_eq!(script.gyro(), 0x700);
+        assert_eq!(script.gyro(), 0x600);
+        assert_eq!(script.gyro(), 0x600);
+        assert_eq!(script.luminance(), 0);
+        let mut host = script.clone();
+        host.rumble(true);
+        assert_eq!(script.rumble_events(), vec![true]);
+    }
+
+    #[test]
+    fn test_solar() {
+        let mut host = ScriptedSensors::new();
+        host.push_luminance(0xF0);
+        let mut solar = SolarSensor::default();
+        solar.write_pins(2, &mut host);
+        solar.write_pins(0, &mut host);
+        let mut pulses = 0;
+        while solar.read_pins() == 0 {
+            solar.write_pins(1, &mut host);
+            solar.write_pins(0, &mut host);
+            pulses += 1;
+        }
+        assert_eq!(pulses, 0x0F);
+    }
+
+    #[test]
+    fn test_gyro() {
+        let mut host = ScriptedSensors::new();
+        host.push_gyro(0xABC);
+        let mut gyro = GyroSensor::default();
+        gyro.write_pins(1 | 2, &mut host);
+        let mut value = 0;
+        for _ in 0..16 {
+            gyro.write_pins(0, &mut host);
+            value = value << 1 | (gyro.read_pins() >> 2) as u16;
+            gyro.write_pins(2, &mut host);
+        }
+        assert_eq!(value, 0xABC);
+    }
+
+    #[test]
+    fn test_tilt() {
+        let mut host = ScriptedSensors::new();
+        host.push_tilt(0x3B5, 0x290);
+        let mut tilt = TiltSensor::default();
+        assert!(TiltSensor::is_tilt(0x0E00_8300) && !TiltSensor::is_tilt(0x0E00_0000));
+        tilt.write(0x0E00_8100, 0xAA, &mut host);
+        assert_eq!(tilt.read(0x0E00_8300), 0);
+        tilt.write(0x0E00_8000, 0x55, &mut host);
+        tilt.write(0x0E00_8100, 0xAA, &mut host);
+        let read: Vec<u8> = (2..6).map(|i| tilt.read(0x0E00_8000 + i * 0x100)).collect();
+        assert_eq!(read, [0xB5, 0x83, 0x90, 0x02]);
+    }
+}
@@ -0,0 +1,487 @@
+use super::backup::save_path;
+use super::bus::{GbaBus, BIOS_LATCH_AFTER_BOOT, MULTIBOOT_ENTRY};
+use super::interrupt::Interrupt;
+use super::observer::AccessKind;
+use super::ppu::FRAME_CYCLES;
+use arm7tdmi::cpu::ARMCpu;
+use arm7tdmi::psr::{CpuMode, Psr};
+use std::io;
+use std::path::Path;
+use util::elf::{Elf, Region};
+
+/*
+ * Power state of the CPU, set by writing HALTCNT.
+ *
+ * Halted: the CPU waits for an enabled interrupt (IE & IF, IME does not matter), the rest of the
+ * system keeps running. Stopped: the display and sound are powered down as well, only the keypad,
+ * serial and cartridge interrupts can end it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerState {
+    Running,
+    Halted,
+    Stopped,
+}
+
+pub struct Gba {
+    pub cpu: ARMCpu,
+    pub bus: GbaBus,
+}
+
+impl Gba {
+    pub fn new(bios: Vec<u8>, rom: Vec<u8>) -> Self {
+        Gba {
+            cpu: ARMCpu::new(),
+            bus: GbaBus::new(bios, rom),
+        }
+    }
+
+    // Sets up the CPU the way the BIOS leaves it before jumping to the cartridge.
+    pub fn skip_bios(&mut self) {
+        let stacks = [
+            (CpuMode::Supervisor, 0x0300_7FE0),
+            (CpuMode::Irq, 0x0300_7FA0),
+            (CpuMode::System, 0x0300_7F00),
+        ];
+        for &(mode, sp) in stacks.iter() {
+            self.cpu.set_cpsr(Psr(mode as u32));
+            self.cpu.set_reg(ARMCpu::SP, sp);
+        }
+        self.cpu.set_reg(ARMCpu::PC, 0x0800_0000);
+        self.bus.set_bios_latch(BIOS_LATCH_AFTER_BOOT);
+    }
+
+    // Starts a multiboot image the way the BIOS does after receiving it, with no cartridge.
+    pub fn boot_multiboot(bios: Vec<u8>, image: &[u8]) -> Self {
+        let mut gba = Gba::new(bios, vec![]);
+        gba.bus.load_multiboot(image);
+        gba.skip_bios();
+        gba.cpu.set_reg(ARMCpu::PC, MULTIBOOT_ENTRY);
+        gba
+    }
+
+    /*
+     * Starts an ELF executable at its entry point with each segment loaded at its physical
+     * address. Without ROM segments it is a multiboot build and its EWRAM segments are loaded
+     * like a received multiboot image. Its symbols name the branch targets in CPU traces.
+     */
+    pub fn boot_elf(bios: Vec<u8>, elf: &Elf) -> Self {
+        let mut gba = Gba::new(bios, elf.image(Region::Rom));
+        if !elf.has_region(Region::Rom) {
+            gba.bus.load_multiboot(&elf.image(Region::Ewram));
+        }
+        for segment in elf.segments.iter() {
+            let mem = match segment.region {
+                Region::Rom => continue,
+                Region::Ewram => &mut gba.bus.ewram,
+                Region::Iwram => &mut gba.bus.iwram,
+            };
+            let offset = segment.offset();
+            mem[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        gba.skip_bios();
+        let mut cpsr = gba.cpu.cpsr();
+        cpsr.set_thumb(elf.entry & 1 != 0);
+        gba.cpu.set_cpsr(cpsr);
+        gba.cpu.set_reg(ARMCpu::PC, elf.entry & !1);
+        gba.cpu.set_symbols(elf.symbols.clone());
+        gba
+    }
+
+    // Loads the save of the ROM at rom_path and keeps it up to date, see backup.rs.
+    pub fn attach_save_file(&mut self, rom_path: &Path) -> io::Result<()> {
+        self.bus.backup.attach_file(&save_path(rom_path))
+    }
+
+    // Why the save file could not be written in the background, see Backup::take_error.
+    pub fn take_save_error(&mut self) -> Option<io::Error> {
+        self.bus.backup.take_error()
+    }
+
+    // Writes unsaved backup changes, call before exiting.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.bus.backup.flush()
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        self.bus.power
+    }
+
+    // Raises an interrupt from outside the system, e.g. the keypad.
+    pub fn request_interrupt(&mut self, irq: Interrupt) {
+        self.bus.interrupts.request(irq);
+    }
+
+    /*
+     * Executes one instruction and returns the cycles it took. A halted or stopped CPU instead
+     * skips ahead to the next scheduled event, or wakes up if an enabled interrupt is pending.
+     * Returns 0 if the CPU is stopped and nothing is scheduled.
+     */
+    pub fn step(&mut self) -> u64 {
+        self.step_until(u64::MAX)
+    }
+
+    // Runs for at least the given number of cycles.
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.bus.scheduler.now() + cycles;
+        while self.bus.scheduler.now() < end {
+            self.step_until(end);
+        }
+    }
+
+    pub fn run_frame(&mut self) {
+        self.run(FRAME_CYCLES);
+    }
+
+    // Like step, but a skip ahead in halt or stop mode never goes past end.
+    fn step_until(&mut self, end: u64) -> u64 {
+        let cycles = match self.bus.power {
+            PowerState::Running => {
+                let irq = self.bus.interrupts.irq_line();
+                self.cpu.set_irq(irq);
+                // An IRQ is taken instead of executing the instruction.
+                let takes_irq = irq && !self.cpu.cpsr().irq_disabled();
+                if !takes_irq && self.bus.observers.watches(AccessKind::Execute) {
+                    self.bus
+                        .notify_execute(self.cpu.next_pc(), self.cpu.is_thumb());
+                }
+                self.cpu.step(&mut self.bus) as u64
+            }
+            PowerState::Halted if self.bus.interrupts.pending() => {
+                self.bus.wake();
+                0
+            }
+            PowerState::Stopped if self.bus.interrupts.wakes_from_stop() => {
+                self.bus.wake();
+                0
+            }
+            _ => {
+                let now = self.bus.scheduler.now();
+                match self.bus.scheduler.next_event() {
+                    Some(time) => time.min(end) - now,
+                    None if end != u64::MAX => end - now,
+                    None => 0,
+                }
+            }
+        };
+        self.bus.advance(cycles);
+        for (addr, len) in self.bus.dma_writes.drain(..) {
+            self.cpu.invalidate_code(addr, len);
+        }
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{LINE_CYCLES, VDRAW_LINES};
+
+    fn rom(code: &[u32]) -> Vec<u8> {
+        code.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_boot_elf() {
+        use util::elf::{Segment, Symbol, Symbols};
+
+        let elf = Elf {
+            entry: 0x0300_0001,
+            segments: vec![
+                Segment {
+                    region: Region::Rom,
+                    addr: 0x0800_0000,
+                    data: rom(&[0xeafffffe]), // b .
+                },
+                Segment {
+                    region: Region::Iwram,
+                    addr: 0x0300_0000,
+                    data: vec![
+                        0x2a, 0x20, // movs r0, #42
+                        0xfe, 0xe7, // b .
+                    ],
+                },
+            ],
+            symbols: Symbols::new(vec![Symbol {
+                name: "main".to_string(),
+                addr: 0x0300_0000,
+                size: 4,
+            }]),
+        };
+        let mut gba = Gba::boot_elf(vec![], &elf);
+        assert!(gba.cpu.is_thumb());
+        gba.run(100);
+        assert_eq!(gba.cpu.reg(0), 42);
+        assert_eq!(gba.bus.peek_32(0x0800_0000), 0xeafffffe);
+        assert_eq!(
+            gba.cpu.symbols().format(0x0300_0002).as_deref(),
+            Some("main+0x2")
+        );
+    }
+
+    #[test]
+    fn test_halt_until_vblank() {
+        let mut gba = Gba::new(
+            vec![],
+            rom(&[
+                0xe3a00301, // mov r0, #0x04000000
+                0xe3a01008, // mov r1, #8
+                0xe1c010b4, // strh r1, [r0, #4] @ DISPSTAT VBlank IRQ
+                0xe2802c02, // add r2, r0, #0x200
+                0xe3a01001, // mov r1, #1
+                0xe1c210b0, // strh r1, [r2] @ IE VBlank
+                0xe3a01000, // mov r1, #0
+                0xe5c21101, // strb r1, [r2, #0x101] @ HALTCNT halt
+                0xe3a03001, // mov r3, #1
+                0xeafffffe, // b .
+            ]),
+        );
+        gba.skip_bios();
+        let mut steps = 0;
+        while gba.cpu.reg(3) == 0 {
+            let _ = gba.step();
+            steps += 1;
+            assert!(steps < 1000, "halt does not skip ahead");
+        }
+        // Woken by VBlank with IME clear, IF stays set
+        assert_eq!(gba.bus.ppu.vcount, VDRAW_LINES);
+        assert_eq!(gba.bus.interrupts.if_, Interrupt::VBlank.mask());
+        assert!(gba.bus.scheduler.now() >= LINE_CYCLES * VDRAW_LINES as u64);
+        assert_eq!(gba.power_state(), PowerState::Running);
+    }
+
+    #[test]
+    fn test_stop_until_keypad() {
+        let mut gba = Gba::new(
+            vec![],
+            rom(&[
+                0xe3a00301, // mov r0, #0x04000000
+                0xe2802c02, // add r2, r0, #0x200
+                0xe3a01a01, // mov r1, #0x1000
+                0xe1c210b0, // strh r1, [r2] @ IE keypad
+                0xe3a01080, // mov r1, #0x80
+                0xe5c21101, // strb r1, [r2, #0x101] @ HALTCNT stop
+                0xe3a03001, // mov r3, #1
+                0xeafffffe, // b .
+            ]),
+        );
+        gba.skip_bios();
+        gba.run(1000);
+        assert_eq!(gba.power_state(), PowerState::Stopped);
+        let vcount = gba.bus.ppu.vcount;
+        gba.run_frame();
+        assert_eq!(gba.bus.ppu.vcount, vcount);
+        assert!(!gba.bus.apu.is_powered());
+
+        gba.request_interrupt(Interrupt::Keypad);
+        gba.run(100);
+        assert_eq!(gba.power_state(), PowerState::Running);
+        assert!(gba.bus.ppu.is_powered() && gba.bus.apu.is_powered());
+        assert_eq!(gba.cpu.reg(3), 1);
+    }
+
+    #[test]
+    fn test_stop_ignores_other_interrupts() {
+        let mut gba = Gba::new(
+            vec![],
+            rom(&[
+                0xe3a00301, // mov r0, #0x04000000
+                0xe2802c02, // add r2, r0, #0x200
+                0xe3a01a01, // mov r1, #0x1000
+                0xe2811009, // add r1, r1, #9
+                0xe1c210b0, // strh r1, [r2] @ IE keypad, timer 0, VBlank
+                0xe3a01080, // mov r1, #0x80
+                0xe5c21101, // strb r1, [r2, #0x101] @ HALTCNT stop
+                0xe3a03001, // mov r3, #1
+                0xeafffffe, // b .
+            ]),
+        );
+        gba.skip_bios();
+        // Already requested when the CPU stops
+        gba.request_interrupt(Interrupt::VBlank);
+        gba.run(1000);
+        assert_eq!(gba.power_state(), PowerState::Stopped);
+        gba.request_interrupt(Interrupt::Timer0);
+        gba.run(1000);
+        assert_eq!(gba.power_state(), PowerState::Stopped);
+        assert_eq!(gba.cpu.reg(3), 0);
+
+        gba.request_interrupt(Interrupt::Keypad);
+        gba.run(100);
+        assert_eq!(gba.power_state(), PowerState::Running);
+    }
+
+    #[test]
+    fn test_dma_invalidates_code() {
+        use arm7tdmi::bus::{Bus, MemoryAccess};
+
+        let mut gba = Gba::new(
+            vec![],
+            rom(&[
+                0xe3a00403, // mov r0, #0x03000000
+                0xe12fff10, // bx r0
+            ]),
+        );
+        gba.bus.iwram[0..8].copy_from_slice(&[0x01, 0x30, 0xa0, 0xe3, 0xfe, 0xff, 0xff, 0xea]);
+        gba.cpu.enable_block_cache(true);
+        gba.skip_bios();
+        gba.run(100);
+        assert_eq!(gba.cpu.reg(3), 1);
+        let blocks = gba.cpu.block_cache().unwrap().len();
+
+        // DMA3 copies the mov over the first instruction in IWRAM
+        let n = MemoryAccess::NonSequential;
+        gba.bus.write_32(0x0400_00D4, 0x0800_0000, n);
+        gba.bus.write_32(0x0400_00D8, 0x0300_0000, n);
+        gba.bus.write_32(0x0400_00DC, 0x8400_0001, n);
+        gba.step();
+        assert!(gba.cpu.block_cache().unwrap().len() < blocks);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut gba = Gba::new(
+            vec![],
+            rom(&[
+                0xe3a00401, // mov r0, #0x01000000
+                0xe5901000, // ldr r1, [r0]
+                0xe3a02000, // mov r2, #0
+                0xe5923000, // ldr r3, [r2]
+                0xeafffffe, // b .
+            ]),
+        );
+        gba.skip_bios();
+        gba.step();
+        gba.step();
+        // The opcode at $+8, the last pipeline stage
+        assert_eq!(gba.cpu.reg(1), 0xe5923000);
+        assert_eq!(gba.cpu.pipeline()[1], gba.cpu.reg(1));
+        gba.step();
+        gba.step();
+        assert_eq!(gba.cpu.reg(3), BIOS_LATCH_AFTER_BOOT);
+    }
+
+    #[test]
+    fn test_observers() {
+        use crate::observer::Access;
+        use arm7tdmi::bus::MemoryWidth;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut gba = Gba::new(
+            vec![],
+            rom(&[
+                0xe3a00402, // mov r0, #0x02000000
+                0xe3a01042, // mov r1, #0x42
+                0xe5c01010, // strb r1, [r0, #0x10]
+                0xe5902010, // ldr r2, [r0, #0x10]
+                0xe5902020, // ldr r2, [r0, #0x20]
+                0xeafffffe, // b .
+            ]),
+        );
+        gba.skip_bios();
+        let log = Rc::new(RefCell::new(Vec::<Access>::new()));
+        let (l, range) = (log.clone(), 0x0200_0000..=0x0200_001F);
+        let read = gba
+            .bus
+            .observers
+            .add(AccessKind::Read, range.clone(), move |a| {
+                l.borrow_mut().push(*a)
+            });
+        let l = log.clone();
+        gba.bus
+            .observers
+            .add(AccessKind::Write, range, move |a| l.borrow_mut().push(*a));
+        let executed = Rc::new(RefCell::new(Vec::new()));
+        let e = executed.clone();
+        gba.bus
+            .observers
+            .add(AccessKind::Execute, 0x0800_0000..=0x0800_000F, move |a| {
+                e.borrow_mut().push((a.addr, a.value))
+            });
+        for _ in 0..5 {
+            gba.step();
+        }
+
+        let log = log.borrow();
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            log[0],
+            Access {
+                kind: AccessKind::Write,
+                addr: 0x0200_0010,
+                width: MemoryWidth::Byte,
+                value: 0x42,
+                pc: 0x0800_0008,
+            }
+        );
+        assert_eq!((log[1].kind, log[1].value), (AccessKind::Read, 0x42));
+        assert_eq!(log[1].pc, 0x0800_000C);
+        assert_eq!(
+            *executed.borrow(),
+            vec![
+                (0x0800_0000, 0xe3a00402),
+                (0x0800_0004, 0xe3a01042),
+                (0x0800_0008, 0xe5c01010),
+                (0x0800_000C, 0xe5902010),
+            ]
+        );
+        assert!(gba.bus.observers.remove(read));
+        assert!(!gba.bus.observers.remove(read));
+        assert!(!gba.bus.observers.watches(AccessKind::Read));
+    }
+
+    #[test]
+    fn test_save_file() {
+        use crate::backup::FLUSH_DELAY;
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("arch_gba-system-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gba");
+        let code = rom(&[
+            0xe3a0040e, // mov r0, #0x0E000000
+            0xe3a0105a, // mov r1, #0x5A
+            0xe5c01001, // strb r1, [r0, #1]
+            0xeafffffe, // b .
+        ]);
+        let mut gba = Gba::new(vec![], code.clone());
+        gba.attach_save_file(&rom_path).unwrap();
+        gba.skip_bios();
+        gba.run(1000);
+        assert!(gba.bus.backup.is_dirty());
+        gba.run(FLUSH_DELAY);
+        assert!(!gba.bus.backup.is_dirty());
+        assert_eq!(fs::read(dir.join("game.sav")).unwrap()[..2], [0xFF, 0x5A]);
+
+        // Loaded again on the next run
+        let mut gba = Gba::new(vec![], code);
+        gba.attach_save_file(&rom_path).unwrap();
+        assert_eq!(gba.bus.backup.read_8(0x0E00_0001, 0), 0x5A);
+        gba.shutdown().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_multiboot() {
+        let mut image = vec![0; 0xC0];
+        image.extend(rom(&[
+            0xea000006, // b 0x020000E0
+            0,          // boot mode, slave ID
+        ]));
+        image.resize(0xE0, 0);
+        image.extend(rom(&[
+            0xe59f0008, // ldr r0, [pc, #8]
+            0xe5d010c4, // ldrb r1, [r0, #0xC4]
+            0xe5d020c5, // ldrb r2, [r0, #0xC5]
+            0xeafffffe, // b .
+            0x02000000,
+        ]));
+        let mut gba = Gba::boot_multiboot(vec![], &image);
+        gba.run(100);
+        assert_eq!(gba.cpu.reg(0), 0x0200_0000);
+        assert_eq!(gba.cpu.reg(1), 2);
+        assert_eq!(gba.cpu.reg(2), 1);
+        assert_eq!(gba.bus.peek_32(0x0200_00E0), 0xe59f0008);
+    }
+}
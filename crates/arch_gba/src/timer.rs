@@ -0,0 +1,151 @@
+/*
+ * Timers. See GBATEK "GBA Timers".
+ *
+ * A running timer is not ticked, the counter is computed from the time it was last set and the
+ * prescaler, and the overflow is a scheduler event. TMxCNT_L writes the reload value and reads
+ * the counter, the counter is loaded from the reload value when the timer is started and on
+ * overflow. A count-up timer (not timer 0) counts overflows of the previous timer instead.
+ */
+pub const TIMER_START: u16 = 1 << 7;
+pub const TIMER_IRQ: u16 = 1 << 6;
+pub const TIMER_COUNT_UP: u16 = 1 << 2;
+
+// Prescaler selection in bits 0-1: 1, 64, 256 or 1024 cycles per tick
+const PRESCALER_SHIFT: [u32; 4] = [0, 6, 8, 10];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timer {
+    pub reload: u16,
+    pub counter: u16,
+    pub control: u16,
+    // Time of the last tick counted in counter
+    last: u64,
+}
+
+impl Timer {
+    pub fn is_running(&self) -> bool {
+        self.control & TIMER_START != 0
+    }
+
+    // Counts overflows of the previous timer. Timer 0 has no previous timer, never set for it.
+    pub fn is_count_up(&self) -> bool {
+        self.control & TIMER_COUNT_UP != 0
+    }
+
+    pub fn irq_enabled(&self) -> bool {
+        self.control & TIMER_IRQ != 0
+    }
+
+    fn shift(&self) -> u32 {
+        PRESCALER_SHIFT[(self.control & 3) as usize]
+    }
+
+    fn counts_cycles(&self) -> bool {
+        self.is_running() && !self.is_count_up()
+    }
+
+    // The counter as the CPU reads it at the given time.
+    pub fn counter_at(&self, now: u64) -> u16 {
+        if self.counts_cycles() {
+            self.counter
+                .wrapping_add(((now - self.last) >> self.shift()) as u16)
+        } else {
+            self.counter
+        }
+    }
+
+    // Folds the ticks up to now into counter.
+    fn sync(&mut self, now: u64) {
+        if self.counts_cycles() {
+            let ticks = (now - self.last) >> self.shift();
+            self.counter = self.counter.wrapping_add(ticks as u16);
+            self.last += ticks << self.shift();
+        }
+    }
+
+    pub fn write_control(&mut self, value: u16, now: u64) {
+        self.sync(now);
+        if !self.is_running() && value & TIMER_START != 0 {
+            self.counter = self.reload;
+        }
+        self.control = value;
+        self.last = now;
+    }
+
+    // Cycles from now until the counter overflows, if it counts cycles.
+    pub fn cycles_to_overflow(&self, now: u64) -> Option<u64> {
+        if !self.counts_cycles() {
+            return None;
+        }
+        let ticks = 0x1_0000 - self.counter as u64;
+        Some(self.last + (ticks << self.shift()) - now)
+    }
+
+    // The counter overflowed at the given time, it starts again from the reload value.
+    pub fn overflow(&mut self, time: u64) {
+        self.counter = self.reload;
+        self.last = time;
+    }
+
+    // An overflow of the previous timer, returns whether this one overflows in turn.
+    pub fn count_up(&mut self, time: u64) -> bool {
+        if !self.is_running() || !self.is_count_up() {
+            return false;
+        }
+        if self.counter == 0xFFFF {
+            self.overflow(time);
+            true
+        } else {
+            self.counter += 1;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counting() {
+        let mut t = Timer {
+            reload: 0xFF00,
+            ..Timer::default()
+        };
+        t.write_control(TIMER_START | 1, 100);
+        assert_eq!(t.counter_at(100), 0xFF00);
+        assert_eq!(t.counter_at(100 + 63), 0xFF00);
+        assert_eq!(t.counter_at(100 + 64 * 3), 0xFF03);
+        assert_eq!(t.cycles_to_overflow(100 + 10), Some(0x100 * 64 - 10));
+
+        // Changing the prescaler keeps the ticks counted so far
+        t.write_control(TIMER_START, 100 + 64 * 3 + 5);
+        assert_eq!(t.counter_at(100 + 64 * 3 + 5), 0xFF03);
+        assert_eq!(t.counter_at(100 + 64 * 3 + 7), 0xFF05);
+
+        t.overflow(1000);
+        assert_eq!(t.counter_at(1010), 0xFF0A);
+
+        // Stopped, the counter holds its value and is not reloaded until started again
+        t.write_control(0, 1010);
+        assert_eq!(t.counter_at(2000), 0xFF0A);
+        assert_eq!(t.cycles_to_overflow(2000), None);
+        t.write_control(TIMER_START, 2000);
+        assert_eq!(t.counter_at(2000), 0xFF00);
+    }
+
+    #[test]
+    fn test_count_up() {
+        let mut t = Timer {
+            reload: 0xFFFE,
+            ..Timer::default()
+        };
+        t.write_control(TIMER_START | TIMER_COUNT_UP, 0);
+        assert_eq!(t.cycles_to_overflow(0), None);
+        assert_eq!(t.counter_at(1000), 0xFFFE);
+        assert!(!t.count_up(10));
+        assert_eq!(t.counter_at(10), 0xFFFF);
+        assert!(t.count_up(20));
+        assert_eq!(t.counter_at(20), 0xFFFE);
+    }
+}
@@ -0,0 +1,370 @@
+use arm7tdmi::bus::{MemoryAccess, MemoryWidth};
+
+/*
+ * Memory access timing. See GBATEK "GBA Memory Map" and "GBA System Control" (WAITCNT).
+ *
+ * Wait states are the cycles an access takes on top of the single bus cycle. EWRAM, palette RAM,
+ * VRAM and the cartridge have a 16 bit bus, a 32 bit access to them is two accesses, the second
+ * one sequential. The cartridge timing is set with WAITCNT (0x4000204):
+ *
+ *   Bit   Expl.
+ *   0-1   SRAM wait              (0..3 = 4,3,2,8 cycles)
+ *   2-3   WS0 first access       (0..3 = 4,3,2,8 cycles)
+ *   4     WS0 second access      (0..1 = 2,1 cycles)
+ *   5-6   WS1 first access       (0..3 = 4,3,2,8 cycles)
+ *   7     WS1 second access      (0..1 = 4,1 cycles)
+ *   8-9   WS2 first access       (0..3 = 4,3,2,8 cycles)
+ *   10    WS2 second access      (0..1 = 8,1 cycles)
+ *   11-12 PHI terminal output
+ *   14    Game Pak prefetch buffer enable
+ *   15    Game Pak type flag (read only, 0 = GBA)
+ *
+ * Sequential cartridge accesses are forced non-sequential at 128KB boundaries.
+ */
+const FIRST_ACCESS: [u32; 4] = [4, 3, 2, 8];
+const SECOND_ACCESS: [[u32; 2]; 3] = [[2, 1], [4, 1], [8, 1]];
+const EWRAM_WAIT: u32 = 2;
+const WAITCNT_WRITABLE: u16 = 0x5FFF;
+const PREFETCH_ENABLE: u16 = 1 << 14;
+
+/*
+ * The prefetch buffer reads up to 8 sequential halfwords from the cartridge while the CPU is not
+ * using the cartridge bus, i.e. during internal cycles and accesses to other regions. Opcode
+ * fetches that hit it take a single cycle. Data accesses to the cartridge and fetches from other
+ * addresses empty it, a fetch then restarts it right after the fetched opcode.
+ */
+const PREFETCH_HALFWORDS: u32 = 8;
+
+#[derive(Debug, Default)]
+struct Prefetch {
+    // Address of the first buffered halfword, the next one to be fetched follows the buffer.
+    head: u32,
+    count: u32,
+    // Cycles spent on the halfword currently being fetched.
+    progress: u32,
+    active: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryTiming {
+    waitcnt: u16,
+    prefetch: Prefetch,
+}
+
+fn is_cartridge(addr: u32) -> bool {
+    (0x08..=0x0D).contains(&(addr >> 24))
+}
+
+impl MemoryTiming {
+    pub fn new() -> Self {
+        MemoryTiming::default()
+    }
+
+    pub fn waitcnt(&self) -> u16 {
+        self.waitcnt
+    }
+
+    pub fn write_waitcnt(&mut self, value: u16) {
+        self.waitcnt = value & WAITCNT_WRITABLE;
+        if !self.prefetch_enabled() {
+            self.prefetch = Prefetch::default();
+        }
+    }
+
+    pub fn prefetch_enabled(&self) -> bool {
+        self.waitcnt & PREFETCH_ENABLE != 0
+    }
+
+    fn first_access(&self, shift: u32) -> u32 {
+        FIRST_ACCESS[(self.waitcnt >> shift) as usize & 3]
+    }
+
+    fn sram_wait(&self) -> u32 {
+        self.first_access(0)
+    }
+
+    // Wait states of a single 16 bit access to one of the three cartridge ROM regions.
+    fn rom_wait(&self, addr: u32, access: MemoryAccess) -> u32 {
+        let ws = ((addr >> 24) - 0x08) as usize / 2;
+        if access == MemoryAccess::Sequential && addr & 0x1_FFFF != 0 {
+            SECOND_ACCESS[ws][(self.waitcnt >> (4 + ws * 3)) as usize & 1]
+        } else {
+            self.first_access(2 + ws as u32 * 3)
+        }
+    }
+
+    fn rom_wait_width(&self, addr: u32, width: MemoryWidth, access: MemoryAccess) -> u32 {
+        match width {
+            MemoryWidth::Word => {
+                self.rom_wait(addr, access) + 1 + self.rom_wait(addr + 2, MemoryAccess::Sequential)
+            }
+            _ => self.rom_wait(addr, access),
+        }
+    }
+
+    // Data accesses.
+    pub fn wait_states(&mut self, addr: u32, width: MemoryWidth, access: MemoryAccess) -> u32 {
+        let wait = match addr >> 24 {
+            0x02 if width == MemoryWidth::Word => EWRAM_WAIT * 2 + 1,
+            0x02 => EWRAM_WAIT,
+            0x05 | 0x06 if width == MemoryWidth::Word => 1,
+            0x08..=0x0D => {
+                self.prefetch = Prefetch::default();
+                return self.rom_wait_width(addr, width, access);
+            }
+            0x0E | 0x0F => {
+                self.prefetch = Prefetch::default();
+                return self.sram_wait();
+            }
+            _ => 0,
+        };
+        self.run_prefetch(1 + wait);
+        wait
+    }
+
+    // Opcode fetches, which the prefetch buffer can serve.
+    pub fn fetch_wait_states(
+        &mut self,
+        addr: u32,
+        width: MemoryWidth,
+        access: MemoryAccess,
+    ) -> u32 {
+        if !is_cartridge(addr) {
+            return self.wait_states(addr, width, access);
+        }
+        if !self.prefetch_enabled() {
+            return self.rom_wait_width(addr, width, access);
+        }
+
+        let halfwords = if width == MemoryWidth::Word { 2 } else { 1 };
+        let p = &self.prefetch;
+        let buffered = p.active && p.head == addr;
+        let hit = buffered && p.count >= halfwords;
+        let wait = if hit {
+            0
+        } else if buffered {
+            // Wait for the rest of the opcode to arrive in the buffer.
+            let seq = 1 + self.rom_wait(addr, MemoryAccess::Sequential);
+            ((halfwords - p.count) * seq - p.progress).saturating_sub(1)
+        } else {
+            self.rom_wait_width(addr, width, access)
+        };
+
+        let p = &mut self.prefetch;
+        if hit {
+            p.count -= halfwords;
+        } else {
+            p.count = 0;
+            p.progress = 0;
+        }
+        p.head = addr + halfwords * 2;
+        p.active = true;
+        if hit {
+            // The cartridge bus is free during a fetch from the buffer.
+            self.run_prefetch(1);
+        }
+        wait
+    }
+
+    // Internal cycles of the CPU.
+    pub fn idle(&mut self, cycles: u32) {
+        self.run_prefetch(cycles);
+    }
+
+    // Lets the prefetch buffer use the cartridge bus for the given number of cycles.
+    fn run_prefetch(&mut self, cycles: u32) {
+        if !self.prefetch.active || !self.prefetch_enabled() {
+            return;
+        }
+        let next = self.prefetch.head + self.prefetch.count * 2;
+        let seq = 1 + self.rom_wait(next, MemoryAccess::Sequential);
+        let p = &mut self.prefetch;
+        if p.count == PREFETCH_HALFWORDS {
+            return;
+        }
+        p.progress += cycles;
+        while p.count < PREFETCH_HALFWORDS && p.progress >= seq {
+            p.count += 1;
+            p.progress -= seq;
+        }
+        if p.count == PREFETCH_HALFWORDS {
+            p.progress = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: MemoryAccess = MemoryAccess::NonSequential;
+    const S: MemoryAccess = MemoryAccess::Sequential;
+
+    #[test]
+    fn test_waitcnt() {
+        let mut t = MemoryTiming::new();
+        // Power on: 4/2 for WS0, 4/4 for WS1, 4/8 for WS2, SRAM 4
+        assert_eq!(t.wait_states(0x0800_0000, MemoryWidth::Halfword, N), 4);
+        assert_eq!(t.wait_states(0x0800_0002, MemoryWidth::Halfword, S), 2);
+        assert_eq!(t.wait_states(0x0A00_0002, MemoryWidth::Halfword, S), 4);
+        assert_eq!(t.wait_states(0x0C00_0002, MemoryWidth::Halfword, S), 8);
+        assert_eq!(t.wait_states(0x0E00_0000, MemoryWidth::Word, N), 4);
+        assert_eq!(t.wait_states(0x0800_0000, MemoryWidth::Word, N), 4 + 1 + 2);
+        // 128KB boundaries are non-sequential
+        assert_eq!(t.wait_states(0x0802_0000, MemoryWidth::Halfword, S), 4);
+        assert_eq!(t.wait_states(0x0200_0000, MemoryWidth::Word, N), 5);
+        assert_eq!(t.wait_states(0x0300_0000, MemoryWidth::Word, N), 0);
+        assert_eq!(t.wait_states(0x0600_0000, MemoryWidth::Word, N), 1);
+        assert_eq!(t.wait_states(0x0500_0000, MemoryWidth::Halfword, N), 0);
+        assert_eq!(t.wait_states(0x0700_0000, MemoryWidth::Word, N), 0);
+
+        // The usual setting of commercial games: 3/1 for WS0, SRAM 8
+        t.write_waitcnt(0x4317);
+        assert_eq!(t.waitcnt(), 0x4317);
+        assert!(t.prefetch_enabled());
+        assert_eq!(t.wait_states(0x0800_0000, MemoryWidth::Halfword, N), 3);
+        assert_eq!(t.wait_states(0x0800_0002, MemoryWidth::Word, S), 3);
+        assert_eq!(t.wait_states(0x0E00_0000, MemoryWidth::Byte, N), 8);
+        t.write_waitcnt(0xFFFF);
+        assert_eq!(t.waitcnt(), 0x5FFF);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut t = MemoryTiming::new();
+        t.write_waitcnt(0x4014); // WS0 3/1, prefetch
+        let fetch = |t: &mut MemoryTiming, addr, access| {
+            t.fetch_wait_states(addr, MemoryWidth::Halfword, access)
+        };
+
+        // A miss pays the cartridge timing and starts the buffer
+        assert_eq!(fetch(&mut t, 0x0800_0000, N), 3);
+        // 10 internal cycles fill 5 halfwords at 2 cycles each, the buffer keeps fetching while
+        // the CPU reads from it
+        t.idle(10);
+        for i in 1..=10 {
+            assert_eq!(fetch(&mut t, 0x0800_0000 + i * 2, S), 0);
+        }
+        // Empty buffer, wait for the halfword being fetched
+        assert_eq!(fetch(&mut t, 0x0800_0016, S), 1);
+
+        // Accesses to other regions let the buffer run
+        t.wait_states(0x0300_0000, MemoryWidth::Word, N);
+        t.wait_states(0x0200_0000, MemoryWidth::Word, N);
+        assert_eq!(
+            t.fetch_wait_states(0x0800_0018, MemoryWidth::Word, S),
+            0,
+            "ARM fetch of two buffered halfwords"
+        );
+
+        // A data access to the cartridge empties it, so does a branch
+        t.idle(16);
+        t.wait_states(0x0800_1000, MemoryWidth::Halfword, N);
+        assert_eq!(fetch(&mut t, 0x0800_001C, S), 1);
+        t.idle(16);
+        assert_eq!(fetch(&mut t, 0x0800_0100, N), 3);
+
+        // The buffer holds 8 halfwords, with the ones fetched meanwhile 16 fetches hit
+        t.idle(100);
+        for i in 1..=16 {
+            assert_eq!(fetch(&mut t, 0x0800_0100 + i * 2, S), 0);
+        }
+        assert_eq!(fetch(&mut t, 0x0800_0122, S), 1);
+
+        // Disabled, every fetch pays the cartridge timing
+        t.write_waitcnt(0x0014);
+        t.idle(100);
+        assert_eq!(fetch(&mut t, 0x0800_0124, S), 1);
+    }
+
+    /*
+     * Timer 0 ticks across the given instructions run from IWRAM with the given WAITCNT, and the
+     * cycles the CPU counted for them. Like the mGBA suite's timing tests, timer 0 runs at one
+     * tick per cycle and is read before and after the instructions, the difference includes the
+     * first read.
+     */
+    fn timer_ticks(waitcnt: u16, instrs: &[u32]) -> (u32, u32) {
+        use crate::system::Gba;
+
+        let mut code = vec![
+            0xe3a04301,                           // mov r4, #0x04000000
+            0xe2843c02,                           // add r3, r4, #0x200
+            0xe2842c01,                           // add r2, r4, #0x100
+            0xe3a07302,                           // mov r7, #0x08000000
+            0xe3a0840e,                           // mov r8, #0x0E000000
+            0xe3a09402,                           // mov r9, #0x02000000
+            0xe3a00000 | (waitcnt & 0xFF) as u32, // mov r0, #waitcnt & 0xFF
+            0xe3800c00 | (waitcnt >> 8) as u32,   // orr r0, r0, #waitcnt & 0xFF00
+            0xe1c300b4,                           // strh r0, [r3, #4] @ WAITCNT
+            0xe3a00080,                           // mov r0, #0x80
+            0xe1c200b2,                           // strh r0, [r2, #2] @ TM0CNT_H, start
+            0xe1d250b0,                           // ldrh r5, [r2] @ TM0CNT_L
+        ];
+        let first = 0x0300_0000 + code.len() as u32 * 4;
+        code.extend_from_slice(instrs);
+        code.push(0xe1d260b0); // ldrh r6, [r2] @ TM0CNT_L
+        let end = 0x0300_0000 + code.len() as u32 * 4;
+        code.push(0xeafffffe); // b .
+
+        let rom = [0xe3a00403u32, 0xe12fff10] // mov r0, #0x03000000; bx r0
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect();
+        let mut gba = Gba::new(vec![], rom);
+        for (i, w) in code.iter().enumerate() {
+            gba.bus.iwram[i * 4..i * 4 + 4].copy_from_slice(&w.to_le_bytes());
+        }
+        gba.skip_bios();
+        let mut cycles = 0;
+        while gba.cpu.next_pc() != end {
+            let pc = gba.cpu.next_pc();
+            let step = gba.step() as u32;
+            if pc >= first && pc < end - 4 {
+                cycles += step;
+            }
+        }
+        let ticks = gba.cpu.reg(6).wrapping_sub(gba.cpu.reg(5)) & 0xFFFF;
+        (ticks, cycles)
+    }
+
+    // Cycles of one instruction, the timer ticks without it subtracted.
+    fn measure(waitcnt: u16, instr: u32) -> u32 {
+        let (base, _) = timer_ticks(waitcnt, &[]);
+        // The first read, 1S + 1N + 1I with I/O registers 1 cycle
+        assert_eq!(base, 3);
+        let (ticks, cycles) = timer_ticks(waitcnt, &[instr]);
+        assert_eq!(
+            ticks - base,
+            cycles,
+            "timer 0 and CPU cycles of {:08x}",
+            instr
+        );
+        ticks - base
+    }
+
+    /*
+     * Loads take 1S (the next fetch, 1 cycle from IWRAM) + 1N (the data access) + 1I, see GBATEK
+     * "ARM CPU Instruction Cycle Times". A 16 bit cartridge bus reads a word as N + S. The wait
+     * states are the ones GBATEK "GBA System Control" lists for each WAITCNT setting, the
+     * expected cycles are worked out from these tables, not taken from the mGBA suite.
+     */
+    #[test]
+    fn test_instruction_timing() {
+        let cases: [(u16, u32, u32, &str); 11] = [
+            (0x0000, 0xe1a00000, 1, "mov r0, r0"),
+            (0x0000, 0xe1d700b0, 1 + 5 + 1, "ldrh r0, [r7] @ WS0 4/2"),
+            (0x0000, 0xe5970000, 1 + 5 + 3 + 1, "ldr r0, [r7] @ WS0 4/2"),
+            (0x0014, 0xe1d700b0, 1 + 4 + 1, "ldrh r0, [r7] @ WS0 3/1"),
+            (0x0014, 0xe5970000, 1 + 4 + 2 + 1, "ldr r0, [r7] @ WS0 3/1"),
+            (0x4317, 0xe5970000, 1 + 4 + 2 + 1, "ldr r0, [r7] @ prefetch"),
+            (0x0018, 0xe5970000, 1 + 3 + 2 + 1, "ldr r0, [r7] @ WS0 2/1"),
+            (0x000C, 0xe1d700b0, 1 + 9 + 1, "ldrh r0, [r7] @ WS0 8/2"),
+            (0x0000, 0xe5d80000, 1 + 5 + 1, "ldrb r0, [r8] @ SRAM 4"),
+            (0x0003, 0xe5d80000, 1 + 9 + 1, "ldrb r0, [r8] @ SRAM 8"),
+            (0x0000, 0xe5990000, 1 + 6 + 1, "ldr r0, [r9] @ EWRAM"),
+        ];
+        for &(waitcnt, instr, cycles, name) in cases.iter() {
+            assert_eq!(measure(waitcnt, instr), cycles, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use super::arm::ShiftType;
+
+/*
+ * Barrel shifter. See ARM7TDMI Reference 4.5.2
+ *
+ * Returns the shifted value and the shifter carry out.
+ *
+ * Shifts by an immediate amount encode a few special cases in a shift amount of 0:
+ *      LSL #0 = no shift, carry unchanged
+ *      LSR #0 = LSR #32
+ *      ASR #0 = ASR #32
+ *      ROR #0 = RRX, rotate right by one through carry
+ *
+ * Shifts by a register amount use the bottom byte of the register, an amount of 0 leaves value
+ * and carry unchanged, and amounts of 32 and above are handled as documented per shift type.
+ */
+pub(crate) fn shift(
+    shift_type: ShiftType,
+    value: u32,
+    amount: u32,
+    carry: bool,
+    immediate: bool,
+) -> (u32, bool) {
+    if amount == 0 {
+        if !immediate {
+            return (value, carry);
+        }
+        return match shift_type {
+            ShiftType::LogicalLeft => (value, carry),
+            ShiftType::LogicalRight => (0, value >> 31 == 1),
+            ShiftType::ArithmeticRight => {
+                let sign = value >> 31 == 1;
+                (if sign { 0xFFFF_FFFF } else { 0 }, sign)
+            }
+            ShiftType::RotateRight => {
+                let result = ((carry as u32) << 31) | (value >> 1);
+                (result, value & 1 == 1)
+            }
+        };
+    }
+
+    match shift_type {
+        ShiftType::LogicalLeft => match amount {
+            1..=31 => (value << amount, (value >> (32 - amount)) & 1 == 1),
+            32 => (0, value & 1 == 1),
+            _ => (0, false),
+        },
+        ShiftType::LogicalRight => match amount {
+            1..=31 => (value >> amount, (value >> (amount - 1)) & 1 == 1),
+            32 => (0, value >> 31 == 1),
+            _ => (0, false),
+        },
+        ShiftType::ArithmeticRight => match amount {
+            1..=31 => (
+                ((value as i32) >> amount) as u32,
+                (value >> (amount - 1)) & 1 == 1,
+            ),
+            _ => {
+                let sign = value >> 31 == 1;
+                (if sign { 0xFFFF_FFFF } else { 0 }, sign)
+            }
+        },
+        ShiftType::RotateRight => {
+            let amount = amount % 32;
+            if amount == 0 {
+                (value, value >> 31 == 1)
+            } else {
+                let result = value.rotate_right(amount);
+                (result, (value >> (amount - 1)) & 1 == 1)
+            }
+        }
+    }
+}
+
+// a + b + carry, returning the result and the C and V flags.
+pub(crate) fn add_with_carry(a: u32, b: u32, carry: bool) -> (u32, bool, bool) {
+    let wide = a as u64 + b as u64 + carry as u64;
+    let result = wide as u32;
+    let c = wide > 0xFFFF_FFFF;
+    let v = (!(a ^ b) & (a ^ result)) >> 31 == 1;
+    (result, c, v)
+}
+
+// a - b - !carry. ARM sets C on subtraction when no borrow occurred.
+pub(crate) fn sub_with_carry(a: u32, b: u32, carry: bool) -> (u32, bool, bool) {
+    add_with_carry(a, !b, carry)
+}
+
+/*
+ * Number of internal cycles (m) the multiplier takes, which depends on how many bytes of the
+ * multiplier operand rs are significant. See ARM7TDMI Reference 6.14
+ */
+pub(crate) fn multiply_cycles(rs: u32, signed: bool) -> u32 {
+    let leading_ones = |mask: u32| signed && rs & mask == mask;
+    if rs & 0xFFFF_FF00 == 0 || leading_ones(0xFFFF_FF00) {
+        1
+    } else if rs & 0xFFFF_0000 == 0 || leading_ones(0xFFFF_0000) {
+        2
+    } else if rs & 0xFF00_0000 == 0 || leading_ones(0xFF00_0000) {
+        3
+    } else {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_immediate_special_cases() {
+        assert_eq!(
+            shift(ShiftType::LogicalLeft, 0x8000_0001, 0, true, true),
+            (0x8000_0001, true)
+        );
+        assert_eq!(
+            shift(ShiftType::LogicalRight, 0x8000_0000, 0, false, true),
+            (0, true)
+        );
+        assert_eq!(
+            shift(ShiftType::ArithmeticRight, 0x8000_0000, 0, false, true),
+            (0xFFFF_FFFF, true)
+        );
+        assert_eq!(
+            shift(ShiftType::RotateRight, 0x0000_0003, 0, true, true),
+            (0x8000_0001, true)
+        );
+    }
+
+    #[test]
+    fn test_shift_register_amounts() {
+        assert_eq!(
+            shift(ShiftType::LogicalLeft, 0x1, 0, true, false),
+            (0x1, true)
+        );
+        assert_eq!(
+            shift(ShiftType::LogicalLeft, 0x1, 32, false, false),
+            (0, true)
+        );
+        assert_eq!(
+            shift(ShiftType::LogicalLeft, 0x1, 33, true, false),
+            (0, false)
+        );
+        assert_eq!(
+            shift(ShiftType::LogicalRight, 0x8000_0000, 32, false, false),
+            (0, true)
+        );
+        assert_eq!(
+            shift(ShiftType::RotateRight, 0x8000_0000, 32, false, false),
+            (0x8000_0000, true)
+        );
+        assert_eq!(
+            shift(ShiftType::RotateRight, 0x0000_00F0, 4, false, false),
+            (0x0000_000F, false)
+        );
+    }
+
+    #[test]
+    fn test_add_sub_flags() {
+        assert_eq!(add_with_carry(0xFFFF_FFFF, 1, false), (0, true, false));
+        assert_eq!(
+            add_with_carry(0x7FFF_FFFF, 1, false),
+            (0x8000_0000, false, true)
+        );
+        assert_eq!(sub_with_carry(5, 3, true), (2, true, false));
+        assert_eq!(sub_with_carry(3, 5, true), (0xFFFF_FFFE, false, false));
+        assert_eq!(
+            sub_with_carry(0x8000_0000, 1, true),
+            (0x7FFF_FFFF, true, true)
+        );
+    }
+
+    #[test]
+    fn test_multiply_cycles() {
+        assert_eq!(multiply_cycles(0xFF, false), 1);
+        assert_eq!(multiply_cycles(0xFFFF_FFF0, true), 1);
+        assert_eq!(multiply_cycles(0xFFFF_FFF0, false), 4);
+        assert_eq!(multiply_cycles(0x1234, false), 2);
+        assert_eq!(multiply_cycles(0x12_3456, false), 3);
+    }
+}
@@ -1,18 +1,21 @@
+use super::alu::{add_with_carry, multiply_cycles, shift, sub_with_carry};
+use super::bus::{Bus, MemoryAccess};
+use super::psr::Psr;
 use super::ConditionField;
 use super::InstructionType;
 use std::convert::TryFrom;
 use std::convert::TryInto;
-use std::io;
-use std::io::ErrorKind;
 use util::get_bits;
 
+pub use super::cpu::ARMCpu;
+
 macro_rules! cond {
     ($i:ident) => {
         ConditionField::new(get_bits($i, 28, 31) as u8)
     };
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ArmV4Type {
     Multiply,
     MultiplyLong,
@@ -38,6 +41,7 @@ pub enum ArmV4Type {
  * The order and list of instruction is taken from Table in arm7tdmi_instruction_set_reference.pdf Section
  * 1.1 on page 1.
  */
+#[allow(clippy::unusual_byte_groupings)]
 pub fn armv4_type(i: u32) -> ArmV4Type {
     let bits27_22 = get_bits(i, 22, 27);
     let bits7_4 = get_bits(i, 4, 7);
@@ -79,15 +83,17 @@ pub fn armv4_type(i: u32) -> ArmV4Type {
         return ArmV4Type::DataProcessingPsr;
     }
 
-    if bits27_26 == 0b01 {
-        return ArmV4Type::LoadStore;
-    }
-
+    // The undefined encoding sits inside the LoadStore space (register offset with bit 4 set) so
+    // it has to be checked first.
     if bits27_25 == 0b011 && bit4 == 1 {
         return ArmV4Type::Undefined;
     }
 
-    if bits27_25 == 0b100 && bit22 == 0 {
+    if bits27_26 == 0b01 {
+        return ArmV4Type::LoadStore;
+    }
+
+    if bits27_25 == 0b100 {
         return ArmV4Type::BlockDataTransfer;
     }
 
@@ -180,19 +186,21 @@ impl Instruction {
         (shift as usize, rm as usize)
     }
 
+    #[allow(clippy::needless_return)]
     pub fn dataprocessing_operand2_as_rotimm(&self) -> (usize, usize) {
         let rot = get_bits(self.0, 8, 11);
         let imm = get_bits(self.0, 0, 7);
         return (rot as usize, imm as usize);
     }
 
+    #[allow(clippy::let_and_return)]
     pub fn dataprocessing_operand2(&self) -> u32 {
         let bits11_0 = get_bits(self.0, 0, 11);
         bits11_0
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DataProcessingOpCode {
     And = 0,
     Eor = 1,
@@ -236,12 +244,29 @@ impl TryFrom<u32> for DataProcessingOpCode {
         }
     }
 }
-#[derive(Debug)]
+
+impl DataProcessingOpCode {
+    // TST, TEQ, CMP and CMN only set flags and do not write rd.
+    pub fn is_test(self) -> bool {
+        matches!(
+            self,
+            DataProcessingOpCode::Tst
+                | DataProcessingOpCode::Teq
+                | DataProcessingOpCode::Cmp
+                | DataProcessingOpCode::Cmn
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct DataProcessingInstr {
-    i: u32,
-    cond: ConditionField,
-    opcode: DataProcessingOpCode,
-    operand2: DataProcessingOperand2,
+    pub i: u32,
+    pub cond: ConditionField,
+    pub opcode: DataProcessingOpCode,
+    pub s: bool,
+    pub rn: u8,
+    pub rd: u8,
+    pub operand2: DataProcessingOperand2,
 }
 
 impl DataProcessingInstr {
@@ -251,39 +276,121 @@ impl DataProcessingInstr {
         "BIC", "MVN", // 0b1111
     ];
 
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         assert_eq!(armv4_type(i), ArmV4Type::DataProcessingPsr);
         let opcode: DataProcessingOpCode = get_bits(i, 21, 24).try_into().unwrap();
         let immbit = get_bits(i, 25, 25) == 1;
-        let mut operand2;
+        let rm = get_bits(i, 0, 3) as u8;
 
-        if immbit {
+        let operand2 = if immbit {
             let rotate2 = get_bits(i, 8, 11);
             let imm = get_bits(i, 0, 7);
-            operand2 = DataProcessingOperand2::ImmRot {
+            DataProcessingOperand2::ImmRot {
                 rotate_count: rotate2,
                 imm_value: imm,
-            };
+            }
+        } else if get_bits(i, 4, 4) == 0 {
+            DataProcessingOperand2::ShiftRegDirect {
+                shift_count: get_bits(i, 7, 11),
+                shift_type: get_bits(i, 5, 6).try_into().unwrap(),
+                rm,
+            }
         } else {
-            if get_bits(i, 4, 4) == 0 {
-                operand2 = DataProcessingOperand2::ShiftRegDirect {
-                    shift_count: get_bits(i, 7, 11),
-                    shift_type: get_bits(i, 5, 6).try_into().unwrap(),
-                };
-            } else {
-                operand2 = DataProcessingOperand2::ShiftRegIndirect {
-                    shift_reg: get_bits(i, 7, 11) as usize,
-                    shift_type: get_bits(i, 5, 6).try_into().unwrap(),
-                };
+            DataProcessingOperand2::ShiftRegIndirect {
+                shift_reg: get_bits(i, 8, 11) as usize,
+                shift_type: get_bits(i, 5, 6).try_into().unwrap(),
+                rm,
             }
-        }
+        };
         DataProcessingInstr {
             i,
             cond: ConditionField::new(get_bits(i, 28, 31) as u8),
             opcode,
+            s: get_bits(i, 20, 20) == 1,
+            rn: get_bits(i, 16, 19) as u8,
+            rd: get_bits(i, 12, 15) as u8,
             operand2,
         }
     }
+
+    pub fn opcode_str(&self) -> &'static str {
+        DataProcessingInstr::OPCODE_NAMES[self.opcode as usize]
+    }
+}
+
+/*
+ * MRS/MSR (PSR Transfer). See ARM7TDMI Reference 4.6
+ *
+ * These are encoded as TST/TEQ/CMP/CMN with the S bit clear, which would otherwise be
+ * pointless instructions.
+ *
+ * MRS Rd, CPSR/SPSR copies the PSR into Rd.
+ * MSR CPSR/SPSR_<fields>, Rm/#imm writes the selected byte fields of the PSR, bit 19 selects the
+ * flags byte (f) and bit 16 the control byte (c).
+ */
+pub fn is_psr_transfer(i: u32) -> bool {
+    let bits27_23 = get_bits(i, 23, 27);
+    let bit20 = get_bits(i, 20, 20);
+    (bits27_23 == 0b00010 || bits27_23 == 0b00110) && bit20 == 0
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MrsInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub spsr: bool,
+    pub rd: u8,
+}
+
+impl MrsInstr {
+    pub fn new(i: u32) -> Self {
+        MrsInstr {
+            i,
+            cond: cond!(i),
+            spsr: get_bits(i, 22, 22) == 1,
+            rd: get_bits(i, 12, 15) as u8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MsrOperand {
+    Reg(u8),
+    Imm(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsrInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub spsr: bool,
+    // Byte mask built from the field bits 19-16, e.g. 0xFF000000 for _f.
+    pub field_mask: u32,
+    pub operand: MsrOperand,
+}
+
+impl MsrInstr {
+    pub fn new(i: u32) -> Self {
+        let mut field_mask = 0;
+        for field in 0..4 {
+            if get_bits(i, 16 + field, 16 + field) == 1 {
+                field_mask |= 0xFF << (field * 8);
+            }
+        }
+        let operand = if get_bits(i, 25, 25) == 1 {
+            let rot = get_bits(i, 8, 11) * 2;
+            MsrOperand::Imm(get_bits(i, 0, 7).rotate_right(rot))
+        } else {
+            MsrOperand::Reg(get_bits(i, 0, 3) as u8)
+        };
+        MsrInstr {
+            i,
+            cond: cond!(i),
+            spsr: get_bits(i, 22, 22) == 1,
+            field_mask,
+            operand,
+        }
+    }
 }
 
 /*
@@ -298,19 +405,19 @@ impl DataProcessingInstr {
  * and 2 byte align and so bit 0 will be cleared before the actual jump, thus can be used to switch
  * to THUMB mode
  *
- * Instruction Cycle Time:
+ * Instruction Cycle Time: 2S + 1N
  *
  *
  */
-#[derive(Debug)]
-struct BxInstr {
-    i: u32,
-    cond: ConditionField,
-    rn: u8,
+#[derive(Debug, Clone, Copy)]
+pub struct BxInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub rn: u8,
 }
 
 impl BxInstr {
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         BxInstr {
             i,
             cond: cond!(i),
@@ -323,16 +430,16 @@ impl BxInstr {
  * B/BL (Branch and Branch with Link). See ARM7TDMI Reference 4.4
  *
  */
-#[derive(Debug)]
-struct BranchInstr {
-    i: u32,
-    cond: ConditionField,
-    link: bool,
-    offset: u32,
+#[derive(Debug, Clone, Copy)]
+pub struct BranchInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub link: bool,
+    pub offset: u32,
 }
 
 impl BranchInstr {
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         BranchInstr {
             i,
             cond: cond!(i),
@@ -340,30 +447,36 @@ impl BranchInstr {
             offset: get_bits(i, 0, 23),
         }
     }
+
+    // The 24 bit word offset, sign extended and converted to bytes.
+    pub fn byte_offset(&self) -> u32 {
+        (((self.offset << 8) as i32) >> 6) as u32
+    }
 }
 
-#[derive(Debug)]
-enum LoadStoreOpcode {
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LoadStoreOpcode {
     Str = 0,
     Ldr = 1,
 }
 
-#[derive(Debug)]
-struct LoadStoreInstr {
-    i: u32,
-    cond: ConditionField,
-    opcode: LoadStoreOpcode,
-    rd: u8,
-    rn: u8,
-    write_back: bool,
-    byte_or_word: bool,
-    up_down: bool,
-    pre_post: bool,
-    offset: LoadStoreOffset,
+#[derive(Debug, Clone, Copy)]
+pub struct LoadStoreInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub opcode: LoadStoreOpcode,
+    pub rd: u8,
+    pub rn: u8,
+    pub write_back: bool,
+    pub byte_or_word: bool,
+    pub up_down: bool,
+    pub pre_post: bool,
+    pub offset: LoadStoreOffset,
 }
 
 impl LoadStoreInstr {
-    fn new(i: u32) -> Self {
+    #[allow(clippy::needless_late_init)]
+    pub fn new(i: u32) -> Self {
         let opcode = if get_bits(i, 20, 20) == 1 {
             LoadStoreOpcode::Ldr
         } else {
@@ -411,8 +524,8 @@ impl LoadStoreInstr {
     }
 }
 
-#[derive(Debug)]
-enum LoadStoreOffset {
+#[derive(Debug, Clone, Copy)]
+pub enum LoadStoreOffset {
     ImmOffset {
         imm: u16,
     },
@@ -423,12 +536,12 @@ enum LoadStoreOffset {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ShiftType {
     LogicalLeft = 0b00,
     LogicalRight = 0b01,
-    ArithmeticLeft = 0b10,
-    ArithmeticRight = 0b11,
+    ArithmeticRight = 0b10,
+    RotateRight = 0b11,
 }
 
 impl TryFrom<u32> for ShiftType {
@@ -437,24 +550,26 @@ impl TryFrom<u32> for ShiftType {
         match v {
             v if v == ShiftType::LogicalLeft as u32 => Ok(ShiftType::LogicalLeft),
             v if v == ShiftType::LogicalRight as u32 => Ok(ShiftType::LogicalRight),
-            v if v == ShiftType::ArithmeticLeft as u32 => Ok(ShiftType::ArithmeticLeft),
             v if v == ShiftType::ArithmeticRight as u32 => Ok(ShiftType::ArithmeticRight),
+            v if v == ShiftType::RotateRight as u32 => Ok(ShiftType::RotateRight),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum DataProcessingOperand2 {
-    // bits 11-7 = shift amount, bits 6-5 = shift type , bit 4 = 0
+    // bits 11-7 = shift amount, bits 6-5 = shift type , bit 4 = 0, bits 3-0 = rm
     ShiftRegDirect {
         shift_count: u32,
         shift_type: ShiftType,
+        rm: u8,
     },
-    // bits 11-7 = register #, bits 7 = 0, bits 6-5 = shift type, bit 4 = 1
+    // bits 11-8 = register #, bits 7 = 0, bits 6-5 = shift type, bit 4 = 1, bits 3-0 = rm
     ShiftRegIndirect {
         shift_reg: usize,
         shift_type: ShiftType,
+        rm: u8,
     },
     // bits 11-8 = rotate amount, bits 7-0 = immediate value
     // shift ammount is rotate_amount * 2
@@ -464,21 +579,21 @@ pub enum DataProcessingOperand2 {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct MulLongInstr {
-    i: u32,
-    cond: ConditionField,
-    unsigned: bool,
-    accumulate: bool,
-    s: bool,
-    rdhi: u8,
-    rdlo: u8,
-    rs: u8,
-    rm: u8,
+    pub i: u32,
+    pub cond: ConditionField,
+    pub unsigned: bool,
+    pub accumulate: bool,
+    pub s: bool,
+    pub rdhi: u8,
+    pub rdlo: u8,
+    pub rs: u8,
+    pub rm: u8,
 }
 
 impl MulLongInstr {
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         let cond = cond!(i);
         let unsigned = get_bits(i, 22, 22) == 0;
         let accumulate = get_bits(i, 21, 21) == 1;
@@ -501,20 +616,20 @@ impl MulLongInstr {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct MulInstr {
-    i: u32,
-    cond: ConditionField,
-    accumulate: bool,
-    s: bool,
-    rd: u8,
-    rn: u8,
-    rs: u8,
-    rm: u8,
+    pub i: u32,
+    pub cond: ConditionField,
+    pub accumulate: bool,
+    pub s: bool,
+    pub rd: u8,
+    pub rn: u8,
+    pub rs: u8,
+    pub rm: u8,
 }
 
 impl MulInstr {
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         let cond = cond!(i);
         let accumulate = get_bits(i, 21, 21) == 1;
         let s = get_bits(i, 20, 20) == 1;
@@ -540,18 +655,18 @@ impl MulInstr {
 // Writes contents of source register Rm, puts it into [Rn],
 // Writes old contents of [Rn] into Rd
 // Rd and Rm may be the same register
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SingleDataSwapInstr {
-    i: u32,
-    cond: ConditionField,
-    b: bool,
-    rn: u8,
-    rd: u8,
-    rm: u8,
+    pub i: u32,
+    pub cond: ConditionField,
+    pub b: bool,
+    pub rn: u8,
+    pub rd: u8,
+    pub rm: u8,
 }
 
 impl SingleDataSwapInstr {
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         let cond = cond!(i);
         let b = get_bits(i, 22, 22) == 1;
         let rn = get_bits(i, 16, 19) as u8;
@@ -572,21 +687,22 @@ impl SingleDataSwapInstr {
  * Halfword and signed byte Load/stores
  *
  */
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct HalfWordDataTransferRegInstr {
-    i: u32,
-    cond: ConditionField,
-    pre: bool,
-    u: bool,
-    w: bool,
-    rn: u8,
-    rd: u8,
-    sh: HalfwordSignedByteInstrType,
-    rm: u8,
+    pub i: u32,
+    pub cond: ConditionField,
+    pub pre: bool,
+    pub u: bool,
+    pub w: bool,
+    pub l: bool,
+    pub rn: u8,
+    pub rd: u8,
+    pub sh: HalfwordSignedByteInstrType,
+    pub rm: u8,
 }
 
 impl HalfWordDataTransferRegInstr {
-    fn new(i: u32) -> Self {
+    pub fn new(i: u32) -> Self {
         let cond = cond!(i);
         let pre = get_bits(i, 24, 24) == 1;
         let u = get_bits(i, 23, 23) == 1;
@@ -602,6 +718,7 @@ impl HalfWordDataTransferRegInstr {
             pre,
             u,
             w,
+            l,
             rn,
             rd,
             sh,
@@ -610,8 +727,50 @@ impl HalfWordDataTransferRegInstr {
     }
 }
 
-#[derive(Debug)]
-enum HalfwordSignedByteInstrType {
+// Same as the register form, but the offset is an 8 bit immediate split into
+// bits 11-8 (high nibble) and bits 3-0 (low nibble).
+#[derive(Debug, Clone, Copy)]
+pub struct HalfWordDataTransferImmInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub pre: bool,
+    pub u: bool,
+    pub w: bool,
+    pub l: bool,
+    pub rn: u8,
+    pub rd: u8,
+    pub sh: HalfwordSignedByteInstrType,
+    pub offset: u8,
+}
+
+impl HalfWordDataTransferImmInstr {
+    pub fn new(i: u32) -> Self {
+        let cond = cond!(i);
+        let pre = get_bits(i, 24, 24) == 1;
+        let u = get_bits(i, 23, 23) == 1;
+        let w = get_bits(i, 21, 21) == 1;
+        let l = get_bits(i, 20, 20) == 1;
+        let rn = get_bits(i, 16, 19) as u8;
+        let rd = get_bits(i, 12, 15) as u8;
+        let sh = get_bits(i, 5, 6).try_into().unwrap();
+        let offset = (get_bits(i, 8, 11) << 4 | get_bits(i, 0, 3)) as u8;
+        HalfWordDataTransferImmInstr {
+            i,
+            cond,
+            pre,
+            u,
+            w,
+            l,
+            rn,
+            rd,
+            sh,
+            offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HalfwordSignedByteInstrType {
     UnsignedHalfword = 0b01,
     SignedByte = 0b10,
     SignedHalfword = 0b11,
@@ -635,11 +794,777 @@ impl TryFrom<u32> for HalfwordSignedByteInstrType {
     }
 }
 
-pub struct ARMCpu {}
+/*
+ * LDM/STM (Block Data Transfer). See ARM7TDMI Reference 4.11
+ *
+ * Bits 15-0 are a register list, lowest register goes to the lowest address.
+ * The S bit (bit 22) either restores the CPSR from the SPSR (LDM with r15 in the list) or
+ * transfers the User mode registers.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct BlockDataTransferInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub pre: bool,
+    pub u: bool,
+    pub s: bool,
+    pub w: bool,
+    pub l: bool,
+    pub rn: u8,
+    pub rlist: u16,
+}
+
+impl BlockDataTransferInstr {
+    pub fn new(i: u32) -> Self {
+        BlockDataTransferInstr {
+            i,
+            cond: cond!(i),
+            pre: get_bits(i, 24, 24) == 1,
+            u: get_bits(i, 23, 23) == 1,
+            s: get_bits(i, 22, 22) == 1,
+            w: get_bits(i, 21, 21) == 1,
+            l: get_bits(i, 20, 20) == 1,
+            rn: get_bits(i, 16, 19) as u8,
+            rlist: get_bits(i, 0, 15) as u16,
+        }
+    }
+}
+
+/*
+ * SWI (Software Interrupt). See ARM7TDMI Reference 4.13
+ *
+ * Bits 23-0 are ignored by the CPU, the handler reads them to find the requested function.
+ */
+#[derive(Debug)]
+pub struct SoftwareInterruptInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub comment: u32,
+}
+
+impl SoftwareInterruptInstr {
+    pub fn new(i: u32) -> Self {
+        SoftwareInterruptInstr {
+            i,
+            cond: cond!(i),
+            comment: get_bits(i, 0, 23),
+        }
+    }
+}
+
+/*
+ * CDP (Coprocessor Data Operations). See ARM7TDMI Reference 4.14
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CoprocDataOpInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub opcode1: u32,
+    pub crn: u8,
+    pub crd: u8,
+    pub cp_num: u8,
+    pub opcode2: u32,
+    pub crm: u8,
+}
+
+impl CoprocDataOpInstr {
+    pub fn new(i: u32) -> Self {
+        CoprocDataOpInstr {
+            i,
+            cond: cond!(i),
+            opcode1: get_bits(i, 20, 23),
+            crn: get_bits(i, 16, 19) as u8,
+            crd: get_bits(i, 12, 15) as u8,
+            cp_num: get_bits(i, 8, 11) as u8,
+            opcode2: get_bits(i, 5, 7),
+            crm: get_bits(i, 0, 3) as u8,
+        }
+    }
+}
+
+/*
+ * LDC/STC (Coprocessor Data Transfers). See ARM7TDMI Reference 4.15
+ *
+ * The offset in bits 7-0 counts words.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CoprocDataTransferInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub pre: bool,
+    pub u: bool,
+    pub n: bool,
+    pub w: bool,
+    pub l: bool,
+    pub rn: u8,
+    pub crd: u8,
+    pub cp_num: u8,
+    pub offset: u8,
+}
+
+impl CoprocDataTransferInstr {
+    pub fn new(i: u32) -> Self {
+        CoprocDataTransferInstr {
+            i,
+            cond: cond!(i),
+            pre: get_bits(i, 24, 24) == 1,
+            u: get_bits(i, 23, 23) == 1,
+            n: get_bits(i, 22, 22) == 1,
+            w: get_bits(i, 21, 21) == 1,
+            l: get_bits(i, 20, 20) == 1,
+            rn: get_bits(i, 16, 19) as u8,
+            crd: get_bits(i, 12, 15) as u8,
+            cp_num: get_bits(i, 8, 11) as u8,
+            offset: get_bits(i, 0, 7) as u8,
+        }
+    }
+}
+
+/*
+ * MCR/MRC (Coprocessor Register Transfers). See ARM7TDMI Reference 4.16
+ *
+ * MRC with rd = r15 sets the N, Z, C and V flags from bits 31-28 of the coprocessor register.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CoprocRegTransferInstr {
+    pub i: u32,
+    pub cond: ConditionField,
+    pub opcode1: u32,
+    pub l: bool,
+    pub crn: u8,
+    pub rd: u8,
+    pub cp_num: u8,
+    pub opcode2: u32,
+    pub crm: u8,
+}
+
+impl CoprocRegTransferInstr {
+    pub fn new(i: u32) -> Self {
+        CoprocRegTransferInstr {
+            i,
+            cond: cond!(i),
+            opcode1: get_bits(i, 21, 23),
+            l: get_bits(i, 20, 20) == 1,
+            crn: get_bits(i, 16, 19) as u8,
+            rd: get_bits(i, 12, 15) as u8,
+            cp_num: get_bits(i, 8, 11) as u8,
+            opcode2: get_bits(i, 5, 7),
+            crm: get_bits(i, 0, 3) as u8,
+        }
+    }
+}
+
+/*
+ * An ARM opcode decoded into its fields. The block cache keeps these so the fields of a cached
+ * instruction are extracted once.
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum ArmInstr {
+    DataProcessing(DataProcessingInstr),
+    Mrs(MrsInstr),
+    Msr(MsrInstr),
+    Multiply(MulInstr),
+    MultiplyLong(MulLongInstr),
+    BranchAndExchange(BxInstr),
+    SingleDataSwap(SingleDataSwapInstr),
+    HalfwordDataTransferReg(HalfWordDataTransferRegInstr),
+    HalfwordDataTransferImm(HalfWordDataTransferImmInstr),
+    LoadStore(LoadStoreInstr),
+    BlockDataTransfer(BlockDataTransferInstr),
+    Branch(BranchInstr),
+    SoftwareInterrupt,
+    CoprocDataOp(CoprocDataOpInstr),
+    CoprocDataTransfer(CoprocDataTransferInstr),
+    CoprocRegTransfer(CoprocRegTransferInstr),
+    Undefined,
+}
+
+impl ArmInstr {
+    pub fn decode(i: u32) -> Self {
+        match armv4_type(i) {
+            ArmV4Type::DataProcessingPsr if is_psr_transfer(i) => {
+                if get_bits(i, 21, 21) == 1 {
+                    ArmInstr::Msr(MsrInstr::new(i))
+                } else {
+                    ArmInstr::Mrs(MrsInstr::new(i))
+                }
+            }
+            ArmV4Type::DataProcessingPsr => ArmInstr::DataProcessing(DataProcessingInstr::new(i)),
+            ArmV4Type::Multiply => ArmInstr::Multiply(MulInstr::new(i)),
+            ArmV4Type::MultiplyLong => ArmInstr::MultiplyLong(MulLongInstr::new(i)),
+            ArmV4Type::BranchAndExchange => ArmInstr::BranchAndExchange(BxInstr::new(i)),
+            ArmV4Type::SingleDataSwap => ArmInstr::SingleDataSwap(SingleDataSwapInstr::new(i)),
+            ArmV4Type::HalfwordDataTransferReg => {
+                ArmInstr::HalfwordDataTransferReg(HalfWordDataTransferRegInstr::new(i))
+            }
+            ArmV4Type::HalfwordDataTransferImm => {
+                ArmInstr::HalfwordDataTransferImm(HalfWordDataTransferImmInstr::new(i))
+            }
+            ArmV4Type::LoadStore => ArmInstr::LoadStore(LoadStoreInstr::new(i)),
+            ArmV4Type::BlockDataTransfer => {
+                ArmInstr::BlockDataTransfer(BlockDataTransferInstr::new(i))
+            }
+            ArmV4Type::Branch => ArmInstr::Branch(BranchInstr::new(i)),
+            ArmV4Type::SoftwareInterrupt => ArmInstr::SoftwareInterrupt,
+            ArmV4Type::CoprocDataOp => ArmInstr::CoprocDataOp(CoprocDataOpInstr::new(i)),
+            ArmV4Type::CoprocDataTransfer => {
+                ArmInstr::CoprocDataTransfer(CoprocDataTransferInstr::new(i))
+            }
+            ArmV4Type::CoprocRegTransfer => {
+                ArmInstr::CoprocRegTransfer(CoprocRegTransferInstr::new(i))
+            }
+            _ => ArmInstr::Undefined,
+        }
+    }
+}
+
+/*
+ * ARM state execution.
+ *
+ * While an ARM instruction executes r15 = address of the instruction + 8. A register specified
+ * shift takes an extra internal cycle, during which the pc advances once more, so r15 reads as
+ * + 12 as an operand of those instructions and as the source of STR/STM.
+ */
+impl ARMCpu {
+    pub(crate) fn execute_arm<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        self.execute_arm_decoded(bus, i, &ArmInstr::decode(i))
+    }
+
+    // Executes an opcode already decoded, e.g. from the block cache.
+    pub(crate) fn execute_arm_decoded<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        i: u32,
+        instr: &ArmInstr,
+    ) {
+        if !cond!(i).passes(&self.cpsr) {
+            return;
+        }
+        match instr {
+            ArmInstr::DataProcessing(instr) => self.execute_data_processing(bus, instr),
+            ArmInstr::Mrs(instr) => self.execute_mrs(instr),
+            ArmInstr::Msr(instr) => self.execute_msr(instr),
+            ArmInstr::Multiply(instr) => self.execute_mul(bus, instr),
+            ArmInstr::MultiplyLong(instr) => self.execute_mul_long(bus, instr),
+            ArmInstr::BranchAndExchange(instr) => self.execute_bx(instr),
+            ArmInstr::SingleDataSwap(instr) => self.execute_swap(bus, instr),
+            ArmInstr::HalfwordDataTransferReg(instr) => {
+                let offset = self.regs[instr.rm as usize];
+                self.execute_halfword_transfer(
+                    bus, instr.pre, instr.u, instr.w, instr.l, instr.rn, instr.rd, instr.sh, offset,
+                )
+            }
+            ArmInstr::HalfwordDataTransferImm(instr) => {
+                let offset = instr.offset as u32;
+                self.execute_halfword_transfer(
+                    bus, instr.pre, instr.u, instr.w, instr.l, instr.rn, instr.rd, instr.sh, offset,
+                )
+            }
+            ArmInstr::LoadStore(instr) => self.execute_load_store(bus, instr),
+            ArmInstr::BlockDataTransfer(instr) => self.execute_block_transfer(bus, instr),
+            ArmInstr::Branch(instr) => self.execute_branch(instr),
+            ArmInstr::SoftwareInterrupt => self.software_interrupt(),
+            ArmInstr::CoprocDataOp(instr) => self.execute_coproc_data_op(instr),
+            ArmInstr::CoprocDataTransfer(instr) => self.execute_coproc_data_transfer(bus, instr),
+            ArmInstr::CoprocRegTransfer(instr) => self.execute_coproc_reg_transfer(instr),
+            ArmInstr::Undefined => self.undefined_instruction(),
+        }
+    }
+
+    // Restores the CPSR from the SPSR, as done by data processing with S and rd = r15 and by
+    // LDM^ with r15 in the list.
+    pub(crate) fn restore_cpsr(&mut self) {
+        if self.cpsr.mode().has_spsr() {
+            let spsr = self.spsr();
+            self.set_cpsr(spsr);
+        }
+    }
+
+    fn execute_data_processing<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        instr: &DataProcessingInstr,
+    ) {
+        let carry = self.cpsr.c();
+        let mut pc_offset = 0;
+        let (op2, shifter_carry) = match instr.operand2 {
+            DataProcessingOperand2::ImmRot {
+                rotate_count,
+                imm_value,
+            } => {
+                if rotate_count == 0 {
+                    (imm_value, carry)
+                } else {
+                    let value = imm_value.rotate_right(rotate_count * 2);
+                    (value, value >> 31 == 1)
+                }
+            }
+            DataProcessingOperand2::ShiftRegDirect {
+                shift_count,
+                shift_type,
+                rm,
+            } => shift(shift_type, self.regs[rm as usize], shift_count, carry, true),
+            DataProcessingOperand2::ShiftRegIndirect {
+                shift_reg,
+                shift_type,
+                rm,
+            } => {
+                self.idle(bus, 1);
+                pc_offset = 4;
+                let amount = self.regs[shift_reg] & 0xFF;
+                let value = self.reg_with_pc_offset(rm as usize, pc_offset);
+                shift(shift_type, value, amount, carry, false)
+            }
+        };
+        let rn = self.reg_with_pc_offset(instr.rn as usize, pc_offset);
+        let (result, c, v) = self.alu(instr.opcode, rn, op2, shifter_carry);
+
+        let rd = instr.rd as usize;
+        if instr.s {
+            if rd == ARMCpu::PC {
+                self.restore_cpsr();
+            } else {
+                self.cpsr.set_nz(result);
+                self.cpsr.set_c(c);
+                self.cpsr.set_v(v);
+            }
+        }
+        if !instr.opcode.is_test() {
+            self.set_reg(rd, result);
+        }
+    }
+
+    // r15 reads + 12 instead of + 8 after an internal cycle.
+    fn reg_with_pc_offset(&self, r: usize, pc_offset: u32) -> u32 {
+        if r == ARMCpu::PC {
+            self.regs[r].wrapping_add(pc_offset)
+        } else {
+            self.regs[r]
+        }
+    }
+
+    /*
+     * The ALU operations shared by ARM data processing and the THUMB ALU instructions. Returns
+     * the result and the C and V flags, logical operations pass through the shifter carry and
+     * leave V unchanged.
+     */
+    pub(crate) fn alu(
+        &self,
+        opcode: DataProcessingOpCode,
+        rn: u32,
+        op2: u32,
+        shifter_carry: bool,
+    ) -> (u32, bool, bool) {
+        let c = self.cpsr.c();
+        let v = self.cpsr.v();
+        match opcode {
+            DataProcessingOpCode::And | DataProcessingOpCode::Tst => (rn & op2, shifter_carry, v),
+            DataProcessingOpCode::Eor | DataProcessingOpCode::Teq => (rn ^ op2, shifter_carry, v),
+            DataProcessingOpCode::Orr => (rn | op2, shifter_carry, v),
+            DataProcessingOpCode::Mov => (op2, shifter_carry, v),
+            DataProcessingOpCode::Bic => (rn & !op2, shifter_carry, v),
+            DataProcessingOpCode::Mvn => (!op2, shifter_carry, v),
+            DataProcessingOpCode::Sub | DataProcessingOpCode::Cmp => sub_with_carry(rn, op2, true),
+            DataProcessingOpCode::Rsb => sub_with_carry(op2, rn, true),
+            DataProcessingOpCode::Add | DataProcessingOpCode::Cmn => add_with_carry(rn, op2, false),
+            DataProcessingOpCode::Adc => add_with_carry(rn, op2, c),
+            DataProcessingOpCode::Sbc => sub_with_carry(rn, op2, c),
+            DataProcessingOpCode::Rsc => sub_with_carry(op2, rn, c),
+        }
+    }
+
+    fn execute_mrs(&mut self, instr: &MrsInstr) {
+        let psr = if instr.spsr { self.spsr() } else { self.cpsr };
+        self.set_reg(instr.rd as usize, psr.0);
+    }
+
+    fn execute_msr(&mut self, instr: &MsrInstr) {
+        let value = match instr.operand {
+            MsrOperand::Reg(rm) => self.regs[rm as usize],
+            MsrOperand::Imm(imm) => imm,
+        };
+        let mut mask = instr.field_mask;
+        if instr.spsr {
+            let spsr = self.spsr();
+            self.set_spsr(Psr((spsr.0 & !mask) | (value & mask)));
+        } else {
+            // User mode can only change the flags.
+            if !self.cpsr.mode().is_privileged() {
+                mask &= Psr::FLAGS_MASK;
+            }
+            let cpsr = Psr((self.cpsr.0 & !mask) | (value & mask));
+            self.set_cpsr(cpsr);
+        }
+    }
+
+    fn execute_mul<B: Bus + ?Sized>(&mut self, bus: &mut B, instr: &MulInstr) {
+        let rs = self.regs[instr.rs as usize];
+        let mut result = self.regs[instr.rm as usize].wrapping_mul(rs);
+        self.idle(bus, multiply_cycles(rs, true));
+        if instr.accumulate {
+            result = result.wrapping_add(self.regs[instr.rn as usize]);
+            self.idle(bus, 1);
+        }
+        if instr.s {
+            self.cpsr.set_nz(result);
+        }
+        self.set_reg(instr.rd as usize, result);
+    }
+
+    fn execute_mul_long<B: Bus + ?Sized>(&mut self, bus: &mut B, instr: &MulLongInstr) {
+        let rm = self.regs[instr.rm as usize];
+        let rs = self.regs[instr.rs as usize];
+        let mut result = if instr.unsigned {
+            (rm as u64).wrapping_mul(rs as u64)
+        } else {
+            (rm as i32 as i64).wrapping_mul(rs as i32 as i64) as u64
+        };
+        self.idle(bus, multiply_cycles(rs, !instr.unsigned) + 1);
+        if instr.accumulate {
+            let acc = (self.regs[instr.rdhi as usize] as u64) << 32
+                | self.regs[instr.rdlo as usize] as u64;
+            result = result.wrapping_add(acc);
+            self.idle(bus, 1);
+        }
+        if instr.s {
+            self.cpsr.set_n(result >> 63 == 1);
+            self.cpsr.set_z(result == 0);
+        }
+        self.set_reg(instr.rdlo as usize, result as u32);
+        self.set_reg(instr.rdhi as usize, (result >> 32) as u32);
+    }
+
+    fn execute_bx(&mut self, instr: &BxInstr) {
+        let target = self.regs[instr.rn as usize];
+        self.cpsr.set_thumb(target & 1 == 1);
+        self.set_reg(ARMCpu::PC, target & !1);
+    }
+
+    fn execute_branch(&mut self, instr: &BranchInstr) {
+        let pc = self.regs[ARMCpu::PC];
+        if instr.link {
+            self.regs[ARMCpu::LR] = pc.wrapping_sub(4);
+        }
+        self.set_reg(ARMCpu::PC, pc.wrapping_add(instr.byte_offset()));
+    }
+
+    fn execute_swap<B: Bus + ?Sized>(&mut self, bus: &mut B, instr: &SingleDataSwapInstr) {
+        let addr = self.regs[instr.rn as usize];
+        let source = self.regs[instr.rm as usize];
+        let value = if instr.b {
+            let value = self.read_8(bus, addr, MemoryAccess::NonSequential) as u32;
+            self.write_8(bus, addr, source as u8, MemoryAccess::NonSequential);
+            value
+        } else {
+            let value = self.read_32_rotated(bus, addr);
+            self.write_32(bus, addr & !3, source, MemoryAccess::NonSequential);
+            value
+        };
+        self.idle(bus, 1);
+        self.set_reg(instr.rd as usize, value);
+    }
+
+    fn execute_load_store<B: Bus + ?Sized>(&mut self, bus: &mut B, instr: &LoadStoreInstr) {
+        let offset = match instr.offset {
+            LoadStoreOffset::ImmOffset { imm } => imm as u32,
+            LoadStoreOffset::ShiftOffset {
+                shift_count,
+                shift_type,
+                rm,
+            } => {
+                shift(
+                    shift_type,
+                    self.regs[rm as usize],
+                    shift_count,
+                    self.cpsr.c(),
+                    true,
+                )
+                .0
+            }
+        };
+        let base = self.regs[instr.rn as usize];
+        let offset_base = if instr.up_down {
+            base.wrapping_add(offset)
+        } else {
+            base.wrapping_sub(offset)
+        };
+        let addr = if instr.pre_post { offset_base } else { base };
+        let write_back = instr.write_back || !instr.pre_post;
+
+        let rd = instr.rd as usize;
+        if instr.opcode == LoadStoreOpcode::Ldr {
+            let value = if instr.byte_or_word {
+                self.read_8(bus, addr, MemoryAccess::NonSequential) as u32
+            } else {
+                self.read_32_rotated(bus, addr)
+            };
+            self.idle(bus, 1);
+            // The loaded value wins when rd is also the base register.
+            if write_back {
+                self.set_reg(instr.rn as usize, offset_base);
+            }
+            self.set_reg(rd, value);
+        } else {
+            let value = self.reg_with_pc_offset(rd, 4);
+            if instr.byte_or_word {
+                self.write_8(bus, addr, value as u8, MemoryAccess::NonSequential);
+            } else {
+                self.write_32(bus, addr & !3, value, MemoryAccess::NonSequential);
+            }
+            if write_back {
+                self.set_reg(instr.rn as usize, offset_base);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn execute_halfword_transfer<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        pre: bool,
+        up: bool,
+        w: bool,
+        load: bool,
+        rn: u8,
+        rd: u8,
+        sh: HalfwordSignedByteInstrType,
+        offset: u32,
+    ) {
+        let base = self.regs[rn as usize];
+        let offset_base = if up {
+            base.wrapping_add(offset)
+        } else {
+            base.wrapping_sub(offset)
+        };
+        let addr = if pre { offset_base } else { base };
+        let write_back = w || !pre;
+
+        if load {
+            let value = match sh {
+                HalfwordSignedByteInstrType::UnsignedHalfword => self.read_16_rotated(bus, addr),
+                HalfwordSignedByteInstrType::SignedByte => {
+                    self.read_8(bus, addr, MemoryAccess::NonSequential) as i8 as i32 as u32
+                }
+                HalfwordSignedByteInstrType::SignedHalfword => self.read_16_signed(bus, addr),
+            };
+            self.idle(bus, 1);
+            if write_back {
+                self.set_reg(rn as usize, offset_base);
+            }
+            self.set_reg(rd as usize, value);
+        } else {
+            let value = self.reg_with_pc_offset(rd as usize, 4);
+            self.write_16(bus, addr & !1, value as u16, MemoryAccess::NonSequential);
+            if write_back {
+                self.set_reg(rn as usize, offset_base);
+            }
+        }
+    }
+
+    /*
+     * LDM/STM. The registers are always transferred lowest register to lowest address, so the
+     * start address is worked out first and the transfer runs upwards.
+     *
+     * Quirks of the ARM7TDMI that software relies on:
+     *      An empty register list transfers r15 only, but the base still moves by 0x40.
+     *      STM with the base in the list stores the original base if it is the first register,
+     *      otherwise the written back base.
+     *      LDM with the base in the list does not write back.
+     */
+    pub(crate) fn execute_block_transfer<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        instr: &BlockDataTransferInstr,
+    ) {
+        let rn = instr.rn as usize;
+        let base = self.regs[rn];
+        let (rlist, count) = if instr.rlist == 0 {
+            (1 << ARMCpu::PC, 16)
+        } else {
+            (instr.rlist, instr.rlist.count_ones())
+        };
+        let size = count * 4;
+        let (start, new_base) = if instr.u {
+            let start = if instr.pre {
+                base.wrapping_add(4)
+            } else {
+                base
+            };
+            (start, base.wrapping_add(size))
+        } else {
+            let start = if instr.pre {
+                base.wrapping_sub(size)
+            } else {
+                base.wrapping_sub(size).wrapping_add(4)
+            };
+            (start, base.wrapping_sub(size))
+        };
+
+        let pc_in_list = rlist & (1 << ARMCpu::PC) != 0;
+        let user_bank = instr.s && !(instr.l && pc_in_list);
+        let mut addr = start;
+        let mut access = MemoryAccess::NonSequential;
+
+        if instr.l {
+            if instr.w {
+                self.set_reg(rn, new_base);
+            }
+            for r in (0..16).filter(|r| rlist & (1 << r) != 0) {
+                let value = self.read_32(bus, addr, access);
+                if user_bank {
+                    self.set_user_reg(r, value);
+                } else {
+                    self.set_reg(r, value);
+                }
+                addr = addr.wrapping_add(4);
+                access = MemoryAccess::Sequential;
+            }
+            self.idle(bus, 1);
+            if instr.s && pc_in_list {
+                self.restore_cpsr();
+            }
+        } else {
+            let first = rlist.trailing_zeros() as usize;
+            for r in (0..16).filter(|r| rlist & (1 << r) != 0) {
+                let value = if r == rn && r != first && instr.w {
+                    new_base
+                } else if user_bank {
+                    self.user_reg(r)
+                } else {
+                    self.reg_with_pc_offset(r, 4)
+                };
+                self.write_32(bus, addr, value, access);
+                addr = addr.wrapping_add(4);
+                access = MemoryAccess::Sequential;
+            }
+            if instr.w {
+                self.set_reg(rn, new_base);
+            }
+        }
+    }
+
+    fn execute_coproc_data_op(&mut self, instr: &CoprocDataOpInstr) {
+        let accepted = match self.coprocessor(instr.cp_num as usize) {
+            Some(cp) => cp.data_op(
+                instr.opcode1,
+                instr.crd as usize,
+                instr.crn as usize,
+                instr.crm as usize,
+                instr.opcode2,
+            ),
+            None => false,
+        };
+        if !accepted {
+            self.undefined_instruction();
+        }
+    }
+
+    fn execute_coproc_reg_transfer(&mut self, instr: &CoprocRegTransferInstr) {
+        let rd = instr.rd as usize;
+        if instr.l {
+            let value = match self.coprocessor(instr.cp_num as usize) {
+                Some(cp) => cp.read_reg(
+                    instr.opcode1,
+                    instr.crn as usize,
+                    instr.crm as usize,
+                    instr.opcode2,
+                ),
+                None => None,
+            };
+            match value {
+                Some(value) if rd == ARMCpu::PC => {
+                    self.cpsr = Psr((self.cpsr.0 & !Psr::FLAGS_MASK) | (value & Psr::FLAGS_MASK));
+                }
+                Some(value) => self.set_reg(rd, value),
+                None => self.undefined_instruction(),
+            }
+        } else {
+            let value = self.reg_with_pc_offset(rd, 4);
+            let accepted = match self.coprocessor(instr.cp_num as usize) {
+                Some(cp) => cp.write_reg(
+                    instr.opcode1,
+                    instr.crn as usize,
+                    instr.crm as usize,
+                    instr.opcode2,
+                    value,
+                ),
+                None => false,
+            };
+            if !accepted {
+                self.undefined_instruction();
+            }
+        }
+    }
+
+    fn execute_coproc_data_transfer<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        instr: &CoprocDataTransferInstr,
+    ) {
+        let crd = instr.crd as usize;
+        let len = match self.coprocessor(instr.cp_num as usize) {
+            Some(cp) => cp.transfer_len(crd, instr.n, instr.l),
+            None => None,
+        };
+        let len = match len {
+            Some(len) => len,
+            None => {
+                self.undefined_instruction();
+                return;
+            }
+        };
 
+        let rn = instr.rn as usize;
+        let base = self.regs[rn];
+        let offset = instr.offset as u32 * 4;
+        let offset_base = if instr.u {
+            base.wrapping_add(offset)
+        } else {
+            base.wrapping_sub(offset)
+        };
+        let mut addr = if instr.pre { offset_base } else { base };
+        let mut access = MemoryAccess::NonSequential;
+        for index in 0..len {
+            if instr.l {
+                let value = self.read_32(bus, addr & !3, access);
+                if let Some(cp) = self.coprocessor(instr.cp_num as usize) {
+                    cp.load_word(crd, index, value);
+                }
+            } else {
+                let value = match self.coprocessor(instr.cp_num as usize) {
+                    Some(cp) => cp.store_word(crd, index),
+                    None => 0,
+                };
+                self.write_32(bus, addr & !3, value, access);
+            }
+            addr = addr.wrapping_add(4);
+            access = MemoryAccess::Sequential;
+        }
+        if instr.w || !instr.pre {
+            self.set_reg(rn, offset_base);
+        }
+    }
+}
+
+#[allow(clippy::bool_assert_comparison)]
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::coprocessor::Coprocessor;
+    use crate::cpu::tests::TestBus;
+    use crate::cpu::Exception;
+    use crate::psr::CpuMode;
     use util::read_instructions_file;
+
+    fn run(code: &[u32], steps: usize) -> (ARMCpu, TestBus) {
+        let mut bus = TestBus::with_code(code);
+        let mut cpu = ARMCpu::new();
+        for _ in 0..steps {
+            cpu.step(&mut bus);
+        }
+        (cpu, bus)
+    }
+
     #[test]
     fn test_instruction() {
         let mut i = Instruction::new(0x0);
@@ -741,4 +1666,181 @@ mod tests {
         println!("instr: {:?}", ldrh_w);
         assert_eq!(ldrh.rd, 1);
     }
+
+    #[test]
+    fn test_undefined_instruction_type() {
+        // The undefined encoding overlaps the register offset LDR/STR encoding
+        assert_eq!(ArmV4Type::Undefined, armv4_type(0xe7f000f0));
+        assert_eq!(ArmV4Type::LoadStore, armv4_type(0xe7910002));
+        // stmdb sp!, {r0}^ has the S bit set
+        assert_eq!(ArmV4Type::BlockDataTransfer, armv4_type(0xe96d0001));
+    }
+
+    #[test]
+    fn test_execute_data_processing() {
+        // mov r0, #0x4000000; add r1, r0, #3; subs r2, r1, r1; movs r3, r0, lsl #6
+        let (cpu, _) = run(&[0xe3a00301, 0xe2801003, 0xe0512001, 0xe1b03300], 4);
+        assert_eq!(cpu.reg(0), 0x0400_0000);
+        assert_eq!(cpu.reg(1), 0x0400_0003);
+        assert_eq!(cpu.reg(2), 0);
+        assert_eq!(cpu.reg(3), 0);
+        assert!(cpu.cpsr().z());
+        assert!(cpu.cpsr().c());
+    }
+
+    #[test]
+    fn test_execute_conditional_branch() {
+        // mov r0, #0; cmp r0, #0; bne +5; mov r1, #1
+        let (cpu, _) = run(&[0xe3a00000, 0xe3500000, 0x1a000005, 0xe3a01001], 4);
+        assert_eq!(cpu.reg(1), 1);
+        // b . loops on itself
+        let (cpu, _) = run(&[0xeafffffe], 3);
+        assert_eq!(cpu.next_pc(), 0);
+    }
+
+    #[test]
+    fn test_execute_bl_and_bx() {
+        // bl +0 (to 0x8); nop; add r0, pc, #1; bx r0
+        let (cpu, _) = run(&[0xeb000000, 0xe1a00000, 0xe28f0001, 0xe12fff10], 3);
+        assert_eq!(cpu.reg(ARMCpu::LR), 4);
+        assert!(cpu.is_thumb());
+        assert_eq!(cpu.next_pc(), 0x10);
+    }
+
+    #[test]
+    fn test_execute_load_store() {
+        // mov r0, #0x100; mov r1, #0xAB; str r1, [r0, #4]!; ldrb r2, [r0], #-4; ldr r3, [r0, #5]
+        let (cpu, bus) = run(
+            &[0xe3a00c01, 0xe3a010ab, 0xe5a01004, 0xe4502004, 0xe5903005],
+            5,
+        );
+        assert_eq!(bus.mem[0x104], 0xAB);
+        assert_eq!(cpu.reg(2), 0xAB);
+        assert_eq!(cpu.reg(0), 0x100);
+        // unaligned load rotates the word
+        assert_eq!(cpu.reg(3), 0xAB00_0000);
+    }
+
+    #[test]
+    fn test_execute_block_transfer() {
+        // mov r0, #0x100; mov r1, #1; mov r2, #2; stmia r0!, {r0, r1, r2}; ldmdb r0, {r3, r4}
+        let (cpu, bus) = run(
+            &[0xe3a00c01, 0xe3a01001, 0xe3a02002, 0xe8a00007, 0xe9100018],
+            5,
+        );
+        // base is first in the list, so the original value is stored
+        assert_eq!(bus.mem[0x100], 0x00);
+        assert_eq!(bus.mem[0x101], 0x01);
+        assert_eq!(cpu.reg(0), 0x10C);
+        assert_eq!(cpu.reg(3), 1);
+        assert_eq!(cpu.reg(4), 2);
+    }
+
+    #[test]
+    fn test_execute_msr_mode_switch() {
+        // mov r0, #0x1F; msr cpsr_c, r0; mrs r1, cpsr
+        let (cpu, _) = run(&[0xe3a0001f, 0xe129f000, 0xe10f1000], 3);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::System);
+        assert_eq!(cpu.reg(1), 0x1F);
+    }
+
+    #[test]
+    fn test_execute_mull() {
+        // mvn r2, #0; mov r3, #2; smull r0, r1, r2, r3; umull r4, r5, r2, r3
+        let (cpu, _) = run(&[0xe3e02000, 0xe3a03002, 0xe0c10392, 0xe0854392], 4);
+        assert_eq!(cpu.reg(0), 0xFFFF_FFFE);
+        assert_eq!(cpu.reg(1), 0xFFFF_FFFF);
+        assert_eq!(cpu.reg(4), 0xFFFF_FFFE);
+        assert_eq!(cpu.reg(5), 1);
+    }
+
+    #[test]
+    fn test_swi() {
+        // mov r0, r0; swi 0x10000
+        let (cpu, _) = run(&[0xe1a00000, 0xef010000], 2);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Supervisor);
+        assert_eq!(cpu.next_pc(), Exception::SoftwareInterrupt.vector());
+        assert_eq!(cpu.reg(ARMCpu::LR), 8);
+    }
+
+    #[test]
+    fn test_absent_coprocessor_traps() {
+        // mrc p15, 0, r0, c0, c0, 0 at 0x0 with no coprocessor attached
+        let (cpu, _) = run(&[0xee100f10], 1);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Undefined);
+        assert_eq!(cpu.next_pc(), Exception::Undefined.vector());
+        assert_eq!(cpu.reg(ARMCpu::LR), 4);
+        assert_eq!(cpu.spsr().mode(), CpuMode::Supervisor);
+    }
+
+    #[test]
+    fn test_undefined_instruction_traps() {
+        let (cpu, _) = run(&[0xe1a00000, 0xe7f000f0], 2);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Undefined);
+        assert_eq!(cpu.reg(ARMCpu::LR), 8);
+    }
+
+    // A coprocessor with 16 plain registers.
+    struct RegisterFile {
+        regs: [u32; 16],
+    }
+
+    impl Coprocessor for RegisterFile {
+        fn write_reg(
+            &mut self,
+            _opcode1: u32,
+            crn: usize,
+            _crm: usize,
+            _opcode2: u32,
+            value: u32,
+        ) -> bool {
+            self.regs[crn] = value;
+            true
+        }
+
+        fn read_reg(
+            &mut self,
+            _opcode1: u32,
+            crn: usize,
+            _crm: usize,
+            _opcode2: u32,
+        ) -> Option<u32> {
+            Some(self.regs[crn])
+        }
+
+        fn transfer_len(&mut self, _crd: usize, long: bool, _load: bool) -> Option<usize> {
+            Some(if long { 2 } else { 1 })
+        }
+
+        fn load_word(&mut self, crd: usize, index: usize, value: u32) {
+            self.regs[crd + index] = value;
+        }
+
+        fn store_word(&mut self, crd: usize, index: usize) -> u32 {
+            self.regs[crd + index]
+        }
+    }
+
+    #[test]
+    fn test_attached_coprocessor() {
+        // mov r0, #0x42; mcr p7, 0, r0, c3, c0, 0; mrc p7, 0, r1, c3, c0, 0;
+        // mov r2, #0x100; stcl p7, c3, [r2], #8; cdp p7, 0, c0, c0, c0, 0
+        let mut bus = TestBus::with_code(&[
+            0xe3a00042, 0xee030710, 0xee131710, 0xe3a02c01, 0xecc23702, 0xee000700,
+        ]);
+        let mut cpu = ARMCpu::new();
+        cpu.attach_coprocessor(7, Box::new(RegisterFile { regs: [0; 16] }));
+        for _ in 0..5 {
+            cpu.step(&mut bus);
+        }
+        assert_eq!(cpu.reg(1), 0x42);
+        assert_eq!(bus.mem[0x100], 0x42);
+        assert_eq!(cpu.reg(2), 0x108);
+        // The coprocessor has no CDP support, so CDP still traps
+        cpu.step(&mut bus);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Undefined);
+
+        assert!(cpu.detach_coprocessor(7).is_some());
+        assert!(cpu.detach_coprocessor(7).is_none());
+    }
 }
@@ -0,0 +1,90 @@
+use arm7tdmi::singlestep::load_vectors;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Failing vectors printed per file, the rest are only counted.
+const MAX_REPORTED: usize = 5;
+
+/*
+ * singlestep <file or directory>...
+ *
+ * Runs single step test vectors, see arm7tdmi::singlestep. Directories are searched for .json
+ * files. Exits with 1 when any vector fails.
+ */
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <file or directory>...", args[0]);
+        process::exit(2);
+    }
+
+    let mut files = vec![];
+    for arg in &args[1..] {
+        let path = Path::new(arg);
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+                Ok(dir) => dir
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+                    .collect(),
+                Err(err) => {
+                    eprintln!("{}: {}", path.display(), err);
+                    process::exit(2);
+                }
+            };
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    let mut total = 0;
+    let mut failed = 0;
+    for file in files {
+        let vectors = match load_vectors(&file) {
+            Ok(vectors) => vectors,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(2);
+            }
+        };
+        let mut file_failed = 0;
+        for (n, vector) in vectors.iter().enumerate() {
+            let mismatches = vector.run();
+            if mismatches.is_empty() {
+                continue;
+            }
+            file_failed += 1;
+            if file_failed <= MAX_REPORTED {
+                println!(
+                    "{} vector {} opcode {:08X}:",
+                    file.display(),
+                    n,
+                    vector.opcode
+                );
+                for m in mismatches {
+                    println!(
+                        "    {}: expected {:08X}, got {:08X}",
+                        m.field, m.expected, m.actual
+                    );
+                }
+            }
+        }
+        println!(
+            "{}: {}/{} passed",
+            file.display(),
+            vectors.len() - file_failed,
+            vectors.len()
+        );
+        total += vectors.len();
+        failed += file_failed;
+    }
+
+    println!("{}/{} passed", total - failed, total);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,50 @@
+use arm7tdmi::trace::first_divergence;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+/*
+ * tracediff <expected trace> <actual trace>
+ *
+ * Compares two instruction traces, e.g. one from mGBA and one from this emulator, and prints the
+ * first instruction where the machine state differs. Exits with 1 when the traces diverge.
+ */
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <expected trace> <actual trace>", args[0]);
+        process::exit(2);
+    }
+
+    let open = |path: &str| match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(2);
+        }
+    };
+    let left = open(&args[1]);
+    let right = open(&args[2]);
+
+    match first_divergence(left, right) {
+        Ok(None) => println!("traces match"),
+        Ok(Some(d)) => {
+            println!(
+                "traces diverge at line {} ({})",
+                d.line,
+                d.fields.join(", ")
+            );
+            if let Some(previous) = d.previous {
+                println!("  {}", previous);
+            }
+            println!("< {}", d.left.as_deref().unwrap_or("<end of trace>"));
+            println!("> {}", d.right.as_deref().unwrap_or("<end of trace>"));
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("error reading traces: {}", err);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * The memory interface of the ARM7TDMI.
+ *
+ * The CPU core does not know anything about the system it is plugged into, every fetch, load and
+ * store goes through a Bus. A system (e.g. the GBA) implements this trait with its memory map.
+ *
+ * Addresses are passed unaligned as the CPU generated them, the bus decides what an unaligned
+ * access means for its memory. The access kind mirrors the SEQ signal of the real bus, a
+ * sequential access is to the address following the previous access.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryAccess {
+    NonSequential,
+    Sequential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryWidth {
+    Byte,
+    Halfword,
+    Word,
+}
+
+pub trait Bus {
+    fn read_8(&mut self, addr: u32, access: MemoryAccess) -> u8;
+    fn read_16(&mut self, addr: u32, access: MemoryAccess) -> u16;
+    fn read_32(&mut self, addr: u32, access: MemoryAccess) -> u32;
+    fn write_8(&mut self, addr: u32, value: u8, access: MemoryAccess);
+    fn write_16(&mut self, addr: u32, value: u16, access: MemoryAccess);
+    fn write_32(&mut self, addr: u32, value: u32, access: MemoryAccess);
+
+    // Opcode fetches, separate from data reads so a bus can tell them apart.
+    fn fetch_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+        self.read_16(addr, access)
+    }
+
+    fn fetch_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+        self.read_32(addr, access)
+    }
+
+    // Extra cycles an access takes on top of the single bus cycle. Called before the access.
+    fn wait_states(&mut self, _addr: u32, _width: MemoryWidth, _access: MemoryAccess) -> u32 {
+        0
+    }
+
+    // Wait states of an opcode fetch, e.g. for a bus with a prefetch buffer.
+    fn fetch_wait_states(&mut self, addr: u32, width: MemoryWidth, access: MemoryAccess) -> u32 {
+        self.wait_states(addr, width, access)
+    }
+
+    // Internal cycles, where the CPU does not use the bus.
+    fn idle(&mut self, _cycles: u32) {}
+
+    /*
+     * Cycles until the system next handles an event, which may raise an IRQ. Code run without
+     * returning to the system after each instruction (see jit.rs) stops there.
+     */
+    fn cycles_to_next_event(&self) -> u32 {
+        u32::MAX
+    }
+}
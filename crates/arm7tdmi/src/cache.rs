@@ -0,0 +1,510 @@
+use super::arm::{armv4_type, ArmInstr, ArmV4Type};
+use super::thumb::{thumb_type, ThumbType};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use util::get_bits;
+
+/*
+ * Pre-decoded basic block cache.
+ *
+ * The cached interpreter decodes each straight-line run of code once and keeps the decoded
+ * instructions in a block keyed by the start address and the CPU state (ARM or THUMB). Blocks are
+ * filled in as the code executes for the first time, so building a block never reads memory the
+ * plain interpreter would not read. ARM instructions are kept with all their fields extracted
+ * (see arm::ArmInstr), THUMB instructions with their format, the THUMB handlers read their few
+ * fields straight from the opcode.
+ *
+ * A block is looked up once when execution enters it, after that a cursor walks its instructions
+ * by index as long as execution continues at the next address. Each block also remembers the
+ * block execution continued in last, so a loop does not look up its blocks again.
+ *
+ * The CPU still fetches every opcode through the bus, so cycle counts and bus side effects are
+ * the same as without the cache. A cached instruction is only used when its opcode matches the
+ * fetched one, so a stale block can never change results, it only costs a decode.
+ *
+ * Blocks are dropped when the memory they were decoded from is written. The CPU reports its own
+ * stores, anything else that writes memory (e.g. DMA) has to call ARMCpu::invalidate_code. A
+ * block that is missed is only decoded again, as the opcodes are compared anyway.
+ */
+
+// Blocks are tracked per page for invalidation.
+const PAGE_SHIFT: u32 = 8;
+const MAX_BLOCK_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DecodedOp {
+    Arm(ArmInstr),
+    Thumb(ThumbType),
+}
+
+struct Decoded {
+    opcode: u32,
+    op: DecodedOp,
+}
+
+struct Block {
+    key: BlockKey,
+    instrs: Vec<Decoded>,
+    // Set once the block reached an instruction that may branch, or the length limit.
+    complete: bool,
+    // Slot of the block execution last continued in, checked against its key before use.
+    next: Option<usize>,
+}
+
+// Start address and THUMB state of a block.
+type BlockKey = (u32, bool);
+
+/*
+ * The keys are addresses, which need no protection against crafted collisions, so they are
+ * hashed with a multiply instead of SipHash.
+ */
+#[derive(Default)]
+pub(crate) struct AddrHasher(u64);
+
+impl Hasher for AddrHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u64(b as u64);
+        }
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub(crate) type AddrMap<K, V> = HashMap<K, V, BuildHasherDefault<AddrHasher>>;
+
+// The block being executed, the index of its next instruction and that instruction's address.
+#[derive(Clone, Copy)]
+struct Cursor {
+    slot: usize,
+    index: usize,
+    addr: u32,
+    thumb: bool,
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    // Blocks by slot, the slots of dropped blocks are reused.
+    blocks: Vec<Option<Block>>,
+    free: Vec<usize>,
+    starts: AddrMap<BlockKey, usize>,
+    pages: AddrMap<u32, Vec<usize>>,
+    cursor: Option<Cursor>,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache::default()
+    }
+
+    // Number of cached blocks.
+    pub fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    // Instructions executed from the cache and instructions that had to be decoded.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.free.clear();
+        self.starts.clear();
+        self.pages.clear();
+        self.cursor = None;
+    }
+
+    // Decoded ARM instruction for the opcode about to execute at addr.
+    #[inline]
+    pub(crate) fn decode_arm(&mut self, addr: u32, opcode: u32) -> ArmInstr {
+        match self.decode(addr, false, opcode) {
+            DecodedOp::Arm(instr) => instr,
+            // Blocks are keyed by the state, an ARM address never has THUMB entries.
+            DecodedOp::Thumb(_) => ArmInstr::decode(opcode),
+        }
+    }
+
+    // Format of the THUMB opcode about to execute at addr.
+    #[inline]
+    pub(crate) fn decode_thumb(&mut self, addr: u32, opcode: u16) -> ThumbType {
+        match self.decode(addr, true, opcode as u32) {
+            DecodedOp::Thumb(kind) => kind,
+            DecodedOp::Arm(_) => thumb_type(opcode),
+        }
+    }
+
+    /*
+     * Returns the decoded form of the opcode about to execute at addr, decoding and caching it
+     * if it is not cached yet.
+     */
+    #[inline]
+    fn decode(&mut self, addr: u32, thumb: bool, opcode: u32) -> DecodedOp {
+        // Most instructions follow the previous one in the same block. The cursor is reset when
+        // its block is dropped, see invalidate.
+        if let Some(c) = self.cursor.as_mut() {
+            if c.addr == addr && c.thumb == thumb {
+                let block = self.blocks[c.slot].as_ref().unwrap();
+                if let Some(cached) = block.instrs.get(c.index) {
+                    if cached.opcode == opcode {
+                        c.index += 1;
+                        c.addr = addr.wrapping_add(if thumb { 2 } else { 4 });
+                        self.hits += 1;
+                        return cached.op;
+                    }
+                }
+            }
+        }
+        self.decode_slow(addr, thumb, opcode)
+    }
+
+    // Enters another block, or decodes an instruction that is not cached yet.
+    #[inline(never)]
+    fn decode_slow(&mut self, addr: u32, thumb: bool, opcode: u32) -> DecodedOp {
+        let mut index = 0;
+        let slot = match self.cursor {
+            Some(c) if c.addr == addr && c.thumb == thumb => {
+                let block = self.blocks[c.slot].as_ref().unwrap();
+                if c.index == block.instrs.len() && block.complete {
+                    // Execution left a complete block at its end and continues in another one.
+                    self.next_block(c.slot, addr, thumb)
+                } else {
+                    index = c.index;
+                    c.slot
+                }
+            }
+            Some(c) => self.next_block(c.slot, addr, thumb),
+            None => self.block_at(addr, thumb),
+        };
+        self.cursor = Some(Cursor {
+            slot,
+            index: index + 1,
+            addr: addr.wrapping_add(if thumb { 2 } else { 4 }),
+            thumb,
+        });
+
+        let block = self.blocks[slot].as_mut().unwrap();
+        if let Some(cached) = block.instrs.get(index) {
+            if cached.opcode == opcode {
+                self.hits += 1;
+                return cached.op;
+            }
+            // The code changed without being invalidated, decode it again from here on.
+            block.instrs.truncate(index);
+            block.complete = false;
+        }
+
+        let op = if thumb {
+            DecodedOp::Thumb(thumb_type(opcode as u16))
+        } else {
+            DecodedOp::Arm(ArmInstr::decode(opcode))
+        };
+        block.instrs.push(Decoded { opcode, op });
+        block.complete = ends_block(thumb, opcode) || block.instrs.len() >= MAX_BLOCK_LEN;
+        self.misses += 1;
+        if (addr >> PAGE_SHIFT) != (block.key.0 >> PAGE_SHIFT) {
+            add_to_page(&mut self.pages, addr, slot);
+        }
+        op
+    }
+
+    // Slot of the block at addr that execution continues in after the block in slot from.
+    fn next_block(&mut self, from: usize, addr: u32, thumb: bool) -> usize {
+        if let Some(next) = self.blocks[from].as_ref().and_then(|b| b.next) {
+            if self.blocks[next]
+                .as_ref()
+                .is_some_and(|b| b.key == (addr, thumb))
+            {
+                return next;
+            }
+        }
+        let slot = self.block_at(addr, thumb);
+        if let Some(block) = self.blocks[from].as_mut() {
+            block.next = Some(slot);
+        }
+        slot
+    }
+
+    // Slot of the block starting at addr, a new empty one if there is none.
+    fn block_at(&mut self, addr: u32, thumb: bool) -> usize {
+        let key = (addr, thumb);
+        if let Some(&slot) = self.starts.get(&key) {
+            return slot;
+        }
+        let block = Block {
+            key,
+            instrs: vec![],
+            complete: false,
+            next: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.blocks[slot] = Some(block);
+                slot
+            }
+            None => {
+                self.blocks.push(Some(block));
+                self.blocks.len() - 1
+            }
+        };
+        self.starts.insert(key, slot);
+        add_to_page(&mut self.pages, addr, slot);
+        slot
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn starts_block(&self, addr: u32, thumb: bool) -> bool {
+        self.starts.contains_key(&(addr, thumb))
+    }
+
+    // False while execution walks through a block, true where it enters one.
+    #[cfg(feature = "jit")]
+    pub(crate) fn enters_block(&self, addr: u32, thumb: bool) -> bool {
+        match self.cursor {
+            Some(c) if c.addr == addr && c.thumb == thumb => {
+                let block = self.blocks[c.slot].as_ref().unwrap();
+                c.index == block.instrs.len() && block.complete
+            }
+            _ => true,
+        }
+    }
+
+    // Moves the cursor past the first count instructions of the block at addr, which ran without
+    // being decoded here.
+    #[cfg(feature = "jit")]
+    pub(crate) fn skip(&mut self, addr: u32, thumb: bool, count: usize) {
+        let size = if thumb { 2 } else { 4 };
+        self.cursor = self.starts.get(&(addr, thumb)).and_then(|&slot| {
+            let block = self.blocks[slot].as_ref()?;
+            if count > block.instrs.len() {
+                return None;
+            }
+            Some(Cursor {
+                slot,
+                index: count,
+                addr: addr.wrapping_add(count as u32 * size),
+                thumb,
+            })
+        });
+    }
+
+    // Opcodes of the block starting at addr, in execution order.
+    #[cfg(feature = "jit")]
+    pub(crate) fn block_opcodes(&self, addr: u32, thumb: bool) -> Option<Vec<u32>> {
+        let slot = *self.starts.get(&(addr, thumb))?;
+        let block = self.blocks[slot].as_ref()?;
+        Some(block.instrs.iter().map(|d| d.opcode).collect())
+    }
+
+    // Drops every block decoded from memory in addr..addr + len.
+    pub fn invalidate(&mut self, addr: u32, len: u32) {
+        if self.pages.is_empty() || len == 0 {
+            return;
+        }
+        let first = addr >> PAGE_SHIFT;
+        let last = addr.wrapping_add(len - 1) >> PAGE_SHIFT;
+        let mut page = first;
+        loop {
+            if let Some(slots) = self.pages.remove(&page) {
+                for slot in slots {
+                    // A block on two pages is listed on both, it may be gone already.
+                    if let Some(block) = self.blocks[slot].take() {
+                        self.starts.remove(&block.key);
+                        self.free.push(slot);
+                        if self.cursor.is_some_and(|c| c.slot == slot) {
+                            self.cursor = None;
+                        }
+                    }
+                }
+            }
+            if page == last {
+                break;
+            }
+            page = page.wrapping_add(1);
+        }
+    }
+}
+
+fn add_to_page(pages: &mut AddrMap<u32, Vec<usize>>, addr: u32, slot: usize) {
+    let slots = pages.entry(addr >> PAGE_SHIFT).or_default();
+    if !slots.contains(&slot) {
+        slots.push(slot);
+    }
+}
+
+// Instructions after which execution may continue somewhere else.
+fn ends_block(thumb: bool, opcode: u32) -> bool {
+    if !thumb {
+        return match armv4_type(opcode) {
+            ArmV4Type::DataProcessingPsr | ArmV4Type::LoadStore => get_bits(opcode, 12, 15) == 15,
+            ArmV4Type::BlockDataTransfer => get_bits(opcode, 15, 15) == 1,
+            ArmV4Type::Multiply
+            | ArmV4Type::MultiplyLong
+            | ArmV4Type::SingleDataSwap
+            | ArmV4Type::HalfwordDataTransferReg
+            | ArmV4Type::HalfwordDataTransferImm => false,
+            _ => true,
+        };
+    }
+    match thumb_type(opcode as u16) {
+        ThumbType::HiRegisterOperationsBx => {
+            get_bits(opcode, 8, 9) == 3
+                || get_bits(opcode, 7, 7) == 1 && get_bits(opcode, 0, 2) == 7
+        }
+        ThumbType::PushPopRegisters => get_bits(opcode, 8, 8) == 1 && get_bits(opcode, 11, 11) == 1,
+        ThumbType::ConditionalBranch
+        | ThumbType::SoftwareInterrupt
+        | ThumbType::UnconditionalBranch
+        | ThumbType::LongBranchWithLink
+        | ThumbType::Undefined => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::TestBus;
+    use crate::cpu::ARMCpu;
+    use std::time::{Duration, Instant};
+
+    // Runs the same program with and without the cache, the results must be identical.
+    fn run_both(bus: &TestBus, steps: usize) -> (ARMCpu, ARMCpu, TestBus) {
+        let mut plain = ARMCpu::new();
+        let mut plain_bus = TestBus {
+            mem: bus.mem.clone(),
+        };
+        let mut cached = ARMCpu::new();
+        let mut cached_bus = TestBus {
+            mem: bus.mem.clone(),
+        };
+        cached.enable_block_cache(true);
+        for n in 0..steps {
+            let a = plain.step(&mut plain_bus);
+            let b = cached.step(&mut cached_bus);
+            assert_eq!(a, b, "cycles differ at step {}", n);
+            for r in 0..16 {
+                assert_eq!(plain.reg(r), cached.reg(r), "r{} differs at step {}", r, n);
+            }
+            assert_eq!(plain.cpsr(), cached.cpsr());
+        }
+        assert_eq!(plain_bus.mem, cached_bus.mem);
+        (plain, cached, cached_bus)
+    }
+
+    #[test]
+    fn test_loop_is_cached() {
+        let bus = TestBus::with_code(&[
+            0xe3a0000a, // mov r0, #10
+            0xe3a01000, // mov r1, #0
+            0xe2811003, // loop: add r1, r1, #3
+            0xe2500001, // subs r0, r0, #1
+            0x1afffffc, // bne loop
+            0xeafffffe, // b .
+        ]);
+        let (_, cached, _) = run_both(&bus, 40);
+        assert_eq!(cached.reg(1), 30);
+        let (hits, misses) = cached.block_cache().unwrap().stats();
+        assert!(hits > misses);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let bus = TestBus::with_code(&[
+            0xe3a00000, // mov r0, #0
+            0xe59f2014, // ldr r2, [pc, #20] @ 0x20
+            0xe2800001, // patch: add r0, r0, #1
+            0xe3500003, // cmp r0, #3
+            0x0afffffe, // beq .
+            0xe50f2014, // str r2, [pc, #-20] @ 0x08, patches the add to add #2
+            0xeafffffa, // b patch
+            0xe1a00000, // nop
+            0xe2800002, // add r0, r0, #2
+        ]);
+        let (_, cached, _) = run_both(&bus, 20);
+        assert_eq!(cached.reg(0), 3);
+        assert_eq!(cached.reg(15), 0x18);
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_cached_interpreter() {
+        let bus = TestBus::with_code(&[
+            0xe3a00000, // loop: mov r0, #0
+            0xe3a01000, // mov r1, #0
+            0xe2811003, // inner: add r1, r1, #3
+            0xe0812000, // add r2, r1, r0
+            0xe1a03182, // mov r3, r2, lsl #3
+            0xe2800001, // add r0, r0, #1
+            0xe3500040, // cmp r0, #64
+            0x1afffff9, // bne inner
+            0xeafffff6, // b loop
+        ]);
+        let steps = 2_000_000;
+        let mut results = vec![];
+        let mut best = [Duration::MAX; 2];
+        // The modes take turns and the best run of each counts, so a busy machine does not decide
+        // the comparison.
+        for _ in 0..3 {
+            for (n, &enabled) in [false, true].iter().enumerate() {
+                let mut cpu = ARMCpu::new();
+                let mut bus = TestBus {
+                    mem: bus.mem.clone(),
+                };
+                cpu.enable_block_cache(enabled);
+                let start = Instant::now();
+                for _ in 0..steps {
+                    cpu.step(&mut bus);
+                }
+                best[n] = best[n].min(start.elapsed());
+                results.push((0..16).map(|r| cpu.reg(r)).collect::<Vec<_>>());
+            }
+        }
+        println!("block cache off: {} steps in {:?}", steps, best[0]);
+        println!("block cache on: {} steps in {:?}", steps, best[1]);
+        assert!(results.windows(2).all(|w| w[0] == w[1]));
+        assert!(
+            best[1] < best[0],
+            "cached run took {:?}, plain {:?}",
+            best[1],
+            best[0]
+        );
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = BlockCache::new();
+        cache.decode(0x100, false, 0xe3a00001);
+        cache.decode(0x104, false, 0xe1a00000);
+        cache.decode(0x200, true, 0x2001);
+        assert_eq!(cache.len(), 2);
+
+        cache.invalidate(0x300, 4);
+        assert_eq!(cache.len(), 2);
+        cache.invalidate(0x104, 2);
+        assert_eq!(cache.len(), 1);
+        cache.invalidate(0x0, 0x1000);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ * Coprocessor interface. See ARM7TDMI Reference 4.14 - 4.17
+ *
+ * The ARM7TDMI can have up to 16 coprocessors (CP0-CP15) attached. The CPU hands the CDP, LDC/STC
+ * and MCR/MRC instructions to the coprocessor selected by bits 11-8 of the instruction.
+ *
+ * If no coprocessor answers (on hardware, CPA stays high) the instruction takes the undefined
+ * instruction trap. The GBA has no coprocessors at all, so on the GBA every coprocessor
+ * instruction ends up at the undefined vector, which some anti-piracy checks rely on.
+ *
+ * Every method has a default that rejects the instruction, so a coprocessor only implements the
+ * operations it supports. Rejected instructions also take the undefined trap.
+ */
+pub trait Coprocessor {
+    // CDP: internal coprocessor operation.
+    fn data_op(
+        &mut self,
+        _opcode1: u32,
+        _crd: usize,
+        _crn: usize,
+        _crm: usize,
+        _opcode2: u32,
+    ) -> bool {
+        false
+    }
+
+    // MCR: ARM register to coprocessor register.
+    fn write_reg(
+        &mut self,
+        _opcode1: u32,
+        _crn: usize,
+        _crm: usize,
+        _opcode2: u32,
+        _value: u32,
+    ) -> bool {
+        false
+    }
+
+    // MRC: coprocessor register to ARM register.
+    fn read_reg(&mut self, _opcode1: u32, _crn: usize, _crm: usize, _opcode2: u32) -> Option<u32> {
+        None
+    }
+
+    // LDC/STC: the coprocessor decides how many words it transfers to or from crd. The N bit
+    // (long) is passed along since its meaning is up to the coprocessor.
+    fn transfer_len(&mut self, _crd: usize, _long: bool, _load: bool) -> Option<usize> {
+        None
+    }
+
+    // LDC: one word loaded from memory, index counts from 0 up to transfer_len.
+    fn load_word(&mut self, _crd: usize, _index: usize, _value: u32) {}
+
+    // STC: one word to be stored to memory.
+    fn store_word(&mut self, _crd: usize, _index: usize) -> u32 {
+        0
+    }
+}
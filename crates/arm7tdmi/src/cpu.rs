@@ -0,0 +1,688 @@
+use super::bus::{Bus, MemoryAccess, MemoryWidth};
+use super::cache::BlockCache;
+use super::coprocessor::Coprocessor;
+#[cfg(feature = "jit")]
+use super::jit::Jit;
+use super::psr::{CpuMode, Psr};
+use super::trace::format_trace_line;
+use std::io::Write;
+use util::elf::Symbols;
+
+/*
+ * Exceptions. See ARM7TDMI Reference 2.9
+ *
+ * An exception saves the CPSR to the SPSR of the exception mode, saves the return address into
+ * that mode's r14, switches to ARM state with IRQs disabled and jumps to the exception vector.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    Reset,
+    Undefined,
+    SoftwareInterrupt,
+    PrefetchAbort,
+    DataAbort,
+    Irq,
+    Fiq,
+}
+
+impl Exception {
+    pub fn vector(self) -> u32 {
+        match self {
+            Exception::Reset => 0x00,
+            Exception::Undefined => 0x04,
+            Exception::SoftwareInterrupt => 0x08,
+            Exception::PrefetchAbort => 0x0C,
+            Exception::DataAbort => 0x10,
+            Exception::Irq => 0x18,
+            Exception::Fiq => 0x1C,
+        }
+    }
+
+    pub fn mode(self) -> CpuMode {
+        match self {
+            Exception::Reset | Exception::SoftwareInterrupt => CpuMode::Supervisor,
+            Exception::Undefined => CpuMode::Undefined,
+            Exception::PrefetchAbort | Exception::DataAbort => CpuMode::Abort,
+            Exception::Irq => CpuMode::Irq,
+            Exception::Fiq => CpuMode::Fiq,
+        }
+    }
+}
+
+/*
+ * ARM7TDMI CPU state.
+ *
+ * regs holds the registers visible in the current mode. The registers of the other modes are kept
+ * in the banked arrays and swapped in by switch_mode.
+ *
+ * The 3 stage pipeline is modelled by keeping the two opcodes that have been fetched but not yet
+ * executed. While an instruction executes, r15 holds the address of the opcode being fetched,
+ * which is the executing instruction + 8 in ARM state and + 4 in THUMB state.
+ */
+pub struct ARMCpu {
+    pub(crate) regs: [u32; 16],
+    pub(crate) cpsr: Psr,
+    // r13 and r14 of each mode bank, see CpuMode::bank.
+    banked_r13_r14: [[u32; 2]; 6],
+    // r8-r12 of FIQ mode and of every other mode.
+    banked_fiq_r8_r12: [u32; 5],
+    banked_usr_r8_r12: [u32; 5],
+    spsr: [Psr; 6],
+    pub(crate) pipeline: [u32; 2],
+    // Set when the executing instruction wrote r15 and the pipeline must be refilled.
+    pub(crate) flushed: bool,
+    pub(crate) next_fetch: MemoryAccess,
+    // Cycles taken by the instruction being stepped.
+    pub(crate) cycles: u32,
+    irq_line: bool,
+    coprocessors: [Option<Box<dyn Coprocessor>>; 16],
+    // Receives a trace line for every executed instruction while set.
+    trace: Option<Box<dyn Write>>,
+    // Named in the trace disassembly.
+    symbols: Symbols,
+    // Decoded blocks while the cached interpreter is enabled.
+    pub(crate) cache: Option<BlockCache>,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Option<Jit>,
+}
+
+impl Default for ARMCpu {
+    fn default() -> Self {
+        ARMCpu::new()
+    }
+}
+
+impl ARMCpu {
+    // Register aliases
+    pub const SP: usize = 13;
+    pub const LR: usize = 14;
+    pub const PC: usize = 15;
+
+    // Creates a CPU in the state it comes out of reset: Supervisor mode, ARM state, IRQ and FIQ
+    // disabled, about to execute from address 0. The pipeline is filled by the first step.
+    pub fn new() -> Self {
+        let mut cpsr = Psr(CpuMode::Supervisor as u32);
+        cpsr.set_irq_disabled(true);
+        cpsr.set_fiq_disabled(true);
+        ARMCpu {
+            regs: [0; 16],
+            cpsr,
+            banked_r13_r14: [[0; 2]; 6],
+            banked_fiq_r8_r12: [0; 5],
+            banked_usr_r8_r12: [0; 5],
+            spsr: [Psr(0); 6],
+            pipeline: [0; 2],
+            flushed: true,
+            next_fetch: MemoryAccess::NonSequential,
+            cycles: 0,
+            irq_line: false,
+            coprocessors: Default::default(),
+            trace: None,
+            symbols: Symbols::default(),
+            cache: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+    }
+
+    pub fn reg(&self, r: usize) -> u32 {
+        self.regs[r]
+    }
+
+    // Writing r15 branches, the pipeline is refilled from the new address by the next step.
+    pub fn set_reg(&mut self, r: usize, value: u32) {
+        self.regs[r] = value;
+        if r == ARMCpu::PC {
+            self.flushed = true;
+        }
+    }
+
+    pub fn cpsr(&self) -> Psr {
+        self.cpsr
+    }
+
+    // Replaces the CPSR, banking registers if the mode changes.
+    pub fn set_cpsr(&mut self, psr: Psr) {
+        self.switch_mode(psr.mode());
+        self.cpsr = psr;
+    }
+
+    // User and System mode have no SPSR, reading it there returns the CPSR.
+    pub fn spsr(&self) -> Psr {
+        let mode = self.cpsr.mode();
+        if mode.has_spsr() {
+            self.spsr[mode.bank()]
+        } else {
+            self.cpsr
+        }
+    }
+
+    pub fn set_spsr(&mut self, psr: Psr) {
+        let mode = self.cpsr.mode();
+        if mode.has_spsr() {
+            self.spsr[mode.bank()] = psr;
+        }
+    }
+
+    pub fn is_thumb(&self) -> bool {
+        self.cpsr.thumb()
+    }
+
+    // Opcodes fetched but not yet executed, the first one executes next.
+    pub fn pipeline(&self) -> [u32; 2] {
+        self.pipeline
+    }
+
+    // Address of the instruction the next step executes.
+    pub fn next_pc(&self) -> u32 {
+        if self.flushed {
+            self.regs[ARMCpu::PC]
+        } else {
+            self.regs[ARMCpu::PC].wrapping_sub(2 * self.instr_size())
+        }
+    }
+
+    // Level of the IRQ input, an IRQ is taken before the next instruction while it is high and
+    // the I bit is clear.
+    pub fn set_irq(&mut self, level: bool) {
+        self.irq_line = level;
+    }
+
+    pub fn attach_coprocessor(&mut self, cp_num: usize, coprocessor: Box<dyn Coprocessor>) {
+        self.coprocessors[cp_num] = Some(coprocessor);
+    }
+
+    pub fn detach_coprocessor(&mut self, cp_num: usize) -> Option<Box<dyn Coprocessor>> {
+        self.coprocessors[cp_num].take()
+    }
+
+    // Starts writing a trace line for every executed instruction, see trace::format_trace_line.
+    pub fn start_trace(&mut self, out: Box<dyn Write>) {
+        self.trace = Some(out);
+    }
+
+    // Stops tracing and hands back the trace output.
+    pub fn stop_trace(&mut self) -> Option<Box<dyn Write>> {
+        let mut out = self.trace.take();
+        if let Some(out) = out.as_mut() {
+            let _ = out.flush();
+        }
+        out
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    // Symbols of the running program, e.g. from an ELF file, for the trace disassembly.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Switches between the plain and the cached interpreter, see cache::BlockCache. Both give
+    // the same results.
+    pub fn enable_block_cache(&mut self, enabled: bool) {
+        if enabled != self.cache.is_some() {
+            self.cache = if enabled {
+                Some(BlockCache::new())
+            } else {
+                None
+            };
+        }
+    }
+
+    pub fn block_cache(&self) -> Option<&BlockCache> {
+        self.cache.as_ref()
+    }
+
+    // Tells the block cache that memory was written by something other than the CPU, e.g. DMA.
+    pub fn invalidate_code(&mut self, addr: u32, len: u32) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate(addr, len);
+        }
+        #[cfg(feature = "jit")]
+        if let Some(jit) = self.jit.as_mut() {
+            jit.invalidate(addr, len);
+        }
+    }
+
+    // Switches the recompiler on or off, see jit::Jit. The recompiler works on the blocks of the
+    // block cache, so enabling it also enables the cache.
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self, enabled: bool) {
+        if enabled {
+            self.enable_block_cache(true);
+            if self.jit.is_none() {
+                self.jit = Some(Jit::new());
+            }
+        } else {
+            self.jit = None;
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn jit(&self) -> Option<&Jit> {
+        self.jit.as_ref()
+    }
+
+    pub(crate) fn coprocessor(&mut self, cp_num: usize) -> Option<&mut Box<dyn Coprocessor>> {
+        self.coprocessors[cp_num].as_mut()
+    }
+
+    pub(crate) fn instr_size(&self) -> u32 {
+        if self.cpsr.thumb() {
+            2
+        } else {
+            4
+        }
+    }
+
+    /*
+     * Executes one instruction, or takes a pending IRQ, and returns the number of cycles it took.
+     */
+    pub fn step<B: Bus + ?Sized>(&mut self, bus: &mut B) -> u32 {
+        self.cycles = 0;
+        if self.flushed {
+            self.refill(bus);
+        }
+
+        if self.irq_line && !self.cpsr.irq_disabled() {
+            // The return address is the next instruction + 4 in both states, so the handler
+            // returns with SUBS pc, lr, #4.
+            let lr = if self.cpsr.thumb() {
+                self.regs[ARMCpu::PC]
+            } else {
+                self.regs[ARMCpu::PC].wrapping_sub(4)
+            };
+            self.enter_exception(Exception::Irq, lr);
+            self.refill(bus);
+            return self.cycles;
+        }
+
+        #[cfg(feature = "jit")]
+        if self.jit.is_some() && self.trace.is_none() && self.run_jit(bus) {
+            return self.cycles;
+        }
+
+        let opcode = self.pipeline[0];
+        if self.trace.is_some() {
+            self.write_trace(opcode);
+        }
+        self.pipeline[0] = self.pipeline[1];
+        let pc = self.regs[ARMCpu::PC];
+        let thumb = self.cpsr.thumb();
+        if thumb {
+            let addr = pc.wrapping_sub(4);
+            let kind = self
+                .cache
+                .as_mut()
+                .map(|cache| cache.decode_thumb(addr, opcode as u16));
+            self.pipeline[1] = self.fetch_16(bus, pc) as u32;
+            match kind {
+                Some(kind) => self.execute_thumb_decoded(bus, opcode as u16, kind),
+                None => self.execute_thumb(bus, opcode as u16),
+            }
+        } else if let Some(cache) = self.cache.as_mut() {
+            let instr = cache.decode_arm(pc.wrapping_sub(8), opcode);
+            self.pipeline[1] = self.fetch_32(bus, pc);
+            self.execute_arm_decoded(bus, opcode, &instr);
+        } else {
+            self.pipeline[1] = self.fetch_32(bus, pc);
+            self.execute_arm(bus, opcode);
+        }
+
+        if self.flushed {
+            self.refill(bus);
+        } else {
+            self.regs[ARMCpu::PC] = self.regs[ARMCpu::PC].wrapping_add(self.instr_size());
+        }
+        self.cycles
+    }
+
+    fn write_trace(&mut self, opcode: u32) {
+        let line = format_trace_line(&self.regs, self.cpsr, self.next_pc(), opcode, &self.symbols);
+        if let Some(out) = self.trace.as_mut() {
+            // A failing trace output must not stop emulation.
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+
+    // Refetches both pipeline stages from r15 after a branch.
+    pub(crate) fn refill<B: Bus + ?Sized>(&mut self, bus: &mut B) {
+        self.next_fetch = MemoryAccess::NonSequential;
+        if self.cpsr.thumb() {
+            let pc = self.regs[ARMCpu::PC] & !1;
+            self.pipeline[0] = self.fetch_16(bus, pc) as u32;
+            self.pipeline[1] = self.fetch_16(bus, pc.wrapping_add(2)) as u32;
+            self.regs[ARMCpu::PC] = pc.wrapping_add(4);
+        } else {
+            let pc = self.regs[ARMCpu::PC] & !3;
+            self.pipeline[0] = self.fetch_32(bus, pc);
+            self.pipeline[1] = self.fetch_32(bus, pc.wrapping_add(4));
+            self.regs[ARMCpu::PC] = pc.wrapping_add(8);
+        }
+        self.flushed = false;
+    }
+
+    /*
+     * Swaps in the banked registers of a new mode.
+     */
+    pub(crate) fn switch_mode(&mut self, mode: CpuMode) {
+        let old = self.cpsr.mode();
+        if old.bank() == mode.bank() {
+            self.cpsr.set_mode(mode);
+            return;
+        }
+        self.banked_r13_r14[old.bank()].copy_from_slice(&self.regs[13..15]);
+        if old == CpuMode::Fiq {
+            self.banked_fiq_r8_r12.copy_from_slice(&self.regs[8..13]);
+            self.regs[8..13].copy_from_slice(&self.banked_usr_r8_r12);
+        }
+        if mode == CpuMode::Fiq {
+            self.banked_usr_r8_r12.copy_from_slice(&self.regs[8..13]);
+            self.regs[8..13].copy_from_slice(&self.banked_fiq_r8_r12);
+        }
+        self.regs[13..15].copy_from_slice(&self.banked_r13_r14[mode.bank()]);
+        self.cpsr.set_mode(mode);
+    }
+
+    // Registers of User mode, used by LDM/STM with the S bit set.
+    pub(crate) fn user_reg(&self, r: usize) -> u32 {
+        let mode = self.cpsr.mode();
+        match r {
+            8..=12 if mode == CpuMode::Fiq => self.banked_usr_r8_r12[r - 8],
+            13 | 14 if mode.bank() != 0 => self.banked_r13_r14[0][r - 13],
+            _ => self.regs[r],
+        }
+    }
+
+    pub(crate) fn set_user_reg(&mut self, r: usize, value: u32) {
+        let mode = self.cpsr.mode();
+        match r {
+            8..=12 if mode == CpuMode::Fiq => self.banked_usr_r8_r12[r - 8] = value,
+            13 | 14 if mode.bank() != 0 => self.banked_r13_r14[0][r - 13] = value,
+            _ => self.regs[r] = value,
+        }
+    }
+
+    pub fn enter_exception(&mut self, exception: Exception, return_addr: u32) {
+        let old_cpsr = self.cpsr;
+        let mode = exception.mode();
+        self.switch_mode(mode);
+        self.spsr[mode.bank()] = old_cpsr;
+        self.regs[ARMCpu::LR] = return_addr;
+        self.cpsr.set_thumb(false);
+        self.cpsr.set_irq_disabled(true);
+        if exception == Exception::Reset || exception == Exception::Fiq {
+            self.cpsr.set_fiq_disabled(true);
+        }
+        self.regs[ARMCpu::PC] = exception.vector();
+        self.flushed = true;
+    }
+
+    // Takes the undefined instruction trap for the executing instruction, the return address is
+    // the instruction following it.
+    pub(crate) fn undefined_instruction(&mut self) {
+        let return_addr = self.regs[ARMCpu::PC].wrapping_sub(self.instr_size());
+        self.enter_exception(Exception::Undefined, return_addr);
+    }
+
+    pub(crate) fn software_interrupt(&mut self) {
+        let return_addr = self.regs[ARMCpu::PC].wrapping_sub(self.instr_size());
+        self.enter_exception(Exception::SoftwareInterrupt, return_addr);
+    }
+
+    /*
+     * Bus accesses, counting cycles. Data accesses make the next opcode fetch non-sequential.
+     */
+    fn bus_cycle<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        width: MemoryWidth,
+        access: MemoryAccess,
+    ) {
+        self.cycles += 1 + bus.wait_states(addr, width, access);
+    }
+
+    pub(crate) fn fetch_cycle<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        width: MemoryWidth,
+        access: MemoryAccess,
+    ) {
+        self.cycles += 1 + bus.fetch_wait_states(addr, width, access);
+    }
+
+    pub(crate) fn fetch_32<B: Bus + ?Sized>(&mut self, bus: &mut B, addr: u32) -> u32 {
+        let access = self.next_fetch;
+        self.fetch_cycle(bus, addr, MemoryWidth::Word, access);
+        self.next_fetch = MemoryAccess::Sequential;
+        bus.fetch_32(addr, access)
+    }
+
+    pub(crate) fn fetch_16<B: Bus + ?Sized>(&mut self, bus: &mut B, addr: u32) -> u16 {
+        let access = self.next_fetch;
+        self.fetch_cycle(bus, addr, MemoryWidth::Halfword, access);
+        self.next_fetch = MemoryAccess::Sequential;
+        bus.fetch_16(addr, access)
+    }
+
+    pub(crate) fn read_32<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        access: MemoryAccess,
+    ) -> u32 {
+        self.bus_cycle(bus, addr, MemoryWidth::Word, access);
+        self.next_fetch = MemoryAccess::NonSequential;
+        bus.read_32(addr, access)
+    }
+
+    pub(crate) fn read_16<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        access: MemoryAccess,
+    ) -> u16 {
+        self.bus_cycle(bus, addr, MemoryWidth::Halfword, access);
+        self.next_fetch = MemoryAccess::NonSequential;
+        bus.read_16(addr, access)
+    }
+
+    pub(crate) fn read_8<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        access: MemoryAccess,
+    ) -> u8 {
+        self.bus_cycle(bus, addr, MemoryWidth::Byte, access);
+        self.next_fetch = MemoryAccess::NonSequential;
+        bus.read_8(addr, access)
+    }
+
+    pub(crate) fn write_32<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        value: u32,
+        access: MemoryAccess,
+    ) {
+        self.bus_cycle(bus, addr, MemoryWidth::Word, access);
+        self.next_fetch = MemoryAccess::NonSequential;
+        self.invalidate_code(addr & !3, 4);
+        bus.write_32(addr, value, access)
+    }
+
+    pub(crate) fn write_16<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        value: u16,
+        access: MemoryAccess,
+    ) {
+        self.bus_cycle(bus, addr, MemoryWidth::Halfword, access);
+        self.next_fetch = MemoryAccess::NonSequential;
+        self.invalidate_code(addr & !1, 2);
+        bus.write_16(addr, value, access)
+    }
+
+    pub(crate) fn write_8<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        addr: u32,
+        value: u8,
+        access: MemoryAccess,
+    ) {
+        self.bus_cycle(bus, addr, MemoryWidth::Byte, access);
+        self.next_fetch = MemoryAccess::NonSequential;
+        self.invalidate_code(addr, 1);
+        bus.write_8(addr, value, access)
+    }
+
+    // Internal cycles, where the CPU does not use the bus.
+    pub(crate) fn idle<B: Bus + ?Sized>(&mut self, bus: &mut B, cycles: u32) {
+        self.cycles += cycles;
+        bus.idle(cycles);
+    }
+
+    // LDR of a misaligned address reads the aligned word and rotates it so the addressed byte is
+    // in the bottom byte.
+    pub(crate) fn read_32_rotated<B: Bus + ?Sized>(&mut self, bus: &mut B, addr: u32) -> u32 {
+        let value = self.read_32(bus, addr & !3, MemoryAccess::NonSequential);
+        value.rotate_right((addr & 3) * 8)
+    }
+
+    // LDRH of an odd address rotates the halfword by 8.
+    pub(crate) fn read_16_rotated<B: Bus + ?Sized>(&mut self, bus: &mut B, addr: u32) -> u32 {
+        let value = self.read_16(bus, addr & !1, MemoryAccess::NonSequential) as u32;
+        value.rotate_right((addr & 1) * 8)
+    }
+
+    // LDRSH of an odd address loads the sign extended byte instead.
+    pub(crate) fn read_16_signed<B: Bus + ?Sized>(&mut self, bus: &mut B, addr: u32) -> u32 {
+        if addr & 1 == 1 {
+            self.read_8(bus, addr, MemoryAccess::NonSequential) as i8 as i32 as u32
+        } else {
+            self.read_16(bus, addr, MemoryAccess::NonSequential) as i16 as i32 as u32
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Flat little endian memory for testing the CPU without a system around it.
+    pub(crate) struct TestBus {
+        pub mem: Vec<u8>,
+    }
+
+    impl TestBus {
+        pub fn new(size: usize) -> Self {
+            TestBus { mem: vec![0; size] }
+        }
+
+        pub fn with_code(code: &[u32]) -> Self {
+            let mut bus = TestBus::new(0x1000);
+            for (n, w) in code.iter().enumerate() {
+                bus.mem[n * 4..n * 4 + 4].copy_from_slice(&w.to_le_bytes());
+            }
+            bus
+        }
+
+        pub fn with_thumb_code(code: &[u16]) -> Self {
+            let mut bus = TestBus::new(0x1000);
+            for (n, h) in code.iter().enumerate() {
+                bus.mem[n * 2..n * 2 + 2].copy_from_slice(&h.to_le_bytes());
+            }
+            bus
+        }
+    }
+
+    impl Bus for TestBus {
+        fn read_8(&mut self, addr: u32, _access: MemoryAccess) -> u8 {
+            self.mem[addr as usize % self.mem.len()]
+        }
+
+        fn read_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+            let addr = addr & !1;
+            u16::from_le_bytes([self.read_8(addr, access), self.read_8(addr + 1, access)])
+        }
+
+        fn read_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+            let addr = addr & !3;
+            self.read_16(addr, access) as u32 | (self.read_16(addr + 2, access) as u32) << 16
+        }
+
+        fn write_8(&mut self, addr: u32, value: u8, _access: MemoryAccess) {
+            let len = self.mem.len();
+            self.mem[addr as usize % len] = value;
+        }
+
+        fn write_16(&mut self, addr: u32, value: u16, access: MemoryAccess) {
+            let addr = addr & !1;
+            self.write_8(addr, value as u8, access);
+            self.write_8(addr + 1, (value >> 8) as u8, access);
+        }
+
+        fn write_32(&mut self, addr: u32, value: u32, access: MemoryAccess) {
+            let addr = addr & !3;
+            self.write_16(addr, value as u16, access);
+            self.write_16(addr + 2, (value >> 16) as u16, access);
+        }
+    }
+
+    #[test]
+    fn test_reset_state() {
+        let cpu = ARMCpu::new();
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Supervisor);
+        assert!(cpu.cpsr().irq_disabled());
+        assert!(cpu.cpsr().fiq_disabled());
+        assert!(!cpu.is_thumb());
+        assert_eq!(cpu.next_pc(), 0);
+    }
+
+    #[test]
+    fn test_mode_banking() {
+        let mut cpu = ARMCpu::new();
+        cpu.set_cpsr(Psr(CpuMode::System as u32));
+        cpu.regs[8] = 8;
+        cpu.regs[13] = 0x0300_7F00;
+        cpu.set_cpsr(Psr(CpuMode::Fiq as u32));
+        assert_eq!(cpu.reg(8), 0);
+        assert_eq!(cpu.reg(13), 0);
+        cpu.regs[8] = 0x88;
+        cpu.regs[13] = 0x0300_7FF0;
+        cpu.set_cpsr(Psr(CpuMode::User as u32));
+        assert_eq!(cpu.reg(8), 8);
+        assert_eq!(cpu.reg(13), 0x0300_7F00);
+        cpu.set_cpsr(Psr(CpuMode::Fiq as u32));
+        assert_eq!(cpu.reg(8), 0x88);
+        assert_eq!(cpu.user_reg(13), 0x0300_7F00);
+    }
+
+    #[test]
+    fn test_irq_entry() {
+        // mov r0, #1; mov r0, #2
+        let mut bus = TestBus::with_code(&[0xe3a00001, 0xe3a00002]);
+        let mut cpu = ARMCpu::new();
+        cpu.step(&mut bus);
+        assert_eq!(cpu.reg(0), 1);
+        let mut cpsr = cpu.cpsr();
+        cpsr.set_irq_disabled(false);
+        cpu.set_cpsr(cpsr);
+        cpu.set_irq(true);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Irq);
+        assert_eq!(cpu.next_pc(), 0x18);
+        // subs pc, lr, #4 must return to the second mov
+        assert_eq!(cpu.reg(ARMCpu::LR) - 4, 4);
+        assert!(!cpu.spsr().irq_disabled());
+    }
+}
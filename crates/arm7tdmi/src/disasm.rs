@@ -0,0 +1,634 @@
+use super::arm::*;
+use super::thumb::{thumb_type, ThumbType};
+use super::ConditionField;
+use util::elf::Symbols;
+use util::get_bits;
+
+/*
+ * Disassembler for ARM and THUMB opcodes.
+ *
+ * The output follows the GNU assembler syntax used by objdump and mGBA: lowercase mnemonics,
+ * immediates in hex, branch targets as absolute addresses. The address of the instruction is
+ * needed to resolve pc relative branch targets. Given the symbols of the program, branch targets
+ * are followed by the symbol they are in like objdump does: "bl 0x08000100 <main>".
+ */
+const REG_NAMES: [&str; 16] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr",
+    "pc",
+];
+
+const SHIFT_NAMES: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+
+fn reg(r: u8) -> &'static str {
+    REG_NAMES[r as usize & 0xF]
+}
+
+fn imm(v: u32) -> String {
+    if v < 10 {
+        format!("#{}", v)
+    } else {
+        format!("#0x{:x}", v)
+    }
+}
+
+fn signed_imm(up: bool, v: u32) -> String {
+    if up {
+        imm(v)
+    } else {
+        format!("#-{}", &imm(v)[1..])
+    }
+}
+
+// Register lists are printed with ranges, e.g. {r0-r3, r5, lr}
+fn reg_list(rlist: u16) -> String {
+    let mut parts: Vec<String> = vec![];
+    let mut r = 0;
+    while r < 16 {
+        if rlist & (1 << r) == 0 {
+            r += 1;
+            continue;
+        }
+        let start = r;
+        while r < 16 && rlist & (1 << r) != 0 {
+            r += 1;
+        }
+        let end = r - 1;
+        if end - start >= 2 {
+            parts.push(format!("{}-{}", reg(start), reg(end)));
+        } else {
+            for n in start..=end {
+                parts.push(reg(n).to_owned());
+            }
+        }
+    }
+    format!("{{{}}}", parts.join(", "))
+}
+
+// Operand of an immediate shift, with the LSR/ASR #32 and RRX encodings of a zero amount.
+fn shift_imm(shift_type: ShiftType, amount: u32) -> String {
+    match (shift_type, amount) {
+        (ShiftType::LogicalLeft, 0) => String::new(),
+        (ShiftType::RotateRight, 0) => ", rrx".to_owned(),
+        (_, 0) => format!(", {} #32", SHIFT_NAMES[shift_type as usize]),
+        (_, n) => format!(", {} #{}", SHIFT_NAMES[shift_type as usize], n),
+    }
+}
+
+fn target(addr: u32, symbols: &Symbols) -> String {
+    match symbols.format(addr) {
+        Some(symbol) => format!("0x{:08x} <{}>", addr, symbol),
+        None => format!("0x{:08x}", addr),
+    }
+}
+
+pub fn disassemble_arm(i: u32, addr: u32) -> String {
+    disassemble_arm_symbols(i, addr, &Symbols::default())
+}
+
+pub fn disassemble_arm_symbols(i: u32, addr: u32, symbols: &Symbols) -> String {
+    let cond = ConditionField::new(get_bits(i, 28, 31) as u8);
+    let c = cond.suffix();
+    match armv4_type(i) {
+        ArmV4Type::DataProcessingPsr if is_psr_transfer(i) => {
+            if get_bits(i, 21, 21) == 1 {
+                disassemble_msr(&MsrInstr::new(i), c)
+            } else {
+                let instr = MrsInstr::new(i);
+                let psr = if instr.spsr { "spsr" } else { "cpsr" };
+                format!("mrs{} {}, {}", c, reg(instr.rd), psr)
+            }
+        }
+        ArmV4Type::DataProcessingPsr => {
+            disassemble_data_processing(&DataProcessingInstr::new(i), c)
+        }
+        ArmV4Type::Multiply => {
+            let instr = MulInstr::new(i);
+            let s = if instr.s { "s" } else { "" };
+            if instr.accumulate {
+                format!(
+                    "mla{}{} {}, {}, {}, {}",
+                    c,
+                    s,
+                    reg(instr.rd),
+                    reg(instr.rm),
+                    reg(instr.rs),
+                    reg(instr.rn)
+                )
+            } else {
+                format!(
+                    "mul{}{} {}, {}, {}",
+                    c,
+                    s,
+                    reg(instr.rd),
+                    reg(instr.rm),
+                    reg(instr.rs)
+                )
+            }
+        }
+        ArmV4Type::MultiplyLong => {
+            let instr = MulLongInstr::new(i);
+            let sign = if instr.unsigned { "u" } else { "s" };
+            let op = if instr.accumulate { "mlal" } else { "mull" };
+            let s = if instr.s { "s" } else { "" };
+            format!(
+                "{}{}{}{} {}, {}, {}, {}",
+                sign,
+                op,
+                c,
+                s,
+                reg(instr.rdlo),
+                reg(instr.rdhi),
+                reg(instr.rm),
+                reg(instr.rs)
+            )
+        }
+        ArmV4Type::BranchAndExchange => format!("bx{} {}", c, reg(BxInstr::new(i).rn)),
+        ArmV4Type::SingleDataSwap => {
+            let instr = SingleDataSwapInstr::new(i);
+            let b = if instr.b { "b" } else { "" };
+            format!(
+                "swp{}{} {}, {}, [{}]",
+                c,
+                b,
+                reg(instr.rd),
+                reg(instr.rm),
+                reg(instr.rn)
+            )
+        }
+        ArmV4Type::HalfwordDataTransferReg => {
+            let instr = HalfWordDataTransferRegInstr::new(i);
+            let sign = if instr.u { "" } else { "-" };
+            let offset = format!("{}{}", sign, reg(instr.rm));
+            let mnemonic = halfword_mnemonic(instr.l, instr.sh);
+            let address = address(instr.rn, &offset, instr.pre, instr.w);
+            format!("{}{} {}, {}", mnemonic, c, reg(instr.rd), address)
+        }
+        ArmV4Type::HalfwordDataTransferImm => {
+            let instr = HalfWordDataTransferImmInstr::new(i);
+            let offset = signed_imm(instr.u, instr.offset as u32);
+            let mnemonic = halfword_mnemonic(instr.l, instr.sh);
+            let address = if instr.offset == 0 && instr.pre {
+                format!("[{}]{}", reg(instr.rn), if instr.w { "!" } else { "" })
+            } else {
+                address(instr.rn, &offset, instr.pre, instr.w)
+            };
+            format!("{}{} {}, {}", mnemonic, c, reg(instr.rd), address)
+        }
+        ArmV4Type::LoadStore => disassemble_load_store(&LoadStoreInstr::new(i), c),
+        ArmV4Type::BlockDataTransfer => {
+            let instr = BlockDataTransferInstr::new(i);
+            let op = if instr.l { "ldm" } else { "stm" };
+            let mode = match (instr.pre, instr.u) {
+                (false, true) => "ia",
+                (true, true) => "ib",
+                (false, false) => "da",
+                (true, false) => "db",
+            };
+            format!(
+                "{}{}{} {}{}, {}{}",
+                op,
+                c,
+                mode,
+                reg(instr.rn),
+                if instr.w { "!" } else { "" },
+                reg_list(instr.rlist),
+                if instr.s { "^" } else { "" }
+            )
+        }
+        ArmV4Type::Branch => {
+            let instr = BranchInstr::new(i);
+            let op = if instr.link { "bl" } else { "b" };
+            let dest = addr.wrapping_add(8).wrapping_add(instr.byte_offset());
+            format!("{}{} {}", op, c, target(dest, symbols))
+        }
+        ArmV4Type::SoftwareInterrupt => {
+            format!("swi{} 0x{:x}", c, SoftwareInterruptInstr::new(i).comment)
+        }
+        ArmV4Type::CoprocDataOp => {
+            let instr = CoprocDataOpInstr::new(i);
+            format!(
+                "cdp{} p{}, {}, c{}, c{}, c{}, {}",
+                c, instr.cp_num, instr.opcode1, instr.crd, instr.crn, instr.crm, instr.opcode2
+            )
+        }
+        ArmV4Type::CoprocRegTransfer => {
+            let instr = CoprocRegTransferInstr::new(i);
+            let op = if instr.l { "mrc" } else { "mcr" };
+            format!(
+                "{}{} p{}, {}, {}, c{}, c{}, {}",
+                op,
+                c,
+                instr.cp_num,
+                instr.opcode1,
+                reg(instr.rd),
+                instr.crn,
+                instr.crm,
+                instr.opcode2
+            )
+        }
+        ArmV4Type::CoprocDataTransfer => {
+            let instr = CoprocDataTransferInstr::new(i);
+            let op = if instr.l { "ldc" } else { "stc" };
+            let n = if instr.n { "l" } else { "" };
+            let offset = signed_imm(instr.u, instr.offset as u32 * 4);
+            format!(
+                "{}{}{} p{}, c{}, {}",
+                op,
+                c,
+                n,
+                instr.cp_num,
+                instr.crd,
+                address(instr.rn, &offset, instr.pre, instr.w)
+            )
+        }
+        _ => "undefined".to_owned(),
+    }
+}
+
+fn halfword_mnemonic(load: bool, sh: HalfwordSignedByteInstrType) -> &'static str {
+    match (load, sh) {
+        (false, _) => "strh",
+        (true, HalfwordSignedByteInstrType::UnsignedHalfword) => "ldrh",
+        (true, HalfwordSignedByteInstrType::SignedByte) => "ldrsb",
+        (true, HalfwordSignedByteInstrType::SignedHalfword) => "ldrsh",
+    }
+}
+
+// [rn, offset]{!} for pre-indexed, [rn], offset for post-indexed
+fn address(rn: u8, offset: &str, pre: bool, w: bool) -> String {
+    if pre {
+        format!("[{}, {}]{}", reg(rn), offset, if w { "!" } else { "" })
+    } else {
+        format!("[{}], {}", reg(rn), offset)
+    }
+}
+
+fn disassemble_data_processing(instr: &DataProcessingInstr, c: &str) -> String {
+    let op2 = match instr.operand2 {
+        DataProcessingOperand2::ImmRot {
+            rotate_count,
+            imm_value,
+        } => imm(imm_value.rotate_right(rotate_count * 2)),
+        DataProcessingOperand2::ShiftRegDirect {
+            shift_count,
+            shift_type,
+            rm,
+        } => format!("{}{}", reg(rm), shift_imm(shift_type, shift_count)),
+        DataProcessingOperand2::ShiftRegIndirect {
+            shift_reg,
+            shift_type,
+            rm,
+        } => format!(
+            "{}, {} {}",
+            reg(rm),
+            SHIFT_NAMES[shift_type as usize],
+            reg(shift_reg as u8)
+        ),
+    };
+    let op = instr.opcode_str().to_lowercase();
+    let s = if instr.s && !instr.opcode.is_test() {
+        "s"
+    } else {
+        ""
+    };
+    match instr.opcode {
+        DataProcessingOpCode::Mov | DataProcessingOpCode::Mvn => {
+            format!("{}{}{} {}, {}", op, c, s, reg(instr.rd), op2)
+        }
+        opcode if opcode.is_test() => format!("{}{} {}, {}", op, c, reg(instr.rn), op2),
+        _ => format!(
+            "{}{}{} {}, {}, {}",
+            op,
+            c,
+            s,
+            reg(instr.rd),
+            reg(instr.rn),
+            op2
+        ),
+    }
+}
+
+fn disassemble_msr(instr: &MsrInstr, c: &str) -> String {
+    let psr = if instr.spsr { "spsr" } else { "cpsr" };
+    let mut fields = String::new();
+    for (n, name) in ["c", "x", "s", "f"].iter().enumerate() {
+        if instr.field_mask & (0xFF << (n * 8)) != 0 {
+            fields.push_str(name);
+        }
+    }
+    // Conventional order is f, s, x, c
+    let fields: String = fields.chars().rev().collect();
+    let operand = match instr.operand {
+        MsrOperand::Reg(rm) => reg(rm).to_owned(),
+        MsrOperand::Imm(v) => imm(v),
+    };
+    format!("msr{} {}_{}, {}", c, psr, fields, operand)
+}
+
+fn disassemble_load_store(instr: &LoadStoreInstr, c: &str) -> String {
+    let op = if instr.opcode == LoadStoreOpcode::Ldr {
+        "ldr"
+    } else {
+        "str"
+    };
+    let b = if instr.byte_or_word { "b" } else { "" };
+    // W in post-indexed form means a user mode (translated) access
+    let t = if !instr.pre_post && instr.write_back {
+        "t"
+    } else {
+        ""
+    };
+    let address = match instr.offset {
+        LoadStoreOffset::ImmOffset { imm } if imm == 0 && instr.pre_post => {
+            format!(
+                "[{}]{}",
+                reg(instr.rn),
+                if instr.write_back { "!" } else { "" }
+            )
+        }
+        LoadStoreOffset::ImmOffset { imm } => address(
+            instr.rn,
+            &signed_imm(instr.up_down, imm as u32),
+            instr.pre_post,
+            instr.write_back,
+        ),
+        LoadStoreOffset::ShiftOffset {
+            shift_count,
+            shift_type,
+            rm,
+        } => {
+            let sign = if instr.up_down { "" } else { "-" };
+            let offset = format!("{}{}{}", sign, reg(rm), shift_imm(shift_type, shift_count));
+            address(instr.rn, &offset, instr.pre_post, instr.write_back)
+        }
+    };
+    format!("{}{}{}{} {}, {}", op, c, b, t, reg(instr.rd), address)
+}
+
+pub fn disassemble_thumb(i: u16, addr: u32) -> String {
+    disassemble_thumb_symbols(i, addr, &Symbols::default())
+}
+
+pub fn disassemble_thumb_symbols(i: u16, addr: u32, symbols: &Symbols) -> String {
+    let i = i as u32;
+    let rd = get_bits(i, 0, 2) as u8;
+    let rs = get_bits(i, 3, 5) as u8;
+    let pc = addr.wrapping_add(4);
+    match thumb_type(i as u16) {
+        ThumbType::MoveShiftedRegister => {
+            let op = SHIFT_NAMES[get_bits(i, 11, 12) as usize];
+            let mut amount = get_bits(i, 6, 10);
+            if amount == 0 && op != "lsl" {
+                amount = 32;
+            }
+            format!("{}s {}, {}, #{}", op, reg(rd), reg(rs), amount)
+        }
+        ThumbType::AddSubtract => {
+            let op = if get_bits(i, 9, 9) == 1 {
+                "subs"
+            } else {
+                "adds"
+            };
+            let operand = if get_bits(i, 10, 10) == 1 {
+                imm(get_bits(i, 6, 8))
+            } else {
+                reg(get_bits(i, 6, 8) as u8).to_owned()
+            };
+            format!("{} {}, {}, {}", op, reg(rd), reg(rs), operand)
+        }
+        ThumbType::MoveCompareAddSubtractImm => {
+            let op = ["movs", "cmp", "adds", "subs"][get_bits(i, 11, 12) as usize];
+            format!(
+                "{} {}, {}",
+                op,
+                reg(get_bits(i, 8, 10) as u8),
+                imm(get_bits(i, 0, 7))
+            )
+        }
+        ThumbType::AluOperations => {
+            let op = [
+                "ands", "eors", "lsls", "lsrs", "asrs", "adcs", "sbcs", "rors", "tst", "negs",
+                "cmp", "cmn", "orrs", "muls", "bics", "mvns",
+            ][get_bits(i, 6, 9) as usize];
+            format!("{} {}, {}", op, reg(rd), reg(rs))
+        }
+        ThumbType::HiRegisterOperationsBx => {
+            let hs = (get_bits(i, 6, 6) << 3 | get_bits(i, 3, 5)) as u8;
+            let hd = (get_bits(i, 7, 7) << 3 | get_bits(i, 0, 2)) as u8;
+            match get_bits(i, 8, 9) {
+                0b00 => format!("add {}, {}", reg(hd), reg(hs)),
+                0b01 => format!("cmp {}, {}", reg(hd), reg(hs)),
+                0b10 => format!("mov {}, {}", reg(hd), reg(hs)),
+                _ => format!("bx {}", reg(hs)),
+            }
+        }
+        ThumbType::PcRelativeLoad => {
+            let target = (pc & !3).wrapping_add(get_bits(i, 0, 7) << 2);
+            format!(
+                "ldr {}, [pc, {}] ; 0x{:08x}",
+                reg(get_bits(i, 8, 10) as u8),
+                imm(get_bits(i, 0, 7) << 2),
+                target
+            )
+        }
+        ThumbType::LoadStoreRegOffset => {
+            let op = ["str", "strb", "ldr", "ldrb"][get_bits(i, 10, 11) as usize];
+            let ro = reg(get_bits(i, 6, 8) as u8);
+            format!("{} {}, [{}, {}]", op, reg(rd), reg(rs), ro)
+        }
+        ThumbType::LoadStoreSignExtended => {
+            // Indexed by H (bit 11) and S (bit 10)
+            let op = ["strh", "ldrsb", "ldrh", "ldrsh"][get_bits(i, 10, 11) as usize];
+            let ro = reg(get_bits(i, 6, 8) as u8);
+            format!("{} {}, [{}, {}]", op, reg(rd), reg(rs), ro)
+        }
+        ThumbType::LoadStoreImmOffset => {
+            let byte = get_bits(i, 12, 12) == 1;
+            let op = match (get_bits(i, 11, 11) == 1, byte) {
+                (false, false) => "str",
+                (false, true) => "strb",
+                (true, false) => "ldr",
+                (true, true) => "ldrb",
+            };
+            let offset = if byte {
+                get_bits(i, 6, 10)
+            } else {
+                get_bits(i, 6, 10) << 2
+            };
+            format!("{} {}, [{}, {}]", op, reg(rd), reg(rs), imm(offset))
+        }
+        ThumbType::LoadStoreHalfword => {
+            let op = if get_bits(i, 11, 11) == 1 {
+                "ldrh"
+            } else {
+                "strh"
+            };
+            format!(
+                "{} {}, [{}, {}]",
+                op,
+                reg(rd),
+                reg(rs),
+                imm(get_bits(i, 6, 10) << 1)
+            )
+        }
+        ThumbType::SpRelativeLoadStore => {
+            let op = if get_bits(i, 11, 11) == 1 {
+                "ldr"
+            } else {
+                "str"
+            };
+            format!(
+                "{} {}, [sp, {}]",
+                op,
+                reg(get_bits(i, 8, 10) as u8),
+                imm(get_bits(i, 0, 7) << 2)
+            )
+        }
+        ThumbType::LoadAddress => {
+            let base = if get_bits(i, 11, 11) == 1 { "sp" } else { "pc" };
+            format!(
+                "add {}, {}, {}",
+                reg(get_bits(i, 8, 10) as u8),
+                base,
+                imm(get_bits(i, 0, 7) << 2)
+            )
+        }
+        ThumbType::AddOffsetToSp => {
+            let offset = get_bits(i, 0, 6) << 2;
+            if get_bits(i, 7, 7) == 1 {
+                format!("sub sp, {}", imm(offset))
+            } else {
+                format!("add sp, {}", imm(offset))
+            }
+        }
+        ThumbType::PushPopRegisters => {
+            let pop = get_bits(i, 11, 11) == 1;
+            let mut rlist = get_bits(i, 0, 7) as u16;
+            if get_bits(i, 8, 8) == 1 {
+                rlist |= if pop { 1 << 15 } else { 1 << 14 };
+            }
+            format!("{} {}", if pop { "pop" } else { "push" }, reg_list(rlist))
+        }
+        ThumbType::MultipleLoadStore => {
+            let op = if get_bits(i, 11, 11) == 1 {
+                "ldmia"
+            } else {
+                "stmia"
+            };
+            format!(
+                "{} {}!, {}",
+                op,
+                reg(get_bits(i, 8, 10) as u8),
+                reg_list(get_bits(i, 0, 7) as u16)
+            )
+        }
+        ThumbType::ConditionalBranch => {
+            let cond = ConditionField::new(get_bits(i, 8, 11) as u8);
+            let offset = ((get_bits(i, 0, 7) as i8 as i32) << 1) as u32;
+            let dest = pc.wrapping_add(offset);
+            format!("b{} {}", cond.suffix(), target(dest, symbols))
+        }
+        ThumbType::SoftwareInterrupt => format!("swi 0x{:x}", get_bits(i, 0, 7)),
+        ThumbType::UnconditionalBranch => {
+            let dest = ((((get_bits(i, 0, 10) << 21) as i32) >> 20) as u32).wrapping_add(pc);
+            format!("b {}", target(dest, symbols))
+        }
+        ThumbType::LongBranchWithLink => {
+            // The two halves are separate opcodes, only the offset each one carries is known here
+            let offset = get_bits(i, 0, 10);
+            if get_bits(i, 11, 11) == 0 {
+                let high = (((offset << 21) as i32) >> 9) as u32;
+                format!("bl 0x{:08x} ; prefix", pc.wrapping_add(high))
+            } else {
+                format!("bl lr + {}", imm(offset << 1))
+            }
+        }
+        ThumbType::Undefined => "undefined".to_owned(),
+    }
+}
+
+/*
+ * THUMB BL is split in two opcodes: the first one leaves pc + the upper half of the offset in lr
+ * and the second one adds the lower half. With lr known, as in a trace, the second one is shown
+ * with its target.
+ */
+pub fn disassemble_thumb_lr(i: u16, addr: u32, lr: u32, symbols: &Symbols) -> String {
+    let i = i as u32;
+    if thumb_type(i as u16) == ThumbType::LongBranchWithLink && get_bits(i, 11, 11) == 1 {
+        let dest = lr.wrapping_add(get_bits(i, 0, 10) << 1);
+        return format!("bl {}", target(dest, symbols));
+    }
+    disassemble_thumb_symbols(i as u16, addr, symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_arm() {
+        assert_eq!(disassemble_arm(0xea00002e, 0x0800_0000), "b 0x080000c0");
+        assert_eq!(disassemble_arm(0xe3a00301, 0), "mov r0, #0x4000000");
+        assert_eq!(disassemble_arm(0xe2811003, 0), "add r1, r1, #3");
+        assert_eq!(disassemble_arm(0xe5801000, 0), "str r1, [r0]");
+        assert_eq!(disassemble_arm(0xe0c010b2, 0), "strh r1, [r0], #2");
+        assert_eq!(disassemble_arm(0xe2522001, 0), "subs r2, r2, #1");
+        assert_eq!(disassemble_arm(0x1afffffc, 0x100), "bne 0x000000f8");
+        assert_eq!(disassemble_arm(0xe59fd0b8, 0), "ldr sp, [pc, #0xb8]");
+        assert_eq!(disassemble_arm(0xe129f000, 0), "msr cpsr_fc, r0");
+        assert_eq!(disassemble_arm(0xe12fff10, 0), "bx r0");
+        assert_eq!(disassemble_arm(0xe92d4010, 0), "stmdb sp!, {r4, lr}");
+        assert_eq!(disassemble_arm(0xe8bd000f, 0), "ldmia sp!, {r0-r3}");
+        assert_eq!(disassemble_arm(0xe0841392, 0), "umull r1, r4, r2, r3");
+        assert_eq!(disassemble_arm(0xe1a00110, 0), "mov r0, r0, lsl r1");
+        assert_eq!(disassemble_arm(0xee100f10, 0), "mrc p15, 0, r0, c0, c0, 0");
+        assert_eq!(disassemble_arm(0xe7f000f0, 0), "undefined");
+    }
+
+    #[test]
+    fn test_symbols() {
+        use util::elf::Symbol;
+
+        let symbols = Symbols::new(vec![Symbol {
+            name: "main".to_string(),
+            addr: 0x0800_00c0,
+            size: 0x40,
+        }]);
+        assert_eq!(
+            disassemble_arm_symbols(0xea00002e, 0x0800_0000, &symbols),
+            "b 0x080000c0 <main>"
+        );
+        assert_eq!(
+            disassemble_arm_symbols(0xebfffffc, 0x0800_00dc, &symbols),
+            "bl 0x080000d4 <main+0x14>"
+        );
+        assert_eq!(
+            disassemble_thumb_symbols(0xe7fe, 0x0800_0200, &symbols),
+            "b 0x08000200"
+        );
+        // The second half of a bl from 0x08000200, the first half set lr to 0x08000204 - 0x200
+        assert_eq!(
+            disassemble_thumb_lr(0xf86a, 0x0800_0202, 0x0800_0004, &symbols),
+            "bl 0x080000d8 <main+0x18>"
+        );
+        assert_eq!(
+            disassemble_thumb_lr(0x2005, 0, 0, &symbols),
+            disassemble_thumb(0x2005, 0)
+        );
+    }
+
+    #[test]
+    fn test_disassemble_thumb() {
+        assert_eq!(disassemble_thumb(0x2005, 0), "movs r0, #5");
+        assert_eq!(disassemble_thumb(0x1a42, 0), "subs r2, r0, r1");
+        assert_eq!(disassemble_thumb(0x4770, 0), "bx lr");
+        assert_eq!(disassemble_thumb(0xb510, 0), "push {r4, lr}");
+        assert_eq!(disassemble_thumb(0xbc10, 0), "pop {r4}");
+        assert_eq!(disassemble_thumb(0xd0fe, 0x100), "beq 0x00000100");
+        assert_eq!(disassemble_thumb(0xe7fe, 0x100), "b 0x00000100");
+        assert_eq!(disassemble_thumb(0x5e42, 0), "ldrsh r2, [r0, r1]");
+        assert_eq!(disassemble_thumb(0x5642, 0), "ldrsb r2, [r0, r1]");
+        assert_eq!(disassemble_thumb(0x5a42, 0), "ldrh r2, [r0, r1]");
+        assert_eq!(disassemble_thumb(0x6808, 0), "ldr r0, [r1, #0]");
+    }
+}
@@ -0,0 +1,1061 @@
+use super::arm::{armv4_type, is_psr_transfer, ArmV4Type, DataProcessingOpCode, ShiftType};
+use super::bus::{Bus, MemoryAccess, MemoryWidth};
+use super::cache::AddrMap;
+use super::cpu::ARMCpu;
+use super::thumb::{thumb_type, ThumbType};
+use std::convert::TryFrom;
+use std::os::raw::{c_int, c_long, c_void};
+use std::ptr;
+use util::get_bits;
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs x86-64 Linux");
+
+/*
+ * x86-64 recompiler.
+ *
+ * Blocks of the block cache (see cache.rs) that start at an address executed often enough are
+ * translated to native code. Only the leading run of simple data processing instructions of a
+ * block is translated: ARM data processing without r15, ADC/SBC/RSC, shifts by register, RRX or
+ * shifts by 32, THUMB formats 1 to 3 without shifts by 32, the format 4 operations without shifts
+ * or carry in and the format 5 operations without r15. Everything else is left to the
+ * interpreter, which also runs a block while it is not hot.
+ *
+ * Translated instructions do not touch memory. Translations are dropped when the memory they came
+ * from is written, so a translated block runs without reading its opcodes again, only the opcodes
+ * in the pipeline are compared with the translated ones. The CPU still times every opcode fetch
+ * like the interpreter and fetches the two opcodes that refill the pipeline afterwards, which
+ * leaves the bus in the same state. Translations are only looked up where execution enters a
+ * block of the cache.
+ *
+ * A translated block runs in one ARMCpu::step, so it ends once it used the cycles left until the
+ * next event of the system (see Bus::cycles_to_next_event), where the interpreter would have
+ * stopped as well. IRQs raised by the event are then taken at the same instruction. Lockstep
+ * compares a CPU using the recompiler with one using the interpreter.
+ */
+
+const HOT_THRESHOLD: u32 = 16;
+// Marks blocks that start with an instruction that cannot be translated.
+const REJECTED: u32 = u32::MAX;
+const PAGE_SHIFT: u32 = 8;
+const CODE_BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    // Value and shifter carry out, None leaves C unchanged.
+    Imm(u32, Option<bool>),
+    Reg(usize),
+    // Register shifted by 1 to 31.
+    Shifted(usize, ShiftType, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct JitOp {
+    opcode: DataProcessingOpCode,
+    rd: usize,
+    rn: usize,
+    operand: Operand,
+    set_flags: bool,
+}
+
+fn translate_arm(i: u32) -> Option<JitOp> {
+    if get_bits(i, 28, 31) != 0xE
+        || armv4_type(i) != ArmV4Type::DataProcessingPsr
+        || is_psr_transfer(i)
+    {
+        return None;
+    }
+    let opcode = DataProcessingOpCode::try_from(get_bits(i, 21, 24)).ok()?;
+    let rn = get_bits(i, 16, 19) as usize;
+    let rd = get_bits(i, 12, 15) as usize;
+    let operand = if get_bits(i, 25, 25) == 1 {
+        let rotate = get_bits(i, 8, 11) * 2;
+        let value = get_bits(i, 0, 7).rotate_right(rotate);
+        let carry = if rotate == 0 {
+            None
+        } else {
+            Some(value >> 31 == 1)
+        };
+        Operand::Imm(value, carry)
+    } else if get_bits(i, 4, 4) == 0 && get_bits(i, 0, 3) != 15 {
+        shifted(
+            get_bits(i, 0, 3) as usize,
+            get_bits(i, 5, 6),
+            get_bits(i, 7, 11),
+        )?
+    } else {
+        return None;
+    };
+    let op = JitOp {
+        opcode,
+        rd,
+        rn,
+        operand,
+        set_flags: get_bits(i, 20, 20) == 1,
+    };
+    if rd == 15 || (uses_rn(opcode) && rn == 15) || !supported(opcode) {
+        return None;
+    }
+    Some(op)
+}
+
+fn translate_thumb(i: u16) -> Option<JitOp> {
+    let i = i as u32;
+    let low = |lsb| get_bits(i, lsb, lsb + 2) as usize;
+    let op = match thumb_type(i as u16) {
+        ThumbType::MoveShiftedRegister => JitOp {
+            opcode: DataProcessingOpCode::Mov,
+            rd: low(0),
+            rn: low(0),
+            operand: shifted(low(3), get_bits(i, 11, 12), get_bits(i, 6, 10))?,
+            set_flags: true,
+        },
+        ThumbType::AddSubtract => JitOp {
+            opcode: if get_bits(i, 9, 9) == 1 {
+                DataProcessingOpCode::Sub
+            } else {
+                DataProcessingOpCode::Add
+            },
+            rd: low(0),
+            rn: low(3),
+            operand: if get_bits(i, 10, 10) == 1 {
+                Operand::Imm(get_bits(i, 6, 8), None)
+            } else {
+                Operand::Reg(low(6))
+            },
+            set_flags: true,
+        },
+        ThumbType::MoveCompareAddSubtractImm => JitOp {
+            opcode: [
+                DataProcessingOpCode::Mov,
+                DataProcessingOpCode::Cmp,
+                DataProcessingOpCode::Add,
+                DataProcessingOpCode::Sub,
+            ][get_bits(i, 11, 12) as usize],
+            rd: low(8),
+            rn: low(8),
+            operand: Operand::Imm(get_bits(i, 0, 7), None),
+            set_flags: true,
+        },
+        ThumbType::AluOperations => {
+            let (opcode, rn, operand) = match get_bits(i, 6, 9) {
+                0x0 => (DataProcessingOpCode::And, low(0), Operand::Reg(low(3))),
+                0x1 => (DataProcessingOpCode::Eor, low(0), Operand::Reg(low(3))),
+                0x8 => (DataProcessingOpCode::Tst, low(0), Operand::Reg(low(3))),
+                // NEG rd, rs is RSB rd, rs, #0
+                0x9 => (DataProcessingOpCode::Rsb, low(3), Operand::Imm(0, None)),
+                0xA => (DataProcessingOpCode::Cmp, low(0), Operand::Reg(low(3))),
+                0xB => (DataProcessingOpCode::Cmn, low(0), Operand::Reg(low(3))),
+                0xC => (DataProcessingOpCode::Orr, low(0), Operand::Reg(low(3))),
+                0xE => (DataProcessingOpCode::Bic, low(0), Operand::Reg(low(3))),
+                0xF => (DataProcessingOpCode::Mvn, low(0), Operand::Reg(low(3))),
+                _ => return None,
+            };
+            JitOp {
+                opcode,
+                rd: low(0),
+                rn,
+                operand,
+                set_flags: true,
+            }
+        }
+        ThumbType::HiRegisterOperationsBx => {
+            let rs = (get_bits(i, 6, 6) << 3 | get_bits(i, 3, 5)) as usize;
+            let rd = (get_bits(i, 7, 7) << 3 | get_bits(i, 0, 2)) as usize;
+            if rs == 15 || rd == 15 {
+                return None;
+            }
+            let (opcode, set_flags) = match get_bits(i, 8, 9) {
+                0 => (DataProcessingOpCode::Add, false),
+                1 => (DataProcessingOpCode::Cmp, true),
+                2 => (DataProcessingOpCode::Mov, false),
+                _ => return None,
+            };
+            JitOp {
+                opcode,
+                rd,
+                rn: rd,
+                operand: Operand::Reg(rs),
+                set_flags,
+            }
+        }
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Register operand shifted by an immediate, None for RRX and the shifts by 32.
+fn shifted(rm: usize, shift_type: u32, amount: u32) -> Option<Operand> {
+    let shift_type = ShiftType::try_from(shift_type).ok()?;
+    match amount {
+        0 if shift_type == ShiftType::LogicalLeft => Some(Operand::Reg(rm)),
+        0 => None,
+        _ => Some(Operand::Shifted(rm, shift_type, amount)),
+    }
+}
+
+fn uses_rn(opcode: DataProcessingOpCode) -> bool {
+    !matches!(
+        opcode,
+        DataProcessingOpCode::Mov | DataProcessingOpCode::Mvn
+    )
+}
+
+fn supported(opcode: DataProcessingOpCode) -> bool {
+    !matches!(
+        opcode,
+        DataProcessingOpCode::Adc | DataProcessingOpCode::Sbc | DataProcessingOpCode::Rsc
+    )
+}
+
+fn writes_rd(opcode: DataProcessingOpCode) -> bool {
+    !opcode.is_test()
+}
+
+// Operations that set C and V from the ALU, the others take C from the shifter.
+fn arithmetic(opcode: DataProcessingOpCode) -> bool {
+    use DataProcessingOpCode::*;
+    matches!(opcode, Sub | Rsb | Add | Cmp | Cmn)
+}
+
+/*
+ * Code generation. A block is a function
+ *
+ * extern "sysv64" fn(regs: *mut u32 (rdi), cpsr: *mut u32 (rsi), count: u64 (rdx))
+ *
+ * that runs the first count instructions of the block. Values are computed in eax (rn) and ecx
+ * (operand 2), flags are collected in r8-r10 and dl, count is kept in r11. All of these are
+ * caller saved so the block needs no prologue.
+ */
+struct Emitter {
+    code: Vec<u8>,
+    // Offsets of the rel32 fields of the jumps to the exit.
+    exits: Vec<usize>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+
+    fn imm32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    // mov eax/ecx, [rdi + r * 4]
+    fn load(&mut self, x86_reg: u8, r: usize) {
+        self.bytes(&[0x8B, 0x47 | x86_reg << 3, (r * 4) as u8]);
+    }
+
+    fn instruction(&mut self, op: &JitOp) {
+        // test r11, r11; jz exit; dec r11
+        self.bytes(&[0x4D, 0x85, 0xDB, 0x0F, 0x84]);
+        self.exits.push(self.code.len());
+        self.imm32(0);
+        self.bytes(&[0x49, 0xFF, 0xCB]);
+
+        match op.operand {
+            Operand::Imm(value, _) => {
+                // mov ecx, imm32
+                self.bytes(&[0xB9]);
+                self.imm32(value);
+            }
+            Operand::Reg(rm) => self.load(1, rm),
+            Operand::Shifted(rm, shift_type, amount) => {
+                self.load(1, rm);
+                // shl/shr/sar/ror ecx, amount
+                let modrm = match shift_type {
+                    ShiftType::LogicalLeft => 0xE1,
+                    ShiftType::LogicalRight => 0xE9,
+                    ShiftType::ArithmeticRight => 0xF9,
+                    ShiftType::RotateRight => 0xC9,
+                };
+                self.bytes(&[0xC1, modrm, amount as u8]);
+                if op.set_flags && !arithmetic(op.opcode) {
+                    // The x86 carry is the last bit shifted out, like the shifter carry out:
+                    // setc r10b
+                    self.bytes(&[0x41, 0x0F, 0x92, 0xC2]);
+                }
+            }
+        }
+        if uses_rn(op.opcode) {
+            self.load(0, op.rn);
+        }
+
+        use DataProcessingOpCode::*;
+        match op.opcode {
+            // and eax, ecx
+            And | Tst => self.bytes(&[0x21, 0xC8]),
+            // xor eax, ecx
+            Eor | Teq => self.bytes(&[0x31, 0xC8]),
+            // sub eax, ecx
+            Sub | Cmp => self.bytes(&[0x29, 0xC8]),
+            // sub ecx, eax; mov eax, ecx
+            Rsb => self.bytes(&[0x29, 0xC1, 0x89, 0xC8]),
+            // add eax, ecx
+            Add | Cmn => self.bytes(&[0x01, 0xC8]),
+            // or eax, ecx
+            Orr => self.bytes(&[0x09, 0xC8]),
+            // mov eax, ecx
+            Mov => self.bytes(&[0x89, 0xC8]),
+            // not ecx; and eax, ecx
+            Bic => self.bytes(&[0xF7, 0xD1, 0x21, 0xC8]),
+            // not ecx; mov eax, ecx
+            Mvn => self.bytes(&[0xF7, 0xD1, 0x89, 0xC8]),
+            Adc | Sbc | Rsc => unreachable!(),
+        }
+
+        if op.set_flags {
+            self.flags(op);
+        }
+        if writes_rd(op.opcode) {
+            // mov [rdi + rd * 4], eax
+            self.bytes(&[0x89, 0x47, (op.rd * 4) as u8]);
+        }
+    }
+
+    fn flags(&mut self, op: &JitOp) {
+        use DataProcessingOpCode::*;
+        let arithmetic = arithmetic(op.opcode);
+        if !arithmetic {
+            // test eax, eax
+            self.bytes(&[0x85, 0xC0]);
+        }
+        // sets r8b; setz r9b
+        self.bytes(&[0x41, 0x0F, 0x98, 0xC0, 0x41, 0x0F, 0x94, 0xC1]);
+        if arithmetic {
+            // setc r10b; seto dl
+            self.bytes(&[0x41, 0x0F, 0x92, 0xC2, 0x0F, 0x90, 0xC2]);
+        }
+
+        let carry = match op.operand {
+            Operand::Imm(_, carry) if !arithmetic => carry,
+            _ => None,
+        };
+        // The shifter carry was saved in r10b.
+        let shifter_carry = !arithmetic && matches!(op.operand, Operand::Shifted(..));
+        let mask = if arithmetic {
+            0x0FFF_FFFF
+        } else if carry.is_some() || shifter_carry {
+            0x1FFF_FFFF
+        } else {
+            0x3FFF_FFFF
+        };
+        // mov ecx, [rsi]; and ecx, mask
+        self.bytes(&[0x8B, 0x0E, 0x81, 0xE1]);
+        self.imm32(mask);
+        // movzx r8d, r8b; shl r8d, 31; or ecx, r8d
+        self.bytes(&[
+            0x45, 0x0F, 0xB6, 0xC0, 0x41, 0xC1, 0xE0, 0x1F, 0x44, 0x09, 0xC1,
+        ]);
+        // movzx r9d, r9b; shl r9d, 30; or ecx, r9d
+        self.bytes(&[
+            0x45, 0x0F, 0xB6, 0xC9, 0x41, 0xC1, 0xE1, 0x1E, 0x44, 0x09, 0xC9,
+        ]);
+        if arithmetic {
+            // movzx r10d, r10b
+            self.bytes(&[0x45, 0x0F, 0xB6, 0xD2]);
+            if matches!(op.opcode, Sub | Rsb | Cmp) {
+                // The ARM carry of a subtraction is the inverted borrow: xor r10d, 1
+                self.bytes(&[0x41, 0x83, 0xF2, 0x01]);
+            }
+            // shl r10d, 29; or ecx, r10d; movzx edx, dl; shl edx, 28; or ecx, edx
+            self.bytes(&[0x41, 0xC1, 0xE2, 0x1D, 0x44, 0x09, 0xD1]);
+            self.bytes(&[0x0F, 0xB6, 0xD2, 0xC1, 0xE2, 0x1C, 0x09, 0xD1]);
+        } else if shifter_carry {
+            // movzx r10d, r10b; shl r10d, 29; or ecx, r10d
+            self.bytes(&[
+                0x45, 0x0F, 0xB6, 0xD2, 0x41, 0xC1, 0xE2, 0x1D, 0x44, 0x09, 0xD1,
+            ]);
+        } else if carry == Some(true) {
+            // or ecx, C
+            self.bytes(&[0x81, 0xC9]);
+            self.imm32(0x2000_0000);
+        }
+        // mov [rsi], ecx
+        self.bytes(&[0x89, 0x0E]);
+    }
+}
+
+fn emit_block(ops: &[JitOp]) -> Vec<u8> {
+    let mut e = Emitter {
+        code: vec![],
+        exits: vec![],
+    };
+    // mov r11, rdx
+    e.bytes(&[0x49, 0x89, 0xD3]);
+    for op in ops {
+        e.instruction(op);
+    }
+    let exit = e.code.len();
+    // ret
+    e.bytes(&[0xC3]);
+    for &pos in &e.exits {
+        let rel = (exit - (pos + 4)) as u32;
+        e.code[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+    e.code
+}
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: c_long,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 0x02;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+type BlockFn = unsafe extern "sysv64" fn(*mut u32, *mut u32, u64);
+
+// Executable memory for translated blocks. It is only writable while a block is copied in.
+struct CodeBuffer {
+    ptr: *mut u8,
+    used: usize,
+}
+
+impl CodeBuffer {
+    fn new() -> Option<CodeBuffer> {
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                CODE_BUFFER_SIZE,
+                PROT_READ | PROT_EXEC,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            return None;
+        }
+        Some(CodeBuffer {
+            ptr: ptr as *mut u8,
+            used: 0,
+        })
+    }
+
+    fn install(&mut self, code: &[u8]) -> Option<BlockFn> {
+        if self.used + code.len() > CODE_BUFFER_SIZE {
+            return None;
+        }
+        unsafe {
+            let base = self.ptr as *mut c_void;
+            if mprotect(base, CODE_BUFFER_SIZE, PROT_READ | PROT_WRITE) != 0 {
+                return None;
+            }
+            let dst = self.ptr.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+            if mprotect(base, CODE_BUFFER_SIZE, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            self.used += code.len();
+            Some(std::mem::transmute::<*mut u8, BlockFn>(dst))
+        }
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr as *mut c_void, CODE_BUFFER_SIZE);
+        }
+    }
+}
+
+struct JitBlock {
+    entry: BlockFn,
+    // Opcodes the block was translated from.
+    opcodes: Vec<u32>,
+}
+
+type BlockKey = (u32, bool);
+
+pub struct Jit {
+    // None when no executable memory could be mapped, everything is interpreted then.
+    buffer: Option<CodeBuffer>,
+    blocks: AddrMap<BlockKey, JitBlock>,
+    pages: AddrMap<u32, Vec<BlockKey>>,
+    // Executions of untranslated blocks, REJECTED for blocks that cannot be translated.
+    heat: AddrMap<BlockKey, u32>,
+    instructions: u64,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Jit {
+            buffer: CodeBuffer::new(),
+            blocks: AddrMap::default(),
+            pages: AddrMap::default(),
+            heat: AddrMap::default(),
+            instructions: 0,
+        }
+    }
+
+    // Number of translated blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    // Instructions executed as native code.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // Counts an execution of the block at key, true once it is hot.
+    fn heat_up(&mut self, key: BlockKey) -> bool {
+        let heat = self.heat.entry(key).or_insert(0);
+        if *heat < HOT_THRESHOLD {
+            *heat += 1;
+        }
+        *heat == HOT_THRESHOLD
+    }
+
+    fn compile(&mut self, key: BlockKey, opcodes: &[u32]) {
+        let (addr, thumb) = key;
+        let ops: Vec<JitOp> = opcodes
+            .iter()
+            .map(|&i| {
+                if thumb {
+                    translate_thumb(i as u16)
+                } else {
+                    translate_arm(i)
+                }
+            })
+            .take_while(Option::is_some)
+            .flatten()
+            .collect();
+        if ops.is_empty() || self.buffer.is_none() {
+            self.heat.insert(key, REJECTED);
+            return;
+        }
+
+        let code = emit_block(&ops);
+        let buffer = self.buffer.as_mut().unwrap();
+        let entry = match buffer.install(&code) {
+            Some(entry) => entry,
+            None => {
+                // Out of code space, start over.
+                buffer.used = 0;
+                self.blocks.clear();
+                self.pages.clear();
+                match buffer.install(&code) {
+                    Some(entry) => entry,
+                    None => {
+                        self.heat.insert(key, REJECTED);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let size = if thumb { 2 } else { 4 };
+        let end = addr.wrapping_add(ops.len() as u32 * size - 1);
+        let mut page = addr >> PAGE_SHIFT;
+        loop {
+            self.pages.entry(page).or_default().push(key);
+            if page == end >> PAGE_SHIFT {
+                break;
+            }
+            page = page.wrapping_add(1);
+        }
+        self.heat.remove(&key);
+        self.blocks.insert(
+            key,
+            JitBlock {
+                entry,
+                opcodes: opcodes[..ops.len()].into(),
+            },
+        );
+    }
+
+    // Drops every translation of memory in addr..addr + len.
+    pub fn invalidate(&mut self, addr: u32, len: u32) {
+        if self.pages.is_empty() || len == 0 {
+            return;
+        }
+        let last = addr.wrapping_add(len - 1) >> PAGE_SHIFT;
+        let mut page = addr >> PAGE_SHIFT;
+        loop {
+            if let Some(keys) = self.pages.remove(&page) {
+                for key in keys {
+                    self.blocks.remove(&key);
+                    self.heat.remove(&key);
+                }
+            }
+            if page == last {
+                break;
+            }
+            page = page.wrapping_add(1);
+        }
+    }
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Jit::new()
+    }
+}
+
+impl ARMCpu {
+    /*
+     * Runs the translated block at the next instruction, translating it first if it became hot.
+     * Returns false if the instruction has to be interpreted.
+     */
+    pub(crate) fn run_jit<B: Bus + ?Sized>(&mut self, bus: &mut B) -> bool {
+        let thumb = self.cpsr.thumb();
+        let addr = self.next_pc();
+        let key = (addr, thumb);
+        let cache = match self.cache.as_ref() {
+            Some(cache) if cache.enters_block(addr, thumb) => cache,
+            _ => return false,
+        };
+        let jit = match self.jit.as_mut() {
+            Some(jit) => jit,
+            None => return false,
+        };
+        let (entry, len, first, second) = match jit.blocks.get(&key) {
+            Some(block) => (
+                block.entry,
+                block.opcodes.len(),
+                block.opcodes[0],
+                block.opcodes.get(1).copied(),
+            ),
+            None => {
+                // Only blocks of the cache are translated, so only their starts are counted
+                if cache.starts_block(addr, thumb) && jit.heat_up(key) {
+                    match cache.block_opcodes(addr, thumb) {
+                        Some(opcodes) => jit.compile(key, &opcodes),
+                        None => {
+                            jit.heat.insert(key, REJECTED);
+                        }
+                    }
+                }
+                return false;
+            }
+        };
+        // The pipeline may hold opcodes fetched before the code was changed.
+        if self.pipeline[0] != first || second.is_some_and(|op| self.pipeline[1] != op) {
+            return false;
+        }
+
+        let size = self.instr_size();
+        let width = if thumb {
+            MemoryWidth::Halfword
+        } else {
+            MemoryWidth::Word
+        };
+        let budget = bus.cycles_to_next_event();
+        let mut pc = self.regs[ARMCpu::PC];
+        let mut count = 0;
+        loop {
+            let access = self.next_fetch;
+            self.fetch_cycle(bus, pc, width, access);
+            self.next_fetch = MemoryAccess::Sequential;
+            pc = pc.wrapping_add(size);
+            count += 1;
+            if count == len || self.cycles >= budget {
+                break;
+            }
+        }
+
+        unsafe { entry(self.regs.as_mut_ptr(), &mut self.cpsr.0, count as u64) };
+        self.regs[ARMCpu::PC] = pc;
+        let next = pc.wrapping_sub(size * 2);
+        for (n, &fetch) in [next, next.wrapping_add(size)].iter().enumerate() {
+            self.pipeline[n] = if thumb {
+                bus.fetch_16(fetch, MemoryAccess::Sequential) as u32
+            } else {
+                bus.fetch_32(fetch, MemoryAccess::Sequential)
+            };
+        }
+        if let Some(cache) = self.cache.as_mut() {
+            cache.skip(addr, thumb, count);
+        }
+        if let Some(jit) = self.jit.as_mut() {
+            jit.instructions += count as u64;
+        }
+        true
+    }
+}
+
+/*
+ * Runs a CPU with the recompiler and a reference CPU with the interpreter side by side, each on
+ * its own copy of the system, and compares registers and CPSR whenever both have used the same
+ * number of cycles. Returns a description of the first difference.
+ */
+pub fn lockstep<B: Bus + ?Sized>(
+    jit_cpu: &mut ARMCpu,
+    jit_bus: &mut B,
+    ref_cpu: &mut ARMCpu,
+    ref_bus: &mut B,
+    steps: usize,
+) -> Result<(), String> {
+    let mut jit_cycles = 0u64;
+    let mut ref_cycles = 0u64;
+    for step in 0..steps {
+        jit_cycles += jit_cpu.step(jit_bus) as u64;
+        while ref_cycles < jit_cycles {
+            ref_cycles += ref_cpu.step(ref_bus) as u64;
+        }
+        if ref_cycles != jit_cycles {
+            return Err(format!(
+                "step {}: cycle count {} differs from interpreter {}",
+                step, jit_cycles, ref_cycles
+            ));
+        }
+        for r in 0..16 {
+            if jit_cpu.reg(r) != ref_cpu.reg(r) {
+                return Err(format!(
+                    "step {}: r{} is {:08X}, interpreter has {:08X}",
+                    step,
+                    r,
+                    jit_cpu.reg(r),
+                    ref_cpu.reg(r)
+                ));
+            }
+        }
+        if jit_cpu.cpsr() != ref_cpu.cpsr() {
+            return Err(format!(
+                "step {}: cpsr is {:08X}, interpreter has {:08X}",
+                step,
+                jit_cpu.cpsr().0,
+                ref_cpu.cpsr().0
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::MemoryAccess;
+    use crate::cpu::tests::TestBus;
+    use std::time::{Duration, Instant};
+
+    fn run_lockstep(bus: &TestBus, steps: usize) -> ARMCpu {
+        let mut jit_cpu = ARMCpu::new();
+        jit_cpu.enable_jit(true);
+        let mut jit_bus = TestBus {
+            mem: bus.mem.clone(),
+        };
+        let mut ref_cpu = ARMCpu::new();
+        let mut ref_bus = TestBus {
+            mem: bus.mem.clone(),
+        };
+        lockstep(
+            &mut jit_cpu,
+            &mut jit_bus,
+            &mut ref_cpu,
+            &mut ref_bus,
+            steps,
+        )
+        .unwrap();
+        assert_eq!(jit_bus.mem, ref_bus.mem);
+        jit_cpu
+    }
+
+    #[test]
+    fn test_translate() {
+        // add r0, r1, #1
+        let op = translate_arm(0xe2810001).unwrap();
+        assert_eq!(op.opcode, DataProcessingOpCode::Add);
+        assert_eq!((op.rd, op.rn), (0, 1));
+        assert_eq!(op.operand, Operand::Imm(1, None));
+        // movs r0, #0x80000000 sets the carry from the rotated immediate
+        let op = translate_arm(0xe3b00102).unwrap();
+        assert_eq!(op.operand, Operand::Imm(0x8000_0000, Some(true)));
+        // add r0, r1, r2, lsl #2
+        let op = translate_arm(0xe0810102).unwrap();
+        assert_eq!(op.operand, Operand::Shifted(2, ShiftType::LogicalLeft, 2));
+        // Conditional, r15, shift by register, RRX, LSR #32 and carry in are interpreted
+        assert_eq!(translate_arm(0x12810001), None);
+        assert_eq!(translate_arm(0xe281f001), None);
+        assert_eq!(translate_arm(0xe0810312), None);
+        assert_eq!(translate_arm(0xe0810062), None);
+        assert_eq!(translate_arm(0xe0810022), None);
+        assert_eq!(translate_arm(0xe0a10002), None);
+        assert_eq!(translate_arm(0xe5910000), None);
+
+        // neg r0, r1
+        let op = translate_thumb(0x4248).unwrap();
+        assert_eq!(op.opcode, DataProcessingOpCode::Rsb);
+        assert_eq!((op.rd, op.rn), (0, 1));
+        // mov r8, r0
+        assert_eq!(translate_thumb(0x4680).unwrap().rd, 8);
+        // lsls r3, r1, #27
+        let op = translate_thumb(0x06cb).unwrap();
+        assert_eq!(op.operand, Operand::Shifted(1, ShiftType::LogicalLeft, 27));
+        // bx lr, lsl r0, r1, lsrs r0, r1, #32
+        assert_eq!(translate_thumb(0x4770), None);
+        assert_eq!(translate_thumb(0x4088), None);
+        assert_eq!(translate_thumb(0x0808), None);
+    }
+
+    #[test]
+    fn test_arm_lockstep() {
+        let bus = TestBus::with_code(&[
+            0xe3a00040, // mov r0, #64
+            0xe3a01000, // mov r1, #0
+            0xe3e02000, // mvn r2, #0
+            0xe0811000, // loop: add r1, r1, r0
+            0xe0213002, // eor r3, r1, r2
+            0xe1c34001, // bic r4, r3, r1
+            0xe0745003, // rsbs r5, r4, r3
+            0xe1956004, // orrs r6, r5, r4
+            0xe2500001, // subs r0, r0, #1
+            0x1afffff8, // bne loop
+            0xe1500001, // cmp r0, r1
+            0xe1700001, // cmn r0, r1
+            0xe3b07102, // movs r7, #0x80000000
+            0xe2977102, // adds r7, r7, #0x80000000
+            0xeafffffe, // b .
+        ]);
+        let cpu = run_lockstep(&bus, 400);
+        assert_eq!(cpu.reg(1), 64 * 65 / 2);
+        assert!(cpu.jit().unwrap().instructions() > 0);
+    }
+
+    #[test]
+    fn test_thumb_lockstep() {
+        let mut bus = TestBus::with_code(&[
+            0xe28f0001, // add r0, pc, #1
+            0xe12fff10, // bx r0
+        ]);
+        let thumb: [u16; 12] = [
+            0x2020, // movs r0, #32
+            0x2100, // movs r1, #0
+            0x4688, // mov r8, r1
+            0x1809, // loop: adds r1, r1, r0
+            0x424a, // negs r2, r1
+            0x4042, // eors r2, r0
+            0x43d3, // mvns r3, r2
+            0x4498, // add r8, r3
+            0x1e40, // subs r0, r0, #1
+            0x2800, // cmp r0, #0
+            0xd1f7, // bne loop
+            0xe7fe, // b .
+        ];
+        for (n, op) in thumb.iter().enumerate() {
+            let addr = 8 + n * 2;
+            bus.mem[addr..addr + 2].copy_from_slice(&op.to_le_bytes());
+        }
+        let cpu = run_lockstep(&bus, 300);
+        assert_eq!(cpu.reg(1), 32 * 33 / 2);
+        assert!(cpu.jit().unwrap().instructions() > 0);
+    }
+
+    /*
+     * Every shift is followed by a branch, so it ends a translated run and lockstep compares the
+     * flags it set.
+     */
+    #[test]
+    fn test_shift_lockstep() {
+        let bus = TestBus::with_code(&[
+            0xe3a00030, // mov r0, #48
+            0xe3e0100f, // mvn r1, #15
+            0xe3a02055, // mov r2, #0x55
+            0xe0811182, // loop: add r1, r1, r2, lsl #3
+            0xeaffffff, // b 1f
+            0xe1b03f81, // 1: movs r3, r1, lsl #31
+            0xeaffffff, // b 1f
+            0xe01140a2, // 1: ands r4, r1, r2, lsr #1
+            0xeaffffff, // b 1f
+            0xe03453c1, // 1: eors r5, r4, r1, asr #7
+            0xeaffffff, // b 1f
+            0xe19566e1, // 1: orrs r6, r5, r1, ror #13
+            0xeaffffff, // b 1f
+            0xe1d67fa3, // 1: bics r7, r6, r3, lsr #31
+            0xeaffffff, // b 1f
+            0xe1f08201, // 1: mvns r8, r1, lsl #4
+            0xeaffffff, // b 1f
+            0xe09192e1, // 1: adds r9, r1, r1, ror #5
+            0xe2500001, // subs r0, r0, #1
+            0x1affffee, // bne loop
+            0xeafffffe, // b .
+        ]);
+        let cpu = run_lockstep(&bus, 1200);
+        assert_eq!(cpu.reg(0), 0);
+        assert!(cpu.jit().unwrap().instructions() > 0);
+
+        let mut bus = TestBus::with_code(&[
+            0xe28f0001, // add r0, pc, #1
+            0xe12fff10, // bx r0
+        ]);
+        let thumb: [u16; 14] = [
+            0x2030, // movs r0, #48
+            0x21f0, // movs r1, #0xf0
+            0x1809, // loop: adds r1, r1, r0
+            0x06cb, // lsls r3, r1, #27
+            0xe7ff, // b 1f
+            0x08cc, // 1: lsrs r4, r1, #3
+            0xe7ff, // b 1f
+            0x125d, // 1: asrs r5, r3, #9
+            0xe7ff, // b 1f
+            0x000e, // 1: lsls r6, r1, #0
+            0xe7ff, // b 1f
+            0x3801, // 1: subs r0, #1
+            0xd1f4, // bne loop
+            0xe7fe, // b .
+        ];
+        for (n, op) in thumb.iter().enumerate() {
+            let addr = 8 + n * 2;
+            bus.mem[addr..addr + 2].copy_from_slice(&op.to_le_bytes());
+        }
+        let cpu = run_lockstep(&bus, 800);
+        assert_eq!(cpu.reg(0), 0);
+        assert!(cpu.jit().unwrap().instructions() > 0);
+    }
+
+    // Reports an event every few cycles.
+    struct EventBus {
+        bus: TestBus,
+        budget: u32,
+    }
+
+    impl Bus for EventBus {
+        fn read_8(&mut self, addr: u32, access: MemoryAccess) -> u8 {
+            self.bus.read_8(addr, access)
+        }
+
+        fn read_16(&mut self, addr: u32, access: MemoryAccess) -> u16 {
+            self.bus.read_16(addr, access)
+        }
+
+        fn read_32(&mut self, addr: u32, access: MemoryAccess) -> u32 {
+            self.bus.read_32(addr, access)
+        }
+
+        fn write_8(&mut self, addr: u32, value: u8, access: MemoryAccess) {
+            self.bus.write_8(addr, value, access)
+        }
+
+        fn write_16(&mut self, addr: u32, value: u16, access: MemoryAccess) {
+            self.bus.write_16(addr, value, access)
+        }
+
+        fn write_32(&mut self, addr: u32, value: u32, access: MemoryAccess) {
+            self.bus.write_32(addr, value, access)
+        }
+
+        fn cycles_to_next_event(&self) -> u32 {
+            self.budget
+        }
+    }
+
+    #[test]
+    fn test_blocks_end_at_events() {
+        let mut bus = EventBus {
+            bus: TestBus::with_code(&[
+                0xe2800001, // loop: add r0, r0, #1
+                0xe2811001, // add r1, r1, #1
+                0xe2822001, // add r2, r2, #1
+                0xe2833001, // add r3, r3, #1
+                0xe2844001, // add r4, r4, #1
+                0xeafffff9, // b loop
+            ]),
+            budget: 2,
+        };
+        let mut cpu = ARMCpu::new();
+        cpu.enable_jit(true);
+        for _ in 0..400 {
+            let before = cpu.jit().unwrap().instructions();
+            let cycles = cpu.step(&mut bus);
+            if cpu.jit().unwrap().instructions() > before {
+                assert!(cycles <= bus.budget);
+            }
+        }
+        assert!(cpu.jit().unwrap().instructions() > 0);
+        // Only the block start is counted, not every instruction in it
+        assert!(cpu.jit().unwrap().heat.len() <= cpu.block_cache().unwrap().len());
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let bus = TestBus::with_code(&[
+            0xe3a00000, // mov r0, #0
+            0xe59f2024, // ldr r2, [pc, #36] @ 0x30
+            0xe3a01000, // mov r1, #0
+            0xe2800001, // loop: add r0, r0, #1
+            0xe2811001, // patch: add r1, r1, #1
+            0xe310003f, // tst r0, #63
+            0x1afffffb, // bne loop
+            0xe50f2014, // str r2, [pc, #-20] @ 0x10, patches the add to add #2
+            0xe3500080, // cmp r0, #128
+            0x1afffff8, // bne loop
+            0xeafffffe, // b .
+            0xe1a00000, // nop
+            0xe2811002, // add r1, r1, #2
+        ]);
+        let cpu = run_lockstep(&bus, 800);
+        assert_eq!(cpu.reg(0), 128);
+        assert_eq!(cpu.reg(1), 64 + 128);
+    }
+
+    // cargo test --release --features jit -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_jit() {
+        let bus = TestBus::with_code(&[
+            0xe3a00000, // loop: mov r0, #0
+            0xe3a01000, // mov r1, #0
+            0xe2811003, // inner: add r1, r1, #3
+            0xe0812000, // add r2, r1, r0
+            0xe0223001, // eor r3, r2, r1
+            0xe1c34001, // bic r4, r3, r1
+            0xe0845003, // add r5, r4, r3
+            0xe1856004, // orr r6, r5, r4
+            0xe2800001, // add r0, r0, #1
+            0xe3500040, // cmp r0, #64
+            0x1afffff6, // bne inner
+            0xeafffff3, // b loop
+        ]);
+        let mut results = vec![];
+        let mut best = [Duration::MAX; 2];
+        // As in the cache bench, the modes take turns and the best run of each counts.
+        for _ in 0..3 {
+            let mut end = 4_000_000;
+            for (n, &jit) in [true, false].iter().enumerate() {
+                let mut cpu = ARMCpu::new();
+                let mut bus = TestBus {
+                    mem: bus.mem.clone(),
+                };
+                cpu.enable_block_cache(true);
+                cpu.enable_jit(jit);
+                let mut cycles = 0;
+                let start = Instant::now();
+                while cycles < end {
+                    cycles += cpu.step(&mut bus);
+                }
+                best[n] = best[n].min(start.elapsed());
+                // A JIT step runs a whole block, the interpreter stops where the last one ended.
+                end = cycles;
+                results.push((0..16).map(|r| cpu.reg(r)).collect::<Vec<_>>());
+            }
+        }
+        println!("jit: {:?}", best[0]);
+        println!("interpreter: {:?}", best[1]);
+        assert!(results.windows(2).all(|w| w[0] == w[1]));
+        assert!(
+            best[0] < best[1],
+            "jit run took {:?}, interpreter {:?}",
+            best[0],
+            best[1]
+        );
+    }
+}
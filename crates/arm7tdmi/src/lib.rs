@@ -1,7 +1,20 @@
+use psr::Psr;
 use std::fmt;
 use std::fmt::Display;
 pub mod thumb;
 pub mod arm;
+mod alu;
+pub mod bus;
+pub mod cache;
+pub mod coprocessor;
+pub mod cpu;
+pub mod disasm;
+#[cfg(feature = "jit")]
+pub mod jit;
+mod json;
+pub mod psr;
+pub mod singlestep;
+pub mod trace;
 
 // ARM7TDMI is an ARM cpu with 2 modes of instruction, a 32-bit ARM and a 16-bit THUMB.
 //
@@ -23,7 +36,7 @@ pub enum InstructionType {
 /*
  * ConditionField holds bits 31-28 of instructions,
  */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ConditionField(u8);
 
 impl ConditionField {
@@ -49,6 +62,52 @@ impl ConditionField {
         ConditionField(i)
     }
 
+    // Whether an instruction with this condition executes given the current flags.
+    pub fn passes(&self, psr: &Psr) -> bool {
+        match self.0 {
+            ConditionField::COND_EQ => psr.z(),
+            ConditionField::COND_NE => !psr.z(),
+            ConditionField::COND_CS_HS => psr.c(),
+            ConditionField::COND_CC_LO => !psr.c(),
+            ConditionField::COND_MI => psr.n(),
+            ConditionField::COND_PL => !psr.n(),
+            ConditionField::COND_VS => psr.v(),
+            ConditionField::COND_VC => !psr.v(),
+            ConditionField::COND_HI => psr.c() && !psr.z(),
+            ConditionField::COND_LS => !psr.c() || psr.z(),
+            ConditionField::COND_GE => psr.n() == psr.v(),
+            ConditionField::COND_LT => psr.n() != psr.v(),
+            ConditionField::COND_GT => !psr.z() && psr.n() == psr.v(),
+            ConditionField::COND_LE => psr.z() || psr.n() != psr.v(),
+            ConditionField::COND_AL => true,
+            // NV is reserved on ARMv4 and never executes
+            _ => false,
+        }
+    }
+
+    // Suffix used in assembler mnemonics, AL is left off.
+    pub fn suffix(&self) -> &'static str {
+        match self.0 {
+            ConditionField::COND_EQ => "eq",
+            ConditionField::COND_NE => "ne",
+            ConditionField::COND_CS_HS => "cs",
+            ConditionField::COND_CC_LO => "cc",
+            ConditionField::COND_MI => "mi",
+            ConditionField::COND_PL => "pl",
+            ConditionField::COND_VS => "vs",
+            ConditionField::COND_VC => "vc",
+            ConditionField::COND_HI => "hi",
+            ConditionField::COND_LS => "ls",
+            ConditionField::COND_GE => "ge",
+            ConditionField::COND_LT => "lt",
+            ConditionField::COND_GT => "gt",
+            ConditionField::COND_LE => "le",
+            ConditionField::COND_AL => "",
+            _ => "nv",
+        }
+    }
+
+    #[allow(clippy::wrong_self_convention)]
     fn to_str(&self) -> &'static str {
         match self.0 {
             ConditionField::COND_EQ => "EQ",
@@ -0,0 +1,197 @@
+use std::convert::TryFrom;
+use util::get_bits;
+
+/*
+ * Processor modes, stored in bits 4-0 of the CPSR. See ARM7TDMI Reference 2.7
+ *
+ * Each exception switches the CPU into its own mode, which has a private (banked) copy of r13
+ * and r14 plus a SPSR that holds the CPSR from before the exception. FIQ additionally banks
+ * r8-r12. User and System share the same registers and have no SPSR.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuMode {
+    User = 0b10000,
+    Fiq = 0b10001,
+    Irq = 0b10010,
+    Supervisor = 0b10011,
+    Abort = 0b10111,
+    Undefined = 0b11011,
+    System = 0b11111,
+}
+
+impl CpuMode {
+    // Index into the banked register arrays. User and System share bank 0.
+    pub(crate) fn bank(self) -> usize {
+        match self {
+            CpuMode::User | CpuMode::System => 0,
+            CpuMode::Fiq => 1,
+            CpuMode::Irq => 2,
+            CpuMode::Supervisor => 3,
+            CpuMode::Abort => 4,
+            CpuMode::Undefined => 5,
+        }
+    }
+
+    pub fn is_privileged(self) -> bool {
+        self != CpuMode::User
+    }
+
+    pub fn has_spsr(self) -> bool {
+        self != CpuMode::User && self != CpuMode::System
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            CpuMode::User => "USR",
+            CpuMode::Fiq => "FIQ",
+            CpuMode::Irq => "IRQ",
+            CpuMode::Supervisor => "SVC",
+            CpuMode::Abort => "ABT",
+            CpuMode::Undefined => "UND",
+            CpuMode::System => "SYS",
+        }
+    }
+}
+
+impl TryFrom<u32> for CpuMode {
+    type Error = ();
+    fn try_from(v: u32) -> Result<Self, Self::Error> {
+        match v {
+            v if v == CpuMode::User as u32 => Ok(CpuMode::User),
+            v if v == CpuMode::Fiq as u32 => Ok(CpuMode::Fiq),
+            v if v == CpuMode::Irq as u32 => Ok(CpuMode::Irq),
+            v if v == CpuMode::Supervisor as u32 => Ok(CpuMode::Supervisor),
+            v if v == CpuMode::Abort as u32 => Ok(CpuMode::Abort),
+            v if v == CpuMode::Undefined as u32 => Ok(CpuMode::Undefined),
+            v if v == CpuMode::System as u32 => Ok(CpuMode::System),
+            _ => Err(()),
+        }
+    }
+}
+
+/*
+ * Program Status Register (CPSR/SPSR). See ARM7TDMI Reference 2.8
+ *
+ * Bit 31 = N (negative), 30 = Z (zero), 29 = C (carry), 28 = V (overflow)
+ * Bit 7 = I (IRQ disable), 6 = F (FIQ disable), 5 = T (THUMB state)
+ * Bits 4-0 = mode
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Psr(pub u32);
+
+impl Psr {
+    pub const N_BIT: usize = 31;
+    pub const Z_BIT: usize = 30;
+    pub const C_BIT: usize = 29;
+    pub const V_BIT: usize = 28;
+    pub const I_BIT: usize = 7;
+    pub const F_BIT: usize = 6;
+    pub const T_BIT: usize = 5;
+
+    // Bits writable through MSR, the rest read back as written but are reserved.
+    pub const FLAGS_MASK: u32 = 0xF000_0000;
+    pub const CONTROL_MASK: u32 = 0x0000_00FF;
+
+    fn bit(&self, b: usize) -> bool {
+        get_bits(self.0, b, b) == 1
+    }
+
+    fn set_bit(&mut self, b: usize, v: bool) {
+        if v {
+            self.0 |= 1 << b;
+        } else {
+            self.0 &= !(1 << b);
+        }
+    }
+
+    pub fn n(&self) -> bool {
+        self.bit(Psr::N_BIT)
+    }
+
+    pub fn z(&self) -> bool {
+        self.bit(Psr::Z_BIT)
+    }
+
+    pub fn c(&self) -> bool {
+        self.bit(Psr::C_BIT)
+    }
+
+    pub fn v(&self) -> bool {
+        self.bit(Psr::V_BIT)
+    }
+
+    pub fn irq_disabled(&self) -> bool {
+        self.bit(Psr::I_BIT)
+    }
+
+    pub fn fiq_disabled(&self) -> bool {
+        self.bit(Psr::F_BIT)
+    }
+
+    pub fn thumb(&self) -> bool {
+        self.bit(Psr::T_BIT)
+    }
+
+    pub fn set_n(&mut self, v: bool) {
+        self.set_bit(Psr::N_BIT, v)
+    }
+
+    pub fn set_z(&mut self, v: bool) {
+        self.set_bit(Psr::Z_BIT, v)
+    }
+
+    pub fn set_c(&mut self, v: bool) {
+        self.set_bit(Psr::C_BIT, v)
+    }
+
+    pub fn set_v(&mut self, v: bool) {
+        self.set_bit(Psr::V_BIT, v)
+    }
+
+    pub fn set_irq_disabled(&mut self, v: bool) {
+        self.set_bit(Psr::I_BIT, v)
+    }
+
+    pub fn set_fiq_disabled(&mut self, v: bool) {
+        self.set_bit(Psr::F_BIT, v)
+    }
+
+    pub fn set_thumb(&mut self, v: bool) {
+        self.set_bit(Psr::T_BIT, v)
+    }
+
+    // Sets N and Z from a result, the common case for logical operations.
+    pub fn set_nz(&mut self, result: u32) {
+        self.set_n(result >> 31 == 1);
+        self.set_z(result == 0);
+    }
+
+    // An invalid mode value is treated as User, the least privileged mode.
+    pub fn mode(&self) -> CpuMode {
+        CpuMode::try_from(get_bits(self.0, 0, 4)).unwrap_or(CpuMode::User)
+    }
+
+    pub fn set_mode(&mut self, mode: CpuMode) {
+        self.0 = (self.0 & !0x1F) | mode as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_psr_flags() {
+        let mut psr = Psr(CpuMode::Supervisor as u32);
+        psr.set_nz(0x8000_0000);
+        assert!(psr.n());
+        assert!(!psr.z());
+        psr.set_c(true);
+        psr.set_v(true);
+        assert_eq!(psr.0 >> 28, 0b1011);
+        assert_eq!(psr.mode(), CpuMode::Supervisor);
+        psr.set_mode(CpuMode::Irq);
+        assert_eq!(psr.mode(), CpuMode::Irq);
+        assert_eq!(psr.0 >> 28, 0b1011);
+    }
+}
@@ -0,0 +1,447 @@
+use super::bus::{Bus, MemoryAccess};
+use super::cpu::ARMCpu;
+use super::json::Json;
+use super::psr::{CpuMode, Psr};
+use std::fs;
+use std::path::Path;
+
+/*
+ * Single step conformance tests.
+ *
+ * Runs JSON test vectors in the layout of the public SingleStepTests ARM7TDMI suites. A file
+ * holds an array of vectors, each one executes a single instruction:
+ *
+ * {
+ *   "initial": { <cpu state> },
+ *   "final": { <cpu state> },
+ *   "transactions": [ {"kind": 0, "size": 4, "addr": 8, "data": 3800000000, ...}, ... ],
+ *   "opcode": 3818913793
+ * }
+ *
+ * A cpu state has "R" (r0-r15 as seen in the mode of "CPSR"), "R_fiq" (r8-r14 of FIQ mode),
+ * "R_svc", "R_abt", "R_irq", "R_und" (r13-r14 of each mode), "CPSR", "SPSR" (in the order fiq,
+ * svc, abt, irq, und), "pipeline" (the two prefetched opcodes, the first executes next) and
+ * "access" (bit 0 set when the next opcode fetch is sequential).
+ *
+ * The memory contents are given by the transactions: kind 0 is an opcode fetch, 1 a data read
+ * and 2 a write. Reads are answered with the data of the matching expected transaction, a read
+ * nothing expects returns 0 and shows up as a transaction mismatch. Cycle counts and access
+ * flags of transactions are not compared.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuState {
+    pub r: [u32; 16],
+    pub r_fiq: [u32; 7],
+    // r13 and r14 of the modes in BANKED_MODES
+    pub r_banked: [[u32; 2]; 4],
+    pub cpsr: u32,
+    pub spsr: [u32; 5],
+    pub pipeline: [u32; 2],
+    pub access: u32,
+}
+
+const BANKED_MODES: [(CpuMode, &str); 4] = [
+    (CpuMode::Supervisor, "svc"),
+    (CpuMode::Abort, "abt"),
+    (CpuMode::Irq, "irq"),
+    (CpuMode::Undefined, "und"),
+];
+
+const SPSR_MODES: [(CpuMode, &str); 5] = [
+    (CpuMode::Fiq, "fiq"),
+    (CpuMode::Supervisor, "svc"),
+    (CpuMode::Abort, "abt"),
+    (CpuMode::Irq, "irq"),
+    (CpuMode::Undefined, "und"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    Fetch,
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transaction {
+    pub kind: TransactionKind,
+    // In bytes
+    pub size: u32,
+    pub addr: u32,
+    pub data: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestVector {
+    pub initial: CpuState,
+    pub final_state: CpuState,
+    pub transactions: Vec<Transaction>,
+    pub opcode: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub field: String,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+fn field_u32(json: &Json, key: &str) -> Result<u32, String> {
+    json.get(key)
+        .and_then(Json::as_u32)
+        .ok_or_else(|| format!("missing or invalid \"{}\"", key))
+}
+
+fn field_array(json: &Json, key: &str, out: &mut [u32]) -> Result<(), String> {
+    let values = json
+        .get(key)
+        .and_then(Json::as_array)
+        .filter(|values| values.len() == out.len())
+        .ok_or_else(|| format!("\"{}\" is not an array of {} numbers", key, out.len()))?;
+    for (o, v) in out.iter_mut().zip(values) {
+        *o = v
+            .as_u32()
+            .ok_or_else(|| format!("invalid number in \"{}\"", key))?;
+    }
+    Ok(())
+}
+
+impl CpuState {
+    pub(crate) fn from_json(json: &Json) -> Result<CpuState, String> {
+        let mut state = CpuState::default();
+        field_array(json, "R", &mut state.r)?;
+        field_array(json, "R_fiq", &mut state.r_fiq)?;
+        for (regs, (_, name)) in state.r_banked.iter_mut().zip(BANKED_MODES.iter()) {
+            field_array(json, &format!("R_{}", name), regs)?;
+        }
+        state.cpsr = field_u32(json, "CPSR")?;
+        field_array(json, "SPSR", &mut state.spsr)?;
+        field_array(json, "pipeline", &mut state.pipeline)?;
+        state.access = field_u32(json, "access")?;
+        Ok(state)
+    }
+
+    // Puts the CPU in this state, ready to execute pipeline[0].
+    pub fn load(&self, cpu: &mut ARMCpu) {
+        let mode = Psr(self.cpsr).mode();
+        for (&(m, _), spsr) in SPSR_MODES.iter().zip(self.spsr.iter()) {
+            cpu.switch_mode(m);
+            cpu.set_spsr(Psr(*spsr));
+        }
+        if mode != CpuMode::Fiq {
+            cpu.switch_mode(CpuMode::Fiq);
+            cpu.regs[8..15].copy_from_slice(&self.r_fiq);
+        }
+        for (&(m, _), regs) in BANKED_MODES.iter().zip(self.r_banked.iter()) {
+            if m != mode {
+                cpu.switch_mode(m);
+                cpu.regs[13..15].copy_from_slice(regs);
+            }
+        }
+        cpu.switch_mode(mode);
+        cpu.regs = self.r;
+        cpu.cpsr = Psr(self.cpsr);
+        cpu.pipeline = self.pipeline;
+        cpu.flushed = false;
+        cpu.next_fetch = if self.access & 1 != 0 {
+            MemoryAccess::Sequential
+        } else {
+            MemoryAccess::NonSequential
+        };
+    }
+
+    /*
+     * Compares the CPU against this state. The banked registers of the current mode are covered
+     * by "R", so only the banks of the other modes are compared. The CPU is left in an
+     * unspecified mode.
+     */
+    pub fn compare(&self, cpu: &mut ARMCpu) -> Vec<Mismatch> {
+        let mut mismatches = vec![];
+        let mut check = |field: String, expected: u32, actual: u32| {
+            if expected != actual {
+                mismatches.push(Mismatch {
+                    field,
+                    expected,
+                    actual,
+                });
+            }
+        };
+
+        for r in 0..16 {
+            check(format!("r{}", r), self.r[r], cpu.regs[r]);
+        }
+        check("cpsr".to_owned(), self.cpsr, cpu.cpsr.0);
+        check("pipeline[0]".to_owned(), self.pipeline[0], cpu.pipeline[0]);
+        check("pipeline[1]".to_owned(), self.pipeline[1], cpu.pipeline[1]);
+
+        let mode = cpu.cpsr.mode();
+        for (&(m, name), spsr) in SPSR_MODES.iter().zip(self.spsr.iter()) {
+            cpu.switch_mode(m);
+            check(format!("spsr_{}", name), *spsr, cpu.spsr().0);
+        }
+        if mode != CpuMode::Fiq {
+            cpu.switch_mode(CpuMode::Fiq);
+            for r in 8..15 {
+                check(format!("r{}_fiq", r), self.r_fiq[r - 8], cpu.regs[r]);
+            }
+        }
+        for (&(m, name), regs) in BANKED_MODES.iter().zip(self.r_banked.iter()) {
+            if m != mode {
+                cpu.switch_mode(m);
+                check(format!("r13_{}", name), regs[0], cpu.regs[13]);
+                check(format!("r14_{}", name), regs[1], cpu.regs[14]);
+            }
+        }
+        mismatches
+    }
+}
+
+impl Transaction {
+    pub(crate) fn from_json(json: &Json) -> Result<Transaction, String> {
+        let kind = match field_u32(json, "kind")? {
+            0 => TransactionKind::Fetch,
+            1 => TransactionKind::Read,
+            2 => TransactionKind::Write,
+            k => return Err(format!("invalid transaction kind {}", k)),
+        };
+        let size = field_u32(json, "size")?;
+        if size != 1 && size != 2 && size != 4 {
+            return Err(format!("invalid transaction size {}", size));
+        }
+        Ok(Transaction {
+            kind,
+            size,
+            addr: field_u32(json, "addr")?,
+            data: field_u32(json, "data")?,
+        })
+    }
+}
+
+impl TestVector {
+    pub(crate) fn from_json(json: &Json) -> Result<TestVector, String> {
+        let transactions = json
+            .get("transactions")
+            .and_then(Json::as_array)
+            .ok_or("missing \"transactions\"")?
+            .iter()
+            .map(Transaction::from_json)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TestVector {
+            initial: CpuState::from_json(json.get("initial").ok_or("missing \"initial\"")?)?,
+            final_state: CpuState::from_json(json.get("final").ok_or("missing \"final\"")?)?,
+            transactions,
+            opcode: field_u32(json, "opcode")?,
+        })
+    }
+
+    /*
+     * Executes the vector on a fresh CPU and returns every field that does not match the
+     * expected final state and transaction list.
+     */
+    pub fn run(&self) -> Vec<Mismatch> {
+        let mut cpu = ARMCpu::new();
+        self.initial.load(&mut cpu);
+        let mut bus = RecordingBus::new(&self.transactions);
+        cpu.step(&mut bus);
+
+        let mut mismatches = self.final_state.compare(&mut cpu);
+        let actual = bus.transactions;
+        if actual.len() != self.transactions.len() {
+            mismatches.push(Mismatch {
+                field: "transactions".to_owned(),
+                expected: self.transactions.len() as u32,
+                actual: actual.len() as u32,
+            });
+        }
+        for (n, (e, a)) in self.transactions.iter().zip(actual.iter()).enumerate() {
+            let fields = [
+                ("kind", e.kind as u32, a.kind as u32),
+                ("size", e.size, a.size),
+                ("addr", e.addr, a.addr),
+                ("data", e.data, a.data),
+            ];
+            for &(name, expected, actual) in fields.iter() {
+                if expected != actual {
+                    mismatches.push(Mismatch {
+                        field: format!("transaction {} {}", n, name),
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        mismatches
+    }
+}
+
+// Reads a file holding a JSON array of test vectors.
+pub fn load_vectors(path: &Path) -> Result<Vec<TestVector>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    parse_vectors(&text).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+pub fn parse_vectors(text: &str) -> Result<Vec<TestVector>, String> {
+    Json::parse(text)?
+        .as_array()
+        .ok_or("expected an array of test vectors")?
+        .iter()
+        .enumerate()
+        .map(|(n, v)| TestVector::from_json(v).map_err(|err| format!("vector {}: {}", n, err)))
+        .collect()
+}
+
+/*
+ * Answers reads from the expected transactions and records every access the CPU makes.
+ */
+struct RecordingBus<'a> {
+    expected: &'a [Transaction],
+    used: Vec<bool>,
+    transactions: Vec<Transaction>,
+}
+
+impl<'a> RecordingBus<'a> {
+    fn new(expected: &'a [Transaction]) -> Self {
+        RecordingBus {
+            expected,
+            used: vec![false; expected.len()],
+            transactions: vec![],
+        }
+    }
+
+    fn read(&mut self, kind: TransactionKind, size: u32, addr: u32) -> u32 {
+        let found = self.expected.iter().enumerate().position(|(n, t)| {
+            !self.used[n] && t.kind != TransactionKind::Write && t.size == size && t.addr == addr
+        });
+        let data = match found {
+            Some(n) => {
+                self.used[n] = true;
+                self.expected[n].data
+            }
+            None => 0,
+        };
+        self.transactions.push(Transaction {
+            kind,
+            size,
+            addr,
+            data,
+        });
+        data
+    }
+
+    fn write(&mut self, size: u32, addr: u32, data: u32) {
+        self.transactions.push(Transaction {
+            kind: TransactionKind::Write,
+            size,
+            addr,
+            data,
+        });
+    }
+}
+
+impl<'a> Bus for RecordingBus<'a> {
+    fn read_8(&mut self, addr: u32, _access: MemoryAccess) -> u8 {
+        self.read(TransactionKind::Read, 1, addr) as u8
+    }
+
+    fn read_16(&mut self, addr: u32, _access: MemoryAccess) -> u16 {
+        self.read(TransactionKind::Read, 2, addr) as u16
+    }
+
+    fn read_32(&mut self, addr: u32, _access: MemoryAccess) -> u32 {
+        self.read(TransactionKind::Read, 4, addr)
+    }
+
+    fn write_8(&mut self, addr: u32, value: u8, _access: MemoryAccess) {
+        self.write(1, addr, value as u32);
+    }
+
+    fn write_16(&mut self, addr: u32, value: u16, _access: MemoryAccess) {
+        self.write(2, addr, value as u32);
+    }
+
+    fn write_32(&mut self, addr: u32, value: u32, _access: MemoryAccess) {
+        self.write(4, addr, value);
+    }
+
+    fn fetch_16(&mut self, addr: u32, _access: MemoryAccess) -> u16 {
+        self.read(TransactionKind::Fetch, 2, addr) as u16
+    }
+
+    fn fetch_32(&mut self, addr: u32, _access: MemoryAccess) -> u32 {
+        self.read(TransactionKind::Fetch, 4, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a cpu state in System mode with everything else zero.
+    fn state_json(r: &[u32; 16], cpsr: u32, pipeline: [u32; 2]) -> String {
+        let r: Vec<String> = r.iter().map(|v| v.to_string()).collect();
+        format!(
+            r#"{{"R": [{}], "R_fiq": [0, 0, 0, 0, 0, 0, 0], "R_svc": [0, 0], "R_abt": [0, 0],
+                "R_irq": [0, 0], "R_und": [0, 0], "CPSR": {}, "SPSR": [0, 0, 0, 0, 0],
+                "pipeline": [{}, {}], "access": 1}}"#,
+            r.join(", "),
+            cpsr,
+            pipeline[0],
+            pipeline[1]
+        )
+    }
+
+    #[test]
+    fn test_run_vectors() {
+        // ldr r0, [r1] at 0x100, r1 = 0x2000
+        let mut r = [0u32; 16];
+        r[1] = 0x2000;
+        r[15] = 0x108;
+        let initial = state_json(&r, 0x1F, [0xe5910000, 0xe1a00000]);
+        r[0] = 0x1234_5678;
+        r[15] = 0x10C;
+        let expected = state_json(&r, 0x1F, [0xe1a00000, 0xe3a00001]);
+        let transactions = r#"[
+            {"kind": 0, "size": 4, "addr": 264, "data": 3818913793, "cycle": 1, "access": 2},
+            {"kind": 1, "size": 4, "addr": 8192, "data": 305419896, "cycle": 2, "access": 0}
+        ]"#;
+        let text = format!(
+            r#"[{{"initial": {}, "final": {}, "transactions": {}, "opcode": 3851485184}}]"#,
+            initial, expected, transactions
+        );
+        let vectors = parse_vectors(&text).unwrap();
+        assert_eq!(vectors.len(), 1);
+        assert_eq!(vectors[0].run(), vec![]);
+
+        // A wrong expectation is reported per field
+        let mut vector = vectors[0].clone();
+        vector.final_state.r[0] = 0;
+        vector.final_state.r_banked[0][1] = 5;
+        vector.transactions[1].data = 0x1111_1111;
+        let mismatches = vector.run();
+        let fields: Vec<&str> = mismatches.iter().map(|m| m.field.as_str()).collect();
+        assert_eq!(fields, vec!["r0", "r14_svc"]);
+        assert_eq!(mismatches[0].actual, 0x1111_1111);
+
+        assert!(parse_vectors("{}").is_err());
+        assert!(parse_vectors(r#"[{"initial": {}}]"#).is_err());
+    }
+
+    #[test]
+    fn test_banked_state() {
+        let mut state = CpuState {
+            cpsr: CpuMode::Irq as u32,
+            r_fiq: [8, 9, 10, 11, 12, 13, 14],
+            ..Default::default()
+        };
+        state.r[13] = 0x0300_7FA0;
+        state.r_banked[0] = [0x0300_7FE0, 0x100];
+        state.spsr[3] = 0x1F;
+
+        let mut cpu = ARMCpu::new();
+        state.load(&mut cpu);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Irq);
+        assert_eq!(cpu.reg(13), 0x0300_7FA0);
+        assert_eq!(cpu.spsr().0, 0x1F);
+        assert_eq!(state.compare(&mut cpu), vec![]);
+    }
+}
@@ -1,7 +1,638 @@
+use super::alu::{add_with_carry, multiply_cycles, shift, sub_with_carry};
+use super::arm::{ARMCpu, BlockDataTransferInstr, DataProcessingOpCode, ShiftType};
+use super::bus::{Bus, MemoryAccess};
+use super::ConditionField;
+use std::convert::TryInto;
+use util::get_bits;
+
 pub struct Instruction(u16);
 
 impl Instruction {
-    fn new(i: u16) -> Self {
+    pub fn new(i: u16) -> Self {
         Instruction(i) 
     }
+
+    pub fn thumb_type(&self) -> ThumbType {
+        thumb_type(self.0)
+    }
+}
+
+/*
+ * THUMB instruction formats. See ARM7TDMI Reference 5.1
+ *
+ * THUMB opcodes are 16 bits wide and most of them are a compressed form of an ARM instruction,
+ * only r0-r7 are directly addressable except for the hi register operations, and only the
+ * conditional branch is conditional.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbType {
+    MoveShiftedRegister,
+    AddSubtract,
+    MoveCompareAddSubtractImm,
+    AluOperations,
+    HiRegisterOperationsBx,
+    PcRelativeLoad,
+    LoadStoreRegOffset,
+    LoadStoreSignExtended,
+    LoadStoreImmOffset,
+    LoadStoreHalfword,
+    SpRelativeLoadStore,
+    LoadAddress,
+    AddOffsetToSp,
+    PushPopRegisters,
+    MultipleLoadStore,
+    ConditionalBranch,
+    SoftwareInterrupt,
+    UnconditionalBranch,
+    LongBranchWithLink,
+    Undefined,
+}
+
+/*
+ * The order of the checks follows the format table in ARM7TDMI Reference 5.1, with the more
+ * specific encodings tested first.
+ */
+pub fn thumb_type(i: u16) -> ThumbType {
+    let i = i as u32;
+    let bits15_13 = get_bits(i, 13, 15);
+    let bits15_11 = get_bits(i, 11, 15);
+    let bits15_12 = get_bits(i, 12, 15);
+    let bits15_10 = get_bits(i, 10, 15);
+    let bits15_8 = get_bits(i, 8, 15);
+
+    if bits15_11 == 0b00011 {
+        return ThumbType::AddSubtract;
+    }
+
+    if bits15_13 == 0b000 {
+        return ThumbType::MoveShiftedRegister;
+    }
+
+    if bits15_13 == 0b001 {
+        return ThumbType::MoveCompareAddSubtractImm;
+    }
+
+    if bits15_10 == 0b010000 {
+        return ThumbType::AluOperations;
+    }
+
+    if bits15_10 == 0b010001 {
+        return ThumbType::HiRegisterOperationsBx;
+    }
+
+    if bits15_11 == 0b01001 {
+        return ThumbType::PcRelativeLoad;
+    }
+
+    if bits15_12 == 0b0101 && get_bits(i, 9, 9) == 0 {
+        return ThumbType::LoadStoreRegOffset;
+    }
+
+    if bits15_12 == 0b0101 {
+        return ThumbType::LoadStoreSignExtended;
+    }
+
+    if bits15_13 == 0b011 {
+        return ThumbType::LoadStoreImmOffset;
+    }
+
+    if bits15_12 == 0b1000 {
+        return ThumbType::LoadStoreHalfword;
+    }
+
+    if bits15_12 == 0b1001 {
+        return ThumbType::SpRelativeLoadStore;
+    }
+
+    if bits15_12 == 0b1010 {
+        return ThumbType::LoadAddress;
+    }
+
+    if bits15_8 == 0b1011_0000 {
+        return ThumbType::AddOffsetToSp;
+    }
+
+    if bits15_12 == 0b1011 && get_bits(i, 9, 10) == 0b10 {
+        return ThumbType::PushPopRegisters;
+    }
+
+    if bits15_12 == 0b1100 {
+        return ThumbType::MultipleLoadStore;
+    }
+
+    if bits15_8 == 0b1101_1111 {
+        return ThumbType::SoftwareInterrupt;
+    }
+
+    // Condition 0b1110 is undefined in THUMB state
+    if bits15_12 == 0b1101 && bits15_8 != 0b1101_1110 {
+        return ThumbType::ConditionalBranch;
+    }
+
+    if bits15_11 == 0b11100 {
+        return ThumbType::UnconditionalBranch;
+    }
+
+    if bits15_12 == 0b1111 {
+        return ThumbType::LongBranchWithLink;
+    }
+
+    ThumbType::Undefined
+}
+
+// Sign extends the bottom `bits` bits of v.
+fn sign_extend(v: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((v << shift) as i32) >> shift) as u32
+}
+
+/*
+ * THUMB state execution.
+ *
+ * While a THUMB instruction executes r15 = address of the instruction + 4.
+ */
+impl ARMCpu {
+    pub(crate) fn execute_thumb<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u16) {
+        self.execute_thumb_decoded(bus, i, thumb_type(i))
+    }
+
+    // Executes an opcode already decoded by thumb_type, e.g. from the block cache.
+    pub(crate) fn execute_thumb_decoded<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        i: u16,
+        kind: ThumbType,
+    ) {
+        match kind {
+            ThumbType::MoveShiftedRegister => self.thumb_move_shifted_register(i as u32),
+            ThumbType::AddSubtract => self.thumb_add_subtract(i as u32),
+            ThumbType::MoveCompareAddSubtractImm => {
+                self.thumb_move_compare_add_subtract_imm(i as u32)
+            }
+            ThumbType::AluOperations => self.thumb_alu_operation(bus, i as u32),
+            ThumbType::HiRegisterOperationsBx => self.thumb_hi_register_operation(i as u32),
+            ThumbType::PcRelativeLoad => self.thumb_pc_relative_load(bus, i as u32),
+            ThumbType::LoadStoreRegOffset => self.thumb_load_store_reg_offset(bus, i as u32),
+            ThumbType::LoadStoreSignExtended => self.thumb_load_store_sign_extended(bus, i as u32),
+            ThumbType::LoadStoreImmOffset => self.thumb_load_store_imm_offset(bus, i as u32),
+            ThumbType::LoadStoreHalfword => self.thumb_load_store_halfword(bus, i as u32),
+            ThumbType::SpRelativeLoadStore => self.thumb_sp_relative_load_store(bus, i as u32),
+            ThumbType::LoadAddress => self.thumb_load_address(i as u32),
+            ThumbType::AddOffsetToSp => self.thumb_add_offset_to_sp(i as u32),
+            ThumbType::PushPopRegisters => self.thumb_push_pop(bus, i as u32),
+            ThumbType::MultipleLoadStore => self.thumb_multiple_load_store(bus, i as u32),
+            ThumbType::ConditionalBranch => self.thumb_conditional_branch(i as u32),
+            ThumbType::SoftwareInterrupt => self.software_interrupt(),
+            ThumbType::UnconditionalBranch => self.thumb_unconditional_branch(i as u32),
+            ThumbType::LongBranchWithLink => self.thumb_long_branch_with_link(i as u32),
+            ThumbType::Undefined => self.undefined_instruction(),
+        }
+    }
+
+    // Format 1: LSL/LSR/ASR rd, rs, #offset5
+    fn thumb_move_shifted_register(&mut self, i: u32) {
+        let shift_type: ShiftType = get_bits(i, 11, 12).try_into().unwrap();
+        let amount = get_bits(i, 6, 10);
+        let rs = self.regs[get_bits(i, 3, 5) as usize];
+        let (result, carry) = shift(shift_type, rs, amount, self.cpsr.c(), true);
+        self.cpsr.set_nz(result);
+        self.cpsr.set_c(carry);
+        self.regs[get_bits(i, 0, 2) as usize] = result;
+    }
+
+    // Format 2: ADD/SUB rd, rs, rn/#offset3
+    fn thumb_add_subtract(&mut self, i: u32) {
+        let immediate = get_bits(i, 10, 10) == 1;
+        let sub = get_bits(i, 9, 9) == 1;
+        let operand = if immediate {
+            get_bits(i, 6, 8)
+        } else {
+            self.regs[get_bits(i, 6, 8) as usize]
+        };
+        let rs = self.regs[get_bits(i, 3, 5) as usize];
+        let (result, c, v) = if sub {
+            sub_with_carry(rs, operand, true)
+        } else {
+            add_with_carry(rs, operand, false)
+        };
+        self.set_nzcv(result, c, v);
+        self.regs[get_bits(i, 0, 2) as usize] = result;
+    }
+
+    // Format 3: MOV/CMP/ADD/SUB rd, #offset8
+    fn thumb_move_compare_add_subtract_imm(&mut self, i: u32) {
+        let rd = get_bits(i, 8, 10) as usize;
+        let imm = get_bits(i, 0, 7);
+        let value = self.regs[rd];
+        match get_bits(i, 11, 12) {
+            0b00 => {
+                self.cpsr.set_nz(imm);
+                self.regs[rd] = imm;
+            }
+            0b01 => {
+                let (result, c, v) = sub_with_carry(value, imm, true);
+                self.set_nzcv(result, c, v);
+            }
+            0b10 => {
+                let (result, c, v) = add_with_carry(value, imm, false);
+                self.set_nzcv(result, c, v);
+                self.regs[rd] = result;
+            }
+            _ => {
+                let (result, c, v) = sub_with_carry(value, imm, true);
+                self.set_nzcv(result, c, v);
+                self.regs[rd] = result;
+            }
+        }
+    }
+
+    fn set_nzcv(&mut self, result: u32, c: bool, v: bool) {
+        self.cpsr.set_nz(result);
+        self.cpsr.set_c(c);
+        self.cpsr.set_v(v);
+    }
+
+    /*
+     * Format 4: ALU operations on r0-r7
+     *
+     * Opcode  0 AND, 1 EOR, 2 LSL, 3 LSR, 4 ASR, 5 ADC, 6 SBC, 7 ROR,
+     *         8 TST, 9 NEG, A CMP, B CMN, C ORR, D MUL, E BIC, F MVN
+     */
+    fn thumb_alu_operation<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let op = get_bits(i, 6, 9);
+        let rs = self.regs[get_bits(i, 3, 5) as usize];
+        let rd = get_bits(i, 0, 2) as usize;
+        let value = self.regs[rd];
+        let carry = self.cpsr.c();
+
+        let shift_type = match op {
+            0x2 => Some(ShiftType::LogicalLeft),
+            0x3 => Some(ShiftType::LogicalRight),
+            0x4 => Some(ShiftType::ArithmeticRight),
+            0x7 => Some(ShiftType::RotateRight),
+            _ => None,
+        };
+        if let Some(shift_type) = shift_type {
+            self.idle(bus, 1);
+            let (result, c) = shift(shift_type, value, rs & 0xFF, carry, false);
+            self.cpsr.set_nz(result);
+            self.cpsr.set_c(c);
+            self.regs[rd] = result;
+            return;
+        }
+
+        if op == 0xD {
+            let result = value.wrapping_mul(rs);
+            self.idle(bus, multiply_cycles(rs, true));
+            self.cpsr.set_nz(result);
+            self.regs[rd] = result;
+            return;
+        }
+
+        let (opcode, rn, op2) = match op {
+            0x0 => (DataProcessingOpCode::And, value, rs),
+            0x1 => (DataProcessingOpCode::Eor, value, rs),
+            0x5 => (DataProcessingOpCode::Adc, value, rs),
+            0x6 => (DataProcessingOpCode::Sbc, value, rs),
+            0x8 => (DataProcessingOpCode::Tst, value, rs),
+            0x9 => (DataProcessingOpCode::Rsb, rs, 0),
+            0xA => (DataProcessingOpCode::Cmp, value, rs),
+            0xB => (DataProcessingOpCode::Cmn, value, rs),
+            0xC => (DataProcessingOpCode::Orr, value, rs),
+            0xE => (DataProcessingOpCode::Bic, value, rs),
+            _ => (DataProcessingOpCode::Mvn, value, rs),
+        };
+        let (result, c, v) = self.alu(opcode, rn, op2, carry);
+        self.set_nzcv(result, c, v);
+        if !opcode.is_test() {
+            self.regs[rd] = result;
+        }
+    }
+
+    // Format 5: ADD/CMP/MOV with a hi register (r8-r15) operand, and BX
+    fn thumb_hi_register_operation(&mut self, i: u32) {
+        let op = get_bits(i, 8, 9);
+        let rs = (get_bits(i, 6, 6) << 3 | get_bits(i, 3, 5)) as usize;
+        let rd = (get_bits(i, 7, 7) << 3 | get_bits(i, 0, 2)) as usize;
+        let source = self.regs[rs];
+        match op {
+            0b00 => {
+                let result = self.regs[rd].wrapping_add(source);
+                self.set_reg(rd, result);
+            }
+            0b01 => {
+                let (result, c, v) = sub_with_carry(self.regs[rd], source, true);
+                self.set_nzcv(result, c, v);
+            }
+            0b10 => self.set_reg(rd, source),
+            _ => {
+                self.cpsr.set_thumb(source & 1 == 1);
+                self.set_reg(ARMCpu::PC, source & !1);
+            }
+        }
+    }
+
+    // Format 6: LDR rd, [pc, #word8], pc has bit 1 cleared
+    fn thumb_pc_relative_load<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let addr = (self.regs[ARMCpu::PC] & !3).wrapping_add(get_bits(i, 0, 7) << 2);
+        let value = self.read_32(bus, addr, MemoryAccess::NonSequential);
+        self.idle(bus, 1);
+        self.regs[get_bits(i, 8, 10) as usize] = value;
+    }
+
+    // Format 7: STR/STRB/LDR/LDRB rd, [rb, ro]
+    fn thumb_load_store_reg_offset<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let load = get_bits(i, 11, 11) == 1;
+        let byte = get_bits(i, 10, 10) == 1;
+        let addr = self.regs[get_bits(i, 3, 5) as usize]
+            .wrapping_add(self.regs[get_bits(i, 6, 8) as usize]);
+        self.thumb_load_store(bus, load, byte, addr, get_bits(i, 0, 2) as usize);
+    }
+
+    // Format 9: STR/STRB/LDR/LDRB rd, [rb, #offset5], word offsets are in units of 4
+    fn thumb_load_store_imm_offset<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let byte = get_bits(i, 12, 12) == 1;
+        let load = get_bits(i, 11, 11) == 1;
+        let offset = if byte {
+            get_bits(i, 6, 10)
+        } else {
+            get_bits(i, 6, 10) << 2
+        };
+        let addr = self.regs[get_bits(i, 3, 5) as usize].wrapping_add(offset);
+        self.thumb_load_store(bus, load, byte, addr, get_bits(i, 0, 2) as usize);
+    }
+
+    fn thumb_load_store<B: Bus + ?Sized>(
+        &mut self,
+        bus: &mut B,
+        load: bool,
+        byte: bool,
+        addr: u32,
+        rd: usize,
+    ) {
+        if load {
+            let value = if byte {
+                self.read_8(bus, addr, MemoryAccess::NonSequential) as u32
+            } else {
+                self.read_32_rotated(bus, addr)
+            };
+            self.idle(bus, 1);
+            self.regs[rd] = value;
+        } else if byte {
+            self.write_8(bus, addr, self.regs[rd] as u8, MemoryAccess::NonSequential);
+        } else {
+            self.write_32(bus, addr & !3, self.regs[rd], MemoryAccess::NonSequential);
+        }
+    }
+
+    // Format 8: STRH/LDSB/LDRH/LDSH rd, [rb, ro]
+    fn thumb_load_store_sign_extended<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let addr = self.regs[get_bits(i, 3, 5) as usize]
+            .wrapping_add(self.regs[get_bits(i, 6, 8) as usize]);
+        let rd = get_bits(i, 0, 2) as usize;
+        let value = match get_bits(i, 10, 11) {
+            0b00 => {
+                self.write_16(
+                    bus,
+                    addr & !1,
+                    self.regs[rd] as u16,
+                    MemoryAccess::NonSequential,
+                );
+                return;
+            }
+            0b01 => self.read_8(bus, addr, MemoryAccess::NonSequential) as i8 as i32 as u32,
+            0b10 => self.read_16_rotated(bus, addr),
+            _ => self.read_16_signed(bus, addr),
+        };
+        self.idle(bus, 1);
+        self.regs[rd] = value;
+    }
+
+    // Format 10: STRH/LDRH rd, [rb, #offset5 * 2]
+    fn thumb_load_store_halfword<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let addr = self.regs[get_bits(i, 3, 5) as usize].wrapping_add(get_bits(i, 6, 10) << 1);
+        let rd = get_bits(i, 0, 2) as usize;
+        if get_bits(i, 11, 11) == 1 {
+            let value = self.read_16_rotated(bus, addr);
+            self.idle(bus, 1);
+            self.regs[rd] = value;
+        } else {
+            self.write_16(
+                bus,
+                addr & !1,
+                self.regs[rd] as u16,
+                MemoryAccess::NonSequential,
+            );
+        }
+    }
+
+    // Format 11: STR/LDR rd, [sp, #word8]
+    fn thumb_sp_relative_load_store<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let addr = self.regs[ARMCpu::SP].wrapping_add(get_bits(i, 0, 7) << 2);
+        let load = get_bits(i, 11, 11) == 1;
+        self.thumb_load_store(bus, load, false, addr, get_bits(i, 8, 10) as usize);
+    }
+
+    // Format 12: ADD rd, pc/sp, #word8
+    fn thumb_load_address(&mut self, i: u32) {
+        let base = if get_bits(i, 11, 11) == 1 {
+            self.regs[ARMCpu::SP]
+        } else {
+            self.regs[ARMCpu::PC] & !3
+        };
+        self.regs[get_bits(i, 8, 10) as usize] = base.wrapping_add(get_bits(i, 0, 7) << 2);
+    }
+
+    // Format 13: ADD sp, #+/-word7
+    fn thumb_add_offset_to_sp(&mut self, i: u32) {
+        let offset = get_bits(i, 0, 6) << 2;
+        let sp = self.regs[ARMCpu::SP];
+        self.regs[ARMCpu::SP] = if get_bits(i, 7, 7) == 1 {
+            sp.wrapping_sub(offset)
+        } else {
+            sp.wrapping_add(offset)
+        };
+    }
+
+    // Format 14: PUSH {rlist, lr} is STMDB sp!, POP {rlist, pc} is LDMIA sp!
+    fn thumb_push_pop<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let load = get_bits(i, 11, 11) == 1;
+        let mut rlist = get_bits(i, 0, 7) as u16;
+        if get_bits(i, 8, 8) == 1 {
+            rlist |= if load {
+                1 << ARMCpu::PC
+            } else {
+                1 << ARMCpu::LR
+            };
+        }
+        let instr = BlockDataTransferInstr {
+            i,
+            cond: ConditionField::new(ConditionField::COND_AL),
+            pre: !load,
+            u: load,
+            s: false,
+            w: true,
+            l: load,
+            rn: ARMCpu::SP as u8,
+            rlist,
+        };
+        self.execute_block_transfer(bus, &instr);
+    }
+
+    // Format 15: STMIA/LDMIA rb!, {rlist}
+    fn thumb_multiple_load_store<B: Bus + ?Sized>(&mut self, bus: &mut B, i: u32) {
+        let load = get_bits(i, 11, 11) == 1;
+        let instr = BlockDataTransferInstr {
+            i,
+            cond: ConditionField::new(ConditionField::COND_AL),
+            pre: false,
+            u: true,
+            s: false,
+            w: true,
+            l: load,
+            rn: get_bits(i, 8, 10) as u8,
+            rlist: get_bits(i, 0, 7) as u16,
+        };
+        self.execute_block_transfer(bus, &instr);
+    }
+
+    // Format 16: B<cond> label, offset8 in halfwords
+    fn thumb_conditional_branch(&mut self, i: u32) {
+        let cond = ConditionField::new(get_bits(i, 8, 11) as u8);
+        if cond.passes(&self.cpsr) {
+            let offset = sign_extend(get_bits(i, 0, 7), 8) << 1;
+            let target = self.regs[ARMCpu::PC].wrapping_add(offset);
+            self.set_reg(ARMCpu::PC, target);
+        }
+    }
+
+    // Format 18: B label, offset11 in halfwords
+    fn thumb_unconditional_branch(&mut self, i: u32) {
+        let offset = sign_extend(get_bits(i, 0, 10), 11) << 1;
+        let target = self.regs[ARMCpu::PC].wrapping_add(offset);
+        self.set_reg(ARMCpu::PC, target);
+    }
+
+    /*
+     * Format 19: BL label
+     *
+     * A pair of instructions. The first (H = 0) puts the high part of the offset plus the pc in
+     * lr. The second (H = 1) adds the low part, branches, and leaves the return address with
+     * bit 0 set in lr.
+     */
+    fn thumb_long_branch_with_link(&mut self, i: u32) {
+        let offset = get_bits(i, 0, 10);
+        let pc = self.regs[ARMCpu::PC];
+        if get_bits(i, 11, 11) == 0 {
+            self.regs[ARMCpu::LR] = pc.wrapping_add(sign_extend(offset, 11) << 12);
+        } else {
+            let target = self.regs[ARMCpu::LR].wrapping_add(offset << 1);
+            self.regs[ARMCpu::LR] = pc.wrapping_sub(2) | 1;
+            self.set_reg(ARMCpu::PC, target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::TestBus;
+    use crate::cpu::Exception;
+    use crate::psr::{CpuMode, Psr};
+
+    fn run(code: &[u16], steps: usize) -> (ARMCpu, TestBus) {
+        let mut bus = TestBus::with_thumb_code(code);
+        let mut cpu = ARMCpu::new();
+        let mut cpsr = Psr(CpuMode::System as u32);
+        cpsr.set_thumb(true);
+        cpu.set_cpsr(cpsr);
+        cpu.set_reg(ARMCpu::SP, 0x800);
+        for _ in 0..steps {
+            cpu.step(&mut bus);
+        }
+        (cpu, bus)
+    }
+
+    #[test]
+    fn test_thumb_type() {
+        assert_eq!(
+            Instruction::new(0x0088).thumb_type(),
+            ThumbType::MoveShiftedRegister
+        );
+        assert_eq!(thumb_type(0x1888), ThumbType::AddSubtract);
+        assert_eq!(thumb_type(0x2001), ThumbType::MoveCompareAddSubtractImm);
+        assert_eq!(thumb_type(0x4348), ThumbType::AluOperations);
+        assert_eq!(thumb_type(0x4770), ThumbType::HiRegisterOperationsBx);
+        assert_eq!(thumb_type(0x4801), ThumbType::PcRelativeLoad);
+        assert_eq!(thumb_type(0x5088), ThumbType::LoadStoreRegOffset);
+        assert_eq!(thumb_type(0x5e88), ThumbType::LoadStoreSignExtended);
+        assert_eq!(thumb_type(0x6808), ThumbType::LoadStoreImmOffset);
+        assert_eq!(thumb_type(0x8808), ThumbType::LoadStoreHalfword);
+        assert_eq!(thumb_type(0x9801), ThumbType::SpRelativeLoadStore);
+        assert_eq!(thumb_type(0xa001), ThumbType::LoadAddress);
+        assert_eq!(thumb_type(0xb082), ThumbType::AddOffsetToSp);
+        assert_eq!(thumb_type(0xb510), ThumbType::PushPopRegisters);
+        assert_eq!(thumb_type(0xc803), ThumbType::MultipleLoadStore);
+        assert_eq!(thumb_type(0xd0fe), ThumbType::ConditionalBranch);
+        assert_eq!(thumb_type(0xdf05), ThumbType::SoftwareInterrupt);
+        assert_eq!(thumb_type(0xde00), ThumbType::Undefined);
+        assert_eq!(thumb_type(0xe7fe), ThumbType::UnconditionalBranch);
+        assert_eq!(thumb_type(0xf000), ThumbType::LongBranchWithLink);
+        assert_eq!(thumb_type(0xe800), ThumbType::Undefined);
+    }
+
+    #[test]
+    fn test_thumb_arithmetic() {
+        // movs r0, #5; movs r1, #7; subs r2, r0, r1; lsls r3, r1, #29; muls r1, r0
+        let (cpu, _) = run(&[0x2005, 0x2107, 0x1a42, 0x074b, 0x4341], 5);
+        assert_eq!(cpu.reg(2), 0xFFFF_FFFE);
+        assert_eq!(cpu.reg(3), 0xE000_0000);
+        assert_eq!(cpu.reg(1), 35);
+        assert!(!cpu.cpsr().n());
+    }
+
+    #[test]
+    fn test_thumb_push_pop() {
+        // movs r4, #0x11; push {r4, lr}; movs r4, #0; pop {r4}; add sp, #4
+        let (cpu, bus) = run(&[0x2411, 0xb510, 0x2400, 0xbc10, 0xb001], 5);
+        assert_eq!(bus.mem[0x7F8], 0x11);
+        assert_eq!(cpu.reg(4), 0x11);
+        assert_eq!(cpu.reg(ARMCpu::SP), 0x800);
+    }
+
+    #[test]
+    fn test_thumb_bl_and_bx_to_arm() {
+        // bl 0x10; ...; at 0x10: bx pc (to ARM 0x14)
+        let mut code = vec![0xf000, 0xf806, 0, 0, 0, 0, 0, 0];
+        code.push(0x4778);
+        let (cpu, _) = run(&code, 3);
+        assert_eq!(cpu.reg(ARMCpu::LR), 0x5);
+        assert!(!cpu.is_thumb());
+        assert_eq!(cpu.next_pc(), 0x14);
+    }
+
+    #[test]
+    fn test_thumb_load_store() {
+        // movs r0, #0x80; movs r1, #0xFF; strh r1, [r0, #2]; movs r1, #2; ldrsh r2, [r0, r1]
+        // ldr r3, [pc, #0] loads the word at 0xC
+        let (cpu, bus) = run(
+            &[
+                0x2080, 0x21ff, 0x8041, 0x2102, 0x5e42, 0x4b00, 0x5678, 0x1234,
+            ],
+            6,
+        );
+        assert_eq!(bus.mem[0x82], 0xFF);
+        assert_eq!(cpu.reg(2), 0xFF);
+        assert_eq!(cpu.reg(3), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_thumb_undefined_traps_to_arm() {
+        let (cpu, _) = run(&[0xde00], 1);
+        assert_eq!(cpu.cpsr().mode(), CpuMode::Undefined);
+        assert!(!cpu.is_thumb());
+        assert!(cpu.spsr().thumb());
+        assert_eq!(cpu.next_pc(), Exception::Undefined.vector());
+        assert_eq!(cpu.reg(ARMCpu::LR), 2);
+    }
 }
@@ -0,0 +1,292 @@
+use super::disasm::{disassemble_arm_symbols, disassemble_thumb_lr};
+use super::psr::Psr;
+use std::io;
+use std::io::BufRead;
+use util::elf::Symbols;
+
+/*
+ * Instruction traces.
+ *
+ * A trace line is written for every executed instruction, before it executes, in the layout of
+ * mGBA's trace output so traces of both emulators can be compared line by line:
+ *
+ * r0 r1 ... r15 cpsr: CPSR | ADDRESS:  OPCODE\tdisassembly
+ *
+ * All values are 8 digit hex. r15 is the value the instruction sees (address + 8 in ARM state,
+ * + 4 in THUMB state). ARM opcodes are printed with 8 digits and THUMB opcodes with 4 digits
+ * padded to the same width, so the state can be read from the opcode column as well as from the
+ * T bit of the CPSR. Branch targets are shown with the symbol they are in, e.g. "<main+0x14>".
+ */
+pub fn format_trace_line(
+    regs: &[u32; 16],
+    cpsr: Psr,
+    addr: u32,
+    opcode: u32,
+    symbols: &Symbols,
+) -> String {
+    let mut line = String::with_capacity(200);
+    for r in regs.iter() {
+        line.push_str(&format!("{:08X} ", r));
+    }
+    line.push_str(&format!("cpsr: {:08X} | ", cpsr.0));
+    if cpsr.thumb() {
+        line.push_str(&format!(
+            "{:08X}:  {:04X}    \t{}",
+            addr,
+            opcode,
+            disassemble_thumb_lr(opcode as u16, addr, regs[14], symbols)
+        ));
+    } else {
+        line.push_str(&format!(
+            "{:08X}:  {:08X}\t{}",
+            addr,
+            opcode,
+            disassemble_arm_symbols(opcode, addr, symbols)
+        ));
+    }
+    line
+}
+
+/*
+ * The machine state fields of a trace line. The disassembly is left out since emulators do not
+ * agree on syntax.
+ */
+#[derive(Debug, PartialEq)]
+pub struct TraceLine {
+    pub regs: [u32; 16],
+    pub cpsr: u32,
+    pub addr: u32,
+    pub opcode: u32,
+    pub thumb: bool,
+}
+
+impl TraceLine {
+    pub fn parse(line: &str) -> Option<TraceLine> {
+        let (state, instr) = line.split_once('|')?;
+
+        let mut regs = [0u32; 16];
+        let mut fields = state.split_whitespace();
+        for r in regs.iter_mut() {
+            *r = u32::from_str_radix(fields.next()?, 16).ok()?;
+        }
+        if fields.next()? != "cpsr:" {
+            return None;
+        }
+        let cpsr = u32::from_str_radix(fields.next()?, 16).ok()?;
+
+        let mut fields = instr.split_whitespace();
+        let addr = u32::from_str_radix(fields.next()?.trim_end_matches(':'), 16).ok()?;
+        let opcode_str = fields.next()?;
+        let opcode = u32::from_str_radix(opcode_str, 16).ok()?;
+        Some(TraceLine {
+            regs,
+            cpsr,
+            addr,
+            opcode,
+            thumb: opcode_str.len() <= 4,
+        })
+    }
+
+    // Names of the fields that differ between two trace lines.
+    pub fn diff(&self, other: &TraceLine) -> Vec<String> {
+        let mut fields = vec![];
+        if self.addr != other.addr {
+            fields.push("address".to_owned());
+        }
+        if self.opcode != other.opcode || self.thumb != other.thumb {
+            fields.push("opcode".to_owned());
+        }
+        for (r, (a, b)) in self.regs.iter().zip(other.regs.iter()).enumerate() {
+            if a != b {
+                fields.push(format!("r{}", r));
+            }
+        }
+        if self.cpsr != other.cpsr {
+            fields.push("cpsr".to_owned());
+        }
+        fields
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    // 1 based line number
+    pub line: usize,
+    pub left: Option<String>,
+    pub right: Option<String>,
+    // The line before the divergence, which both traces agree on.
+    pub previous: Option<String>,
+    pub fields: Vec<String>,
+}
+
+/*
+ * Finds the first line where two traces disagree on machine state. Lines that are not trace
+ * lines (e.g. log output mixed into the trace) have to match exactly. A trace that ends before
+ * the other diverges at the first missing line.
+ */
+pub fn first_divergence<L: BufRead, R: BufRead>(
+    left: L,
+    right: R,
+) -> io::Result<Option<Divergence>> {
+    let mut left = left.lines();
+    let mut right = right.lines();
+    let mut previous = None;
+    let mut line = 0;
+    loop {
+        line += 1;
+        let l = left.next().transpose()?;
+        let r = right.next().transpose()?;
+        let fields = match (&l, &r) {
+            (None, None) => return Ok(None),
+            (Some(a), Some(b)) => match (TraceLine::parse(a), TraceLine::parse(b)) {
+                (Some(ta), Some(tb)) => ta.diff(&tb),
+                _ if a.trim_end() == b.trim_end() => vec![],
+                _ => vec!["line".to_owned()],
+            },
+            _ => vec!["length".to_owned()],
+        };
+        if !fields.is_empty() {
+            return Ok(Some(Divergence {
+                line,
+                left: l,
+                right: r,
+                previous,
+                fields,
+            }));
+        }
+        previous = l;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::tests::TestBus;
+    use crate::cpu::ARMCpu;
+    use crate::psr::CpuMode;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    // Collects trace output so the test can read it back while the CPU owns the writer.
+    #[derive(Clone)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_format_and_parse() {
+        let mut regs = [0u32; 16];
+        regs[13] = 0x0300_7F00;
+        regs[15] = 0x0800_0008;
+        let cpsr = Psr(CpuMode::System as u32);
+        let line = format_trace_line(&regs, cpsr, 0x0800_0000, 0xea00002e, &Symbols::default());
+        assert!(line.ends_with("cpsr: 0000001F | 08000000:  EA00002E\tb 0x080000c0"));
+        let parsed = TraceLine::parse(&line).unwrap();
+        assert_eq!(parsed.regs, regs);
+        assert_eq!(parsed.cpsr, 0x1F);
+        assert_eq!(parsed.addr, 0x0800_0000);
+        assert!(!parsed.thumb);
+
+        let mut cpsr = cpsr;
+        cpsr.set_thumb(true);
+        let line = format_trace_line(&regs, cpsr, 0x0800_0000, 0x2005, &Symbols::default());
+        assert!(line.contains("08000000:  2005    \tmovs r0, #5"));
+        assert!(TraceLine::parse(&line).unwrap().thumb);
+    }
+
+    #[test]
+    fn test_cpu_trace() {
+        // mov r0, #1; mov r1, #2
+        let mut bus = TestBus::with_code(&[0xe3a00001, 0xe3a01002]);
+        let mut cpu = ARMCpu::new();
+        let out = SharedBuf(Rc::new(RefCell::new(vec![])));
+        cpu.start_trace(Box::new(out.clone()));
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert!(cpu.stop_trace().is_some());
+        cpu.step(&mut bus);
+
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let second = TraceLine::parse(lines[1]).unwrap();
+        assert_eq!(second.addr, 4);
+        assert_eq!(second.regs[0], 1);
+        assert_eq!(second.regs[15], 12);
+        assert!(lines[1].ends_with("mov r1, #2"));
+    }
+
+    #[test]
+    fn test_trace_symbols() {
+        use util::elf::Symbol;
+
+        // bl func; func: movs r0, #5
+        let mut bus = TestBus::with_thumb_code(&[0xf000, 0xf802, 0x0000, 0x0000, 0x2005]);
+        let mut cpu = ARMCpu::new();
+        let mut cpsr = Psr(CpuMode::System as u32);
+        cpsr.set_thumb(true);
+        cpu.set_cpsr(cpsr);
+        cpu.set_symbols(Symbols::new(vec![Symbol {
+            name: "func".to_string(),
+            addr: 0x8,
+            size: 0x2,
+        }]));
+        let out = SharedBuf(Rc::new(RefCell::new(vec![])));
+        cpu.start_trace(Box::new(out.clone()));
+        for _ in 0..3 {
+            cpu.step(&mut bus);
+        }
+        cpu.stop_trace();
+
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[1].ends_with("bl 0x00000008 <func>"));
+        assert!(lines[2].contains("00000008:  2005"));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let mut regs = [0u32; 16];
+        let cpsr = Psr(CpuMode::System as u32);
+        let a0 = format_trace_line(&regs, cpsr, 0, 0xe3a00001, &Symbols::default());
+        regs[0] = 1;
+        let a1 = format_trace_line(&regs, cpsr, 4, 0xe3a01002, &Symbols::default());
+        regs[0] = 2;
+        let b1 = format_trace_line(&regs, cpsr, 4, 0xe3a01002, &Symbols::default());
+
+        let left = format!("{}\n{}\n", a0, a1);
+        let same = first_divergence(left.as_bytes(), left.as_bytes()).unwrap();
+        assert_eq!(same, None);
+
+        // Disassembly differences are not a divergence
+        let other_syntax = format!("{}\n{}\n", a0.replace("mov r0, #1", "MOV R0,#0x1"), a1);
+        assert_eq!(
+            first_divergence(left.as_bytes(), other_syntax.as_bytes()).unwrap(),
+            None
+        );
+
+        let right = format!("{}\n{}\n", a0, b1);
+        let d = first_divergence(left.as_bytes(), right.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.line, 2);
+        assert_eq!(d.fields, vec!["r0".to_owned()]);
+        assert_eq!(d.previous, Some(a0.clone()));
+
+        let short = format!("{}\n", a0);
+        let d = first_divergence(left.as_bytes(), short.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.line, 2);
+        assert_eq!(d.right, None);
+    }
+}
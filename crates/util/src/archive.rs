@@ -0,0 +1,659 @@
+use crate::crc32::crc32;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+/*
+ * ROMs packed in zip and gzip files, told apart from raw images by their first bytes rather than
+ * the file extension.
+ *
+ * zip   "PK\x03\x04", a local header and the data of each entry, then a central directory
+ *       listing the entries and an end record pointing to it. Entries are stored or deflated.
+ * gzip  0x1F 0x8B, a header with an optional original file name, one deflate stream, then the
+ *       CRC32 and size of the data.
+ *
+ * The CRC32 of the unpacked data is checked in both. Deflate itself is decoded by inflate below.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Container {
+    Raw,
+    Zip,
+    Gzip,
+}
+
+impl Container {
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(LOCAL_HEADER) || data.starts_with(END_RECORD) {
+            Container::Zip
+        } else if data.starts_with(&[0x1F, 0x8B]) {
+            Container::Gzip
+        } else {
+            Container::Raw
+        }
+    }
+}
+
+// Extensions of the zip entries picked when no entry is named.
+pub const ROM_EXTENSIONS: [&str; 2] = ["gba", "mb"];
+
+const LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+const CENTRAL_HEADER: &[u8] = b"PK\x01\x02";
+const END_RECORD: &[u8] = b"PK\x05\x06";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveError {
+    // The data ends in the middle of a header or stream.
+    Truncated,
+    // Invalid deflate data or zip structures.
+    Corrupt(&'static str),
+    // A compression method other than stored or deflate, or an encrypted entry.
+    Unsupported(String),
+    // No .gba or .mb entry in the zip file.
+    NoRom,
+    MissingEntry(String),
+    Checksum { expected: u32, actual: u32 },
+    // Unpacks to more than the limit given.
+    TooLarge { limit: usize },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Truncated => write!(f, "archive is truncated"),
+            ArchiveError::Corrupt(what) => write!(f, "archive is corrupt: {}", what),
+            ArchiveError::Unsupported(what) => write!(f, "unsupported {}", what),
+            ArchiveError::NoRom => write!(f, "no .gba or .mb file in the archive"),
+            ArchiveError::MissingEntry(name) => write!(f, "no {} in the archive", name),
+            ArchiveError::Checksum { expected, actual } => write!(
+                f,
+                "unpacked data has CRC32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            ArchiveError::TooLarge { limit } => {
+                write!(f, "unpacks to more than the {} byte maximum", limit)
+            }
+        }
+    }
+}
+
+impl Error for ArchiveError {}
+
+#[derive(Debug)]
+pub struct Extracted {
+    pub container: Container,
+    // The entry name in a zip file, the original file name if a gzip file has one.
+    pub name: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/*
+ * Unpacks a zip or gzip file, raw data is returned as is. entry names the zip entry to unpack,
+ * by default it is the first one with a ROM extension.
+ */
+pub fn extract(
+    data: Vec<u8>,
+    entry: Option<&str>,
+    limit: usize,
+) -> Result<Extracted, ArchiveError> {
+    let container = Container::detect(&data);
+    let (name, data) = match container {
+        Container::Raw => (None, data),
+        Container::Zip => unzip(&data, entry, limit)?,
+        Container::Gzip => gunzip(&data, limit)?,
+    };
+    Ok(Extracted {
+        container,
+        name,
+        data,
+    })
+}
+
+// A little endian number of len bytes at pos.
+fn field(data: &[u8], pos: usize, len: usize) -> Result<usize, ArchiveError> {
+    let bytes = pos
+        .checked_add(len)
+        .and_then(|end| data.get(pos..end))
+        .ok_or(ArchiveError::Truncated)?;
+    Ok(bytes.iter().rev().fold(0, |v, &b| v << 8 | b as usize))
+}
+
+fn check_crc(data: &[u8], expected: u32) -> Result<(), ArchiveError> {
+    let actual = crc32(data);
+    if expected != actual {
+        return Err(ArchiveError::Checksum { expected, actual });
+    }
+    Ok(())
+}
+
+fn is_rom_name(name: &str) -> bool {
+    // Skip the resource forks macOS adds next to each file
+    if name.starts_with("__MACOSX/") {
+        return false;
+    }
+    Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ROM_EXTENSIONS.iter().any(|r| e.eq_ignore_ascii_case(r)))
+}
+
+fn unzip(
+    data: &[u8],
+    entry: Option<&str>,
+    limit: usize,
+) -> Result<(Option<String>, Vec<u8>), ArchiveError> {
+    // The end record is the last thing in the file, only followed by a comment
+    let end = (0..=data.len().saturating_sub(22))
+        .rev()
+        .find(|&i| data[i..].starts_with(END_RECORD))
+        .ok_or(ArchiveError::Corrupt("no zip end record"))?;
+    let count = field(data, end + 10, 2)?;
+    let mut pos = field(data, end + 16, 4)?;
+    let mut found = None;
+    for _ in 0..count {
+        if !data
+            .get(pos..)
+            .is_some_and(|d| d.starts_with(CENTRAL_HEADER))
+        {
+            return Err(ArchiveError::Corrupt("bad zip central directory"));
+        }
+        let name_len = field(data, pos + 28, 2)?;
+        let name = data
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or(ArchiveError::Truncated)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        let wanted = match entry {
+            Some(entry) => name == entry,
+            None => is_rom_name(&name),
+        };
+        if wanted {
+            found = Some((name, pos));
+            break;
+        }
+        pos += 46 + name_len + field(data, pos + 30, 2)? + field(data, pos + 32, 2)?;
+    }
+    let (name, pos) = found.ok_or_else(|| match entry {
+        Some(entry) => ArchiveError::MissingEntry(entry.to_string()),
+        None => ArchiveError::NoRom,
+    })?;
+
+    if field(data, pos + 8, 2)? & 1 != 0 {
+        return Err(ArchiveError::Unsupported("encrypted zip entry".to_string()));
+    }
+    let method = field(data, pos + 10, 2)?;
+    let crc = field(data, pos + 16, 4)? as u32;
+    let compressed_size = field(data, pos + 20, 4)?;
+    if field(data, pos + 24, 4)? > limit {
+        return Err(ArchiveError::TooLarge { limit });
+    }
+    let local = field(data, pos + 42, 4)?;
+    if !data
+        .get(local..)
+        .is_some_and(|d| d.starts_with(LOCAL_HEADER))
+    {
+        return Err(ArchiveError::Corrupt("bad zip local header"));
+    }
+    // The local header has its own name and extra field lengths
+    let start = local + 30 + field(data, local + 26, 2)? + field(data, local + 28, 2)?;
+    let raw = data
+        .get(start..start + compressed_size)
+        .ok_or(ArchiveError::Truncated)?;
+    let unpacked = match method {
+        0 => raw.to_vec(),
+        8 => inflate(raw, limit)?.0,
+        method => {
+            return Err(ArchiveError::Unsupported(format!(
+                "zip compression method {}",
+                method
+            )))
+        }
+    };
+    check_crc(&unpacked, crc)?;
+    Ok((Some(name), unpacked))
+}
+
+const GZIP_HCRC: u8 = 1 << 1;
+const GZIP_EXTRA: u8 = 1 << 2;
+const GZIP_NAME: u8 = 1 << 3;
+const GZIP_COMMENT: u8 = 1 << 4;
+
+fn gunzip(data: &[u8], limit: usize) -> Result<(Option<String>, Vec<u8>), ArchiveError> {
+    let header = data.get(..10).ok_or(ArchiveError::Truncated)?;
+    if header[2] != 8 {
+        return Err(ArchiveError::Unsupported(format!(
+            "gzip compression method {}",
+            header[2]
+        )));
+    }
+    let flags = header[3];
+    let mut pos = 10;
+    if flags & GZIP_EXTRA != 0 {
+        pos += 2 + field(data, pos, 2)?;
+    }
+    // The name and comment are zero terminated
+    let text = |pos: &mut usize| -> Result<String, ArchiveError> {
+        let rest = data.get(*pos..).ok_or(ArchiveError::Truncated)?;
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(ArchiveError::Truncated)?;
+        *pos += len + 1;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    };
+    let name = if flags & GZIP_NAME != 0 {
+        Some(text(&mut pos)?)
+    } else {
+        None
+    };
+    if flags & GZIP_COMMENT != 0 {
+        text(&mut pos)?;
+    }
+    if flags & GZIP_HCRC != 0 {
+        pos += 2;
+    }
+
+    let (unpacked, used) = inflate(data.get(pos..).ok_or(ArchiveError::Truncated)?, limit)?;
+    let trailer = pos + used;
+    check_crc(&unpacked, field(data, trailer, 4)? as u32)?;
+    if field(data, trailer + 4, 4)? != unpacked.len() & 0xFFFF_FFFF {
+        return Err(ArchiveError::Corrupt("gzip size mismatch"));
+    }
+    Ok((name, unpacked))
+}
+
+/*
+ * Deflate, see RFC 1951. A stream is a series of blocks, each stored as is or compressed with
+ * Huffman codes, fixed ones or ones described at the start of the block. Compressed blocks
+ * contain literal bytes and copies of up to 258 bytes from up to 32KB back in the output.
+ *
+ * Bits are read from the least significant bit of each byte, Huffman codes most significant bit
+ * first. Codes are decoded a bit at a time from the number of codes of each length, like zlib's
+ * puff, which is slower than a lookup table but small.
+ */
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// The order the code length code lengths of a dynamic block are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const MAX_BITS: usize = 15;
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Bits {
+            data,
+            pos: 0,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, ArchiveError> {
+        while self.count < n {
+            let b = *self.data.get(self.pos).ok_or(ArchiveError::Truncated)?;
+            self.pos += 1;
+            self.buf |= (b as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Drops the rest of the current byte, bytes are only loaded as needed so that is all there is.
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+struct Huffman {
+    // Number of codes of each length.
+    counts: [u16; MAX_BITS + 1],
+    // Symbols ordered by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // The canonical code for the code length of each symbol, 0 for unused symbols.
+    fn new(lengths: &[u8]) -> Result<Self, ArchiveError> {
+        let mut counts = [0; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = 2 * left - count as i32;
+            if left < 0 {
+                return Err(ArchiveError::Corrupt("over-subscribed Huffman code"));
+            }
+        }
+        let mut offsets = [0; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, ArchiveError> {
+        // First code and index into symbols of the current length
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(ArchiveError::Corrupt("invalid Huffman code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literal = Huffman::new(&lengths).unwrap();
+    let distance = Huffman::new(&[5; 30]).unwrap();
+    (literal, distance)
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), ArchiveError> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    let mut lengths = [0; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+
+    // The code lengths of both codes, run length encoded
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (len, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or(ArchiveError::Corrupt("repeat with no code length"))?;
+                (previous, 3 + bits.bits(2)?)
+            }
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > literals + distances {
+            return Err(ArchiveError::Corrupt("too many code lengths"));
+        }
+        lengths.extend((0..repeat).map(|_| len));
+    }
+    if lengths[256] == 0 {
+        return Err(ArchiveError::Corrupt("no end of block code"));
+    }
+    let literal = Huffman::new(&lengths[..literals])?;
+    let distance = Huffman::new(&lengths[literals..])?;
+    Ok((literal, distance))
+}
+
+fn stored_block(bits: &mut Bits, out: &mut Vec<u8>, limit: usize) -> Result<(), ArchiveError> {
+    bits.align();
+    let len = field(bits.data, bits.pos, 2)?;
+    if field(bits.data, bits.pos + 2, 2)? != !len & 0xFFFF {
+        return Err(ArchiveError::Corrupt("stored block length mismatch"));
+    }
+    let start = bits.pos + 4;
+    let block = bits
+        .data
+        .get(start..start + len)
+        .ok_or(ArchiveError::Truncated)?;
+    if out.len() + len > limit {
+        return Err(ArchiveError::TooLarge { limit });
+    }
+    out.extend_from_slice(block);
+    bits.pos = start + len;
+    Ok(())
+}
+
+fn compressed_block(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    limit: usize,
+    literal: &Huffman,
+    distance: &Huffman,
+) -> Result<(), ArchiveError> {
+    loop {
+        let symbol = literal.decode(bits)? as usize;
+        let len = match symbol {
+            0..=255 => 1,
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(ArchiveError::Corrupt("invalid length code"));
+                }
+                LENGTH_BASE[i] as usize + bits.bits(LENGTH_EXTRA[i])? as usize
+            }
+        };
+        if out.len() + len > limit {
+            return Err(ArchiveError::TooLarge { limit });
+        }
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        let i = distance.decode(bits)? as usize;
+        if i >= DISTANCE_BASE.len() {
+            return Err(ArchiveError::Corrupt("invalid distance code"));
+        }
+        let back = DISTANCE_BASE[i] as usize + bits.bits(DISTANCE_EXTRA[i])? as usize;
+        if back > out.len() {
+            return Err(ArchiveError::Corrupt("distance too far back"));
+        }
+        // The copy can overlap the bytes it writes
+        let start = out.len() - back;
+        for i in start..start + len {
+            out.push(out[i]);
+        }
+    }
+}
+
+// Decodes a deflate stream, returns the data and the number of bytes the stream took.
+pub fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), ArchiveError> {
+    let mut bits = Bits::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => stored_block(&mut bits, &mut out, limit)?,
+            1 => {
+                let (literal, distance) = fixed_codes();
+                compressed_block(&mut bits, &mut out, limit, &literal, &distance)?
+            }
+            2 => {
+                let (literal, distance) = dynamic_codes(&mut bits)?;
+                compressed_block(&mut bits, &mut out, limit, &literal, &distance)?
+            }
+            _ => return Err(ArchiveError::Corrupt("invalid block type")),
+        }
+        if last {
+            return Ok((out, bits.pos));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: usize = 1 << 20;
+
+    // A deflate stream of one stored block.
+    fn stored(data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u16;
+        let mut out = vec![1];
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    // A zip file of (name, method, unpacked, packed) entries.
+    fn zip(entries: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, method, unpacked, packed) in entries {
+            let mut header = Vec::new();
+            header.extend_from_slice(&method.to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&crc32(unpacked).to_le_bytes());
+            header.extend_from_slice(&(packed.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(unpacked.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0; 2]);
+
+            central.extend_from_slice(CENTRAL_HEADER);
+            central.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            central.extend_from_slice(&header);
+            central.extend_from_slice(&[0; 10]);
+            central.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(LOCAL_HEADER);
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&header);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(packed);
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(END_RECORD);
+        out.extend_from_slice(&[0; 4]);
+        let count = entries.len() as u16;
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+        out
+    }
+
+    #[test]
+    fn test_inflate() {
+        let (data, used) = inflate(&stored(b"hello"), LIMIT).unwrap();
+        assert_eq!((data.as_slice(), used), (&b"hello"[..], 10));
+
+        // Fixed codes, with copies
+        let fixed = [
+            0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x90, 0x8A, 0x00,
+        ];
+        let (data, used) = inflate(&fixed, LIMIT).unwrap();
+        assert_eq!((data.as_slice(), used), (&b"hello hello hello!"[..], 11));
+
+        // Dynamic codes
+        let dynamic = [
+            0x25, 0x8B, 0xC1, 0x11, 0x00, 0x40, 0x0C, 0x01, 0x6B, 0x33, 0x1E, 0x1A, 0xD0, 0x7F,
+            0x2D, 0xB7, 0x72, 0xF9, 0x60, 0x89, 0xC4, 0xA5, 0xA9, 0x5A, 0xF7, 0x2C, 0x6E, 0xCC,
+            0x80, 0xDF, 0x25, 0x46, 0xAC, 0x3A, 0x80, 0xB5, 0x95, 0x67, 0xF8, 0x12, 0xCC, 0x54,
+            0xCC, 0x27, 0xE4, 0x0D, 0x6E, 0xC2, 0xCF, 0x25, 0x3F,
+        ];
+        let text = "AAAAAGTGTATTCTAAGTGATTAAAGCTTCAAAGTGGGCAGTCATCGAAATTAATACAATTGTAACGACCATGCT\
+                    CCATAACATTATAATAAGTACATTC";
+        assert_eq!(inflate(&dynamic, LIMIT).unwrap().0, text.as_bytes());
+
+        assert_eq!(
+            inflate(&fixed, 10).unwrap_err(),
+            ArchiveError::TooLarge { limit: 10 }
+        );
+        assert_eq!(
+            inflate(&fixed[..5], LIMIT).unwrap_err(),
+            ArchiveError::Truncated
+        );
+        assert!(matches!(
+            inflate(&[0x07], LIMIT),
+            Err(ArchiveError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut gz = vec![0x1F, 0x8B, 8, GZIP_NAME, 0, 0, 0, 0, 0, 3];
+        gz.extend_from_slice(b"game.mb\0");
+        gz.extend_from_slice(&stored(b"ROM"));
+        gz.extend_from_slice(&crc32(b"ROM").to_le_bytes());
+        gz.extend_from_slice(&3u32.to_le_bytes());
+        let extracted = extract(gz.clone(), None, LIMIT).unwrap();
+        assert_eq!(extracted.container, Container::Gzip);
+        assert_eq!(extracted.name.as_deref(), Some("game.mb"));
+        assert_eq!(extracted.data, b"ROM");
+
+        let len = gz.len();
+        gz[len - 8] ^= 1;
+        let err = extract(gz, None, LIMIT).unwrap_err();
+        assert!(matches!(err, ArchiveError::Checksum { .. }));
+    }
+
+    #[test]
+    fn test_zip() {
+        let fixed = [
+            0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x90, 0x8A, 0x00,
+        ];
+        let data = zip(&[
+            ("readme.txt", 0, b"read me", b"read me"),
+            ("__MACOSX/._game.gba", 0, b"fork", b"fork"),
+            ("game.gba", 8, b"hello hello hello!", &fixed),
+        ]);
+        assert_eq!(Container::detect(&data), Container::Zip);
+        let extracted = extract(data.clone(), None, LIMIT).unwrap();
+        assert_eq!(extracted.name.as_deref(), Some("game.gba"));
+        assert_eq!(extracted.data, b"hello hello hello!");
+        let extracted = extract(data.clone(), Some("readme.txt"), LIMIT).unwrap();
+        assert_eq!(extracted.data, b"read me");
+        assert_eq!(
+            extract(data, Some("other.gba"), LIMIT).unwrap_err(),
+            ArchiveError::MissingEntry("other.gba".to_string())
+        );
+
+        let data = zip(&[("readme.txt", 0, b"read me", b"read me")]);
+        assert_eq!(extract(data, None, LIMIT).unwrap_err(), ArchiveError::NoRom);
+        let data = zip(&[("game.gba", 14, b"lzma", b"lzma")]);
+        assert!(matches!(
+            extract(data, None, LIMIT),
+            Err(ArchiveError::Unsupported(_))
+        ));
+
+        assert_eq!(Container::detect(b"\x2E\0\0\xEA"), Container::Raw);
+    }
+}
@@ -0,0 +1,437 @@
+use std::error::Error;
+use std::fmt;
+
+/*
+ * ELF32 ARM executables as linked by devkitARM, before objcopy turns them into .gba files. See
+ * the System V ABI "ELF Object File Format" and the ARM ELF supplement.
+ *
+ * The program headers give the segments to load. Their physical address is where the data is
+ * stored, the ROM for a cartridge build even for code that crt0 copies to IWRAM at startup, EWRAM
+ * for a multiboot build. Segments with no data in the file (.bss) are skipped, RAM starts zeroed.
+ *
+ * The .symtab section names functions and variables, kept to print addresses as main+0x14.
+ */
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const CLASS_32: u8 = 1;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: usize = 2;
+const MACHINE_ARM: usize = 40;
+const PT_LOAD: usize = 1;
+const SHT_SYMTAB: usize = 2;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Rom,
+    Ewram,
+    Iwram,
+}
+
+impl Region {
+    pub fn of(addr: u32) -> Option<Self> {
+        match addr >> 24 {
+            0x02 => Some(Region::Ewram),
+            0x03 => Some(Region::Iwram),
+            0x08 | 0x09 => Some(Region::Rom),
+            _ => None,
+        }
+    }
+
+    pub fn base(self) -> u32 {
+        match self {
+            Region::Rom => 0x0800_0000,
+            Region::Ewram => 0x0200_0000,
+            Region::Iwram => 0x0300_0000,
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            Region::Rom => 32 * 1024 * 1024,
+            Region::Ewram => 256 * 1024,
+            Region::Iwram => 32 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub region: Region,
+    // Physical address.
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    // Offset of the segment from the start of its region.
+    pub fn offset(&self) -> usize {
+        (self.addr - self.region.base()) as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    // The Thumb bit of function addresses is cleared.
+    pub addr: u32,
+    // 0 for labels from assembly without a .size.
+    pub size: u32,
+}
+
+// Symbols sorted by address, one per address.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols(Vec<Symbol>);
+
+impl Symbols {
+    /*
+     * Of the symbols at the same address the first one is kept, put functions and variables
+     * before plain labels such as linker defined __iwram_start.
+     */
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.addr);
+        symbols.dedup_by_key(|s| s.addr);
+        Symbols(symbols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.0.iter().find(|s| s.name == name)
+    }
+
+    // The symbol an address is in and the offset into it.
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let i = self.0.partition_point(|s| s.addr <= addr).checked_sub(1)?;
+        let symbol = &self.0[i];
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    // "main" or "main+0x14".
+    pub fn format(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(symbol, offset)| match offset {
+            0 => symbol.name.clone(),
+            _ => format!("{}+0x{:x}", symbol.name, offset),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElfError {
+    // A header or table ends past the end of the file.
+    Truncated,
+    // Not a 32 bit little endian ARM file.
+    NotArm,
+    // A relocatable object or shared library rather than a linked executable.
+    NotExecutable,
+    // A segment outside of the ROM, EWRAM and IWRAM, or past their end.
+    BadSegment { addr: u32, len: usize },
+    // A segment in a region a ROM image cannot hold.
+    Unmappable { addr: u32 },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::NotArm => write!(f, "not a 32 bit little endian ARM ELF file"),
+            ElfError::NotExecutable => write!(f, "ELF file is not an executable"),
+            ElfError::BadSegment { addr, len } => write!(
+                f,
+                "{} byte segment at 0x{:08x} is not in ROM, EWRAM or IWRAM",
+                len, addr
+            ),
+            ElfError::Unmappable { addr } => write!(
+                f,
+                "segment at 0x{:08x} cannot be loaded from a ROM image",
+                addr
+            ),
+        }
+    }
+}
+
+impl Error for ElfError {}
+
+// A little endian number of len bytes at pos.
+fn field(data: &[u8], pos: usize, len: usize) -> Result<usize, ElfError> {
+    let bytes = pos
+        .checked_add(len)
+        .and_then(|end| data.get(pos..end))
+        .ok_or(ElfError::Truncated)?;
+    Ok(bytes.iter().rev().fold(0, |v, &b| v << 8 | b as usize))
+}
+
+fn slice(data: &[u8], pos: usize, len: usize) -> Result<&[u8], ElfError> {
+    pos.checked_add(len)
+        .and_then(|end| data.get(pos..end))
+        .ok_or(ElfError::Truncated)
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Symbols,
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        let ident = slice(data, 0, 16)?;
+        if !Elf::is_elf(ident) || ident[4] != CLASS_32 || ident[5] != LITTLE_ENDIAN {
+            return Err(ElfError::NotArm);
+        }
+        if field(data, 18, 2)? != MACHINE_ARM {
+            return Err(ElfError::NotArm);
+        }
+        if field(data, 16, 2)? != TYPE_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        let entry = field(data, 24, 4)? as u32;
+
+        let (phoff, phentsize, phnum) = (
+            field(data, 28, 4)?,
+            field(data, 42, 2)?,
+            field(data, 44, 2)?,
+        );
+        let mut segments = vec![];
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            let (offset, len) = (field(data, ph + 4, 4)?, field(data, ph + 16, 4)?);
+            if field(data, ph, 4)? != PT_LOAD || len == 0 {
+                continue;
+            }
+            let addr = field(data, ph + 12, 4)? as u32;
+            let region = Region::of(addr)
+                .filter(|r| (addr - r.base()) as usize + len <= r.size())
+                .ok_or(ElfError::BadSegment { addr, len })?;
+            segments.push(Segment {
+                region,
+                addr,
+                data: slice(data, offset, len)?.to_vec(),
+            });
+        }
+
+        let symbols = Elf::parse_symbols(data)?;
+        Ok(Elf {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    fn parse_symbols(data: &[u8]) -> Result<Symbols, ElfError> {
+        let (shoff, shentsize, shnum) = (
+            field(data, 32, 4)?,
+            field(data, 46, 2)?,
+            field(data, 48, 2)?,
+        );
+        let section = |i: usize| shoff + i * shentsize;
+        let symtab = match (0..shnum)
+            .map(section)
+            .find(|&sh| field(data, sh + 4, 4).ok() == Some(SHT_SYMTAB))
+        {
+            Some(sh) => sh,
+            None => return Ok(Symbols::default()),
+        };
+        let strtab = section(field(data, symtab + 24, 4)?);
+        let strings = slice(
+            data,
+            field(data, strtab + 16, 4)?,
+            field(data, strtab + 20, 4)?,
+        )?;
+        let table = slice(
+            data,
+            field(data, symtab + 16, 4)?,
+            field(data, symtab + 20, 4)?,
+        )?;
+
+        let mut symbols = vec![];
+        for sym in table.chunks_exact(16) {
+            let info = sym[12] & 0xF;
+            let rank = match info {
+                STT_FUNC | STT_OBJECT => 0,
+                STT_NOTYPE => 1,
+                _ => continue,
+            };
+            // Undefined symbols have section index 0
+            if field(sym, 14, 2)? == 0 {
+                continue;
+            }
+            let name = strings.get(field(sym, 0, 4)?..).unwrap_or_default();
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            // $a, $t and $d mark ARM code, Thumb code and data, they are not names
+            if name.is_empty() || name[0] == b'$' {
+                continue;
+            }
+            let mut addr = field(sym, 4, 4)? as u32;
+            if info == STT_FUNC {
+                addr &= !1;
+            }
+            let symbol = Symbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                addr,
+                size: field(sym, 8, 4)? as u32,
+            };
+            symbols.push((rank, symbol));
+        }
+        symbols.sort_by_key(|(rank, _)| *rank);
+        Ok(Symbols::new(symbols.into_iter().map(|(_, s)| s).collect()))
+    }
+
+    pub fn has_region(&self, region: Region) -> bool {
+        self.segments.iter().any(|s| s.region == region)
+    }
+
+    // The segments of a region laid out from its base address, gaps filled with zeros.
+    pub fn image(&self, region: Region) -> Vec<u8> {
+        let mut image = vec![];
+        for segment in self.segments.iter().filter(|s| s.region == region) {
+            let offset = segment.offset();
+            let end = offset + segment.data.len();
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[offset..end].copy_from_slice(&segment.data);
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An executable with the (physical address, data) segments and (name, value, size, type)
+    // symbols.
+    fn build(entry: u32, segments: &[(u32, &[u8])], symbols: &[(&str, u32, u32, u8)]) -> Vec<u8> {
+        let mut out = vec![0; 52];
+        out[..7].copy_from_slice(b"\x7FELF\x01\x01\x01");
+        out[16..20].copy_from_slice(&[2, 0, 40, 0]);
+        out[24..28].copy_from_slice(&entry.to_le_bytes());
+        let phoff = out.len();
+        out[28..32].copy_from_slice(&(phoff as u32).to_le_bytes());
+        out[42..46].copy_from_slice(&[32, 0, segments.len() as u8, 0]);
+        let mut data = phoff + 32 * segments.len();
+        for &(addr, bytes) in segments {
+            let mut ph = [0u32; 8];
+            ph[0] = PT_LOAD as u32;
+            ph[1] = data as u32;
+            ph[2] = addr;
+            ph[3] = addr;
+            ph[4] = bytes.len() as u32;
+            ph[5] = bytes.len() as u32;
+            ph.iter()
+                .for_each(|w| out.extend_from_slice(&w.to_le_bytes()));
+            data += bytes.len();
+        }
+        for &(_, bytes) in segments {
+            out.extend_from_slice(bytes);
+        }
+
+        let mut strings = vec![0];
+        let mut table = vec![0; 16];
+        for &(name, value, size, kind) in symbols {
+            table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            table.extend_from_slice(&value.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.extend_from_slice(&[0x10 | kind, 0, 1, 0]);
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        let (table_off, strings_off) = (out.len(), out.len() + table.len());
+        out.extend_from_slice(&table);
+        out.extend_from_slice(&strings);
+        let shoff = out.len();
+        out[32..36].copy_from_slice(&(shoff as u32).to_le_bytes());
+        out[46..50].copy_from_slice(&[40, 0, 3, 0]);
+        // Null section, .symtab linked to .strtab
+        out.extend_from_slice(&[0; 40]);
+        let sections = [
+            (SHT_SYMTAB as u32, table_off, table.len(), 2),
+            (3, strings_off, strings.len(), 0),
+        ];
+        for &(kind, offset, len, link) in sections.iter() {
+            let mut sh = [0u32; 10];
+            sh[1] = kind;
+            sh[4] = offset as u32;
+            sh[5] = len as u32;
+            sh[6] = link;
+            sh.iter()
+                .for_each(|w| out.extend_from_slice(&w.to_le_bytes()));
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = build(
+            0x0800_0000,
+            &[(0x0800_0000, b"ROM!"), (0x0800_0010, b"more")],
+            &[
+                ("_start", 0x0800_0000, 0, STT_NOTYPE),
+                ("__text_start", 0x0800_0000, 0, STT_NOTYPE),
+                ("main", 0x0800_0101, 0x20, STT_FUNC),
+                ("$t", 0x0800_0100, 0, STT_NOTYPE),
+                ("counter", 0x0300_0000, 4, STT_OBJECT),
+            ],
+        );
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry, 0x0800_0000);
+        assert_eq!(elf.segments.len(), 2);
+        assert!(elf.has_region(Region::Rom) && !elf.has_region(Region::Ewram));
+        assert_eq!(elf.image(Region::Rom), b"ROM!\0\0\0\0\0\0\0\0\0\0\0\0more");
+
+        let symbols = &elf.symbols;
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.format(0x0800_0100).as_deref(), Some("main"));
+        assert_eq!(symbols.format(0x0800_0114).as_deref(), Some("main+0x14"));
+        assert_eq!(symbols.format(0x0800_0120), None);
+        assert_eq!(symbols.format(0x0800_0004).as_deref(), Some("_start+0x4"));
+        assert_eq!(symbols.format(0x0300_0002).as_deref(), Some("counter+0x2"));
+        assert_eq!(symbols.format(0x0200_0000), None);
+        assert_eq!(symbols.get("main").map(|s| s.addr), Some(0x0800_0100));
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = build(0x0200_0000, &[(0x0200_0000, b"x")], &[]);
+        assert!(Elf::parse(&data).unwrap().symbols.is_empty());
+        assert_eq!(Elf::parse(&data[..40]).unwrap_err(), ElfError::Truncated);
+
+        let mut other = data.clone();
+        other[18] = 62;
+        assert_eq!(Elf::parse(&other).unwrap_err(), ElfError::NotArm);
+        let mut object = data.clone();
+        object[16] = 1;
+        assert_eq!(Elf::parse(&object).unwrap_err(), ElfError::NotExecutable);
+
+        let data = build(0, &[(0x0300_7FFF, b"xx")], &[]);
+        assert_eq!(
+            Elf::parse(&data).unwrap_err(),
+            ElfError::BadSegment {
+                addr: 0x0300_7FFF,
+                len: 2
+            }
+        );
+    }
+}
@@ -1,10 +1,21 @@
-use std::str;
-use std::fs;
 use std::convert::TryInto;
-use std::io::{self, Read};
-use std::fs::File;
-use std::path::Path;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, ArchiveError, Container};
+use crate::elf::{Elf, ElfError, Region, Symbols};
+use crate::patch::{self, PatchError, PatchFormat};
 
+/*
+ * GBA ROM images. See GBATEK "GBA Cartridge Header".
+ *
+ * A Rom keeps the whole image together with its parsed header. Parsing never panics, the header
+ * fields are stored as they are found and checking them is up to the caller.
+ */
+#[derive(Debug, Clone, PartialEq)]
 pub struct GbaCartridgeHeader {
     pub rom_entry_branch_instr: u32,
     pub nintendo_logo: [u8; 156],
@@ -18,6 +29,7 @@ pub struct GbaCartridgeHeader {
     pub software_version: u8,
     pub checksum: u8,
     pub reserved_area2: [u8; 2],
+    // Multiboot entries, only present in images at least MULTIBOOT_HEADER_SIZE long.
     pub ram_entry_branch_instr: u32,
     pub boot_mode: u8,
     pub slave_id_number: u8,
@@ -26,139 +38,606 @@ pub struct GbaCartridgeHeader {
 }
 
 impl GbaCartridgeHeader {
-    const ROM_ENTRY_BRANCH_INSTR: usize = 0;
-    const NINTENDO_LOGO_OFFSET: usize = 0x4;
-    const GAME_TITLE_LOGO_OFFSET: usize = 0xA0;
-    const GAME_CODE_OFFSET: usize = 0xAC;
-    const MAKER_CODE_OFFSET: usize = 0xB0;
-    const FIXED_VALUE_OFFSET: usize = 0xB2;
-    const MAIN_UNIT_CODE_OFFSET: usize = 0xB3;
-    const DEVICE_TYPE_OFFSET: usize = 0xB4;
-    const RESERVED_AREA1_OFFSET: usize = 0xB5;
-    const SOFTWARE_VERSION_OFFSET: usize = 0xBC;
-    const CHECKSUM_OFFSET: usize = 0xBD;
-    const RESERVED_AREA2_OFFSET: usize = 0xBE;
-    const RAM_ENTRY_BRANCH_INSTR_OFFSET: usize = 0xC0;
-    const BOOT_MODE_OFFSET: usize = 0xC4;
-    const SLAVE_ID_NUMBER_OFFSET: usize = 0xC5;
-    const NOT_USED_PADDING_OFFSET: usize = 0xC6;
-    const JOYBUS_ENTRY_POINT_OFFSET: usize = 0xE0;
-}
-
-pub struct GbaData {
-    header: GbaCartridgeHeader,
-    data: Vec<u8>
-}
-
-impl GbaData  {
-    fn open<T: AsRef<Path>>(filepath: T) -> Result<Self, io::Error>  {
-        let mut f = File::open(filepath.as_ref())?;
-        let fsmeta = fs::metadata(filepath.as_ref())?;
-        let fsize = fsmeta.len();
-        let mut buf = vec![0u8; fsize as usize];
-        let len = f.read(&mut buf[..]);
-        if let Err(err) = len {
-            Err(err)
-        } else {
-            if len.unwrap() < GbaCartridgeHeader::RAM_ENTRY_BRANCH_INSTR_OFFSET as usize {
-                Err(io::Error::new(io::ErrorKind::InvalidData, "insufficient data"))
-            } else {
-                Ok(GbaData::from_slice(&buf[..]))
-            }
+    pub const ROM_ENTRY_BRANCH_INSTR: usize = 0;
+    pub const NINTENDO_LOGO_OFFSET: usize = 0x4;
+    pub const GAME_TITLE_LOGO_OFFSET: usize = 0xA0;
+    pub const GAME_CODE_OFFSET: usize = 0xAC;
+    pub const MAKER_CODE_OFFSET: usize = 0xB0;
+    pub const FIXED_VALUE_OFFSET: usize = 0xB2;
+    pub const MAIN_UNIT_CODE_OFFSET: usize = 0xB3;
+    pub const DEVICE_TYPE_OFFSET: usize = 0xB4;
+    pub const RESERVED_AREA1_OFFSET: usize = 0xB5;
+    pub const SOFTWARE_VERSION_OFFSET: usize = 0xBC;
+    pub const CHECKSUM_OFFSET: usize = 0xBD;
+    pub const RESERVED_AREA2_OFFSET: usize = 0xBE;
+    pub const RAM_ENTRY_BRANCH_INSTR_OFFSET: usize = 0xC0;
+    pub const BOOT_MODE_OFFSET: usize = 0xC4;
+    pub const SLAVE_ID_NUMBER_OFFSET: usize = 0xC5;
+    pub const NOT_USED_PADDING_OFFSET: usize = 0xC6;
+    pub const JOYBUS_ENTRY_POINT_OFFSET: usize = 0xE0;
+
+    pub const HEADER_SIZE: usize = 0xC0;
+    pub const MULTIBOOT_HEADER_SIZE: usize = 0xE4;
+
+    // Writes every field back at its offset, the multiboot entries only if buf has room for them.
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<(), RomError> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(RomError::TooShort { len: buf.len() });
+        }
+        buf[Self::ROM_ENTRY_BRANCH_INSTR..Self::NINTENDO_LOGO_OFFSET]
+            .copy_from_slice(&self.rom_entry_branch_instr.to_le_bytes());
+        buf[Self::NINTENDO_LOGO_OFFSET..Self::GAME_TITLE_LOGO_OFFSET]
+            .copy_from_slice(&self.nintendo_logo);
+        buf[Self::GAME_TITLE_LOGO_OFFSET..Self::GAME_CODE_OFFSET].copy_from_slice(&self.game_title);
+        buf[Self::GAME_CODE_OFFSET..Self::MAKER_CODE_OFFSET].copy_from_slice(&self.game_code);
+        buf[Self::MAKER_CODE_OFFSET..Self::FIXED_VALUE_OFFSET].copy_from_slice(&self.maker_code);
+        buf[Self::FIXED_VALUE_OFFSET] = self.fixed_value;
+        buf[Self::MAIN_UNIT_CODE_OFFSET] = self.main_unit_code;
+        buf[Self::DEVICE_TYPE_OFFSET] = self.device_type;
+        buf[Self::RESERVED_AREA1_OFFSET..Self::SOFTWARE_VERSION_OFFSET]
+            .copy_from_slice(&self.reserved_area1);
+        buf[Self::SOFTWARE_VERSION_OFFSET] = self.software_version;
+        buf[Self::CHECKSUM_OFFSET] = self.checksum;
+        buf[Self::RESERVED_AREA2_OFFSET..Self::HEADER_SIZE].copy_from_slice(&self.reserved_area2);
+        if buf.len() >= Self::MULTIBOOT_HEADER_SIZE {
+            buf[Self::RAM_ENTRY_BRANCH_INSTR_OFFSET..Self::BOOT_MODE_OFFSET]
+                .copy_from_slice(&self.ram_entry_branch_instr.to_le_bytes());
+            buf[Self::BOOT_MODE_OFFSET] = self.boot_mode;
+            buf[Self::SLAVE_ID_NUMBER_OFFSET] = self.slave_id_number;
+            buf[Self::NOT_USED_PADDING_OFFSET..Self::JOYBUS_ENTRY_POINT_OFFSET]
+                .copy_from_slice(&self.not_used_padding);
+            buf[Self::JOYBUS_ENTRY_POINT_OFFSET..Self::MULTIBOOT_HEADER_SIZE]
+                .copy_from_slice(&self.joybus_entry_point.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    // The bytes 0xA0-0xBC, which the complement check covers.
+    fn checked_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(0x1D);
+        bytes.extend_from_slice(&self.game_title);
+        bytes.extend_from_slice(&self.game_code);
+        bytes.extend_from_slice(&self.maker_code);
+        bytes.extend_from_slice(&[self.fixed_value, self.main_unit_code, self.device_type]);
+        bytes.extend_from_slice(&self.reserved_area1);
+        bytes.push(self.software_version);
+        bytes
+    }
+
+    // The header checksum at 0xBD: -(sum of 0xA0-0xBC) - 0x19.
+    pub fn complement_check(&self) -> u8 {
+        let sum = self
+            .checked_bytes()
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b));
+        0u8.wrapping_sub(sum).wrapping_sub(0x19)
+    }
+
+    // Checks the fields the BIOS checks and those that should hold printable text.
+    pub fn validate(&self) -> HeaderReport {
+        let mut issues = vec![];
+        let computed = self.complement_check();
+        if self.checksum != computed {
+            issues.push(HeaderIssue::ChecksumMismatch {
+                stored: self.checksum,
+                computed,
+            });
+        }
+        if let Some(offset) =
+            (0..NINTENDO_LOGO.len()).find(|&i| self.nintendo_logo[i] != NINTENDO_LOGO[i])
+        {
+            issues.push(HeaderIssue::LogoMismatch { offset });
+        }
+        if self.fixed_value != FIXED_VALUE {
+            issues.push(HeaderIssue::FixedValue(self.fixed_value));
+        }
+        if self.main_unit_code != 0 {
+            issues.push(HeaderIssue::MainUnitCode(self.main_unit_code));
+        }
+        if let Some(offset) = first_non_ascii(&self.game_title) {
+            issues.push(HeaderIssue::NonAsciiTitle { offset });
+        }
+        if let Some(offset) = first_non_ascii(&self.game_code) {
+            issues.push(HeaderIssue::NonAsciiGameCode { offset });
+        }
+        HeaderReport {
+            computed_checksum: computed,
+            issues,
         }
     }
 
-    fn from_slice<T: AsRef<[u8]>>(buf: T) -> Self {
-        let mut gba_header  =  GbaCartridgeHeader {
-            rom_entry_branch_instr: 0,
-            nintendo_logo: [0; 156],
-            game_title: [0; 12],
-            game_code: [0; 4],
-            maker_code: [0; 2],
-            fixed_value: 0,
-            main_unit_code: 0,
-            device_type: 0,
-            reserved_area1: [0; 7],
-            software_version: 0,
-            checksum: 0,
-            reserved_area2: [0; 2],
+    pub fn parse(buf: &[u8]) -> Result<Self, RomError> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(RomError::TooShort { len: buf.len() });
+        }
+        // The lengths are fixed by the offsets, the conversions cannot fail.
+        let field = |start: usize, end: usize| &buf[start..end];
+        let word = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+
+        let mut header = GbaCartridgeHeader {
+            rom_entry_branch_instr: word(Self::ROM_ENTRY_BRANCH_INSTR),
+            nintendo_logo: field(Self::NINTENDO_LOGO_OFFSET, Self::GAME_TITLE_LOGO_OFFSET)
+                .try_into()
+                .unwrap(),
+            game_title: field(Self::GAME_TITLE_LOGO_OFFSET, Self::GAME_CODE_OFFSET)
+                .try_into()
+                .unwrap(),
+            game_code: field(Self::GAME_CODE_OFFSET, Self::MAKER_CODE_OFFSET)
+                .try_into()
+                .unwrap(),
+            maker_code: field(Self::MAKER_CODE_OFFSET, Self::FIXED_VALUE_OFFSET)
+                .try_into()
+                .unwrap(),
+            fixed_value: buf[Self::FIXED_VALUE_OFFSET],
+            main_unit_code: buf[Self::MAIN_UNIT_CODE_OFFSET],
+            device_type: buf[Self::DEVICE_TYPE_OFFSET],
+            reserved_area1: field(Self::RESERVED_AREA1_OFFSET, Self::SOFTWARE_VERSION_OFFSET)
+                .try_into()
+                .unwrap(),
+            software_version: buf[Self::SOFTWARE_VERSION_OFFSET],
+            checksum: buf[Self::CHECKSUM_OFFSET],
+            reserved_area2: field(Self::RESERVED_AREA2_OFFSET, Self::HEADER_SIZE)
+                .try_into()
+                .unwrap(),
             ram_entry_branch_instr: 0,
             boot_mode: 0,
             slave_id_number: 0,
             not_used_padding: [0; 26],
-            joybus_entry_point: 0
+            joybus_entry_point: 0,
         };
-        let mut buf2: &[u8] = &buf.as_ref()[GbaCartridgeHeader::ROM_ENTRY_BRANCH_INSTR..GbaCartridgeHeader::NINTENDO_LOGO_OFFSET];
-        gba_header.rom_entry_branch_instr = u32::from_le_bytes(buf2.try_into().unwrap());
+        if buf.len() >= Self::MULTIBOOT_HEADER_SIZE {
+            header.ram_entry_branch_instr = word(Self::RAM_ENTRY_BRANCH_INSTR_OFFSET);
+            header.boot_mode = buf[Self::BOOT_MODE_OFFSET];
+            header.slave_id_number = buf[Self::SLAVE_ID_NUMBER_OFFSET];
+            header.not_used_padding = field(
+                Self::NOT_USED_PADDING_OFFSET,
+                Self::JOYBUS_ENTRY_POINT_OFFSET,
+            )
+            .try_into()
+            .unwrap();
+            header.joybus_entry_point = word(Self::JOYBUS_ENTRY_POINT_OFFSET);
+        }
+        Ok(header)
+    }
+}
+
+// The compressed logo bitmap the BIOS compares before booting a cartridge.
+pub const NINTENDO_LOGO: [u8; 156] = [
+    0x24, 0xFF, 0xAE, 0x51, 0x69, 0x9A, 0xA2, 0x21, 0x3D, 0x84, 0x82, 0x0A, 0x84, 0xE4, 0x09, 0xAD,
+    0x11, 0x24, 0x8B, 0x98, 0xC0, 0x81, 0x7F, 0x21, 0xA3, 0x52, 0xBE, 0x19, 0x93, 0x09, 0xCE, 0x20,
+    0x10, 0x46, 0x4A, 0x4A, 0xF8, 0x27, 0x31, 0xEC, 0x58, 0xC7, 0xE8, 0x33, 0x82, 0xE3, 0xCE, 0xBF,
+    0x85, 0xF4, 0xDF, 0x94, 0xCE, 0x4B, 0x09, 0xC1, 0x94, 0x56, 0x8A, 0xC0, 0x13, 0x72, 0xA7, 0xFC,
+    0x9F, 0x84, 0x4D, 0x73, 0xA3, 0xCA, 0x9A, 0x61, 0x58, 0x97, 0xA3, 0x27, 0xFC, 0x03, 0x98, 0x76,
+    0x23, 0x1D, 0xC7, 0x61, 0x03, 0x04, 0xAE, 0x56, 0xBF, 0x38, 0x84, 0x00, 0x40, 0xA7, 0x0E, 0xFD,
+    0xFF, 0x52, 0xFE, 0x03, 0x6F, 0x95, 0x30, 0xF1, 0x97, 0xFB, 0xC0, 0x85, 0x60, 0xD6, 0x80, 0x25,
+    0xA9, 0x63, 0xBE, 0x03, 0x01, 0x4E, 0x38, 0xE2, 0xF9, 0xA2, 0x34, 0xFF, 0xBB, 0x3E, 0x03, 0x44,
+    0x78, 0x00, 0x90, 0xCB, 0x88, 0x11, 0x3A, 0x94, 0x65, 0xC0, 0x7C, 0x63, 0x87, 0xF0, 0x3C, 0xAF,
+    0xD6, 0x25, 0xE4, 0x8B, 0x38, 0x0A, 0xAC, 0x72, 0x21, 0xD4, 0xF8, 0x07,
+];
+
+pub const FIXED_VALUE: u8 = 0x96;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderIssue {
+    ChecksumMismatch { stored: u8, computed: u8 },
+    // Offset of the first differing byte within the logo.
+    LogoMismatch { offset: usize },
+    FixedValue(u8),
+    MainUnitCode(u8),
+    // Offset of the first offending byte within the field.
+    NonAsciiTitle { offset: usize },
+    NonAsciiGameCode { offset: usize },
+}
+
+impl fmt::Display for HeaderIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderIssue::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "complement check is 0x{:02x}, should be 0x{:02x}",
+                stored, computed
+            ),
+            HeaderIssue::LogoMismatch { offset } => {
+                write!(f, "logo differs at byte 0x{:02x}", offset)
+            }
+            HeaderIssue::FixedValue(v) => {
+                write!(f, "fixed value is 0x{:02x}, should be 0x96", v)
+            }
+            HeaderIssue::MainUnitCode(v) => {
+                write!(f, "main unit code is 0x{:02x}, should be 0x00", v)
+            }
+            HeaderIssue::NonAsciiTitle { offset } => {
+                write!(f, "title byte {} is not ASCII", offset)
+            }
+            HeaderIssue::NonAsciiGameCode { offset } => {
+                write!(f, "game code byte {} is not ASCII", offset)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderReport {
+    pub computed_checksum: u8,
+    pub issues: Vec<HeaderIssue>,
+}
+
+impl HeaderReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
 
-        buf2 = &buf.as_ref()[GbaCartridgeHeader::NINTENDO_LOGO_OFFSET..GbaCartridgeHeader::GAME_TITLE_LOGO_OFFSET]; 
-        gba_header.nintendo_logo.clone_from_slice(buf2);
+// Printable ASCII, zeros only as padding at the end.
+fn first_non_ascii(bytes: &[u8]) -> Option<usize> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end]
+        .iter()
+        .position(|&b| !(0x20..0x7F).contains(&b))
+        .or_else(|| bytes[end..].iter().position(|&b| b != 0).map(|i| end + i))
+}
 
-        buf2 = &buf.as_ref()[GbaCartridgeHeader::GAME_TITLE_LOGO_OFFSET..GbaCartridgeHeader::GAME_CODE_OFFSET];
-        gba_header.game_title.clone_from_slice(buf2);
+/*
+ * What Rom::fix changes besides the logo, the fixed value and the checksum, which are always
+ * set. Text longer than its field is cut, shorter text is padded with zeros.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct FixOptions {
+    pub title: Option<String>,
+    pub game_code: Option<String>,
+    pub maker_code: Option<String>,
+    pub version: Option<u8>,
+    // Pad the image with 0xFF to the next power of two.
+    pub pad: bool,
+}
 
-        buf2 = &buf.as_ref()[GbaCartridgeHeader::GAME_CODE_OFFSET..GbaCartridgeHeader::MAKER_CODE_OFFSET];
-        gba_header.game_code.clone_from_slice(buf2);
+fn set_text(field: &mut [u8], text: &str) {
+    let bytes = text.as_bytes();
+    let len = bytes.len().min(field.len());
+    field.fill(0);
+    field[..len].copy_from_slice(&bytes[..len]);
+}
 
-        buf2 = &buf.as_ref()[GbaCartridgeHeader::MAKER_CODE_OFFSET..GbaCartridgeHeader::FIXED_VALUE_OFFSET];
-        gba_header.maker_code.clone_from_slice(buf2); 
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    // Shorter than the cartridge header.
+    TooShort { len: usize },
+    // Larger than the 32MB cartridge address space.
+    TooLarge { len: usize },
+    // A multiboot image larger than the 256KB of EWRAM it is loaded into.
+    MultibootTooLarge { len: usize },
+    Archive { path: PathBuf, err: ArchiveError },
+    Elf(ElfError),
+    Patch { path: PathBuf, err: PatchError },
+}
 
-        gba_header.fixed_value = buf.as_ref()[GbaCartridgeHeader::FIXED_VALUE_OFFSET];
-        gba_header.main_unit_code = buf.as_ref()[GbaCartridgeHeader::MAIN_UNIT_CODE_OFFSET];
-        gba_header.device_type = buf.as_ref()[GbaCartridgeHeader::DEVICE_TYPE_OFFSET];
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "cannot read ROM: {}", err),
+            RomError::TooShort { len } => write!(
+                f,
+                "ROM is {} bytes, shorter than the {} byte header",
+                len,
+                GbaCartridgeHeader::HEADER_SIZE
+            ),
+            RomError::TooLarge { len } => write!(
+                f,
+                "ROM is {} bytes, larger than the {} byte maximum",
+                len, MAX_ROM_SIZE
+            ),
+            RomError::MultibootTooLarge { len } => write!(
+                f,
+                "multiboot image is {} bytes, larger than the {} byte maximum",
+                len, MULTIBOOT_MAX_SIZE
+            ),
+            RomError::Archive { path, err } => write!(f, "{}: {}", path.display(), err),
+            RomError::Elf(err) => write!(f, "{}", err),
+            RomError::Patch { path, err } => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
 
-        buf2 = &buf.as_ref()[GbaCartridgeHeader::RESERVED_AREA1_OFFSET..GbaCartridgeHeader::SOFTWARE_VERSION_OFFSET];
-        gba_header.reserved_area1.clone_from_slice(buf2);
-        
-        gba_header.software_version = buf.as_ref()[GbaCartridgeHeader::SOFTWARE_VERSION_OFFSET];
-        gba_header.checksum = buf.as_ref()[GbaCartridgeHeader::CHECKSUM_OFFSET];
-       
-        // TODO: Implement Multiboot stuff
-        if buf.as_ref().len() > GbaCartridgeHeader::RAM_ENTRY_BRANCH_INSTR_OFFSET {
-            buf2 = &buf.as_ref()[GbaCartridgeHeader::RESERVED_AREA2_OFFSET..GbaCartridgeHeader::RAM_ENTRY_BRANCH_INSTR_OFFSET];
-            gba_header.reserved_area1.clone_from_slice(buf2);
+impl Error for RomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RomError::Io(err) => Some(err),
+            RomError::Archive { err, .. } => Some(err),
+            RomError::Elf(err) => Some(err),
+            RomError::Patch { err, .. } => Some(err),
+            _ => None,
         }
+    }
+}
 
-        GbaData { header: gba_header, data: vec![]}
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+
+pub const MAX_ROM_SIZE: usize = 32 * 1024 * 1024;
+pub const MULTIBOOT_MAX_SIZE: usize = 256 * 1024;
+
+/*
+ * Cartridge images run from ROM at 0x08000000. Multiboot images are sent over the link cable by
+ * another GBA, the BIOS copies them to EWRAM at 0x02000000 and enters them through the RAM entry
+ * point at 0xC0, after filling in the boot mode and slave ID bytes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageKind {
+    Cartridge,
+    Multiboot,
+}
+
+fn word_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/*
+ * The header looks the same for both kinds, so the code is looked at instead. Starting at the
+ * RAM entry point, or the ROM entry point if there is no branch at 0xC0, branches are followed
+ * and the first PC relative load of an address in EWRAM or ROM decides: code linked to run from
+ * EWRAM is a multiboot image. Images too large for EWRAM are always cartridges.
+ */
+pub fn detect_kind(data: &[u8]) -> ImageKind {
+    let is_branch = |op: u32| op & 0xFF00_0000 == 0xEA00_0000;
+    if data.len() > MULTIBOOT_MAX_SIZE {
+        return ImageKind::Cartridge;
+    }
+    let ram_entry = GbaCartridgeHeader::RAM_ENTRY_BRANCH_INSTR_OFFSET;
+    let mut pc = match word_at(data, ram_entry) {
+        Some(op) if is_branch(op) => ram_entry,
+        _ => 0,
+    };
+    for _ in 0..64 {
+        let op = match word_at(data, pc) {
+            Some(op) => op,
+            None => break,
+        };
+        if is_branch(op) {
+            let offset = ((op << 8) as i32 >> 6) as isize;
+            pc = match (pc as isize + 8).checked_add(offset) {
+                Some(target) if target >= 0 => target as usize,
+                _ => break,
+            };
+            continue;
+        }
+        // ldr rd, [pc, #+-imm]
+        if op & 0x0F7F_0000 == 0x051F_0000 {
+            let imm = (op & 0xFFF) as usize;
+            let literal = if op & (1 << 23) != 0 {
+                Some(pc + 8 + imm)
+            } else {
+                (pc + 8).checked_sub(imm)
+            };
+            match literal.and_then(|addr| word_at(data, addr)) {
+                Some(0x0200_0000..=0x0203_FFFF) => return ImageKind::Multiboot,
+                Some(0x0800_0000..=0x09FF_FFFF) => return ImageKind::Cartridge,
+                _ => {}
+            }
+        }
+        pc += 4;
+    }
+    ImageKind::Cartridge
+}
+
+#[derive(Debug, Clone)]
+pub struct Rom {
+    header: GbaCartridgeHeader,
+    data: Vec<u8>,
+    kind: ImageKind,
+    // From the ELF file the image was built from, empty otherwise.
+    symbols: Symbols,
+}
+
+// Header text fields are ASCII padded with zeros, other bytes are replaced rather than rejected.
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+impl Rom {
+    /*
+     * Files named *.mb are multiboot images, others are told apart by their code. A patch next
+     * to the file with the same name, "game.ips", "game.ups" or "game.bps", is applied.
+     */
+    pub fn open<T: AsRef<Path>>(filepath: T) -> Result<Self, RomError> {
+        Rom::open_entry(filepath, None)
+    }
+
+    /*
+     * Like open, but zip and gzip files are unpacked first. entry names the ROM in a zip file, by
+     * default it is the first .gba or .mb file in it. ELF executables are turned into the image
+     * objcopy would make of them, see from_elf.
+     */
+    pub fn open_entry<T: AsRef<Path>>(filepath: T, entry: Option<&str>) -> Result<Self, RomError> {
+        Rom::load(filepath.as_ref(), entry, true)
+    }
+
+    // Like open_entry without applying a sibling patch, for tools that write the image back.
+    pub fn open_unpatched<T: AsRef<Path>>(
+        filepath: T,
+        entry: Option<&str>,
+    ) -> Result<Self, RomError> {
+        Rom::load(filepath.as_ref(), entry, false)
+    }
+
+    fn load(filepath: &Path, entry: Option<&str>, apply_patch: bool) -> Result<Self, RomError> {
+        let extracted =
+            archive::extract(fs::read(filepath)?, entry, MAX_ROM_SIZE).map_err(|err| {
+                RomError::Archive {
+                    path: filepath.to_path_buf(),
+                    err,
+                }
+            })?;
+        // The name of the image itself tells multiboot ones, game.mb in a zip file or game.mb.gz
+        let name = match (extracted.container, extracted.name) {
+            (_, Some(name)) => PathBuf::from(name),
+            (Container::Gzip, None) => filepath.with_extension(""),
+            _ => filepath.to_path_buf(),
+        };
+        let mut data = extracted.data;
+        let elf = if Elf::is_elf(&data) {
+            let elf = Elf::parse(&data).map_err(RomError::Elf)?;
+            data = Rom::elf_image(&elf)?;
+            Some(elf)
+        } else {
+            None
+        };
+        if let Some(path) = Rom::sibling_patch(filepath).filter(|_| apply_patch) {
+            data = patch::apply(&fs::read(&path)?, &data)
+                .map_err(|err| RomError::Patch { path, err })?;
+        }
+        let mut rom = Rom::from_bytes(data)?;
+        if let Some(elf) = elf {
+            if !elf.has_region(Region::Rom) {
+                rom.set_kind(ImageKind::Multiboot)?;
+            }
+            rom.symbols = elf.symbols;
+        }
+        if name.extension().is_some_and(|e| e == "mb") {
+            rom.set_kind(ImageKind::Multiboot)?;
+        }
+        Ok(rom)
     }
 
-    fn rom_entry(&self) -> u32 {
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, RomError> {
+        if data.len() > MAX_ROM_SIZE {
+            return Err(RomError::TooLarge { len: data.len() });
+        }
+        let header = GbaCartridgeHeader::parse(&data)?;
+        let kind = detect_kind(&data);
+        Ok(Rom {
+            header,
+            data,
+            kind,
+            symbols: Symbols::default(),
+        })
+    }
+
+    /*
+     * The ROM segments of a cartridge build, or the EWRAM segments of a multiboot build, which
+     * has no ROM segments. Segments anywhere else can only be loaded by booting the ELF file.
+     */
+    pub fn from_elf(elf: &Elf) -> Result<Self, RomError> {
+        let mut rom = Rom::from_bytes(Rom::elf_image(elf)?)?;
+        if !elf.has_region(Region::Rom) {
+            rom.set_kind(ImageKind::Multiboot)?;
+        }
+        rom.symbols = elf.symbols.clone();
+        Ok(rom)
+    }
+
+    fn elf_image(elf: &Elf) -> Result<Vec<u8>, RomError> {
+        let region = if elf.has_region(Region::Rom) {
+            Region::Rom
+        } else {
+            Region::Ewram
+        };
+        if let Some(segment) = elf.segments.iter().find(|s| s.region != region) {
+            return Err(RomError::Elf(ElfError::Unmappable { addr: segment.addr }));
+        }
+        Ok(elf.image(region))
+    }
+
+    pub fn from_slice<T: AsRef<[u8]>>(buf: T) -> Result<Self, RomError> {
+        Rom::from_bytes(buf.as_ref().to_vec())
+    }
+
+    pub fn sibling_patch(filepath: &Path) -> Option<PathBuf> {
+        PatchFormat::EXTENSIONS
+            .iter()
+            .map(|ext| filepath.with_extension(ext))
+            .find(|path| path.is_file())
+    }
+
+    pub fn header(&self) -> &GbaCartridgeHeader {
+        &self.header
+    }
+
+    // The whole image, header included.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn kind(&self) -> ImageKind {
+        self.kind
+    }
+
+    pub fn is_multiboot(&self) -> bool {
+        self.kind == ImageKind::Multiboot
+    }
+
+    // Overrides the detected kind.
+    pub fn set_kind(&mut self, kind: ImageKind) -> Result<(), RomError> {
+        if kind == ImageKind::Multiboot && self.data.len() > MULTIBOOT_MAX_SIZE {
+            return Err(RomError::MultibootTooLarge {
+                len: self.data.len(),
+            });
+        }
+        self.kind = kind;
+        Ok(())
+    }
+
+    pub fn rom_entry(&self) -> u32 {
         self.header.rom_entry_branch_instr
     }
 
-    fn nintendo_logo(&self) -> Vec<u8> {
-        self.header.nintendo_logo.to_vec()
+    // Multiboot only, zero for images shorter than the multiboot header.
+    pub fn ram_entry(&self) -> u32 {
+        self.header.ram_entry_branch_instr
+    }
+
+    pub fn boot_mode(&self) -> u8 {
+        self.header.boot_mode
+    }
+
+    pub fn slave_id(&self) -> u8 {
+        self.header.slave_id_number
     }
 
-    fn game_title(&self) -> String {
-        let game_title = str::from_utf8(&self.header.game_title[..]).unwrap();
-        game_title.to_owned()
+    pub fn nintendo_logo(&self) -> &[u8] {
+        &self.header.nintendo_logo
     }
 
-    fn game_code(&self) -> Vec<u8> {
-        self.header.game_code.to_vec()
+    pub fn game_title(&self) -> String {
+        header_string(&self.header.game_title)
     }
 
-    fn maker_code(&self) -> String {
-        let maker_code = str::from_utf8(&self.header.maker_code[..]).unwrap();
-        maker_code.to_owned()
+    pub fn game_code(&self) -> String {
+        header_string(&self.header.game_code)
     }
 
-    fn fixed_value(&self) -> u8 {
-        assert!(self.header.fixed_value == 0x96);
+    pub fn maker_code(&self) -> String {
+        header_string(&self.header.maker_code)
+    }
+
+    // 0x96 in a valid header.
+    pub fn fixed_value(&self) -> u8 {
         self.header.fixed_value
     }
 
-    fn main_unit_code(&self) -> u8 {
-        assert!(self.header.main_unit_code == 0x00);
+    // 0x00 in a valid header.
+    pub fn main_unit_code(&self) -> u8 {
         self.header.main_unit_code
     }
-    
-    fn device_type(&self) -> u8 {
+
+    pub fn device_type(&self) -> u8 {
         // Normally this is 0, but with Nintendo hardware debugger bit 7
         // identifies debug handler entry point and size of Debugging
         // And Communication System size
-        // Bit 7 = 0 
+        // Bit 7 = 0
         //      Address: 0x9FFC000
         //      DACS Size: 8-Mbit
         // Bit 7 = 1
@@ -167,27 +646,308 @@ impl GbaData  {
         self.header.device_type
     }
 
+    pub fn software_version(&self) -> u8 {
+        self.header.software_version
+    }
 
+    pub fn validate(&self) -> HeaderReport {
+        self.header.validate()
+    }
 
+    // Makes the header bootable the way gbafix does and writes it back into the image.
+    pub fn fix(&mut self, options: &FixOptions) {
+        let header = &mut self.header;
+        header.nintendo_logo = NINTENDO_LOGO;
+        header.fixed_value = FIXED_VALUE;
+        if let Some(title) = &options.title {
+            set_text(&mut header.game_title, title);
+        }
+        if let Some(code) = &options.game_code {
+            set_text(&mut header.game_code, code);
+        }
+        if let Some(code) = &options.maker_code {
+            set_text(&mut header.maker_code, code);
+        }
+        if let Some(version) = options.version {
+            header.software_version = version;
+        }
+        header.checksum = header.complement_check();
+        // Cannot fail, the image was long enough to parse the header from.
+        let _ = header.write_to(&mut self.data);
+        if options.pad {
+            let size = self.data.len().next_power_of_two();
+            self.data.resize(size, 0xFF);
+        }
+    }
+
+    pub fn save<T: AsRef<Path>>(&self, filepath: T) -> Result<(), RomError> {
+        fs::write(filepath, &self.data)?;
+        Ok(())
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+
+    fn header_bytes() -> Vec<u8> {
+        let mut data = vec![0u8; GbaCartridgeHeader::HEADER_SIZE];
+        data[0..4].copy_from_slice(&[0xEF, 0xBE, 0xAD, 0xDE]);
+        data[0xA0..0xA5].copy_from_slice(b"TITLE");
+        data[0xAC..0xB0].copy_from_slice(b"ABCE");
+        data[0xB0..0xB2].copy_from_slice(&[b'0', 0xFF]);
+        data[0xB2] = 0x96;
+        data[0xBE..0xC0].copy_from_slice(&[1, 2]);
+        data
+    }
+
     #[test]
     fn gbadata_open() {
-        let gba = GbaData::open("blah.gba");
-        assert!(gba.is_err());
+        let rom = Rom::open("blah.gba");
+        assert!(matches!(rom, Err(RomError::Io(_))));
+        let rom = Rom::open("a.gba").unwrap();
+        assert_eq!(
+            rom.data().len() as u64,
+            fs::metadata("a.gba").unwrap().len()
+        );
     }
 
-
     #[test]
     fn gbadata_from_slice() {
-        let mut data: Vec<u8> = vec![0x0u8; GbaCartridgeHeader::RAM_ENTRY_BRANCH_INSTR_OFFSET];
-        data.splice(0..4, [0xEFu8, 0xBEu8, 0xADu8, 0xDEu8].iter().cloned());
-        let gba = GbaData::from_slice(&data[..]);
-        println!("addr: {:x}", gba.rom_entry());
-        let nintendo_logo = gba.nintendo_logo();
-        println!("logo: {:?}", nintendo_logo);
-        println!("title: {}", gba.game_title());
+        let mut data = header_bytes();
+        data.extend_from_slice(&[0x55; 0x100]);
+        let rom = Rom::from_slice(&data).unwrap();
+        assert_eq!(rom.rom_entry(), 0xDEADBEEF);
+        assert_eq!(rom.game_title(), "TITLE");
+        assert_eq!(rom.game_code(), "ABCE");
+        assert_eq!(rom.maker_code(), "0\u{FFFD}");
+        assert_eq!(rom.fixed_value(), 0x96);
+        assert_eq!(rom.header().reserved_area2, [1, 2]);
+        assert_eq!(rom.header().reserved_area1, [0; 7]);
+        assert_eq!(rom.header().joybus_entry_point, 0x5555_5555);
+        // The body is kept
+        assert_eq!(rom.data(), &data[..]);
+    }
+
+    #[test]
+    fn rom_errors() {
+        let data = header_bytes();
+        assert!(Rom::from_slice(&data).is_ok());
+        let err = Rom::from_slice(&data[..0xBF]).unwrap_err();
+        assert!(matches!(err, RomError::TooShort { len: 0xBF }));
+        assert_eq!(
+            err.to_string(),
+            "ROM is 191 bytes, shorter than the 192 byte header"
+        );
+        let err = Rom::from_bytes(vec![0; MAX_ROM_SIZE + 1]).unwrap_err();
+        assert!(matches!(err, RomError::TooLarge { .. }));
+    }
+
+    #[test]
+    fn multiboot_detection() {
+        let image = |literal: u32| {
+            let mut data = header_bytes();
+            data.resize(0x200, 0);
+            let code: [(usize, u32); 4] = [
+                (0x00, 0xEA00_002E),  // b 0xC0
+                (0xC0, 0xEA00_000E),  // b 0x100
+                (0x100, 0xE59F_0000), // ldr r0, [pc, #0]
+                (0x108, literal),
+            ];
+            for &(offset, word) in code.iter() {
+                data[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+            }
+            data
+        };
+        let mut data = image(0x0200_0200);
+        assert_eq!(detect_kind(&data), ImageKind::Multiboot);
+        data[0xC4] = 3;
+        data[0xC5] = 1;
+        let rom = Rom::from_slice(&data).unwrap();
+        assert!(rom.is_multiboot());
+        assert_eq!(rom.ram_entry(), 0xEA00_000E);
+        assert_eq!((rom.boot_mode(), rom.slave_id()), (3, 1));
+
+        assert_eq!(detect_kind(&image(0x0800_0200)), ImageKind::Cartridge);
+        let mut large = image(0x0200_0200);
+        large.resize(MULTIBOOT_MAX_SIZE + 4, 0);
+        assert_eq!(detect_kind(&large), ImageKind::Cartridge);
+        let mut rom = Rom::from_bytes(large).unwrap();
+        let err = rom.set_kind(ImageKind::Multiboot).unwrap_err();
+        assert!(matches!(err, RomError::MultibootTooLarge { .. }));
+        assert!(!Rom::open("a.gba").unwrap().is_multiboot());
+    }
+
+    #[test]
+    fn sibling_patch() {
+        let dir = std::env::temp_dir().join(format!("util-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.gba");
+        fs::write(&path, header_bytes()).unwrap();
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0, 0, 0xA0, 0, 4]);
+        ips.extend_from_slice(b"HACK");
+        ips.extend_from_slice(b"EOF");
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+        assert_eq!(Rom::open(&path).unwrap().game_title(), "HACKE");
+
+        // A patch for another ROM fails the load
+        fs::remove_file(dir.join("game.ips")).unwrap();
+        fs::write(
+            dir.join("game.ups"),
+            b"UPS1\x80\x80\0\0\0\0\0\0\0\0\0\0\0\0",
+        )
+        .unwrap();
+        let err = Rom::open(&path).unwrap_err();
+        assert!(matches!(err, RomError::Patch { .. }));
+        let rom = Rom::open_unpatched(&path, None).unwrap();
+        assert_eq!(rom.data(), header_bytes().as_slice());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_gzip() {
+        let data = header_bytes();
+        let mut gz = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 3];
+        gz.push(1);
+        gz.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        gz.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        gz.extend_from_slice(&data);
+        gz.extend_from_slice(&crate::crc32::crc32(&data).to_le_bytes());
+        gz.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+        let dir = std::env::temp_dir().join(format!("util-gzip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // No name stored in the file, game.mb.gz holds a multiboot image
+        let path = dir.join("game.mb.gz");
+        fs::write(&path, &gz).unwrap();
+        let rom = Rom::open(&path).unwrap();
+        assert_eq!(rom.data(), data.as_slice());
+        assert!(rom.is_multiboot());
+
+        gz.truncate(20);
+        fs::write(&path, &gz).unwrap();
+        let err = Rom::open(&path).unwrap_err();
+        assert!(matches!(err, RomError::Archive { .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn from_elf() {
+        use crate::elf::{Segment, Symbol};
+
+        let segment = |region: Region, data: Vec<u8>| Segment {
+            region,
+            addr: region.base(),
+            data,
+        };
+        let mut elf = Elf {
+            entry: 0x0800_0000,
+            segments: vec![segment(Region::Rom, header_bytes())],
+            symbols: Symbols::new(vec![Symbol {
+                name: "main".to_string(),
+                addr: 0x0800_0100,
+                size: 0x40,
+            }]),
+        };
+        let rom = Rom::from_elf(&elf).unwrap();
+        assert_eq!(rom.data(), header_bytes().as_slice());
+        assert!(!rom.is_multiboot());
+        assert_eq!(
+            rom.symbols().format(0x0800_0114).as_deref(),
+            Some("main+0x14")
+        );
+
+        elf.segments.push(segment(Region::Iwram, vec![0; 4]));
+        let err = Rom::from_elf(&elf).unwrap_err();
+        assert!(matches!(
+            err,
+            RomError::Elf(ElfError::Unmappable { addr: 0x0300_0000 })
+        ));
+
+        elf.segments = vec![segment(Region::Ewram, header_bytes())];
+        assert!(Rom::from_elf(&elf).unwrap().is_multiboot());
+    }
+
+    #[test]
+    fn header_validation() {
+        let mut data = header_bytes();
+        data[0x04..0xA0].copy_from_slice(&NINTENDO_LOGO);
+        let mut header = GbaCartridgeHeader::parse(&data).unwrap();
+        let report = header.validate();
+        assert_eq!(
+            report.issues,
+            vec![HeaderIssue::ChecksumMismatch {
+                stored: 0,
+                computed: report.computed_checksum
+            }]
+        );
+        header.checksum = report.computed_checksum;
+        assert!(header.validate().is_valid());
+
+        header.nintendo_logo[10] ^= 1;
+        header.fixed_value = 0;
+        header.main_unit_code = 1;
+        header.game_title[6] = b'X';
+        header.game_code[1] = 0xC3;
+        let issues = header.validate().issues;
+        assert_eq!(
+            issues,
+            vec![
+                HeaderIssue::ChecksumMismatch {
+                    stored: header.checksum,
+                    computed: header.complement_check()
+                },
+                HeaderIssue::LogoMismatch { offset: 10 },
+                HeaderIssue::FixedValue(0),
+                HeaderIssue::MainUnitCode(1),
+                HeaderIssue::NonAsciiTitle { offset: 6 },
+                HeaderIssue::NonAsciiGameCode { offset: 1 },
+            ]
+        );
+        assert_eq!(issues[2].to_string(), "fixed value is 0x00, should be 0x96");
+
+        // A devkitARM build without the logo
+        let rom = Rom::open("a.gba").unwrap();
+        assert_eq!(
+            rom.validate().issues,
+            vec![HeaderIssue::LogoMismatch { offset: 0 }]
+        );
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut data = header_bytes();
+        data.extend((0..0x24).map(|i| i as u8));
+        let header = GbaCartridgeHeader::parse(&data).unwrap();
+        let mut out = vec![0xAA; data.len()];
+        header.write_to(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(header.write_to(&mut [0; 0x10]).is_err());
+    }
+
+    #[test]
+    fn fix_header() {
+        let mut data = header_bytes();
+        data.resize(0x1234, 0);
+        let mut rom = Rom::from_bytes(data).unwrap();
+        rom.fix(&FixOptions {
+            title: Some("A LONG GAME TITLE".to_string()),
+            game_code: Some("AXYE".to_string()),
+            maker_code: Some("01".to_string()),
+            version: Some(2),
+            pad: true,
+        });
+        assert!(rom.validate().is_valid());
+        assert_eq!(rom.game_title(), "A LONG GAME ");
+        assert_eq!(rom.game_code(), "AXYE");
+        assert_eq!(rom.software_version(), 2);
+        assert_eq!(rom.data().len(), 0x2000);
+        assert_eq!(rom.data()[0x1FFF], 0xFF);
+
+        // The written image parses to the same header
+        let reread = Rom::from_slice(rom.data()).unwrap();
+        assert_eq!(reread.header(), rom.header());
+        assert_eq!(reread.data()[4..0xA0], NINTENDO_LOGO[..]);
     }
 }
@@ -2,8 +2,12 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::path::Path;
-#[cfg(test)]
-mod gba;
+pub mod archive;
+pub mod crc32;
+pub mod elf;
+pub mod gba;
+pub mod patch;
+pub mod save;
 
 pub fn get_bits(i: u32, lsb: usize, msb: usize) -> u32 {
     let num_bits = msb - lsb + 1;
@@ -47,6 +51,7 @@ pub fn read_instructions_file<T: AsRef<Path>>(
     Ok(to_vec_words(&buf))
 }
 
+#[cfg(test)]
 mod tests {
     use super::get_bits;
     use super::read_instructions_file;
@@ -0,0 +1,457 @@
+use crate::crc32::crc32;
+use crate::gba::MAX_ROM_SIZE;
+use std::error::Error;
+use std::fmt;
+
+/*
+ * ROM patches, applied in memory when the ROM is loaded.
+ *
+ * IPS   "PATCH", records of a 24 bit offset and 16 bit size followed by the bytes, or with size 0
+ *       a 16 bit count and a byte to repeat (RLE), "EOF" and optionally a 24 bit size to truncate
+ *       to. All numbers big endian.
+ * UPS   "UPS1", source and target size, then hunks of a number of bytes to skip and bytes to XOR
+ *       with the source up to a 0 byte.
+ * BPS   "BPS1", source size, target size, metadata, then actions that copy from the source, the
+ *       patch or the already written target.
+ *
+ * UPS and BPS numbers are variable length, and both end with the CRC32 of the source, of the
+ * target and of the patch itself, which are checked.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl PatchFormat {
+    pub fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(b"PATCH") {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+
+    // File extensions, in the order sibling patches are looked for.
+    pub const EXTENSIONS: [&'static str; 3] = ["ips", "ups", "bps"];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    // The patch ends in the middle of a record.
+    Truncated,
+    // A record reads or writes outside the source or target.
+    OutOfBounds,
+    SourceChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+    PatchChecksum { expected: u32, actual: u32 },
+    SourceSize { expected: usize, actual: usize },
+    // The patched ROM would be larger than a cartridge can be.
+    TargetSize { size: usize },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::OutOfBounds => write!(f, "patch addresses data out of bounds"),
+            PatchError::SourceChecksum { expected, actual } => write!(
+                f,
+                "patch is for a ROM with CRC32 {:08X}, this one has {:08X}",
+                expected, actual
+            ),
+            PatchError::TargetChecksum { expected, actual } => write!(
+                f,
+                "patched ROM has CRC32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            PatchError::PatchChecksum { expected, actual } => write!(
+                f,
+                "patch has CRC32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            PatchError::SourceSize { expected, actual } => write!(
+                f,
+                "patch is for a {} byte ROM, this one is {} bytes",
+                expected, actual
+            ),
+            PatchError::TargetSize { size } => write!(
+                f,
+                "patched ROM would be {} bytes, larger than the {} byte maximum",
+                size, MAX_ROM_SIZE
+            ),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+// Detects the format from the magic bytes and applies the patch to source.
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(patch, source),
+        Some(PatchFormat::Ups) => apply_ups(patch, source),
+        Some(PatchFormat::Bps) => apply_bps(patch, source),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.pos.checked_add(len).ok_or(PatchError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(PatchError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |v, &b| v << 8 | b as usize))
+    }
+
+    // UPS and BPS numbers: 7 bits per byte, least significant first, bit 7 set on the last.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let b = self.byte()?;
+            let add = (b as usize & 0x7F).checked_mul(shift);
+            value = add
+                .and_then(|a| value.checked_add(a))
+                .ok_or(PatchError::OutOfBounds)?;
+            if b & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = source.to_vec();
+    let mut r = Reader::new(patch, 5);
+    loop {
+        if r.bytes(3)? == b"EOF" {
+            break;
+        }
+        r.pos -= 3;
+        let offset = r.be(3)?;
+        let (len, fill) = match r.be(2)? {
+            0 => (r.be(2)?, Some(r.byte()?)),
+            len => (len, None),
+        };
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        let dest = &mut target[offset..offset + len];
+        match fill {
+            Some(b) => dest.fill(b),
+            None => dest.copy_from_slice(r.bytes(len)?),
+        }
+    }
+    if let Ok(size) = r.be(3) {
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+// Checks the CRC32s at the end of a UPS or BPS patch before it is applied.
+fn check_footer(patch: &[u8], source: &[u8]) -> Result<(u32, usize), PatchError> {
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Truncated);
+    }
+    let end = patch.len() - 12;
+    let crc = |i: usize| u32::from_le_bytes([patch[i], patch[i + 1], patch[i + 2], patch[i + 3]]);
+    let expected = crc(end + 8);
+    let actual = crc32(&patch[..end + 8]);
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+    let (expected, actual) = (crc(end), crc32(source));
+    if expected != actual {
+        return Err(PatchError::SourceChecksum { expected, actual });
+    }
+    Ok((crc(end + 4), end))
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32(target);
+    if expected != actual {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+    Ok(())
+}
+
+fn check_source_size(expected: usize, source: &[u8]) -> Result<(), PatchError> {
+    if expected != source.len() {
+        return Err(PatchError::SourceSize {
+            expected,
+            actual: source.len(),
+        });
+    }
+    Ok(())
+}
+
+// Checked before anything the size of the target is allocated.
+fn check_target_size(size: usize) -> Result<usize, PatchError> {
+    if size > MAX_ROM_SIZE {
+        return Err(PatchError::TargetSize { size });
+    }
+    Ok(size)
+}
+
+fn apply_ups(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (target_crc, end) = check_footer(patch, source)?;
+    let mut r = Reader::new(&patch[..end], 4);
+    check_source_size(r.varint()?, source)?;
+    let target_size = check_target_size(r.varint()?)?;
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut pos: usize = 0;
+    while r.pos < end {
+        pos = pos
+            .checked_add(r.varint()?)
+            .ok_or(PatchError::OutOfBounds)?;
+        loop {
+            let b = r.byte()?;
+            // Bytes past the end of the target are dropped
+            if let Some(t) = target.get_mut(pos) {
+                *t ^= b;
+            }
+            pos = pos.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+            if b == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+// A BPS relative offset, bit 0 is the sign.
+fn relative(base: usize, data: usize) -> Result<usize, PatchError> {
+    let delta = data >> 1;
+    let result = if data & 1 != 0 {
+        base.checked_sub(delta)
+    } else {
+        base.checked_add(delta)
+    };
+    result.ok_or(PatchError::OutOfBounds)
+}
+
+fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (target_crc, end) = check_footer(patch, source)?;
+    let mut r = Reader::new(&patch[..end], 4);
+    check_source_size(r.varint()?, source)?;
+    let target_size = check_target_size(r.varint()?)?;
+    let metadata = r.varint()?;
+    r.bytes(metadata)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0, 0);
+    while r.pos < end {
+        let data = r.varint()?;
+        let len = (data >> 2) + 1;
+        if target.len() + len > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+        match data & 3 {
+            // Source read
+            0 => {
+                let pos = target.len();
+                let bytes = source.get(pos..pos + len).ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+            }
+            // Target read
+            1 => target.extend_from_slice(r.bytes(len)?),
+            // Source copy
+            2 => {
+                source_offset = relative(source_offset, r.varint()?)?;
+                let end = source_offset
+                    .checked_add(len)
+                    .ok_or(PatchError::OutOfBounds)?;
+                let bytes = source
+                    .get(source_offset..end)
+                    .ok_or(PatchError::OutOfBounds)?;
+                target.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            // Target copy, byte by byte as it may overlap what it writes
+            _ => {
+                target_offset = relative(target_offset, r.varint()?)?;
+                if target_offset >= target.len() {
+                    return Err(PatchError::OutOfBounds);
+                }
+                for _ in 0..len {
+                    let b = target[target_offset];
+                    target.push(b);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let b = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(b | 0x80);
+                return;
+            }
+            out.push(b);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_varint() {
+        for &v in [0, 1, 127, 128, 300, 16_511, 16_512, 1 << 25].iter() {
+            let mut bytes = Vec::new();
+            varint(v, &mut bytes);
+            assert_eq!(Reader::new(&bytes, 0).varint(), Ok(v));
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let source = vec![0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 1, RLE 3 x 0xAA at 6 (growing the image), truncate to 9
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0x11, 0x22]);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 3, 0xAA]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(&patch, &source).unwrap(),
+            [0, 0x11, 0x22, 0, 0, 0, 0xAA, 0xAA, 0xAA]
+        );
+        patch.extend_from_slice(&[0, 0, 4]);
+        assert_eq!(apply(&patch, &source).unwrap(), [0, 0x11, 0x22, 0]);
+        assert_eq!(apply(&patch[..12], &source), Err(PatchError::Truncated));
+        assert_eq!(apply(b"NOPE", &source), Err(PatchError::UnknownFormat));
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = b"Hello, World".to_vec();
+        let target = b"Hello, Rust!!".to_vec();
+        let mut patch = b"UPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        // Skip 7 bytes, XOR up to the end
+        varint(7, &mut patch);
+        let xor: Vec<u8> = (7..target.len())
+            .map(|i| target[i] ^ source.get(i).copied().unwrap_or(0))
+            .collect();
+        patch.extend_from_slice(&xor);
+        patch.push(0);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+
+        let err = apply(&patch, b"Hello, world").unwrap_err();
+        assert!(matches!(err, PatchError::SourceChecksum { .. }));
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        let err = apply(&corrupt, &source).unwrap_err();
+        assert!(matches!(err, PatchError::PatchChecksum { .. }));
+    }
+
+    #[test]
+    fn test_crafted_sizes() {
+        let source = b"source".to_vec();
+        for magic in [b"UPS1", b"BPS1"].iter() {
+            let mut patch = magic.to_vec();
+            varint(source.len(), &mut patch);
+            varint(1 << 46, &mut patch);
+            varint(0, &mut patch);
+            let patch = with_footer(patch, &source, b"");
+            assert_eq!(
+                apply(&patch, &source).unwrap_err(),
+                PatchError::TargetSize { size: 1 << 46 }
+            );
+        }
+
+        // A skip that overflows the target position
+        let mut patch = b"UPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(source.len(), &mut patch);
+        varint(usize::MAX / 2, &mut patch);
+        patch.push(0);
+        varint(usize::MAX / 2, &mut patch);
+        patch.push(0);
+        let patch = with_footer(patch, &source, &source);
+        assert_eq!(apply(&patch, &source).unwrap_err(), PatchError::OutOfBounds);
+
+        // A source copy whose end overflows
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(4, &mut patch);
+        varint(0, &mut patch);
+        varint((3 << 2) | 2, &mut patch);
+        varint(usize::MAX & !1, &mut patch);
+        let patch = with_footer(patch, &source, b"sour");
+        assert_eq!(apply(&patch, &source).unwrap_err(), PatchError::OutOfBounds);
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"abcdefgh".to_vec();
+        let target = b"abcdXYXYXYefgh".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(3, &mut patch);
+        patch.extend_from_slice(b"xml");
+        // Source read 4, target read "XY", target copy 4 from offset 4, source copy 4 from 4
+        varint((4 - 1) << 2, &mut patch);
+        varint((2 - 1) << 2 | 1, &mut patch);
+        patch.extend_from_slice(b"XY");
+        varint((4 - 1) << 2 | 3, &mut patch);
+        varint(4 << 1, &mut patch);
+        varint((4 - 1) << 2 | 2, &mut patch);
+        varint(4 << 1, &mut patch);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+
+        let err = apply(&patch, b"abcdefghi").unwrap_err();
+        assert!(matches!(err, PatchError::SourceChecksum { .. }));
+    }
+}
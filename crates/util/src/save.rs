@@ -0,0 +1,169 @@
+use crate::gba::Rom;
+use std::fmt;
+
+/*
+ * Backup save type detection.
+ *
+ * The Nintendo SDK links a library for the backup chip into the game, and each library carries
+ * an ID string like "FLASH1M_V103" at a word aligned address. Games that are detected wrong, or
+ * that contain the string without using the chip, are listed in OVERRIDES by game code.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaveType {
+    None,
+    Sram,
+    Flash64K,
+    Flash128K,
+    // 512 bytes or 8KB, the size is found when the game first accesses it.
+    Eeprom,
+}
+
+impl fmt::Display for SaveType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SaveType::None => "none",
+            SaveType::Sram => "SRAM 32KB",
+            SaveType::Flash64K => "Flash 64KB",
+            SaveType::Flash128K => "Flash 128KB",
+            SaveType::Eeprom => "EEPROM",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DetectionSource {
+    Override,
+    // The library ID string found in the ROM.
+    Signature(String),
+    // Nothing found, the game has no backup chip.
+    Default,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveDetection {
+    pub save_type: SaveType,
+    pub source: DetectionSource,
+}
+
+const SIGNATURES: [(&[u8], SaveType); 6] = [
+    (b"FLASH1M_V", SaveType::Flash128K),
+    (b"FLASH512_V", SaveType::Flash64K),
+    (b"FLASH_V", SaveType::Flash64K),
+    (b"EEPROM_V", SaveType::Eeprom),
+    (b"SRAM_F_V", SaveType::Sram),
+    (b"SRAM_V", SaveType::Sram),
+];
+
+pub const OVERRIDES: [(&str, SaveType); 14] = [
+    // Pokemon Ruby, Sapphire, Emerald, FireRed, LeafGreen
+    ("AXVE", SaveType::Flash128K),
+    ("AXPE", SaveType::Flash128K),
+    ("BPEE", SaveType::Flash128K),
+    ("BPRE", SaveType::Flash128K),
+    ("BPGE", SaveType::Flash128K),
+    // Boktai
+    ("U3IE", SaveType::Eeprom),
+    // Yoshi Topsy-Turvy
+    ("KYGE", SaveType::Eeprom),
+    // WarioWare Twisted
+    ("RZWE", SaveType::Sram),
+    // Drill Dozer
+    ("V49E", SaveType::Sram),
+    // Classic NES Series: Bomberman, Super Mario Bros.
+    ("FBME", SaveType::Eeprom),
+    ("FSME", SaveType::Eeprom),
+    // Dragon Ball Z: The Legacy of Goku II
+    ("ALFE", SaveType::Eeprom),
+    // Top Gun: Combat Zones and Iridion II contain an ID but have no backup chip
+    ("A2YE", SaveType::None),
+    ("AI2E", SaveType::None),
+];
+
+// The ID string at offset, up to the first byte that is not printable.
+fn id_string(data: &[u8], offset: usize) -> String {
+    let end = data[offset..]
+        .iter()
+        .take(16)
+        .position(|b| !b.is_ascii_graphic())
+        .map_or(data.len().min(offset + 16), |n| offset + n);
+    String::from_utf8_lossy(&data[offset..end]).into_owned()
+}
+
+pub fn detect(rom: &Rom) -> SaveDetection {
+    detect_image(rom.data())
+}
+
+// Same as detect, for an image with the header at the start that was not parsed into a Rom.
+pub fn detect_image(data: &[u8]) -> SaveDetection {
+    let code = data.get(0xAC..0xB0);
+    if let Some(&(_, save_type)) = OVERRIDES.iter().find(|(c, _)| code == Some(c.as_bytes())) {
+        return SaveDetection {
+            save_type,
+            source: DetectionSource::Override,
+        };
+    }
+
+    for offset in (0..data.len()).step_by(4) {
+        let rest = &data[offset..];
+        if let Some(&(_, save_type)) = SIGNATURES.iter().find(|(id, _)| rest.starts_with(id)) {
+            return SaveDetection {
+                save_type,
+                source: DetectionSource::Signature(id_string(data, offset)),
+            };
+        }
+    }
+    SaveDetection {
+        save_type: SaveType::None,
+        source: DetectionSource::Default,
+    }
+}
+
+impl Rom {
+    pub fn detect_save_type(&self) -> SaveDetection {
+        detect(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with(code: &[u8; 4], at: usize, id: &[u8]) -> Rom {
+        let mut data = vec![0; 0x400];
+        data[0xAC..0xB0].copy_from_slice(code);
+        data[at..at + id.len()].copy_from_slice(id);
+        Rom::from_bytes(data).unwrap()
+    }
+
+    #[test]
+    fn test_detect() {
+        let cases: [(&[u8], SaveType); 6] = [
+            (b"SRAM_V113", SaveType::Sram),
+            (b"SRAM_F_V100", SaveType::Sram),
+            (b"FLASH_V126", SaveType::Flash64K),
+            (b"FLASH512_V131", SaveType::Flash64K),
+            (b"FLASH1M_V103", SaveType::Flash128K),
+            (b"EEPROM_V124", SaveType::Eeprom),
+        ];
+        for &(id, save_type) in cases.iter() {
+            let detection = rom_with(b"TEST", 0x200, id).detect_save_type();
+            assert_eq!(detection.save_type, save_type);
+            assert_eq!(
+                detection.source,
+                DetectionSource::Signature(String::from_utf8(id.to_vec()).unwrap())
+            );
+        }
+
+        // Only word aligned IDs count
+        let rom = rom_with(b"TEST", 0x202, b"SRAM_V113");
+        assert_eq!(rom.detect_save_type().save_type, SaveType::None);
+        assert_eq!(rom.detect_save_type().source, DetectionSource::Default);
+
+        // The override wins over the ID string
+        let rom = rom_with(b"AI2E", 0x200, b"EEPROM_V124");
+        let detection = rom.detect_save_type();
+        assert_eq!(detection.save_type, SaveType::None);
+        assert_eq!(detection.source, DetectionSource::Override);
+    }
+}
@@ -1,3 +1,135 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process;
+use util::archive::Container;
+use util::elf::Elf;
+use util::gba::{FixOptions, ImageKind, Rom};
+use util::save::DetectionSource;
+
+const USAGE: &str = "usage:
+    gameboyrustance info <rom> [zip entry]
+    gameboyrustance fix <rom> [-e zip entry] [-t title] [-c game code] [-m maker code] [-r version] [-p] [-o output]";
+
+// Prints the header fields, the header check results and the detected save type.
+fn info(args: &[String]) -> Result<(), String> {
+    let input = args.first().ok_or("missing ROM file")?;
+    let entry = args.get(1).map(String::as_str);
+    let rom = Rom::open_entry(input, entry).map_err(|e| format!("{}: {}", input, e))?;
+    println!("title:      {}", rom.game_title());
+    println!("game code:  {}", rom.game_code());
+    println!("maker code: {}", rom.maker_code());
+    println!("version:    {}", rom.software_version());
+    println!("size:       {} bytes", rom.data().len());
+    let kind = match rom.kind() {
+        ImageKind::Cartridge => "cartridge",
+        ImageKind::Multiboot => "multiboot",
+    };
+    println!("image:      {}", kind);
+    if !rom.symbols().is_empty() {
+        println!("symbols:    {}", rom.symbols().len());
+    }
+    let save = rom.detect_save_type();
+    let source = match save.source {
+        DetectionSource::Override => "override".to_string(),
+        DetectionSource::Signature(id) => id,
+        DetectionSource::Default => "no signature".to_string(),
+    };
+    println!("save type:  {} ({})", save.save_type, source);
+    let report = rom.validate();
+    if report.is_valid() {
+        println!("header:     ok");
+    }
+    for issue in report.issues.iter() {
+        println!("header:     {}", issue);
+    }
+    Ok(())
+}
+
+// A plain ROM image, not packed in an archive or built from an ELF file.
+fn is_raw_rom(path: &Path) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(4);
+    File::open(path)?.take(4).read_to_end(&mut magic)?;
+    Ok(Container::detect(&magic) == Container::Raw && !Elf::is_elf(&magic))
+}
+
+/*
+ * gbafix replacement: inserts the logo, sets the header fields given on the command line,
+ * recomputes the complement check and optionally pads the image to a power of two. The ROM is
+ * fixed in place unless an output file is given, which is needed for ROMs in zip or gzip files
+ * and for ELF files. Patches next to the ROM are not applied, the unpatched image is fixed.
+ */
+fn fix(args: &[String]) -> Result<(), String> {
+    let mut options = FixOptions::default();
+    let mut input = None;
+    let mut output = None;
+    let mut entry = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-e" => entry = Some(value()?),
+            "-t" => options.title = Some(value()?),
+            "-c" => options.game_code = Some(value()?),
+            "-m" => options.maker_code = Some(value()?),
+            "-r" => {
+                let version = value()?;
+                options.version = Some(
+                    version
+                        .parse()
+                        .map_err(|_| format!("invalid version {}", version))?,
+                );
+            }
+            "-p" => options.pad = true,
+            "-o" => output = Some(value()?),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let input = input.ok_or("missing ROM file")?;
+    let mut rom =
+        Rom::open_unpatched(&input, entry.as_deref()).map_err(|e| format!("{}: {}", input, e))?;
+    rom.fix(&options);
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let raw = is_raw_rom(Path::new(&input)).map_err(|e| format!("{}: {}", input, e))?;
+            if !raw {
+                return Err(format!(
+                    "{}: archived and ELF ROMs need an output file (-o)",
+                    input
+                ));
+            }
+            input
+        }
+    };
+    rom.save(&output)
+        .map_err(|e| format!("{}: {}", output, e))?;
+    println!(
+        "{}: {} [{}] checksum 0x{:02x}",
+        output,
+        rom.game_title(),
+        rom.game_code(),
+        rom.header().checksum
+    );
+    Ok(())
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("info") => info(&args[2..]),
+        Some("fix") => fix(&args[2..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
 }